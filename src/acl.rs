@@ -0,0 +1,166 @@
+use std::{io, net::IpAddr, str::FromStr};
+
+use crate::{
+    context::RequestContext,
+    dnssec::canonical_name,
+    label::{labels_from_str, DNSLabel},
+};
+
+/// Decides which clients may do something, such as transfer a zone: those with an
+/// address in one of a set of networks, and those that signed their request with one of
+/// a set of TSIG keys. An empty list allows no one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Acl {
+    networks: Vec<(IpAddr, u8)>,
+    keys: Vec<Vec<DNSLabel>>,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows the clients in the network `address`/`prefix_len`.
+    pub fn with_network(mut self, address: IpAddr, prefix_len: u8) -> Self {
+        self.networks.push((address.to_canonical(), prefix_len));
+        self
+    }
+
+    /// Allows the client with this address.
+    pub fn with_address(self, address: IpAddr) -> Self {
+        let address = address.to_canonical();
+        let prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        self.with_network(address, prefix_len)
+    }
+
+    /// Allows the clients that sign their requests with the TSIG key `name`.
+    pub fn with_key(mut self, name: &[DNSLabel]) -> Self {
+        self.keys.push(canonical_name(name));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty() && self.keys.is_empty()
+    }
+
+    /// Whether the client that sent a request is allowed, by its address or by the TSIG
+    /// key the request was verified with
+    pub fn allows(&self, context: &RequestContext) -> bool {
+        let address = context.client_addr.ip().to_canonical();
+        self.networks
+            .iter()
+            .any(|(network, prefix_len)| in_network(address, *network, *prefix_len))
+            || context
+                .tsig_key
+                .as_ref()
+                .is_some_and(|key| self.keys.contains(&canonical_name(key)))
+    }
+}
+
+/// Parses a comma-separated list of addresses, networks such as `192.0.2.0/24`, and
+/// TSIG keys such as `key transfer.example`.
+impl FromStr for Acl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut acl = Acl::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            acl = match entry.strip_prefix("key ") {
+                Some(name) => {
+                    let name = labels_from_str(name.trim().trim_end_matches('.'))
+                        .map_err(|e| invalid(format!("invalid key name: {}", e)))?;
+                    acl.with_key(&name)
+                }
+                None => {
+                    let (address, prefix_len) = match entry.split_once('/') {
+                        Some((address, prefix_len)) => (address, Some(prefix_len)),
+                        None => (entry, None),
+                    };
+                    let address: IpAddr = address
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid address {:?}", address)))?;
+                    match prefix_len {
+                        Some(prefix_len) => {
+                            let max = if address.is_ipv4() { 32 } else { 128 };
+                            let prefix_len = prefix_len
+                                .parse()
+                                .ok()
+                                .filter(|prefix_len| *prefix_len <= max)
+                                .ok_or_else(|| invalid(format!("invalid network {:?}", entry)))?;
+                            acl.with_network(address, prefix_len)
+                        }
+                        None => acl.with_address(address),
+                    }
+                }
+            };
+        }
+        Ok(acl)
+    }
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    let (address, network, bits) = match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            (u32::from(address) as u128, u32::from(network) as u128, 32)
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            (u128::from(address), u128::from(network), 128)
+        }
+        _ => return false,
+    };
+    let prefix_len = (prefix_len as u32).min(bits);
+    let mask = match prefix_len {
+        0 => 0,
+        prefix_len => u128::MAX << (bits - prefix_len) & (u128::MAX >> (128 - bits)),
+    };
+    address & mask == network & mask
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Transport;
+
+    fn context(client: &str, tsig_key: Option<&str>) -> RequestContext {
+        let mut context = RequestContext::new(
+            client.parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Tcp,
+            vec![],
+        );
+        context.tsig_key = tsig_key.map(|key| labels_from_str(key).unwrap());
+        context
+    }
+
+    #[test]
+    fn test_allows_networks_and_keys() {
+        let acl: Acl = "192.0.2.0/24, 2001:db8::1, key Transfer.Example"
+            .parse()
+            .unwrap();
+
+        assert!(acl.allows(&context("192.0.2.77:5300", None)));
+        assert!(acl.allows(&context("[::ffff:192.0.2.1]:5300", None)));
+        assert!(!acl.allows(&context("192.0.3.1:5300", None)));
+        assert!(acl.allows(&context("[2001:db8::1]:5300", None)));
+        assert!(!acl.allows(&context("[2001:db8::2]:5300", None)));
+        assert!(acl.allows(&context("198.51.100.1:5300", Some("transfer.example"))));
+        assert!(!acl.allows(&context("198.51.100.1:5300", Some("other.example"))));
+
+        assert!(!Acl::new().allows(&context("192.0.2.1:5300", None)));
+        assert!("192.0.2.0/33".parse::<Acl>().is_err());
+    }
+}
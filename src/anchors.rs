@@ -0,0 +1,612 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use log::{info, warn};
+
+use crate::{
+    dnssec::{
+        ds_matches, format_timestamp, key_tag, parse_timestamp, verify_rrset, FLAG_REVOKE, FLAG_SEP,
+    },
+    label::{is_subdomain, labels_to_string, DNSLabel},
+    resourcerecord::ResourceRecordType,
+    response::{DnsRecordData, DnsResourceRecord},
+    zone::parse_master_file,
+};
+
+// Trust anchors and their automated updates, see RFC 5011
+
+// The hold-down times of RFC 5011, section 2.4.1
+const DEFAULT_ADD_HOLD_DOWN: Duration = Duration::from_secs(30 * 86400);
+const DEFAULT_REMOVE_HOLD_DOWN: Duration = Duration::from_secs(30 * 86400);
+
+// Bounds on the active refresh interval, RFC 5011 section 2.3
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+const MAX_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 86400);
+
+/// The state of a managed trust anchor key (RFC 5011, section 4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnchorState {
+    /// A new key that becomes trusted once it has been seen for the add hold-down time
+    AddPend,
+    /// A trusted key
+    Valid,
+    /// A trusted key that the zone no longer publishes
+    Missing,
+    /// A key the zone has revoked, which is forgotten after the remove hold-down time
+    Revoked,
+}
+
+impl AnchorState {
+    fn is_trusted(self) -> bool {
+        matches!(self, AnchorState::Valid | AnchorState::Missing)
+    }
+}
+
+impl fmt::Display for AnchorState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnchorState::AddPend => "ADDPEND",
+            AnchorState::Valid => "VALID",
+            AnchorState::Missing => "MISSING",
+            AnchorState::Revoked => "REVOKED",
+        })
+    }
+}
+
+impl FromStr for AnchorState {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ADDPEND" => Ok(AnchorState::AddPend),
+            "VALID" => Ok(AnchorState::Valid),
+            "MISSING" => Ok(AnchorState::Missing),
+            "REVOKED" => Ok(AnchorState::Revoked),
+            _ => Err(invalid(format!("unknown trust anchor state {:?}", s))),
+        }
+    }
+}
+
+/// A key of a zone whose trust is tracked after RFC 5011
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManagedKey {
+    pub zone: Vec<DNSLabel>,
+    /// The DNSKEY RDATA, with the REVOKE flag once the key is revoked
+    pub dnskey: DnsRecordData,
+    pub state: AnchorState,
+    /// When the key entered its state
+    pub since: u32,
+}
+
+/// The trust anchors of a validating resolver: DS or DNSKEY records of the zones that
+/// chains of trust start from.
+///
+/// Anchors are static unless they are managed with [`TrustAnchors::managed`]: the
+/// resolver then follows the DNSKEY RRsets of the anchored zones after RFC 5011, so
+/// that keys the zones introduce become trusted after the add hold-down time and keys
+/// they revoke are no longer trusted. The state of the managed keys is kept in a file
+/// and takes the place of the configured anchors once it exists.
+pub struct TrustAnchors {
+    // Configured anchors of zones that have no managed keys yet
+    configured: Vec<DnsResourceRecord>,
+    managed: Vec<ManagedKey>,
+    state_file: Option<PathBuf>,
+    add_hold_down: u32,
+    remove_hold_down: u32,
+}
+
+impl Default for TrustAnchors {
+    fn default() -> Self {
+        TrustAnchors {
+            configured: vec![],
+            managed: vec![],
+            state_file: None,
+            add_hold_down: DEFAULT_ADD_HOLD_DOWN.as_secs() as u32,
+            remove_hold_down: DEFAULT_REMOVE_HOLD_DOWN.as_secs() as u32,
+        }
+    }
+}
+
+impl TrustAnchors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses trust anchors from DS and DNSKEY records in master file format, such as
+    /// `. IN DS 20326 8 2 E06D44B8...`. Relative names are relative to the root.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut anchors = Self::new();
+        for record in parse_master_file(text, &[])? {
+            anchors.add(record)?;
+        }
+        Ok(anchors)
+    }
+
+    /// Loads trust anchors from a file, see [`TrustAnchors::parse`].
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Adds `anchor`, a DS or DNSKEY record.
+    pub fn add(&mut self, anchor: DnsResourceRecord) -> io::Result<()> {
+        if !matches!(
+            anchor.rdata,
+            DnsRecordData::DS { .. } | DnsRecordData::DNSKEY { .. }
+        ) {
+            return Err(invalid(format!(
+                "a trust anchor is a DS or DNSKEY record, not {}",
+                anchor.rtype
+            )));
+        }
+        self.configured.push(anchor);
+        Ok(())
+    }
+
+    /// Manages the anchors after RFC 5011, keeping their state in `state_file`. If the
+    /// file exists, the keys in it replace the configured anchors of their zones.
+    pub fn managed(mut self, state_file: impl AsRef<Path>) -> io::Result<Self> {
+        let state_file = state_file.as_ref().to_path_buf();
+        match fs::read_to_string(&state_file) {
+            Ok(text) => {
+                self.managed = parse_state(&text)
+                    .map_err(|e| invalid(format!("{}: {}", state_file.display(), e)))?;
+                let managed = &self.managed;
+                self.configured
+                    .retain(|anchor| !managed.iter().any(|key| key.zone == anchor.name));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.state_file = Some(state_file);
+        Ok(self)
+    }
+
+    /// How long a new key has to be seen before it is trusted, and how long a revoked
+    /// key is remembered, 30 days each by default
+    pub fn with_hold_down(mut self, add: Duration, remove: Duration) -> Self {
+        self.add_hold_down = add.as_secs().min(u32::MAX as u64) as u32;
+        self.remove_hold_down = remove.as_secs().min(u32::MAX as u64) as u32;
+        self
+    }
+
+    /// Whether there are no anchors at all. Zones whose keys have all been revoked
+    /// still count, so that they fail validation rather than pass as unsigned.
+    pub fn is_empty(&self) -> bool {
+        self.configured.is_empty() && self.managed.is_empty()
+    }
+
+    pub fn is_managed(&self) -> bool {
+        self.state_file.is_some()
+    }
+
+    pub fn managed_keys(&self) -> &[ManagedKey] {
+        &self.managed
+    }
+
+    /// The zones with trust anchors
+    pub fn zones(&self) -> Vec<Vec<DNSLabel>> {
+        let mut zones: Vec<Vec<DNSLabel>> = Vec::new();
+        let names = self
+            .configured
+            .iter()
+            .map(|anchor| &anchor.name)
+            .chain(self.managed.iter().map(|key| &key.zone));
+        for name in names {
+            if !zones.contains(name) {
+                zones.push(name.clone());
+            }
+        }
+        zones
+    }
+
+    /// Whether `name` lies at or below a zone with trust anchors
+    pub fn covers(&self, name: &[DNSLabel]) -> bool {
+        self.zones().iter().any(|zone| is_subdomain(name, zone))
+    }
+
+    /// The DS and DNSKEY RDATA that the keys of `zone` are authenticated against
+    pub fn entry_points(&self, zone: &[DNSLabel]) -> Vec<DnsRecordData> {
+        self.configured
+            .iter()
+            .filter(|anchor| anchor.name == zone)
+            .map(|anchor| anchor.rdata.clone())
+            .chain(
+                self.managed
+                    .iter()
+                    .filter(|key| key.zone == zone && key.state.is_trusted())
+                    .map(|key| key.dnskey.clone()),
+            )
+            .collect()
+    }
+
+    /// Takes in the DNSKEY RRset of `zone` with its signatures, as seen at `now`, and
+    /// moves the managed keys along their states (RFC 5011, section 4). An RRset that
+    /// no trusted key signs changes nothing, apart from revoking keys that sign it with
+    /// their REVOKE flag set. Returns whether anything changed, after saving the state.
+    pub fn update(
+        &mut self,
+        zone: &[DNSLabel],
+        dnskeys: &[DnsResourceRecord],
+        rrsigs: &[DnsResourceRecord],
+        now: u32,
+    ) -> io::Result<bool> {
+        if !self.is_managed() {
+            return Ok(false);
+        }
+        let keys: Vec<&DnsResourceRecord> = dnskeys
+            .iter()
+            .filter(|key| key.name == zone && key.rtype == ResourceRecordType::DNSKEY)
+            .collect();
+        let mut changed = false;
+
+        // A key revokes itself by signing the RRset with the REVOKE flag set
+        for key in keys
+            .iter()
+            .filter(|key| flags(&key.rdata) & FLAG_REVOKE != 0)
+        {
+            if verify_rrset(dnskeys, rrsigs, std::slice::from_ref(*key), now).is_none() {
+                continue;
+            }
+            let revoked = match self.trusted_key(zone, key) {
+                Some(revoked) => revoked,
+                None => continue,
+            };
+            info!(
+                "trust anchor {} of {}. has been revoked",
+                key_tag(&key.rdata),
+                labels_to_string(zone)
+            );
+            self.managed
+                .retain(|managed| !same_key(&managed.dnskey, &key.rdata));
+            self.configured
+                .retain(|anchor| anchor.name != zone || anchor.rdata != revoked);
+            self.managed.push(ManagedKey {
+                zone: zone.to_vec(),
+                dnskey: key.rdata.clone(),
+                state: AnchorState::Revoked,
+                since: now,
+            });
+            changed = true;
+        }
+
+        // Everything else takes an RRset that a trusted key signs
+        let trusted: Vec<DnsResourceRecord> = keys
+            .iter()
+            .filter(|key| self.trusted_key(zone, key).is_some())
+            .map(|key| (*key).clone())
+            .collect();
+        if verify_rrset(dnskeys, rrsigs, &trusted, now).is_none() {
+            if changed {
+                self.save()?;
+            }
+            return Ok(changed);
+        }
+
+        for key in &keys {
+            if flags(&key.rdata) & (FLAG_SEP | FLAG_REVOKE) != FLAG_SEP {
+                continue;
+            }
+            let tag = key_tag(&key.rdata);
+            let configured = self
+                .configured
+                .iter()
+                .any(|anchor| anchor.name == zone && matches_anchor(&anchor.rdata, key));
+            let add_hold_down = self.add_hold_down;
+            match self
+                .managed
+                .iter_mut()
+                .find(|managed| managed.zone == zone && same_key(&managed.dnskey, &key.rdata))
+            {
+                Some(managed) => match managed.state {
+                    AnchorState::Missing => {
+                        managed.state = AnchorState::Valid;
+                        managed.since = now;
+                        changed = true;
+                    }
+                    AnchorState::AddPend
+                        if now.wrapping_sub(managed.since) as i32 >= add_hold_down as i32 =>
+                    {
+                        info!(
+                            "trust anchor {} of {}. is now trusted",
+                            tag,
+                            labels_to_string(zone)
+                        );
+                        managed.state = AnchorState::Valid;
+                        managed.since = now;
+                        changed = true;
+                    }
+                    _ => {}
+                },
+                None => {
+                    // Keys the configured anchors refer to are trusted right away
+                    let state = match configured {
+                        true => AnchorState::Valid,
+                        false => AnchorState::AddPend,
+                    };
+                    info!(
+                        "new trust anchor {} of {}.: {}",
+                        tag,
+                        labels_to_string(zone),
+                        state
+                    );
+                    self.managed.push(ManagedKey {
+                        zone: zone.to_vec(),
+                        dnskey: key.rdata.clone(),
+                        state,
+                        since: now,
+                    });
+                    changed = true;
+                }
+            }
+        }
+
+        // Keys that have gone are missing if trusted, and start over if pending
+        let remove_hold_down = self.remove_hold_down;
+        let before = self.managed.len();
+        self.managed.retain(|managed| {
+            let published = keys.iter().any(|key| same_key(&managed.dnskey, &key.rdata));
+            match managed.state {
+                AnchorState::AddPend => managed.zone != zone || published,
+                AnchorState::Revoked => {
+                    (now.wrapping_sub(managed.since) as i32) < remove_hold_down as i32
+                }
+                _ => true,
+            }
+        });
+        changed |= self.managed.len() != before;
+        for managed in &mut self.managed {
+            let published = keys.iter().any(|key| same_key(&managed.dnskey, &key.rdata));
+            if managed.zone == zone && managed.state == AnchorState::Valid && !published {
+                warn!(
+                    "trust anchor {} of {}. is missing",
+                    key_tag(&managed.dnskey),
+                    labels_to_string(zone)
+                );
+                managed.state = AnchorState::Missing;
+                managed.since = now;
+                changed = true;
+            }
+        }
+
+        // The managed keys take over from the configured anchors
+        let before = self.configured.len();
+        self.configured.retain(|anchor| anchor.name != zone);
+        changed |= self.configured.len() != before;
+
+        if changed {
+            self.save()?;
+        }
+        Ok(changed)
+    }
+
+    // The configured anchor or managed key that makes `key` trusted, ignoring the
+    // REVOKE flag of `key`
+    fn trusted_key(&self, zone: &[DNSLabel], key: &DnsResourceRecord) -> Option<DnsRecordData> {
+        let mut unrevoked = key.clone();
+        if let DnsRecordData::DNSKEY { flags, .. } = &mut unrevoked.rdata {
+            *flags &= !FLAG_REVOKE;
+        }
+        self.entry_points(zone)
+            .into_iter()
+            .find(|entry_point| matches_anchor(entry_point, &unrevoked))
+    }
+
+    /// Writes the state of the managed keys to the state file.
+    pub fn save(&self) -> io::Result<()> {
+        let state_file = match &self.state_file {
+            Some(state_file) => state_file,
+            None => return Ok(()),
+        };
+        let mut text = String::from("; RFC 5011 trust anchor state: zone, state, since, key\n");
+        for key in &self.managed {
+            text.push_str(&format!(
+                "{}. {} {} DNSKEY {}\n",
+                labels_to_string(&key.zone),
+                key.state,
+                format_timestamp(key.since),
+                key.dnskey
+            ));
+        }
+        // Written aside and renamed, so that a crash does not lose the anchors
+        let mut temporary = state_file.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, text)?;
+        fs::rename(&temporary, state_file)
+    }
+}
+
+/// How long to wait before fetching the DNSKEY RRset of an anchored zone again: half
+/// its original TTL or of the validity left to its signatures, between an hour and 15
+/// days (RFC 5011, section 2.3)
+pub fn refresh_interval(
+    dnskeys: &[DnsResourceRecord],
+    rrsigs: &[DnsResourceRecord],
+    now: u32,
+) -> Duration {
+    let ttl = dnskeys.iter().map(|key| key.ttl).min();
+    let expiration = rrsigs
+        .iter()
+        .filter_map(|rrsig| match &rrsig.rdata {
+            DnsRecordData::RRSIG(rrsig) if rrsig.type_covered == ResourceRecordType::DNSKEY => {
+                Some(rrsig.expiration.wrapping_sub(now).min(i32::MAX as u32))
+            }
+            _ => None,
+        })
+        .min();
+    let interval = ttl.into_iter().chain(expiration).min().unwrap_or(0) / 2;
+    Duration::from_secs(interval as u64).clamp(MIN_REFRESH_INTERVAL, MAX_REFRESH_INTERVAL)
+}
+
+fn flags(dnskey: &DnsRecordData) -> u16 {
+    match dnskey {
+        DnsRecordData::DNSKEY { flags, .. } => *flags,
+        _ => 0,
+    }
+}
+
+// Whether two DNSKEY RDATA hold the same key, whatever their flags
+fn same_key(a: &DnsRecordData, b: &DnsRecordData) -> bool {
+    match (a, b) {
+        (
+            DnsRecordData::DNSKEY {
+                algorithm,
+                public_key,
+                ..
+            },
+            DnsRecordData::DNSKEY {
+                algorithm: other_algorithm,
+                public_key: other_key,
+                ..
+            },
+        ) => algorithm == other_algorithm && public_key == other_key,
+        _ => false,
+    }
+}
+
+// Whether the DS or DNSKEY RDATA `anchor` refers to `key`
+fn matches_anchor(anchor: &DnsRecordData, key: &DnsResourceRecord) -> bool {
+    match anchor {
+        DnsRecordData::DS { .. } => ds_matches(anchor, key),
+        _ => *anchor == key.rdata,
+    }
+}
+
+fn parse_state(text: &str) -> io::Result<Vec<ManagedKey>> {
+    let mut keys = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(4, char::is_whitespace).collect();
+        let (zone, state, since, dnskey) = match fields.as_slice() {
+            [zone, state, since, dnskey] => (zone, state, since, dnskey),
+            _ => return Err(invalid(format!("line {}: missing fields", index + 1))),
+        };
+        let record = parse_master_file(&format!("{} 0 IN {}", zone, dnskey), &[])?
+            .pop()
+            .filter(|record| record.rtype == ResourceRecordType::DNSKEY)
+            .ok_or_else(|| invalid(format!("line {}: no DNSKEY record", index + 1)))?;
+        keys.push(ManagedKey {
+            zone: record.name,
+            dnskey: record.rdata,
+            state: state.parse()?,
+            since: parse_timestamp(since)?,
+        });
+    }
+    Ok(keys)
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dnssec::{
+        ds_record, SigningKey, ALGORITHM_ECDSAP256SHA256, DIGEST_SHA256, FLAG_ZONE,
+    };
+    use crate::label::labels_from_str;
+
+    const DAY: u32 = 86400;
+
+    // The DNSKEY RRset of `zone` with `keys`, signed by the keys with the SEP flag
+    fn dnskey_rrset(
+        zone: &[DNSLabel],
+        keys: &[&SigningKey],
+        now: u32,
+    ) -> (Vec<DnsResourceRecord>, Vec<DnsResourceRecord>) {
+        let rrset: Vec<DnsResourceRecord> = keys
+            .iter()
+            .map(|key| key.dnskey_record(zone, 3600))
+            .collect();
+        let rrsigs = keys
+            .iter()
+            .filter(|key| key.is_key_signing())
+            .map(|key| {
+                key.sign_rrset(&rrset, zone, now - DAY, now + 10 * DAY)
+                    .unwrap()
+            })
+            .collect();
+        (rrset, rrsigs)
+    }
+
+    #[test]
+    fn test_tracks_keys_after_rfc5011() {
+        let dir = std::env::temp_dir().join(format!("dns-anchors-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let state_file = dir.join("anchors.state");
+
+        let zone = labels_from_str("example").unwrap();
+        let flags = FLAG_ZONE | FLAG_SEP;
+        let old = SigningKey::generate(ALGORITHM_ECDSAP256SHA256, flags).unwrap();
+        let new = SigningKey::generate(ALGORITHM_ECDSAP256SHA256, flags).unwrap();
+        let revoked =
+            SigningKey::from_pkcs8(ALGORITHM_ECDSAP256SHA256, flags | FLAG_REVOKE, old.pkcs8())
+                .unwrap();
+        let ds = ds_record(&old.dnskey_record(&zone, 3600), DIGEST_SHA256).unwrap();
+        let mut anchors = TrustAnchors::parse(&format!("{}\n", ds))
+            .unwrap()
+            .managed(&state_file)
+            .unwrap();
+        let mut now = 1_700_000_000;
+
+        // The key the configured DS refers to is trusted right away
+        let (rrset, rrsigs) = dnskey_rrset(&zone, &[&old], now);
+        assert!(anchors.update(&zone, &rrset, &rrsigs, now).unwrap());
+        assert_eq!(anchors.managed_keys().len(), 1);
+        assert_eq!(anchors.managed_keys()[0].state, AnchorState::Valid);
+        assert_eq!(anchors.entry_points(&zone), vec![old.dnskey()]);
+
+        // A new key waits out the add hold-down
+        let (rrset, rrsigs) = dnskey_rrset(&zone, &[&old, &new], now);
+        assert!(anchors.update(&zone, &rrset, &rrsigs, now).unwrap());
+        assert_eq!(anchors.managed_keys()[1].state, AnchorState::AddPend);
+        now += 29 * DAY;
+        let (rrset, rrsigs) = dnskey_rrset(&zone, &[&old, &new], now);
+        assert!(!anchors.update(&zone, &rrset, &rrsigs, now).unwrap());
+        now += DAY;
+        let (rrset, rrsigs) = dnskey_rrset(&zone, &[&old, &new], now);
+        assert!(anchors.update(&zone, &rrset, &rrsigs, now).unwrap());
+        assert_eq!(anchors.managed_keys()[1].state, AnchorState::Valid);
+
+        // A key that nothing trusts cannot change the anchors
+        let rogue = SigningKey::generate(ALGORITHM_ECDSAP256SHA256, flags).unwrap();
+        let (rrset, rrsigs) = dnskey_rrset(&zone, &[&rogue], now);
+        assert!(!anchors.update(&zone, &rrset, &rrsigs, now).unwrap());
+        assert_eq!(anchors.entry_points(&zone).len(), 2);
+
+        // The old key revokes itself and is forgotten after the remove hold-down
+        let (rrset, rrsigs) = dnskey_rrset(&zone, &[&revoked, &new], now);
+        assert!(anchors.update(&zone, &rrset, &rrsigs, now).unwrap());
+        assert_eq!(anchors.entry_points(&zone), vec![new.dnskey()]);
+        let states: Vec<AnchorState> = anchors.managed_keys().iter().map(|key| key.state).collect();
+        assert_eq!(states, vec![AnchorState::Valid, AnchorState::Revoked]);
+
+        // The state survives a restart and takes the place of the configured DS
+        let reloaded = TrustAnchors::parse(&format!("{}\n", ds))
+            .unwrap()
+            .managed(&state_file)
+            .unwrap();
+        assert_eq!(reloaded.managed_keys(), anchors.managed_keys());
+        assert_eq!(reloaded.entry_points(&zone), vec![new.dnskey()]);
+
+        now += 30 * DAY;
+        let (rrset, rrsigs) = dnskey_rrset(&zone, &[&new], now);
+        assert!(anchors.update(&zone, &rrset, &rrsigs, now).unwrap());
+        assert_eq!(anchors.managed_keys().len(), 1);
+        assert_eq!(anchors.managed_keys()[0].dnskey, new.dnskey());
+        assert_eq!(refresh_interval(&rrset, &rrsigs, now), MIN_REFRESH_INTERVAL);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
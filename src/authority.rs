@@ -0,0 +1,1562 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use futures::future::join_all;
+use log::{info, warn};
+use tokio::{
+    sync::Notify,
+    task::{AbortHandle, JoinSet},
+};
+
+use crate::{
+    acl::Acl,
+    context::{RequestContext, Transport},
+    dnssec::{base32hex_encode, label_count, nsec3_hash, NSEC3_HASH_SHA1},
+    edns::Edns,
+    handler::{DnsRequestError, DnsRequestHandler},
+    journal::{Journal, ZoneDiff},
+    label::{is_subdomain, labels_from_str, labels_to_string, CanonicalName, DNSLabel},
+    notify::Notifier,
+    request::{DNSRequest, OPCODE_NOTIFY, OPCODE_UPDATE},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord, DnsResponse},
+    secondary::SecondaryZone,
+    signer::{OnlineDenial, OnlineSigner},
+    update::{apply_updates, bump_serial, check_prerequisites, check_updates, UpdatePolicy},
+    zone::{Lookup, Zone},
+};
+
+// Upper bound on CNAME and DNAME records followed within the served zones
+const MAX_CHAIN_LENGTH: usize = 8;
+// The type code of the NXNAME pseudo-type of compact denial (RFC 9824)
+const NXNAME: u16 = 128;
+
+/// Answers authoritatively from a set of zones. Each question is answered from the zone
+/// with the longest origin that contains it; questions outside of all zones are
+/// refused.
+///
+/// Aliases are followed as long as their targets lie in one of the zones. Negative
+/// answers carry the zone's SOA record for negative caching (RFC 2308), and names in
+/// delegated child zones get a referral with the glue the zone holds.
+///
+/// Clients that set the DO bit get the signatures and NSEC or NSEC3 proofs of signed
+/// zones. Zones are either signed ahead of time, or signed online as they are served
+/// by an [`OnlineSigner`].
+///
+/// Zones can be transferred by the clients their transfer ACL allows, whole (AXFR) over
+/// TCP, or incrementally (IXFR) for zones with a journal that records their changes.
+///
+/// Secondary zones are copied from their primaries by [`SecondaryZone`] tasks, see
+/// [`AuthoritativeHandler::maintain_secondaries`], which NOTIFY messages from the
+/// primaries wake up. In turn, the secondaries of zones that change are notified, see
+/// [`AuthoritativeHandler::send_notifies`].
+///
+/// Zones with an update policy accept UPDATE messages (RFC 2136) from the clients it
+/// allows, which are recorded in the zone's journal like any other change.
+#[derive(Default)]
+pub struct AuthoritativeHandler {
+    zones: RwLock<Vec<Arc<Zone>>>,
+    signers: RwLock<HashMap<Vec<DNSLabel>, Arc<OnlineSigner>>>,
+    transfer_acls: RwLock<HashMap<Vec<DNSLabel>, Acl>>,
+    journals: Mutex<HashMap<Vec<DNSLabel>, Journal>>,
+    secondaries: RwLock<HashMap<Vec<DNSLabel>, Arc<SecondaryZone>>>,
+    also_notify: RwLock<HashMap<Vec<DNSLabel>, Vec<SocketAddr>>>,
+    // The zones that changed since their secondaries were last notified
+    changed: Mutex<Vec<Vec<DNSLabel>>>,
+    zone_changed: Notify,
+    update_policies: RwLock<HashMap<Vec<DNSLabel>, UpdatePolicy>>,
+    // Held while an UPDATE is applied, so that concurrent ones do not undo each other
+    updating: Mutex<()>,
+}
+
+impl AuthoritativeHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_zone(self, zone: Zone) -> Self {
+        self.add_zone(zone);
+        self
+    }
+
+    /// Signs the answers from the zone `origin` online with `signer`.
+    pub fn with_online_signing(self, origin: &[DNSLabel], signer: OnlineSigner) -> Self {
+        self.set_signer(origin, Some(signer));
+        self
+    }
+
+    /// Lets the clients `acl` allows transfer the zone `origin`.
+    pub fn with_transfer_acl(self, origin: &[DNSLabel], acl: Acl) -> Self {
+        self.set_transfer_acl(origin, Some(acl));
+        self
+    }
+
+    /// Sets who may transfer the zone `origin`; with `None`, no one may.
+    pub fn set_transfer_acl(&self, origin: &[DNSLabel], acl: Option<Acl>) {
+        let mut acls = self.transfer_acls.write().unwrap();
+        match acl {
+            Some(acl) => acls.insert(origin.to_vec(), acl),
+            None => acls.remove(origin),
+        };
+    }
+
+    /// Serves `zone`, replacing a zone with the same origin. A zone that is signed
+    /// online gets its DNSKEY, CDS and CDNSKEY records from its signer.
+    pub fn add_zone(&self, mut zone: Zone) {
+        if let Some(signer) = self.signer(zone.origin()) {
+            add_apex_records(&mut zone, &signer);
+        }
+        let mut zones = self.zones.write().unwrap();
+        if let Some(old) = zones
+            .iter()
+            .find(|existing| existing.origin() == zone.origin())
+        {
+            self.record_changes(old, &zone);
+        }
+        let old_serial = zones
+            .iter()
+            .find(|existing| existing.origin() == zone.origin())
+            .map(|existing| existing.serial());
+        if old_serial != Some(zone.serial()) {
+            let mut changed = self.changed.lock().unwrap();
+            if !changed.iter().any(|origin| origin == zone.origin()) {
+                changed.push(zone.origin().to_vec());
+            }
+            self.zone_changed.notify_one();
+        }
+        zones.retain(|existing| existing.origin() != zone.origin());
+        zones.push(Arc::new(zone));
+    }
+
+    /// Lets the clients `policy` allows change the zone `origin` with UPDATE messages.
+    pub fn with_update_policy(self, origin: &[DNSLabel], policy: UpdatePolicy) -> Self {
+        self.update_policies
+            .write()
+            .unwrap()
+            .insert(origin.to_vec(), policy);
+        self
+    }
+
+    /// Applies an UPDATE message (RFC 2136) to the zone it names, if its prerequisites
+    /// hold and the zone's update policy allows all of its changes, and increments the
+    /// serial unless the update sets a newer SOA record itself. Either all changes are
+    /// made or none. Zones that are signed but have no online signer cannot be updated.
+    pub fn update(
+        &self,
+        request: &DNSRequest,
+        context: &RequestContext,
+    ) -> Result<DnsResponse, DnsRequestError> {
+        let question = match request.questions.as_slice() {
+            [question] if question.qtype == ResourceRecordType::SOA => question,
+            _ => return Err(DnsRequestError::FormErr),
+        };
+        let _updating = self.updating.lock().unwrap();
+        let zone = self
+            .zone(&question.qname)
+            .filter(|zone| zone_class(zone) == Some(question.qclass))
+            .ok_or(DnsRequestError::NotAuth)?;
+        let zone_name = labels_to_string(zone.origin());
+        // Secondaries would have to forward updates to their primary
+        if self.secondary(zone.origin()).is_some() {
+            return Err(DnsRequestError::NotImp);
+        }
+        // Without an online signer the signatures of a signed zone would go stale
+        if self.signer(zone.origin()).is_none()
+            && zone
+                .records()
+                .any(|record| record.rtype == ResourceRecordType::RRSIG)
+        {
+            warn!(
+                "Refused update of {}. from {}: the zone is signed offline",
+                zone_name, context.client_addr
+            );
+            return Err(DnsRequestError::NotImp);
+        }
+
+        check_prerequisites(&zone, &request.answers)?;
+        check_updates(&zone, &request.authority)?;
+        let key = context.tsig_key.as_deref();
+        let allowed = self
+            .update_policies
+            .read()
+            .unwrap()
+            .get(zone.origin())
+            .is_some_and(|policy| {
+                request
+                    .authority
+                    .iter()
+                    .all(|record| policy.allows(key, &record.name, record.rtype))
+            });
+        if !allowed {
+            warn!(
+                "Refused update of {}. from {} with key {}",
+                zone_name,
+                context.client_addr,
+                key.map_or("(none)".to_string(), |key| format!(
+                    "{}.",
+                    labels_to_string(key)
+                ))
+            );
+            return Err(DnsRequestError::Refused);
+        }
+
+        let mut updated = Zone::clone(&zone);
+        if apply_updates(&mut updated, &request.authority) {
+            let serial = zone.serial().unwrap_or_default();
+            if updated.serial().unwrap_or_default().wrapping_sub(serial) as i32 <= 0 {
+                bump_serial(&mut updated);
+            }
+            info!(
+                "Updated {}. to serial {} for {}",
+                zone_name,
+                updated.serial().unwrap_or_default(),
+                context.client_addr
+            );
+            self.add_zone(updated);
+        }
+        Ok(DnsResponse::reply_to(request))
+    }
+
+    /// Notifies `targets` of changes to the zone `origin`, along with its NS targets.
+    pub fn with_also_notify(self, origin: &[DNSLabel], targets: Vec<SocketAddr>) -> Self {
+        self.also_notify
+            .write()
+            .unwrap()
+            .insert(origin.to_vec(), targets);
+        self
+    }
+
+    /// Sends NOTIFY messages with `notifier` for each zone that is loaded or changes
+    /// its serial, including the zones loaded before. A NOTIFY still being retried when
+    /// the zone changes again is replaced by one for the new version. Runs until the
+    /// task is dropped, which stops the NOTIFY messages in flight.
+    pub async fn send_notifies(self: Arc<Self>, notifier: Notifier) {
+        let notifier = Arc::new(notifier);
+        let mut notifies = JoinSet::new();
+        let mut in_flight: HashMap<(Vec<DNSLabel>, SocketAddr), AbortHandle> = HashMap::new();
+        loop {
+            while notifies.try_join_next().is_some() {}
+            in_flight.retain(|_, notify| !notify.is_finished());
+            let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+            for zone in changed.iter().filter_map(|origin| self.zone(origin)) {
+                let also_notify = self
+                    .also_notify
+                    .read()
+                    .unwrap()
+                    .get(zone.origin())
+                    .cloned()
+                    .unwrap_or_default();
+                let targets = notifier.targets(&self, &zone, &also_notify).await;
+                for target in targets {
+                    let notifier = notifier.clone();
+                    let zone = zone.clone();
+                    let origin = zone.origin().to_vec();
+                    let notify =
+                        notifies.spawn(async move { notifier.notify(&zone, target).await });
+                    if let Some(previous) = in_flight.insert((origin, target), notify) {
+                        previous.abort();
+                    }
+                }
+            }
+            self.zone_changed.notified().await;
+        }
+    }
+
+    /// Serves `secondary` once it has been transferred from its primary.
+    pub fn with_secondary(self, secondary: SecondaryZone) -> Self {
+        self.secondaries
+            .write()
+            .unwrap()
+            .insert(secondary.origin().to_vec(), Arc::new(secondary));
+        self
+    }
+
+    pub fn secondary(&self, origin: &[DNSLabel]) -> Option<Arc<SecondaryZone>> {
+        self.secondaries.read().unwrap().get(origin).cloned()
+    }
+
+    /// Keeps the secondary zones up to date with their primaries, see
+    /// [`SecondaryZone::maintain`]. Runs until the task is dropped.
+    pub async fn maintain_secondaries(self: Arc<Self>) {
+        let secondaries: Vec<_> = self.secondaries.read().unwrap().values().cloned().collect();
+        join_all(
+            secondaries
+                .into_iter()
+                .map(|secondary| secondary.maintain(self.clone())),
+        )
+        .await;
+    }
+
+    /// Answers a NOTIFY message (RFC 1996) for a secondary zone from its primary, and
+    /// makes the zone check the primary for a new version.
+    pub fn notify(
+        &self,
+        request: &DNSRequest,
+        context: &RequestContext,
+    ) -> Result<DnsResponse, DnsRequestError> {
+        let question = match request.questions.as_slice() {
+            [question] if question.qtype == ResourceRecordType::SOA => question,
+            _ => return Err(DnsRequestError::FormErr),
+        };
+        let secondary = self
+            .secondary(&question.qname)
+            .ok_or(DnsRequestError::NotAuth)?;
+        if !secondary.accepts_notify(context) {
+            warn!(
+                "Refused NOTIFY for {}. from {}",
+                labels_to_string(secondary.origin()),
+                context.client_addr
+            );
+            return Err(DnsRequestError::Refused);
+        }
+        info!(
+            "Received NOTIFY for {}. from {}",
+            labels_to_string(secondary.origin()),
+            context.client_addr
+        );
+        secondary.notify();
+        let mut response = DnsResponse::reply_to(request);
+        response.header.flags |= 0x0400; // AA
+        Ok(response)
+    }
+
+    /// Records the changes of the zone `origin` in `journal` from now on, to answer
+    /// IXFR requests with.
+    pub fn with_journal(self, origin: &[DNSLabel], journal: Journal) -> Self {
+        self.journals
+            .lock()
+            .unwrap()
+            .insert(origin.to_vec(), journal);
+        self
+    }
+
+    // Adds the changes from `old` to `new` to the journal of the zone, if it has one
+    fn record_changes(&self, old: &Zone, new: &Zone) {
+        let mut journals = self.journals.lock().unwrap();
+        let journal = match journals.get_mut(old.origin()) {
+            Some(journal) => journal,
+            None => return,
+        };
+        let recorded = match ZoneDiff::between(old, new) {
+            Some(diff) => journal.push(diff),
+            // The journal cannot lead clients from their version to this one
+            None if old.serial() != new.serial() => journal.clear(),
+            None => Ok(()),
+        };
+        if let Err(e) = recorded {
+            warn!(
+                "Failed to record the changes of {}. in its journal: {}",
+                labels_to_string(old.origin()),
+                e
+            );
+        }
+    }
+
+    /// Signs the answers from the zone `origin` online with `signer` from now on, or no
+    /// longer with `None`. The DNSKEY records of the signer replace those of the zone,
+    /// so a new signer with the keys of the next step of a rollover takes effect at
+    /// once.
+    pub fn set_signer(&self, origin: &[DNSLabel], signer: Option<OnlineSigner>) {
+        let mut signers = self.signers.write().unwrap();
+        match signer {
+            Some(signer) => signers.insert(origin.to_vec(), Arc::new(signer)),
+            None => signers.remove(origin),
+        };
+        drop(signers);
+        if let Some(zone) = self.zone(origin) {
+            self.add_zone(Zone::clone(&zone));
+        }
+    }
+
+    /// The online signer of the zone `origin`
+    pub fn signer(&self, origin: &[DNSLabel]) -> Option<Arc<OnlineSigner>> {
+        self.signers.read().unwrap().get(origin).cloned()
+    }
+
+    pub fn remove_zone(&self, origin: &[DNSLabel]) -> Option<Arc<Zone>> {
+        let mut zones = self.zones.write().unwrap();
+        let index = zones.iter().position(|zone| zone.origin() == origin)?;
+        Some(zones.remove(index))
+    }
+
+    /// The zone with exactly this origin
+    pub fn zone(&self, origin: &[DNSLabel]) -> Option<Arc<Zone>> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .find(|zone| zone.origin() == origin)
+            .cloned()
+    }
+
+    /// The closest zone containing `name`
+    pub fn find_zone(&self, name: &[DNSLabel]) -> Option<Arc<Zone>> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|zone| is_subdomain(name, zone.origin()))
+            .max_by_key(|zone| zone.origin().len())
+            .cloned()
+    }
+
+    pub fn answer(&self, request: &DNSRequest) -> Result<DnsResponse, DnsRequestError> {
+        let question = match request.questions.as_slice() {
+            [question] => question,
+            _ => return Err(DnsRequestError::FormErr),
+        };
+        if request.header.flags & 0x7800 != 0 {
+            return Err(DnsRequestError::NotImp);
+        }
+        // Transfers depend on who asks, see `transfer`
+        if matches!(
+            question.qtype,
+            ResourceRecordType::AXFR | ResourceRecordType::IXFR
+        ) {
+            return Err(DnsRequestError::Refused);
+        }
+        let mut zone = self
+            .zone_for(&question.qname, question.qtype)
+            .filter(|zone| zone_class(zone) == Some(question.qclass))
+            .ok_or(DnsRequestError::Refused)?;
+        let dnssec_ok = request.edns().is_some_and(|edns| edns.dnssec_ok);
+        let mut signer = self.signer(zone.origin());
+
+        let mut response = DnsResponse::reply_to(request);
+        response.header.flags |= 0x0400; // AA
+        if dnssec_ok {
+            response.set_edns(Edns {
+                dnssec_ok: true,
+                ..Edns::default()
+            });
+        }
+
+        let mut name = question.qname.clone();
+        for _ in 0..=MAX_CHAIN_LENGTH {
+            let dnssec = Dnssec {
+                zone: &zone,
+                signer: signer.as_deref(),
+                enabled: dnssec_ok,
+            };
+            let target = match zone.lookup(&name, question.qtype) {
+                Lookup::Answer(records) => {
+                    let records = dnssec.signed(records);
+                    response
+                        .authority
+                        .extend(dnssec.wildcard_proof(&name, &records));
+                    response.answers.extend(records);
+                    break;
+                }
+                Lookup::Cname(cname) => {
+                    let target = alias_target(&cname);
+                    let records = dnssec.signed(vec![cname]);
+                    response
+                        .authority
+                        .extend(dnssec.wildcard_proof(&name, &records));
+                    response.answers.extend(records);
+                    target
+                }
+                Lookup::Dname { dname, cname } => {
+                    let target = alias_target(&cname);
+                    response.answers.extend(dnssec.signed(vec![dname]));
+                    response.answers.push(cname);
+                    target
+                }
+                Lookup::Referral { ns, glue } => {
+                    // After an alias the resolver follows the referral by itself
+                    if response.answers.is_empty() {
+                        response.header.flags &= !0x0400;
+                        if dnssec_ok {
+                            let cut = ns[0].name.clone();
+                            let ds = zone.rrset(&cut, ResourceRecordType::DS);
+                            if ds.is_empty() {
+                                response
+                                    .authority
+                                    .extend(dnssec.denial(&cut, Proof::Delegation));
+                            } else {
+                                response.authority.extend(dnssec.signed(ds));
+                            }
+                        }
+                        response.authority.splice(0..0, ns);
+                        response.additional = glue;
+                    }
+                    break;
+                }
+                Lookup::NoData => {
+                    response
+                        .authority
+                        .extend(dnssec.signed(negative_soa(&zone).into_iter().collect()));
+                    if dnssec_ok {
+                        response
+                            .authority
+                            .extend(dnssec.denial(&name, Proof::NoData));
+                    }
+                    break;
+                }
+                Lookup::NxDomain => {
+                    // RFC 6604: the RCODE describes the last name of the chain. Compact
+                    // denial answers as if the name existed.
+                    if !dnssec.is_compact() {
+                        response.set_response_code(DnsRequestError::NXDomain.to_response_code());
+                    }
+                    response
+                        .authority
+                        .extend(dnssec.signed(negative_soa(&zone).into_iter().collect()));
+                    if dnssec_ok {
+                        response
+                            .authority
+                            .extend(dnssec.denial(&name, Proof::NxDomain));
+                    }
+                    break;
+                }
+            };
+
+            match target.and_then(|target| Some((self.find_zone(&target)?, target))) {
+                Some((next, target)) if zone_class(&next) == Some(question.qclass) => {
+                    signer = self.signer(next.origin());
+                    zone = next;
+                    name = target;
+                }
+                _ => break,
+            }
+        }
+
+        response.add_svcb_additionals(|name, rtype| match self.find_zone(name) {
+            Some(zone) => match zone.lookup(name, rtype) {
+                Lookup::Answer(records) => records,
+                _ => vec![],
+            },
+            None => vec![],
+        });
+        Ok(response)
+    }
+
+    /// Answers a zone transfer request. AXFR (RFC 5936) gets all records of the zone,
+    /// starting and ending with its SOA record, which the server spreads over as many
+    /// messages as they need. IXFR (RFC 1995) gets the changes since the client's
+    /// serial from the zone's journal, or all records if the journal does not reach
+    /// back that far.
+    ///
+    /// Transfers to clients the zone's transfer ACL does not allow are refused, and so
+    /// is AXFR over UDP. An IXFR answer that does not fit into a UDP response is cut
+    /// down to the current SOA record, which tells the client to ask again over TCP.
+    pub fn transfer(
+        &self,
+        request: &DNSRequest,
+        context: &RequestContext,
+    ) -> Result<DnsResponse, DnsRequestError> {
+        let question = match request.questions.as_slice() {
+            [question] => question,
+            _ => return Err(DnsRequestError::FormErr),
+        };
+        let zone = self
+            .zone(&question.qname)
+            .filter(|zone| zone_class(zone) == Some(question.qclass))
+            .ok_or(DnsRequestError::NotAuth)?;
+        // The client's version of the zone
+        let client_serial = match question.qtype {
+            ResourceRecordType::IXFR => Some(
+                request
+                    .authority
+                    .iter()
+                    .find_map(|record| match record.rdata {
+                        DnsRecordData::SOA { serial, .. } => Some(serial),
+                        _ => None,
+                    })
+                    .ok_or(DnsRequestError::FormErr)?,
+            ),
+            _ => None,
+        };
+        let allowed = self
+            .transfer_acls
+            .read()
+            .unwrap()
+            .get(zone.origin())
+            .is_some_and(|acl| acl.allows(context));
+        let udp = context.transport == Transport::Udp;
+        if !allowed || (udp && client_serial.is_none()) {
+            warn!(
+                "Refused {} of {}. to {} over {}",
+                question.qtype,
+                labels_to_string(zone.origin()),
+                context.client_addr,
+                context.transport
+            );
+            return Err(DnsRequestError::Refused);
+        }
+        let soa = zone.soa().ok_or(DnsRequestError::ServFail)?.clone();
+        let serial = zone.serial().unwrap_or_default();
+
+        let mut response = DnsResponse::reply_to(request);
+        response.header.flags |= 0x0400; // AA
+        let journal = self.journals.lock().unwrap();
+        let diffs = client_serial.and_then(|client_serial| {
+            match serial.wrapping_sub(client_serial) as i32 <= 0 {
+                true => Some(&[][..]),
+                false => journal.get(zone.origin())?.since(client_serial),
+            }
+        });
+        match diffs {
+            Some(diffs) => {
+                info!(
+                    "Sending {} changes of {}. up to serial {} to {}",
+                    diffs.len(),
+                    labels_to_string(zone.origin()),
+                    serial,
+                    context.client_addr
+                );
+                // Up to date clients only get the SOA record
+                let current = diffs.last().map_or(&soa, |diff| &diff.new_soa);
+                response.answers.push(current.clone());
+                if !diffs.is_empty() {
+                    response
+                        .answers
+                        .extend(diffs.iter().flat_map(ZoneDiff::records).cloned());
+                    response.answers.push(current.clone());
+                }
+            }
+            None => {
+                info!(
+                    "Transferring {}. with serial {} to {}",
+                    labels_to_string(zone.origin()),
+                    serial,
+                    context.client_addr
+                );
+                response.answers.push(soa.clone());
+                response.answers.extend(
+                    zone.records()
+                        .filter(|record| record.rtype != ResourceRecordType::SOA)
+                        .cloned(),
+                );
+                response.answers.push(soa.clone());
+            }
+        }
+        drop(journal);
+
+        if udp {
+            let limit = request
+                .edns()
+                .map_or(512, |edns| edns.udp_payload_size.max(512))
+                as usize;
+            if response
+                .to_bytes()
+                .map_or(true, |bytes| bytes.len() > limit)
+            {
+                response.answers = vec![soa];
+            }
+        }
+        Ok(response)
+    }
+
+    // The zone that answers for `name`. DS records live on the parent side of a zone
+    // cut, so they are looked up above a zone apex if the parent is served as well.
+    fn zone_for(&self, name: &[DNSLabel], qtype: ResourceRecordType) -> Option<Arc<Zone>> {
+        if qtype == ResourceRecordType::DS && !name.is_empty() {
+            if let Some(parent) = self.find_zone(&name[1..]) {
+                return Some(parent);
+            }
+        }
+        self.find_zone(name)
+    }
+}
+
+impl DnsRequestHandler for AuthoritativeHandler {
+    fn handle_request(
+        self: Arc<Self>,
+        request: DNSRequest,
+        context: RequestContext,
+    ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+        let result = match request.questions.first() {
+            _ if request.opcode() == OPCODE_NOTIFY => self.notify(&request, &context),
+            _ if request.opcode() == OPCODE_UPDATE => self.update(&request, &context),
+            Some(question)
+                if matches!(
+                    question.qtype,
+                    ResourceRecordType::AXFR | ResourceRecordType::IXFR
+                ) =>
+            {
+                self.transfer(&request, &context)
+            }
+            _ => self.answer(&request),
+        };
+        Box::pin(async move { result })
+    }
+}
+
+fn zone_class(zone: &Zone) -> Option<DnsClass> {
+    zone.soa().map(|soa| soa.class)
+}
+
+fn alias_target(record: &DnsResourceRecord) -> Option<Vec<DNSLabel>> {
+    match &record.rdata {
+        DnsRecordData::CNAME(target) => labels_from_str(target).ok(),
+        _ => None,
+    }
+}
+
+// What the NSEC or NSEC3 records of a negative answer have to prove
+#[derive(Clone, Copy)]
+enum Proof {
+    NxDomain,
+    NoData,
+    /// The name does not exist itself but matched a wildcard with this many labels
+    Wildcard(u8),
+    /// The delegation has no DS records
+    Delegation,
+}
+
+// Adds the DNSSEC records of `zone` to answers, if the client asked for them
+struct Dnssec<'a> {
+    zone: &'a Zone,
+    signer: Option<&'a OnlineSigner>,
+    enabled: bool,
+}
+
+impl Dnssec<'_> {
+    // `records` followed by the signatures of their RRsets
+    fn signed(&self, records: Vec<DnsResourceRecord>) -> Vec<DnsResourceRecord> {
+        if !self.enabled {
+            return records;
+        }
+        let mut signatures = Vec::new();
+        let mut seen: Vec<(&[DNSLabel], ResourceRecordType)> = Vec::new();
+        for record in &records {
+            if seen.contains(&(&record.name, record.rtype)) {
+                continue;
+            }
+            seen.push((&record.name, record.rtype));
+            match self.signer {
+                Some(signer) => signatures.extend(self.sign_online(signer, record, &records)),
+                None => signatures.extend(self.presigned_signatures(record)),
+            }
+        }
+        [records, signatures].concat()
+    }
+
+    // The RRSIG records the zone holds for the RRset of `record`
+    fn presigned_signatures(&self, record: &DnsResourceRecord) -> Vec<DnsResourceRecord> {
+        // Signatures of wildcard expansions are renamed by the lookup like the records
+        let rrsigs = match self.zone.node(&record.name) {
+            Some(_) => self.zone.rrset(&record.name, ResourceRecordType::RRSIG),
+            None => match self.zone.lookup(&record.name, ResourceRecordType::RRSIG) {
+                Lookup::Answer(rrsigs) => rrsigs,
+                _ => vec![],
+            },
+        };
+        rrsigs
+            .into_iter()
+            .filter(|rrsig| {
+                matches!(&rrsig.rdata, DnsRecordData::RRSIG(data) if data.type_covered == record.rtype)
+            })
+            .collect()
+    }
+
+    // Signs the RRset of `record` among `records`. RRsets of the zone are signed as the
+    // zone holds them, so that SOA records with the TTL of negative answers and
+    // wildcard expansions share their signatures with the originals.
+    fn sign_online(
+        &self,
+        signer: &OnlineSigner,
+        record: &DnsResourceRecord,
+        records: &[DnsResourceRecord],
+    ) -> Vec<DnsResourceRecord> {
+        let zone = self.zone;
+        let wildcard = wildcard_of(zone.closest_encloser(&record.name));
+        let expanded = match zone.name_exists(&record.name) {
+            true => vec![],
+            false => zone.rrset(&wildcard, record.rtype),
+        };
+        let rrset = match zone.rrset(&record.name, record.rtype) {
+            rrset if !rrset.is_empty() => rrset,
+            _ if !expanded.is_empty() => expanded,
+            _ => records
+                .iter()
+                .filter(|other| other.name == record.name && other.rtype == record.rtype)
+                .cloned()
+                .collect(),
+        };
+
+        match signer.sign(&rrset, zone.origin()) {
+            Ok(rrsigs) => rrsigs
+                .into_iter()
+                .map(|rrsig| DnsResourceRecord {
+                    name: record.name.clone(),
+                    ..rrsig
+                })
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "Failed to sign {} {}: {}",
+                    labels_to_string(&record.name),
+                    record.rtype,
+                    e
+                );
+                vec![]
+            }
+        }
+    }
+
+    // The proof that `name` does not exist, if `records` were expanded from a wildcard
+    fn wildcard_proof(
+        &self,
+        name: &[DNSLabel],
+        records: &[DnsResourceRecord],
+    ) -> Vec<DnsResourceRecord> {
+        let labels = records.iter().find_map(|record| match &record.rdata {
+            DnsRecordData::RRSIG(rrsig) if rrsig.labels < label_count(name) => Some(rrsig.labels),
+            _ => None,
+        });
+        match labels {
+            Some(labels) => self.denial(name, Proof::Wildcard(labels)),
+            None => vec![],
+        }
+    }
+
+    // The signed NSEC or NSEC3 records that prove `proof` for `name`
+    fn denial(&self, name: &[DNSLabel], proof: Proof) -> Vec<DnsResourceRecord> {
+        if !self.enabled {
+            return vec![];
+        }
+        let records = match self.signer {
+            Some(signer) => white_lies(signer.denial(), self.zone, name, proof),
+            None => presigned_denial(self.zone, name, proof),
+        };
+        self.signed(records)
+    }
+
+    // Whether names that do not exist are answered with compact denial
+    fn is_compact(&self) -> bool {
+        self.enabled
+            && self
+                .signer
+                .is_some_and(|signer| *signer.denial() == OnlineDenial::Compact)
+    }
+}
+
+// The NSEC or NSEC3 records of a zone signed ahead of time that prove `proof` for
+// `name` (RFC 4035, section 3.1.3, and RFC 5155, section 7.2)
+fn presigned_denial(zone: &Zone, name: &[DNSLabel], proof: Proof) -> Vec<DnsResourceRecord> {
+    let origin = zone.origin();
+    let nsec3param = zone
+        .rrset(origin, ResourceRecordType::NSEC3PARAM)
+        .into_iter()
+        .find_map(|record| match record.rdata {
+            DnsRecordData::NSEC3PARAM {
+                iterations, salt, ..
+            } => Some((iterations, salt)),
+            _ => None,
+        });
+    let records: Vec<Option<DnsResourceRecord>> = match nsec3param {
+        None => {
+            let matching = |name: &[DNSLabel]| zone.rrset(name, ResourceRecordType::NSEC).pop();
+            let covering =
+                |name: &[DNSLabel]| zone.preceding(name, ResourceRecordType::NSEC).cloned();
+            let wildcard = wildcard_of(zone.closest_encloser(name));
+            match proof {
+                Proof::NxDomain => vec![covering(name), covering(&wildcard)],
+                Proof::NoData if zone.node(name).is_some() => vec![matching(name)],
+                // An empty non-terminal or a wildcard match
+                Proof::NoData => vec![covering(name), matching(&wildcard)],
+                Proof::Wildcard(_) => vec![covering(name)],
+                Proof::Delegation => vec![matching(name)],
+            }
+        }
+        Some((iterations, salt)) => {
+            let hashed = |name: &[DNSLabel]| {
+                let mut hashed =
+                    vec![
+                        DNSLabel::new(&base32hex_encode(&nsec3_hash(name, &salt, iterations)))
+                            .unwrap(),
+                    ];
+                hashed.extend_from_slice(origin);
+                hashed
+            };
+            let matching =
+                |name: &[DNSLabel]| zone.rrset(&hashed(name), ResourceRecordType::NSEC3).pop();
+            let covering = |name: &[DNSLabel]| {
+                zone.preceding(&hashed(name), ResourceRecordType::NSEC3)
+                    .cloned()
+            };
+            // The closest provable encloser, which may be the name itself
+            let encloser = (origin.len()..=name.len())
+                .rev()
+                .map(|depth| &name[name.len() - depth..])
+                .find(|ancestor| matching(ancestor).is_some())
+                .unwrap_or(origin);
+            let next_closer = &name[name.len() - (encloser.len() + 1).min(name.len())..];
+            let closest_encloser_proof = || vec![matching(encloser), covering(next_closer)];
+
+            match proof {
+                Proof::Wildcard(labels) => {
+                    vec![covering(
+                        &name[name.len() - (labels as usize + 1).min(name.len())..],
+                    )]
+                }
+                _ if encloser.len() == name.len() => vec![matching(name)],
+                Proof::NxDomain => {
+                    let mut records = closest_encloser_proof();
+                    records.push(covering(&wildcard_of(encloser)));
+                    records
+                }
+                Proof::NoData => {
+                    let mut records = closest_encloser_proof();
+                    records.push(matching(&wildcard_of(encloser)));
+                    records
+                }
+                // An opt-out span covers the delegation
+                Proof::Delegation => closest_encloser_proof(),
+            }
+        }
+    };
+
+    let mut proof: Vec<DnsResourceRecord> = Vec::new();
+    for record in records.into_iter().flatten() {
+        if !proof.contains(&record) {
+            proof.push(record);
+        }
+    }
+    proof
+}
+
+// NSEC or NSEC3 records made up for an online signed zone that prove `proof` for
+// `name` and cover no other name (RFC 4470 and RFC 7129, appendix B)
+fn white_lies(
+    denial: &OnlineDenial,
+    zone: &Zone,
+    name: &[DNSLabel],
+    proof: Proof,
+) -> Vec<DnsResourceRecord> {
+    let origin = zone.origin();
+    let soa = match zone.soa() {
+        Some(soa) => soa,
+        None => return vec![],
+    };
+    let ttl = match soa.rdata {
+        DnsRecordData::SOA { minimum, .. } => soa.ttl.min(minimum),
+        _ => soa.ttl,
+    };
+    let types_at = |name: &[DNSLabel]| {
+        let mut types: Vec<ResourceRecordType> = Vec::new();
+        for record in zone.node(name).unwrap_or_default() {
+            if !types.contains(&record.rtype) {
+                types.push(record.rtype);
+            }
+        }
+        types
+    };
+    let exists = zone.name_exists(name);
+    let encloser = zone.closest_encloser(name).to_vec();
+    let wildcard = wildcard_of(&encloser);
+
+    match denial {
+        OnlineDenial::Nsec | OnlineDenial::Compact => {
+            let nsec =
+                |owner: Vec<DNSLabel>, next: &[DNSLabel], mut types: Vec<ResourceRecordType>| {
+                    types.extend([ResourceRecordType::RRSIG, ResourceRecordType::NSEC]);
+                    DnsResourceRecord {
+                        name: owner,
+                        rtype: ResourceRecordType::NSEC,
+                        class: soa.class,
+                        ttl,
+                        rdata: DnsRecordData::NSEC {
+                            next_domain: labels_to_string(&successor(next)),
+                            types,
+                        },
+                    }
+                };
+            let matching = |name: &[DNSLabel], types| nsec(name.to_vec(), name, types);
+            // From just after the name before `name` to just after `name`
+            let covering = |name: &[DNSLabel]| {
+                let previous = zone.previous_name(name).unwrap_or(origin);
+                let encloser = zone.closest_encloser(name);
+                let previous =
+                    match CanonicalName(previous.to_vec()) < CanonicalName(encloser.to_vec()) {
+                        true => encloser,
+                        false => previous,
+                    };
+                nsec(successor(previous), name, vec![])
+            };
+
+            let compact = *denial == OnlineDenial::Compact;
+            match proof {
+                Proof::NxDomain if compact => {
+                    vec![matching(name, vec![ResourceRecordType::from(NXNAME)])]
+                }
+                Proof::NxDomain => vec![covering(name), covering(&wildcard)],
+                Proof::Delegation => vec![matching(name, types_at(name))],
+                Proof::NoData if exists => vec![matching(name, types_at(name))],
+                // A wildcard match without records of the type
+                Proof::NoData if compact => vec![matching(name, types_at(&wildcard))],
+                Proof::NoData => vec![covering(name), matching(&wildcard, types_at(&wildcard))],
+                Proof::Wildcard(_) => vec![covering(name)],
+            }
+        }
+        OnlineDenial::Nsec3 { iterations, salt } => {
+            let nsec3 = |hash: Vec<u8>, next_hashed: Vec<u8>, types| {
+                let mut owner = vec![DNSLabel::new(&base32hex_encode(&hash)).unwrap()];
+                owner.extend_from_slice(origin);
+                DnsResourceRecord {
+                    name: owner,
+                    rtype: ResourceRecordType::NSEC3,
+                    class: soa.class,
+                    ttl,
+                    rdata: DnsRecordData::NSEC3 {
+                        hash_algorithm: NSEC3_HASH_SHA1,
+                        flags: 0,
+                        iterations: *iterations,
+                        salt: salt.clone(),
+                        next_hashed,
+                        types,
+                    },
+                }
+            };
+            let matching = |name: &[DNSLabel]| {
+                let mut types = types_at(name);
+                let unsigned_delegation = name != origin
+                    && types.contains(&ResourceRecordType::NS)
+                    && !types.contains(&ResourceRecordType::DS);
+                // Empty non-terminals have no types at all
+                if !types.is_empty() && !unsigned_delegation {
+                    types.push(ResourceRecordType::RRSIG);
+                }
+                let hash = nsec3_hash(name, salt, *iterations);
+                nsec3(hash.clone(), add_to_hash(&hash, 1), types)
+            };
+            let covering = |name: &[DNSLabel]| {
+                let hash = nsec3_hash(name, salt, *iterations);
+                nsec3(add_to_hash(&hash, -1), add_to_hash(&hash, 1), vec![])
+            };
+            let next_closer = |labels: usize| &name[name.len() - (labels + 1).min(name.len())..];
+
+            match proof {
+                Proof::Delegation => vec![matching(name)],
+                Proof::NoData if exists => vec![matching(name)],
+                Proof::NxDomain => vec![
+                    matching(&encloser),
+                    covering(next_closer(encloser.len())),
+                    covering(&wildcard),
+                ],
+                Proof::NoData => vec![
+                    matching(&encloser),
+                    covering(next_closer(encloser.len())),
+                    matching(&wildcard),
+                ],
+                Proof::Wildcard(labels) => vec![covering(next_closer(labels as usize))],
+            }
+        }
+    }
+}
+
+// The name that sorts canonically right after `name`
+fn successor(name: &[DNSLabel]) -> Vec<DNSLabel> {
+    let mut successor = vec![DNSLabel::new("\0").unwrap()];
+    successor.extend_from_slice(name);
+    successor
+}
+
+// Adds `delta` to a hash as a big-endian number, wrapping around
+fn add_to_hash(hash: &[u8], delta: i8) -> Vec<u8> {
+    let mut hash = hash.to_vec();
+    for byte in hash.iter_mut().rev() {
+        let (value, carry) = match delta {
+            1 => byte.overflowing_add(1),
+            _ => byte.overflowing_sub(1),
+        };
+        *byte = value;
+        if !carry {
+            break;
+        }
+    }
+    hash
+}
+
+fn wildcard_of(encloser: &[DNSLabel]) -> Vec<DNSLabel> {
+    let mut wildcard = vec![DNSLabel::new("*").unwrap()];
+    wildcard.extend_from_slice(encloser);
+    wildcard
+}
+
+// Replaces the DNSKEY, CDS and CDNSKEY records at the apex of `zone` with those of
+// `signer`
+fn add_apex_records(zone: &mut Zone, signer: &OnlineSigner) {
+    let origin = zone.origin().to_vec();
+    for rtype in [
+        ResourceRecordType::DNSKEY,
+        ResourceRecordType::CDS,
+        ResourceRecordType::CDNSKEY,
+    ] {
+        zone.remove_rrset(&origin, rtype);
+    }
+    let ttl = zone.soa().map_or(3600, |soa| soa.ttl);
+    for record in signer.apex_records(&origin, ttl) {
+        // The record lies at the apex, so it always fits
+        let _ = zone.insert(record);
+    }
+}
+
+// The SOA record for negative answers, whose TTL limits how long they are cached
+fn negative_soa(zone: &Zone) -> Option<DnsResourceRecord> {
+    let mut soa = zone.soa()?.clone();
+    if let DnsRecordData::SOA { minimum, .. } = soa.rdata {
+        soa.ttl = soa.ttl.min(minimum);
+    }
+    Some(soa)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        client::{exchange_transfer, exchange_udp, transfer_query},
+        dnssec::{self, verify_rrset, SigningKey, ALGORITHM_ECDSAP256SHA256, FLAG_SEP, FLAG_ZONE},
+        journal::Transfer,
+        label::labels_from_str,
+        server::DnsServer,
+        tsig::{TsigAlgorithm, TsigKey},
+        validator::{prove_nodata, prove_nxdomain, prove_wildcard_expansion, Security},
+    };
+
+    const EXAMPLE_COM: &str = "
+$TTL 3600
+@       SOA ns hostmaster 1 7200 900 604800 300
+        NS  ns
+ns      A   192.0.2.53
+www     A   192.0.2.1
+alias   CNAME www
+other   CNAME www.example.net.
+sub     NS  ns.sub
+ns.sub  A   192.0.2.54
+*.wild  A   192.0.2.9
+";
+
+    const EXAMPLE_NET: &str = "
+$TTL 3600
+@       SOA ns hostmaster 1 7200 900 604800 300
+        NS  ns
+www     A   192.0.2.2
+";
+
+    fn handler() -> AuthoritativeHandler {
+        AuthoritativeHandler::new()
+            .with_zone(Zone::parse(EXAMPLE_COM, "example.com.").unwrap())
+            .with_zone(Zone::parse(EXAMPLE_NET, "example.net.").unwrap())
+    }
+
+    fn query(name: &str, qtype: ResourceRecordType) -> DNSRequest {
+        DNSRequest::query(name, qtype, DnsClass::IN)
+    }
+
+    #[test]
+    fn test_answers_and_follows_aliases_across_zones() {
+        let handler = handler();
+
+        let response = handler
+            .answer(&query("alias.example.com", ResourceRecordType::A))
+            .unwrap();
+        assert_ne!(response.header.flags & 0x0400, 0);
+        assert_eq!(response.answers.len(), 2);
+
+        let response = handler
+            .answer(&query("other.example.com", ResourceRecordType::A))
+            .unwrap();
+        assert_eq!(
+            response.answers[1].rdata,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 2))
+        );
+    }
+
+    #[test]
+    fn test_negative_answers_and_referrals() {
+        let handler = handler();
+
+        let response = handler
+            .answer(&query("nope.example.com", ResourceRecordType::A))
+            .unwrap();
+        assert_eq!(response.response_code(), 3);
+        assert_eq!(response.authority[0].rtype, ResourceRecordType::SOA);
+        assert_eq!(response.authority[0].ttl, 300);
+
+        let response = handler
+            .answer(&query("www.sub.example.com", ResourceRecordType::A))
+            .unwrap();
+        assert_eq!(response.header.flags & 0x0400, 0);
+        assert_eq!(response.authority[0].rtype, ResourceRecordType::NS);
+        assert_eq!(response.additional.len(), 1);
+
+        assert!(matches!(
+            handler.answer(&query("example.org", ResourceRecordType::A)),
+            Err(DnsRequestError::Refused)
+        ));
+    }
+
+    fn dnssec_query(name: &str, qtype: ResourceRecordType) -> DNSRequest {
+        let mut request = query(name, qtype);
+        request.additional.push(
+            Edns {
+                dnssec_ok: true,
+                ..Edns::default()
+            }
+            .to_record(),
+        );
+        request.header.arcount = 1;
+        request
+    }
+
+    // Checks the signature of every RRset in `records` and returns them without the
+    // signatures
+    fn verified(
+        records: &[DnsResourceRecord],
+        keys: &[DnsResourceRecord],
+    ) -> Vec<DnsResourceRecord> {
+        let unsigned: Vec<DnsResourceRecord> = records
+            .iter()
+            .filter(|record| record.rtype != ResourceRecordType::RRSIG)
+            .cloned()
+            .collect();
+        for record in &unsigned {
+            let rrset: Vec<DnsResourceRecord> = unsigned
+                .iter()
+                .filter(|other| other.name == record.name && other.rtype == record.rtype)
+                .cloned()
+                .collect();
+            let rrsigs = dnssec::signatures_for(records, &record.name, record.rtype);
+            assert!(
+                verify_rrset(&rrset, &rrsigs, keys, dnssec::now()).is_some(),
+                "{:?} is not signed",
+                record
+            );
+        }
+        unsigned
+    }
+
+    #[test]
+    fn test_signs_answers_and_denials_online() {
+        let origin = labels_from_str("example.com").unwrap();
+        let name = |name| labels_from_str(name).unwrap();
+        let denials = [
+            OnlineDenial::Nsec,
+            OnlineDenial::Nsec3 {
+                iterations: 0,
+                salt: vec![],
+            },
+            OnlineDenial::Compact,
+        ];
+
+        for denial in denials {
+            let keys = vec![
+                SigningKey::generate(ALGORITHM_ECDSAP256SHA256, FLAG_ZONE | FLAG_SEP).unwrap(),
+                SigningKey::generate(ALGORITHM_ECDSAP256SHA256, FLAG_ZONE).unwrap(),
+            ];
+            let handler = AuthoritativeHandler::new()
+                .with_zone(Zone::parse(EXAMPLE_COM, "example.com.").unwrap())
+                .with_online_signing(&origin, OnlineSigner::new(keys, denial.clone()));
+
+            let response = handler
+                .answer(&dnssec_query("example.com", ResourceRecordType::DNSKEY))
+                .unwrap();
+            let keys = verified(&response.answers, &response.answers);
+            assert_eq!(keys.len(), 2);
+            // Only the key-signing key signs the keys
+            assert_eq!(response.answers.len(), 3);
+
+            let response = handler
+                .answer(&dnssec_query("www.example.com", ResourceRecordType::A))
+                .unwrap();
+            assert_eq!(verified(&response.answers, &keys).len(), 1);
+            // Signatures come from the cache until they near their expiration
+            let again = handler
+                .answer(&dnssec_query("www.example.com", ResourceRecordType::A))
+                .unwrap();
+            assert_eq!(again.answers, response.answers);
+
+            let response = handler
+                .answer(&dnssec_query("a.wild.example.com", ResourceRecordType::A))
+                .unwrap();
+            verified(&response.answers, &keys);
+            let proof = verified(&response.authority, &keys);
+            assert_eq!(
+                prove_wildcard_expansion(&name("a.wild.example.com"), 3, &origin, &proof),
+                Security::Secure
+            );
+
+            let response = handler
+                .answer(&dnssec_query("nope.example.com", ResourceRecordType::A))
+                .unwrap();
+            let proof = verified(&response.authority, &keys);
+            let security = match denial {
+                OnlineDenial::Compact => {
+                    assert_eq!(response.response_code(), 0);
+                    prove_nodata(
+                        &name("nope.example.com"),
+                        ResourceRecordType::A,
+                        &origin,
+                        &proof,
+                    )
+                }
+                _ => {
+                    assert_eq!(response.response_code(), 3);
+                    prove_nxdomain(&name("nope.example.com"), &origin, &proof)
+                }
+            };
+            assert_eq!(security, Security::Secure);
+
+            for (qname, qtype) in [
+                ("www.example.com", ResourceRecordType::TXT),
+                ("wild.example.com", ResourceRecordType::A),
+                ("a.wild.example.com", ResourceRecordType::TXT),
+            ] {
+                let response = handler.answer(&dnssec_query(qname, qtype)).unwrap();
+                let proof = verified(&response.authority, &keys);
+                assert_eq!(
+                    prove_nodata(&name(qname), qtype, &origin, &proof),
+                    Security::Secure,
+                    "{} {} with {:?}",
+                    qname,
+                    qtype,
+                    denial
+                );
+            }
+
+            // Without DO the answers stay as they are
+            let response = handler
+                .answer(&query("nope.example.com", ResourceRecordType::A))
+                .unwrap();
+            assert_eq!(response.response_code(), 3);
+            assert_eq!(response.authority.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfers_zones_to_allowed_clients_over_tcp() {
+        let origin = labels_from_str("example.com").unwrap();
+        let key = TsigKey::new(
+            labels_from_str("transfer.example").unwrap(),
+            TsigAlgorithm::HmacSha256,
+            "shared secret",
+        );
+        // Enough records to need several messages
+        let mut text = EXAMPLE_COM.to_string();
+        for index in 0..2000 {
+            text.push_str(&format!("txt{} TXT \"{}\"\n", index, "x".repeat(40)));
+        }
+        let zone = Zone::parse(&text, "example.com.").unwrap();
+        let handler = AuthoritativeHandler::new()
+            .with_zone(zone.clone())
+            .with_transfer_acl(&origin, Acl::new().with_key(key.name()));
+        let server = DnsServer::new(handler).with_tsig_key(key.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = tokio::net::UdpSocket::bind(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(server);
+        let tcp_server = server.clone();
+        tokio::spawn(async move { tcp_server.serve_tcp(listener).await });
+        tokio::spawn(async move { server.serve(socket).await });
+
+        let query = query("example.com", ResourceRecordType::AXFR);
+        let timeout = Duration::from_secs(2);
+        let records = exchange_transfer(addr, &query, Some(&key), timeout)
+            .await
+            .unwrap();
+        assert_eq!(records.len(), zone.records().count() + 1);
+        assert_eq!(records[0].rtype, ResourceRecordType::SOA);
+        assert_eq!(records.last(), records.first());
+        let transferred = Zone::from_records(origin.clone(), records).unwrap();
+        assert_eq!(transferred.to_master_file(), zone.to_master_file());
+
+        // Without the key, with another one, and over UDP the transfer is refused
+        let error = exchange_transfer(addr, &query, None, timeout)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("RCODE 5"));
+        let other = TsigKey::new(key.name().to_vec(), TsigAlgorithm::HmacSha256, "guess");
+        assert!(exchange_transfer(addr, &query, Some(&other), timeout)
+            .await
+            .is_err());
+        let response = exchange_udp(addr, &query, timeout).await.unwrap();
+        assert_eq!(response.response_code(), 5);
+        assert!(response.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_answers_ixfr_from_the_journal() {
+        let origin = labels_from_str("example.com").unwrap();
+        let version = |serial, extra| Zone::version(EXAMPLE_COM, serial, extra);
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let handler = AuthoritativeHandler::new()
+            .with_zone(version(1, ""))
+            .with_journal(&origin, Journal::new())
+            .with_transfer_acl(&origin, Acl::new().with_address(localhost));
+        handler.add_zone(version(2, "new A 192.0.2.2\n"));
+        let v3 = version(3, "new A 192.0.2.3\nmail A 192.0.2.25\n");
+        handler.add_zone(v3.clone());
+
+        let server = Arc::new(DnsServer::new(handler));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = tokio::net::UdpSocket::bind(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp_server = server.clone();
+        tokio::spawn(async move { tcp_server.serve_tcp(listener).await });
+        tokio::spawn(async move { server.serve(socket).await });
+        let timeout = Duration::from_secs(2);
+
+        // From serial 1, the two changes since
+        let v1 = version(1, "");
+        let query = transfer_query(&origin, DnsClass::IN, v1.soa());
+        let records = exchange_transfer(addr, &query, None, timeout)
+            .await
+            .unwrap();
+        let diffs = match Transfer::from_records(records).unwrap() {
+            Transfer::Incremental(diffs) => diffs,
+            transfer => panic!("expected an incremental transfer, got {:?}", transfer),
+        };
+        assert_eq!(diffs.len(), 2);
+        let mut zone = v1.clone();
+        for diff in &diffs {
+            diff.apply(&mut zone).unwrap();
+        }
+        assert_eq!(zone.to_master_file(), v3.to_master_file());
+
+        // A serial older than the journal gets the whole zone, the current one just the
+        // SOA record
+        let v0 = version(0, "");
+        let query = transfer_query(&origin, DnsClass::IN, v0.soa());
+        let records = exchange_transfer(addr, &query, None, timeout)
+            .await
+            .unwrap();
+        match Transfer::from_records(records).unwrap() {
+            Transfer::Full(records) => assert_eq!(records.len(), v3.records().count()),
+            transfer => panic!("expected a full transfer, got {:?}", transfer),
+        }
+        let query = transfer_query(&origin, DnsClass::IN, v3.soa());
+        let records = exchange_transfer(addr, &query, None, timeout)
+            .await
+            .unwrap();
+        assert_eq!(
+            Transfer::from_records(records).unwrap(),
+            Transfer::Current(v3.soa().unwrap().clone())
+        );
+
+        // Over UDP as long as the answer fits, and otherwise just the SOA record
+        let query = transfer_query(&origin, DnsClass::IN, version(2, "").soa());
+        let response = exchange_udp(addr, &query, timeout).await.unwrap();
+        assert_eq!(response.response_code(), 0);
+        match Transfer::from_records(response.answers).unwrap() {
+            Transfer::Incremental(diffs) => assert_eq!(diffs.len(), 1),
+            transfer => panic!("expected an incremental transfer, got {:?}", transfer),
+        }
+        let query = transfer_query(&origin, DnsClass::IN, v0.soa());
+        let response = exchange_udp(addr, &query, timeout).await.unwrap();
+        assert_eq!(response.answers, vec![v3.soa().unwrap().clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_applies_authorized_updates() {
+        let origin = labels_from_str("example.com").unwrap();
+        let key = labels_from_str("dhcp.example").unwrap();
+        let handler = Arc::new(
+            AuthoritativeHandler::new()
+                .with_zone(Zone::parse(EXAMPLE_COM, "example.com.").unwrap())
+                .with_journal(&origin, Journal::new())
+                .with_update_policy(
+                    &origin,
+                    "grant dhcp.example subdomain dyn.example.com A"
+                        .parse()
+                        .unwrap(),
+                ),
+        );
+        let update = |prerequisites: Vec<DnsResourceRecord>, updates: Vec<DnsResourceRecord>| {
+            let mut request = query("example.com", ResourceRecordType::SOA);
+            request.header.flags = (OPCODE_UPDATE as u16) << 11;
+            request.header.ancount = prerequisites.len() as u16;
+            request.header.nscount = updates.len() as u16;
+            request.answers = prerequisites;
+            request.authority = updates;
+            // Empty RDATA survives the wire
+            DNSRequest::parse(&request.to_bytes().unwrap()).unwrap()
+        };
+        let send = |request: DNSRequest, key: Option<&[DNSLabel]>| {
+            let mut context = RequestContext::new(
+                "192.0.2.99:5300".parse().unwrap(),
+                "127.0.0.1:53".parse().unwrap(),
+                Transport::Udp,
+                vec![],
+            );
+            context.tsig_key = key.map(<[DNSLabel]>::to_vec);
+            handler.clone().handle_request(request, context)
+        };
+        let host = DnsResourceRecord {
+            name: labels_from_str("host.dyn.example.com").unwrap(),
+            rtype: ResourceRecordType::A,
+            class: DnsClass::IN,
+            ttl: 300,
+            rdata: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 77)),
+        };
+        let absent = DnsResourceRecord {
+            class: DnsClass::NONE,
+            ttl: 0,
+            rdata: DnsRecordData::Unknown(ResourceRecordType::A, vec![]),
+            ..host.clone()
+        };
+
+        // Unsigned, and outside the policy, nothing changes
+        let result = send(update(vec![], vec![host.clone()]), None).await;
+        assert_eq!(result.unwrap_err(), DnsRequestError::Refused);
+        let mut www = host.clone();
+        www.name = labels_from_str("www.example.com").unwrap();
+        let result = send(update(vec![], vec![host.clone(), www]), Some(&key)).await;
+        assert_eq!(result.unwrap_err(), DnsRequestError::Refused);
+        assert_eq!(handler.zone(&origin).unwrap().serial(), Some(1));
+
+        // Added if the name has no A records yet, which it then has
+        let request = update(vec![absent.clone()], vec![host.clone()]);
+        let response = send(request.clone(), Some(&key)).await.unwrap();
+        assert_eq!(response.response_code(), 0);
+        assert_eq!(
+            response.header.flags & 0xf800,
+            0x8000 | (OPCODE_UPDATE as u16) << 11
+        );
+        let zone = handler.zone(&origin).unwrap();
+        assert_eq!(zone.serial(), Some(2));
+        assert_eq!(
+            zone.rrset(&host.name, ResourceRecordType::A),
+            vec![host.clone()]
+        );
+        let result = send(request, Some(&key)).await;
+        assert_eq!(result.unwrap_err(), DnsRequestError::YXRRSet);
+
+        {
+            let journals = handler.journals.lock().unwrap();
+            let diffs = journals[&origin].since(1).unwrap();
+            assert_eq!(diffs.len(), 1);
+            assert_eq!(diffs[0].added, vec![host.clone()]);
+        }
+
+        // A zone signed offline cannot be changed without invalidating its signatures
+        let signed = format!(
+            "{}www RRSIG A 13 3 3600 20300101000000 20200101000000 12345 example.com. AAAA\n",
+            EXAMPLE_COM
+        );
+        handler.add_zone(Zone::parse(&signed, "example.com.").unwrap());
+        let mut other = host.clone();
+        other.name = labels_from_str("other.dyn.example.com").unwrap();
+        let result = send(update(vec![], vec![other.clone()]), Some(&key)).await;
+        assert_eq!(result.unwrap_err(), DnsRequestError::NotImp);
+        let zone = handler.zone(&origin).unwrap();
+        assert!(zone.rrset(&other.name, ResourceRecordType::A).is_empty());
+    }
+}
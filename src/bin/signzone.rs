@@ -0,0 +1,390 @@
+//! Signs a zone file offline: reads the zone and its keys, adds the DNSKEY records, an
+//! NSEC or NSEC3 chain and the signatures, and writes the signed zone along with the DS
+//! records for the parent zone.
+
+use dns::dnssec::{self, parse_timestamp, SigningKey, DIGEST_SHA256};
+use dns::keystore::{KeyStore, RolloverPolicy};
+use dns::label::{labels_to_string, DNSLabel};
+use dns::resourcerecord::ResourceRecordType;
+use dns::signer::{ds_records, sign_zone, Denial};
+use dns::zone::{parse_hex, Zone};
+use log::info;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+const USAGE: &str = "usage: signzone [options] <zone file> <key>...
+       signzone [options] -K <key directory> <zone file>
+
+Signs the zone with the keys, given as key files without or with their .key or .private
+extension. With both key-signing and zone-signing keys, the former sign the DNSKEY, CDS
+and CDNSKEY RRsets and the latter everything else.
+
+With -K, the keys come from a key store in the directory instead, which generates keys
+and rolls them over as they come to the end of their lifetime, and publishes CDS and
+CDNSKEY records for the parent zone.
+
+options:
+  -K DIR         key store directory
+  -L DAYS        with -K, lifetime of zone-signing keys (default: 90)
+  -l DAYS        with -K, lifetime of key-signing keys (default: unlimited)
+  -o ORIGIN      zone origin (default: the name of the zone file)
+  -f FILE        output file, - for standard output (default: <zone file>.signed)
+  -d DIR         directory for the dsset-<origin> file with the DS records (default: .)
+  -a DIGEST      digest type of the DS records (default: 2, SHA-256)
+  -3 SALT        NSEC3 with a salt in hex, - for none, instead of NSEC
+  -H ITERATIONS  additional NSEC3 hash iterations (default: 0)
+  -A             NSEC3 opt-out: leave unsigned delegations out of the chain
+  -s START       signature inception (default: now-3600)
+  -e END         signature expiration (default: +2592000, 30 days after the inception)
+
+START and END are YYYYMMDDHHmmSS in UTC, seconds since the epoch, now+N or now-N for
+N seconds from now, and END may also be +N for N seconds after START.
+";
+
+// The validity period of signatures if no expiration is given
+const DEFAULT_VALIDITY: u32 = 30 * 86400;
+const DEFAULT_INCEPTION_OFFSET: u32 = 3600;
+
+struct Options {
+    zone_file: PathBuf,
+    keys: Vec<PathBuf>,
+    key_store: Option<PathBuf>,
+    zsk_lifetime: Option<Duration>,
+    ksk_lifetime: Option<Duration>,
+    origin: Option<String>,
+    output: Option<String>,
+    ds_dir: PathBuf,
+    digest_type: u8,
+    salt: Option<Vec<u8>>,
+    iterations: u16,
+    opt_out: bool,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+fn main() {
+    env_logger::init();
+
+    let options = match parse_options(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("signzone: {}\n\n{}", error, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(error) = run(options) {
+        eprintln!("signzone: {}", error);
+        process::exit(1);
+    }
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        zone_file: PathBuf::new(),
+        keys: vec![],
+        key_store: None,
+        zsk_lifetime: RolloverPolicy::default().zsk_lifetime,
+        ksk_lifetime: RolloverPolicy::default().ksk_lifetime,
+        origin: None,
+        output: None,
+        ds_dir: PathBuf::from("."),
+        digest_type: DIGEST_SHA256,
+        salt: None,
+        iterations: 0,
+        opt_out: false,
+        start: None,
+        end: None,
+    };
+    let mut positional = vec![];
+
+    while let Some(arg) = args.next() {
+        let mut value = |option: &str| {
+            args.next()
+                .ok_or_else(|| format!("option {} needs a value", option))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            }
+            "-K" => options.key_store = Some(PathBuf::from(value("-K")?)),
+            "-L" => options.zsk_lifetime = Some(parse_days(&value("-L")?)?),
+            "-l" => options.ksk_lifetime = Some(parse_days(&value("-l")?)?),
+            "-o" => options.origin = Some(value("-o")?),
+            "-f" => options.output = Some(value("-f")?),
+            "-d" => options.ds_dir = PathBuf::from(value("-d")?),
+            "-a" => {
+                options.digest_type = value("-a")?
+                    .parse()
+                    .map_err(|_| "invalid digest type".to_string())?
+            }
+            "-3" => {
+                options.salt = Some(match value("-3")?.as_str() {
+                    "-" => vec![],
+                    salt => parse_hex(salt).map_err(|_| format!("invalid salt {:?}", salt))?,
+                })
+            }
+            "-H" => {
+                options.iterations = value("-H")?
+                    .parse()
+                    .map_err(|_| "invalid number of iterations".to_string())?
+            }
+            "-A" => options.opt_out = true,
+            "-s" => options.start = Some(value("-s")?),
+            "-e" => options.end = Some(value("-e")?),
+            option if option.starts_with('-') && option != "-" => {
+                return Err(format!("unknown option {}", option))
+            }
+            _ => positional.push(PathBuf::from(arg)),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    options.zone_file = positional.next().ok_or("no zone file given")?;
+    options.keys = positional.collect();
+    match (&options.key_store, options.keys.is_empty()) {
+        (None, true) => return Err("no keys given".into()),
+        (Some(_), false) => return Err("keys cannot be given along with -K".into()),
+        _ => {}
+    }
+    if options.salt.is_none() && (options.opt_out || options.iterations != 0) {
+        return Err("-A and -H only apply to NSEC3, which is chosen with -3".into());
+    }
+    Ok(options)
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let origin = match &options.origin {
+        Some(origin) => origin.clone(),
+        None => options
+            .zone_file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| invalid("cannot tell the origin from the zone file name, use -o"))?,
+    };
+    let zone = Zone::load(&options.zone_file, &origin)?;
+    let origin = zone.origin().to_vec();
+
+    let now = dnssec::now();
+    let inception = match &options.start {
+        Some(start) => parse_time(start, now, None)?,
+        None => now.wrapping_sub(DEFAULT_INCEPTION_OFFSET),
+    };
+    let expiration = match &options.end {
+        Some(end) => parse_time(end, now, Some(inception))?,
+        None => inception.wrapping_add(DEFAULT_VALIDITY),
+    };
+    if expiration.wrapping_sub(inception) as i32 <= 0 {
+        return Err(invalid("signatures would expire before their inception"));
+    }
+
+    let denial = match &options.salt {
+        Some(salt) => Denial::Nsec3 {
+            iterations: options.iterations,
+            salt: salt.clone(),
+            opt_out: options.opt_out,
+        },
+        None => Denial::Nsec,
+    };
+    let (signed, ds) = match &options.key_store {
+        Some(dir) => {
+            let policy = RolloverPolicy {
+                zsk_lifetime: options.zsk_lifetime,
+                ksk_lifetime: options.ksk_lifetime,
+                ..RolloverPolicy::default()
+            };
+            let mut store = KeyStore::open(dir, &origin, policy)?;
+            store.maintain(now)?;
+            let signed = store.sign_zone(&zone, &denial, now, inception, expiration)?;
+            (signed, store.ds_records(now, options.digest_type))
+        }
+        None => {
+            let keys = load_keys(&options.keys, &origin)?;
+            let signed = sign_zone(&zone, &keys, &denial, inception, expiration)?;
+            let ttl = signed
+                .rrset(&origin, ResourceRecordType::DNSKEY)
+                .first()
+                .map_or(3600, |dnskey| dnskey.ttl);
+            let ds = ds_records(&origin, &keys, ttl, options.digest_type);
+            (signed, ds)
+        }
+    };
+
+    match options.output.as_deref() {
+        Some("-") => print!("{}", signed.to_master_file()),
+        output => {
+            let path = match output {
+                Some(output) => PathBuf::from(output),
+                None => {
+                    let mut path = options.zone_file.clone().into_os_string();
+                    path.push(".signed");
+                    PathBuf::from(path)
+                }
+            };
+            signed.save(&path)?;
+            info!("wrote {}", path.display());
+        }
+    }
+
+    if ds.is_empty() {
+        return Err(invalid(format!(
+            "unsupported DS digest type {}",
+            options.digest_type
+        )));
+    }
+    let dsset = dsset_path(&options.ds_dir, &labels_to_string(&origin));
+    let text: String = ds.iter().map(|ds| format!("{}\n", ds)).collect();
+    fs::write(&dsset, text)?;
+    info!("wrote {}", dsset.display());
+    Ok(())
+}
+
+fn load_keys(paths: &[PathBuf], origin: &[DNSLabel]) -> io::Result<Vec<SigningKey>> {
+    let mut keys = vec![];
+    for path in paths {
+        let (owner, key) = SigningKey::load(path)?;
+        if owner != origin {
+            return Err(invalid(format!(
+                "{} is a key of {}., not {}.",
+                path.display(),
+                labels_to_string(&owner),
+                labels_to_string(origin)
+            )));
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+fn parse_days(text: &str) -> Result<Duration, String> {
+    text.parse::<u64>()
+        .map(|days| Duration::from_secs(days * 86400))
+        .map_err(|_| format!("invalid number of days {:?}", text))
+}
+
+// A signature time: absolute, relative to now, or with `start`, relative to that
+fn parse_time(text: &str, now: u32, start: Option<u32>) -> io::Result<u32> {
+    let offset = |text: &str| -> io::Result<u32> {
+        text.parse()
+            .map_err(|_| invalid(format!("invalid time {:?}", text)))
+    };
+    if let Some(seconds) = text.strip_prefix("now+") {
+        Ok(now.wrapping_add(offset(seconds)?))
+    } else if let Some(seconds) = text.strip_prefix("now-") {
+        Ok(now.wrapping_sub(offset(seconds)?))
+    } else if let (Some(seconds), Some(start)) = (text.strip_prefix('+'), start) {
+        Ok(start.wrapping_add(offset(seconds)?))
+    } else {
+        parse_timestamp(text)
+    }
+}
+
+// BIND's name for the file with the DS records of a zone
+fn dsset_path(dir: &Path, origin: &str) -> PathBuf {
+    dir.join(format!("dsset-{}.", origin))
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns::dnssec::{
+        ds_matches, signatures_for, verify_rrset, ALGORITHM_ECDSAP256SHA256, FLAG_SEP, FLAG_ZONE,
+    };
+    use dns::label::labels_from_str;
+    use dns::response::{DnsRecordData, DnsResourceRecord};
+    use dns::zone::parse_master_file;
+
+    const EXAMPLE: &str = "$TTL 3600
+@    SOA ns hostmaster 1 7200 900 1209600 300
+     NS  ns
+ns   A   192.0.2.53
+www  A   192.0.2.1
+";
+
+    fn options(args: &[&str]) -> Result<Options, String> {
+        parse_options(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_signs_a_zone_file_and_writes_the_ds_records() {
+        let dir = env::temp_dir().join(format!("dns-signzone-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let origin = labels_from_str("example.com").unwrap();
+        let zone_file = dir.join("example.com");
+        fs::write(&zone_file, EXAMPLE).unwrap();
+        let ksk = SigningKey::generate(ALGORITHM_ECDSAP256SHA256, FLAG_ZONE | FLAG_SEP).unwrap();
+        let zsk = SigningKey::generate(ALGORITHM_ECDSAP256SHA256, FLAG_ZONE).unwrap();
+        let ksk_path = ksk.save(&dir, &origin).unwrap();
+        let zsk_path = zsk.save(&dir, &origin).unwrap();
+
+        let path = |path: &Path| path.to_str().unwrap().to_string();
+        let args = [
+            "-d".to_string(),
+            path(&dir),
+            path(&zone_file),
+            path(&ksk_path),
+            path(&zsk_path),
+        ];
+        run(options(&args.iter().map(String::as_str).collect::<Vec<_>>()).unwrap()).unwrap();
+
+        // The signed zone next to the zone file, named after it, with both keys, an NSEC
+        // chain and signatures by the key-signing key over the keys only
+        let signed = Zone::load(dir.join("example.com.signed"), "example.com.").unwrap();
+        let records: Vec<DnsResourceRecord> = signed.records().cloned().collect();
+        let dnskeys = signed.rrset(&origin, ResourceRecordType::DNSKEY);
+        assert_eq!(dnskeys.len(), 2);
+        assert_eq!(
+            records
+                .iter()
+                .filter(|record| record.rtype == ResourceRecordType::NSEC)
+                .count(),
+            3
+        );
+        let now = dnssec::now();
+        for (owner, rtype, key) in [
+            ("example.com", ResourceRecordType::DNSKEY, &ksk),
+            ("example.com", ResourceRecordType::SOA, &zsk),
+            ("www.example.com", ResourceRecordType::A, &zsk),
+            ("ns.example.com", ResourceRecordType::NSEC, &zsk),
+        ] {
+            let owner = labels_from_str(owner).unwrap();
+            let rrsigs = signatures_for(&records, &owner, rtype);
+            assert_eq!(rrsigs.len(), 1);
+            assert!(
+                matches!(&rrsigs[0].rdata, DnsRecordData::RRSIG(rrsig) if rrsig.key_tag == key.key_tag())
+            );
+            let rrset = signed.rrset(&owner, rtype);
+            assert!(verify_rrset(&rrset, &rrsigs, &dnskeys, now).is_some());
+        }
+
+        // The dsset file holds the DS record of the key-signing key
+        let dsset = fs::read_to_string(dir.join("dsset-example.com.")).unwrap();
+        let ds = parse_master_file(&dsset, &origin).unwrap();
+        assert_eq!(ds.len(), 1);
+        assert_eq!(ds[0].rtype, ResourceRecordType::DS);
+        assert!(ds_matches(&ds[0].rdata, &ksk.dnskey_record(&origin, 3600)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_invalid_options() {
+        assert!(options(&["example.com", "Kexample.com.+013+12345"]).is_ok());
+        assert!(options(&["example.com"]).is_err());
+        assert!(options(&["-K", "keys", "example.com", "Kexample.com.+013+12345"]).is_err());
+        assert!(options(&["-A", "example.com", "Kexample.com.+013+12345"]).is_err());
+        assert!(options(&["-3", "zz", "example.com", "Kexample.com.+013+12345"]).is_err());
+    }
+}
@@ -0,0 +1,1196 @@
+use std::{
+    collections::HashSet,
+    fs,
+    future::Future,
+    io::{self, Cursor, Read},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use log::{debug, warn};
+use lru::LruCache;
+
+use crate::{
+    context::RequestContext,
+    edns::{Edns, EdnsOption, EDE_STALE_ANSWER},
+    handler::{DnsRequestError, DnsRequestHandler},
+    label::{is_subdomain, DNSLabel},
+    layer::Layer,
+    request::{DNSQuestion, DNSRequest},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{write_message, DnsRecordData, DnsResourceRecord, DnsResponse},
+};
+
+const DEFAULT_MAX_TTL: u32 = 86400;
+// RFC 2308, section 5 suggests one to three hours for negative answers
+const DEFAULT_MAX_NEGATIVE_TTL: u32 = 10800;
+// RFC 8767, section 4 recommends 30 seconds for stale answers, and waiting 1.8 seconds
+// for a fresh answer before falling back to one
+const STALE_TTL: u32 = 30;
+const DEFAULT_STALE_ANSWER_TIMEOUT: Duration = Duration::from_millis(1800);
+
+const SNAPSHOT_MAGIC: &[u8] = b"DNSCACHE\x01";
+
+/// What a cached response answers: the question and the request flags that change the
+/// answer
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: Vec<DNSLabel>,
+    pub qtype: ResourceRecordType,
+    pub qclass: DnsClass,
+    /// The DO bit, which asks for DNSSEC records
+    pub dnssec_ok: bool,
+    /// The CD bit, which asks for data that failed validation
+    pub checking_disabled: bool,
+}
+
+impl CacheKey {
+    /// The key for a standard query with a single question, `None` for any other
+    /// request. Zone transfers and requests signed with TSIG are answered depending on
+    /// who asks, so they have no key either.
+    pub fn from_request(request: &DNSRequest) -> Option<Self> {
+        let question = match request.questions.as_slice() {
+            [question] if request.header.flags & 0x7800 == 0 => question,
+            _ => return None,
+        };
+        let transfer = matches!(
+            question.qtype,
+            ResourceRecordType::AXFR | ResourceRecordType::IXFR
+        );
+        let signed = request
+            .additional
+            .iter()
+            .any(|record| record.rtype == ResourceRecordType::TSIG);
+        if transfer || signed {
+            return None;
+        }
+        Some(CacheKey {
+            name: question.qname.clone(),
+            qtype: question.qtype,
+            qclass: question.qclass,
+            dnssec_ok: request.edns().is_some_and(|edns| edns.dnssec_ok),
+            checking_disabled: request.header.flags & 0x0010 != 0,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    response: DnsResponse,
+    inserted: Instant,
+    ttl: u32,
+}
+
+impl CacheEntry {
+    fn expires(&self) -> Instant {
+        self.inserted + Duration::from_secs(self.ttl as u64)
+    }
+}
+
+/// A bounded cache of responses, shared between the handlers that use it. The least
+/// recently used entries are evicted first when the cache is full.
+///
+/// Positive answers are cached for the lowest TTL among their records, and NXDOMAIN and
+/// NODATA answers for the TTL of the SOA record in their authority section, capped by
+/// its minimum field (RFC 2308, section 5). Responses without an SOA record, such as
+/// referrals, and failures are not cached. TTLs served from the cache count down with
+/// the time the response spent in it.
+///
+/// With serve-stale enabled (RFC 8767), expired entries are kept for a while longer, so
+/// handlers can fall back to them when a fresh answer cannot be had.
+pub struct DnsCache {
+    entries: Mutex<LruCache<CacheKey, CacheEntry>>,
+    min_ttl: u32,
+    max_ttl: u32,
+    max_negative_ttl: u32,
+    stale_window: Duration,
+    stale_answer_timeout: Duration,
+    prefetch_threshold: Option<f64>,
+    // Keys with a refresh in flight
+    prefetching: Mutex<HashSet<CacheKey>>,
+    counters: Counters,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale_hits: AtomicU64,
+    prefetches: AtomicU64,
+    insertions: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Counters of a [`DnsCache`] since it was created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    /// Requests answered from fresh entries
+    pub hits: u64,
+    /// Requests for which there was no fresh entry
+    pub misses: u64,
+    /// Requests answered from expired entries
+    pub stale_hits: u64,
+    pub prefetches: u64,
+    pub insertions: u64,
+    /// Entries dropped to make room for new ones
+    pub evictions: u64,
+}
+
+/// What the cache holds for a question, as reported by [`DnsCache::lookup`]
+#[derive(Debug, Clone)]
+pub struct CacheEntryInfo {
+    pub key: CacheKey,
+    pub rcode: u8,
+    /// The answer and authority records, with their remaining TTLs
+    pub records: Vec<DnsResourceRecord>,
+    /// The TTL the entry was cached with
+    pub original_ttl: u32,
+    /// Seconds until the entry expires, zero for stale entries
+    pub remaining_ttl: u32,
+    /// Whether the entry has expired and is only kept to serve stale answers
+    pub stale: bool,
+}
+
+impl DnsCache {
+    /// Creates a cache that holds up to `capacity` responses.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        DnsCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            min_ttl: 0,
+            max_ttl: DEFAULT_MAX_TTL,
+            max_negative_ttl: DEFAULT_MAX_NEGATIVE_TTL,
+            stale_window: Duration::ZERO,
+            stale_answer_timeout: DEFAULT_STALE_ANSWER_TIMEOUT,
+            prefetch_threshold: None,
+            prefetching: Mutex::new(HashSet::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Raises lower TTLs to `min_ttl`, for positive and negative answers alike.
+    pub fn with_min_ttl(mut self, min_ttl: u32) -> Self {
+        self.min_ttl = min_ttl;
+        self
+    }
+
+    /// Lowers higher TTLs of positive answers to `max_ttl`.
+    pub fn with_max_ttl(mut self, max_ttl: u32) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// Lowers higher TTLs of negative answers to `max_negative_ttl`.
+    pub fn with_max_negative_ttl(mut self, max_negative_ttl: u32) -> Self {
+        self.max_negative_ttl = max_negative_ttl;
+        self
+    }
+
+    /// Keeps entries for `window` after they expire, to answer with when resolution
+    /// fails or takes too long. Off with the default of zero.
+    pub fn with_serve_stale(mut self, window: Duration) -> Self {
+        self.stale_window = window;
+        self
+    }
+
+    /// How long a [`CachingHandler`] waits for a fresh answer before it answers with
+    /// stale data, 1.8 seconds by default
+    pub fn with_stale_answer_timeout(mut self, timeout: Duration) -> Self {
+        self.stale_answer_timeout = timeout;
+        self
+    }
+
+    /// Refreshes entries that are still being asked for once `threshold`, a fraction
+    /// between 0 and 1, of their TTL has passed, so popular names never expire. Off by
+    /// default.
+    pub fn with_prefetch(mut self, threshold: f64) -> Self {
+        self.prefetch_threshold = Some(threshold.clamp(0.0, 1.0));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Answers `request` from the cache, with its ID and TTLs reduced by the time the
+    /// response has been cached.
+    pub fn get(&self, request: &DNSRequest) -> Option<DnsResponse> {
+        self.get_at(request, Instant::now())
+    }
+
+    fn get_at(&self, request: &DNSRequest, now: Instant) -> Option<DnsResponse> {
+        self.lookup_at(request, now, false)
+            .map(|(response, _)| response)
+    }
+
+    // Answers from a fresh entry. With `claim_prefetch`, also reports whether the entry
+    // is due for a refresh, which the caller then has to run and finish with
+    // `finish_prefetch`.
+    fn lookup_at(
+        &self,
+        request: &DNSRequest,
+        now: Instant,
+        claim_prefetch: bool,
+    ) -> Option<(DnsResponse, bool)> {
+        let key = CacheKey::from_request(request)?;
+        let mut entries = self.entries.lock().unwrap();
+
+        let entry = match entries.get(&key) {
+            Some(entry) if entry.expires() > now => entry,
+            Some(entry) => {
+                if entry.expires() + self.stale_window <= now {
+                    entries.pop(&key);
+                }
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        let elapsed = now.duration_since(entry.inserted);
+        let response = cached_response(request, entry, |ttl| {
+            ttl.saturating_sub(elapsed.as_secs() as u32)
+        });
+
+        let prefetch = claim_prefetch
+            && self
+                .prefetch_threshold
+                .is_some_and(|threshold| elapsed.as_secs_f64() >= entry.ttl as f64 * threshold)
+            && self.prefetching.lock().unwrap().insert(key);
+        if prefetch {
+            self.counters.prefetches.fetch_add(1, Ordering::Relaxed);
+        }
+        Some((response, prefetch))
+    }
+
+    fn finish_prefetch(&self, key: &CacheKey) {
+        self.prefetching.lock().unwrap().remove(key);
+    }
+
+    /// Answers `request` from an expired entry that is still within the serve-stale
+    /// window. The records get a TTL of 30 seconds, and clients that sent an OPT record
+    /// get the Stale Answer Extended DNS Error with it.
+    pub fn get_stale(&self, request: &DNSRequest) -> Option<DnsResponse> {
+        self.get_stale_at(request, Instant::now())
+    }
+
+    fn get_stale_at(&self, request: &DNSRequest, now: Instant) -> Option<DnsResponse> {
+        let key = CacheKey::from_request(request)?;
+        let mut entries = self.entries.lock().unwrap();
+
+        let entry = entries.get(&key)?;
+        if entry.expires() + self.stale_window <= now {
+            return None;
+        }
+        self.counters.stale_hits.fetch_add(1, Ordering::Relaxed);
+        let mut response = cached_response(request, entry, |_| STALE_TTL);
+        if let Some(mut edns) = response.edns() {
+            edns.set_option(EdnsOption::ExtendedError {
+                info_code: EDE_STALE_ANSWER,
+                extra_text: String::new(),
+            });
+            response.set_edns(edns);
+        }
+        Some(response)
+    }
+
+    // Whether `request` could be answered with stale data right now
+    fn has_stale(&self, request: &DNSRequest) -> bool {
+        let now = Instant::now();
+        CacheKey::from_request(request).is_some_and(|key| {
+            self.entries
+                .lock()
+                .unwrap()
+                .peek(&key)
+                .is_some_and(|entry| entry.expires() + self.stale_window > now)
+        })
+    }
+
+    /// Caches `response` as the answer for `key` if it is cacheable.
+    pub fn insert(&self, key: CacheKey, response: &DnsResponse) {
+        self.insert_at(key, response, Instant::now())
+    }
+
+    fn insert_at(&self, key: CacheKey, response: &DnsResponse, now: Instant) {
+        let mut response = response.clone();
+        response
+            .additional
+            .retain(|record| record.rtype != ResourceRecordType::OPT);
+
+        let ttl = match self.ttl(&response) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+        let negative = response.answers.is_empty();
+        for record in response
+            .answers
+            .iter_mut()
+            .chain(&mut response.authority)
+            .chain(&mut response.additional)
+        {
+            // Negative answers live exactly as long as the entry, positive records may
+            // outlive the shortest one
+            record.ttl = if negative {
+                ttl
+            } else {
+                record.ttl.clamp(ttl, ttl.max(self.max_ttl))
+            };
+        }
+
+        let entry = CacheEntry {
+            response,
+            inserted: now,
+            ttl,
+        };
+        self.put(&mut self.entries.lock().unwrap(), key, entry);
+    }
+
+    fn put(&self, entries: &mut LruCache<CacheKey, CacheEntry>, key: CacheKey, entry: CacheEntry) {
+        self.counters.insertions.fetch_add(1, Ordering::Relaxed);
+        if let Some((replaced, _)) = entries.push(key.clone(), entry) {
+            if replaced != key {
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Everything cached for `name`, for any type and class, without marking it as used
+    pub fn lookup(&self, name: &[DNSLabel]) -> Vec<CacheEntryInfo> {
+        self.lookup_info_at(name, Instant::now())
+    }
+
+    fn lookup_info_at(&self, name: &[DNSLabel], now: Instant) -> Vec<CacheEntryInfo> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(key, entry)| key.name == name && entry.expires() + self.stale_window > now)
+            .map(|(key, entry)| {
+                let elapsed = now.duration_since(entry.inserted).as_secs() as u32;
+                let records = entry
+                    .response
+                    .answers
+                    .iter()
+                    .chain(&entry.response.authority)
+                    .map(|record| DnsResourceRecord {
+                        ttl: record.ttl.saturating_sub(elapsed),
+                        ..record.clone()
+                    })
+                    .collect();
+                CacheEntryInfo {
+                    key: key.clone(),
+                    rcode: entry.response.response_code(),
+                    records,
+                    original_ttl: entry.ttl,
+                    remaining_ttl: entry.ttl.saturating_sub(elapsed),
+                    stale: entry.expires() <= now,
+                }
+            })
+            .collect()
+    }
+
+    /// Removes all entries for exactly `name`, returning how many there were.
+    pub fn flush_name(&self, name: &[DNSLabel]) -> usize {
+        self.flush_where(|key| key.name == name)
+    }
+
+    /// Removes all entries for `name` and the names below it, returning how many there
+    /// were.
+    pub fn flush_subtree(&self, name: &[DNSLabel]) -> usize {
+        self.flush_where(|key| is_subdomain(&key.name, name))
+    }
+
+    /// Removes all entries, returning how many there were.
+    pub fn flush_all(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    fn flush_where<F>(&self, matches: F) -> usize
+    where
+        F: Fn(&CacheKey) -> bool,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<CacheKey> = entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| matches(key))
+            .cloned()
+            .collect();
+        for key in &keys {
+            entries.pop(key);
+        }
+        keys.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        CacheStats {
+            entries: entries.len(),
+            capacity: entries.cap().get(),
+            hits: counter(&self.counters.hits),
+            misses: counter(&self.counters.misses),
+            stale_hits: counter(&self.counters.stale_hits),
+            prefetches: counter(&self.counters.prefetches),
+            insertions: counter(&self.counters.insertions),
+            evictions: counter(&self.counters.evictions),
+        }
+    }
+
+    // How long a response may be cached, `None` if it may not
+    fn ttl(&self, response: &DnsResponse) -> Option<u32> {
+        if response.header.flags & 0x0200 != 0 {
+            return None;
+        }
+
+        let negative_ttl = || {
+            let ttl = response
+                .authority
+                .iter()
+                .find_map(|record| match record.rdata {
+                    DnsRecordData::SOA { minimum, .. } => Some(record.ttl.min(minimum)),
+                    _ => None,
+                })?;
+            Some(ttl.min(self.max_negative_ttl).max(self.min_ttl))
+        };
+
+        match response.response_code() {
+            0 if !response.answers.is_empty() => {
+                let ttl = response.answers.iter().map(|record| record.ttl).min()?;
+                Some(ttl.min(self.max_ttl).max(self.min_ttl))
+            }
+            0 | 3 => negative_ttl(),
+            _ => None,
+        }
+    }
+
+    /// Writes all entries, including stale ones, to `path`. The file is replaced
+    /// atomically, so a crash while saving leaves the previous snapshot intact.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let path = path.as_ref();
+        let mut snapshot = Vec::new();
+        let count = self.write_snapshot(&mut snapshot, Instant::now(), SystemTime::now())?;
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, snapshot)?;
+        fs::rename(&temporary, path)?;
+        Ok(count)
+    }
+
+    /// Adds the entries of a snapshot written by [`DnsCache::save`], with their TTLs
+    /// reduced by the time since it was taken. Entries that expired in the meantime
+    /// are skipped, unless they are still within the serve-stale window.
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let snapshot = fs::read(path)?;
+        self.read_snapshot(&snapshot, Instant::now(), SystemTime::now())
+    }
+
+    /// Saves the cache to `path` every `interval` until the task is aborted.
+    pub fn save_periodically(
+        cache: Arc<DnsCache>,
+        path: PathBuf,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match cache.save(&path) {
+                    Ok(count) => debug!("Saved {} cache entries to {}", count, path.display()),
+                    Err(e) => warn!("Failed to save the cache to {}: {}", path.display(), e),
+                }
+            }
+        })
+    }
+
+    // The snapshot format is a header of magic, version and the time of the snapshot,
+    // then per entry the key flags, the entry's TTL, the seconds it had left (negative
+    // for stale entries) and the response as a DNS message with the key's question.
+    fn write_snapshot(
+        &self,
+        buffer: &mut Vec<u8>,
+        now: Instant,
+        now_system: SystemTime,
+    ) -> io::Result<usize> {
+        let taken = now_system
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        buffer.extend_from_slice(SNAPSHOT_MAGIC);
+        buffer.write_u64::<NetworkEndian>(taken.as_secs())?;
+
+        let entries = self.entries.lock().unwrap();
+        // Least recently used first, so loading restores the order
+        for (key, entry) in entries.iter().rev() {
+            let remaining = if entry.expires() >= now {
+                entry.expires().duration_since(now).as_secs() as i64
+            } else {
+                -(now.duration_since(entry.expires()).as_secs() as i64)
+            };
+
+            let mut flags = 0;
+            if key.dnssec_ok {
+                flags |= 1;
+            }
+            if key.checking_disabled {
+                flags |= 2;
+            }
+            let question = DNSQuestion {
+                qname: key.name.clone(),
+                qtype: key.qtype,
+                qclass: key.qclass,
+            };
+            let response = &entry.response;
+            let message = write_message(
+                0,
+                response.header.flags,
+                &[question],
+                [&response.answers, &response.authority, &response.additional],
+            )?;
+
+            buffer.write_u8(flags)?;
+            buffer.write_u32::<NetworkEndian>(entry.ttl)?;
+            buffer.write_i64::<NetworkEndian>(remaining)?;
+            buffer.write_u32::<NetworkEndian>(message.len() as u32)?;
+            buffer.extend_from_slice(&message);
+        }
+        Ok(entries.len())
+    }
+
+    fn read_snapshot(
+        &self,
+        snapshot: &[u8],
+        now: Instant,
+        now_system: SystemTime,
+    ) -> io::Result<usize> {
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        if !snapshot.starts_with(SNAPSHOT_MAGIC) {
+            return Err(invalid("not a cache snapshot"));
+        }
+        let mut cursor = Cursor::new(&snapshot[SNAPSHOT_MAGIC.len()..]);
+        let taken = UNIX_EPOCH + Duration::from_secs(cursor.read_u64::<NetworkEndian>()?);
+        let elapsed = now_system
+            .duration_since(taken)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        let mut loaded = 0;
+        let mut entries = self.entries.lock().unwrap();
+        while (cursor.position() as usize) < cursor.get_ref().len() {
+            let flags = cursor.read_u8()?;
+            let ttl = cursor.read_u32::<NetworkEndian>()?;
+            let remaining = cursor.read_i64::<NetworkEndian>()? - elapsed;
+            let len = cursor.read_u32::<NetworkEndian>()? as usize;
+            let mut message = vec![0; len];
+            cursor.read_exact(&mut message)?;
+
+            let mut response = DnsResponse::parse(&message)?;
+            let question = response
+                .questions
+                .pop()
+                .ok_or_else(|| invalid("snapshot entry without question"))?;
+            if remaining + self.stale_window.as_secs() as i64 <= 0 {
+                continue;
+            }
+            let age = Duration::from_secs((ttl as i64 - remaining).max(0) as u64);
+            let inserted = match now.checked_sub(age) {
+                Some(inserted) => inserted,
+                None => continue,
+            };
+
+            let key = CacheKey {
+                name: question.qname,
+                qtype: question.qtype,
+                qclass: question.qclass,
+                dnssec_ok: flags & 1 != 0,
+                checking_disabled: flags & 2 != 0,
+            };
+            self.put(
+                &mut entries,
+                key,
+                CacheEntry {
+                    response,
+                    inserted,
+                    ttl,
+                },
+            );
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+}
+
+// Answers `request` with the records of `entry`, their TTLs mapped by `ttl`
+fn cached_response<F>(request: &DNSRequest, entry: &CacheEntry, ttl: F) -> DnsResponse
+where
+    F: Fn(u32) -> u32,
+{
+    let records = |records: &[DnsResourceRecord]| -> Vec<DnsResourceRecord> {
+        records
+            .iter()
+            .map(|record| DnsResourceRecord {
+                ttl: ttl(record.ttl),
+                ..record.clone()
+            })
+            .collect()
+    };
+
+    let mut response = DnsResponse::reply_to(request);
+    // RA, AD and the RCODE carry over, AA does not: the cache is not authoritative
+    response.header.flags |= entry.response.header.flags & 0x00AF;
+    response.answers = records(&entry.response.answers);
+    response.authority = records(&entry.response.authority);
+    response.additional = records(&entry.response.additional);
+    if let Some(edns) = request.edns() {
+        response.set_edns(Edns {
+            dnssec_ok: edns.dnssec_ok,
+            ..Edns::default()
+        });
+    }
+    response
+}
+
+/// A layer that puts a [`DnsCache`] in front of handlers
+#[derive(Clone)]
+pub struct CacheLayer {
+    cache: Arc<DnsCache>,
+}
+
+impl CacheLayer {
+    pub fn new(cache: Arc<DnsCache>) -> Self {
+        CacheLayer { cache }
+    }
+}
+
+impl<H: DnsRequestHandler> Layer<H> for CacheLayer {
+    type Handler = CachingHandler<H>;
+
+    fn layer(&self, inner: H) -> CachingHandler<H> {
+        CachingHandler::new(inner, self.cache.clone())
+    }
+}
+
+/// Answers requests from a [`DnsCache`] where possible and caches the responses of the
+/// wrapped handler otherwise.
+pub struct CachingHandler<H: DnsRequestHandler> {
+    inner: Arc<H>,
+    cache: Arc<DnsCache>,
+}
+
+impl<H: DnsRequestHandler> CachingHandler<H> {
+    pub fn new(inner: H, cache: Arc<DnsCache>) -> Self {
+        CachingHandler {
+            inner: Arc::new(inner),
+            cache,
+        }
+    }
+
+    pub fn cache(&self) -> &Arc<DnsCache> {
+        &self.cache
+    }
+
+    // Refreshes the entry for `request` in the background
+    fn prefetch(&self, request: DNSRequest, context: RequestContext) {
+        let key = match CacheKey::from_request(&request) {
+            Some(key) => key,
+            None => return,
+        };
+        let inner = self.inner.clone();
+        let cache = self.cache.clone();
+        tokio::spawn(async move {
+            match inner.handle_request(request, context).await {
+                Ok(response) if response.response_code() != 2 => {
+                    cache.insert(key.clone(), &response)
+                }
+                _ => debug!("Prefetching {:?} failed", key),
+            }
+            cache.finish_prefetch(&key);
+        });
+    }
+}
+
+impl<H: DnsRequestHandler> DnsRequestHandler for CachingHandler<H> {
+    fn handle_request(
+        self: Arc<Self>,
+        request: DNSRequest,
+        context: RequestContext,
+    ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+        if let Some((response, prefetch)) = self.cache.lookup_at(&request, Instant::now(), true) {
+            if prefetch {
+                self.prefetch(request, context);
+            }
+            return Box::pin(async move { Ok(response) });
+        }
+
+        Box::pin(async move {
+            let key = CacheKey::from_request(&request);
+            let resolution = self.inner.clone().handle_request(request.clone(), context);
+
+            let result = if self.cache.has_stale(&request) {
+                // Resolution goes on in the background if it takes too long, so its
+                // answer still refreshes the cache
+                let mut resolution = tokio::spawn(resolution);
+                match tokio::time::timeout(self.cache.stale_answer_timeout, &mut resolution).await {
+                    Ok(result) => result.unwrap_or(Err(DnsRequestError::ServFail)),
+                    Err(_) => {
+                        let cache = self.cache.clone();
+                        let key = key.clone();
+                        tokio::spawn(async move {
+                            if let (Ok(Ok(response)), Some(key)) = (resolution.await, key) {
+                                cache.insert(key, &response);
+                            }
+                        });
+                        Err(DnsRequestError::ServFail)
+                    }
+                }
+            } else {
+                resolution.await
+            };
+
+            match result {
+                Ok(response) if response.response_code() != 2 => {
+                    if let Some(key) = key {
+                        self.cache.insert(key, &response);
+                    }
+                    Ok(response)
+                }
+                failure => match self.cache.get_stale(&request) {
+                    Some(stale) => Ok(stale),
+                    None => failure,
+                },
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        acl::Acl, authority::AuthoritativeHandler, context::Transport, label::labels_from_str,
+        zone::Zone,
+    };
+
+    fn request(name: &str, dnssec_ok: bool) -> DNSRequest {
+        let additional = if dnssec_ok {
+            vec![Edns {
+                dnssec_ok: true,
+                ..Edns::default()
+            }
+            .to_record()]
+        } else {
+            vec![]
+        };
+        let mut request = DNSRequest::query(name, ResourceRecordType::A, DnsClass::IN);
+        request.header.arcount = additional.len() as u16;
+        request.additional = additional;
+        request
+    }
+
+    fn answer(request: &DNSRequest, ttl: u32) -> DnsResponse {
+        let mut response = DnsResponse::reply_to(request);
+        response.header.flags |= 0x0480; // AA, RA
+        response.answers.push(DnsResourceRecord {
+            name: request.questions[0].qname.clone(),
+            rtype: ResourceRecordType::A,
+            class: DnsClass::IN,
+            ttl,
+            rdata: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        });
+        response
+    }
+
+    fn nxdomain(request: &DNSRequest, soa_ttl: u32, minimum: u32) -> DnsResponse {
+        let mut response = DnsResponse::reply_to(request);
+        response.set_response_code(3);
+        response.authority.push(DnsResourceRecord {
+            name: labels_from_str("example.com").unwrap(),
+            rtype: ResourceRecordType::SOA,
+            class: DnsClass::IN,
+            ttl: soa_ttl,
+            rdata: DnsRecordData::SOA {
+                mname: "ns.example.com".into(),
+                rname: "hostmaster.example.com".into(),
+                serial: 1,
+                refresh: 7200,
+                retry: 900,
+                expire: 604800,
+                minimum,
+            },
+        });
+        response
+    }
+
+    fn key(request: &DNSRequest) -> CacheKey {
+        CacheKey::from_request(request).unwrap()
+    }
+
+    #[test]
+    fn test_ttls_count_down() {
+        let cache = DnsCache::new(16);
+        let now = Instant::now();
+        let request = request("www.example.com", false);
+        cache.insert_at(key(&request), &answer(&request, 300), now);
+
+        let mut later = request.clone();
+        later.header.id = 8;
+        let response = cache
+            .get_at(&later, now + Duration::from_secs(100))
+            .unwrap();
+        assert_eq!(response.header.id, 8);
+        assert_eq!(response.header.flags & 0x0400, 0);
+        assert_ne!(response.header.flags & 0x0080, 0);
+        assert_eq!(response.answers[0].ttl, 200);
+
+        assert!(cache
+            .get_at(&request, now + Duration::from_secs(300))
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_negative_answers_use_soa_minimum() {
+        let cache = DnsCache::new(16);
+        let now = Instant::now();
+        let request = request("nope.example.com", false);
+        cache.insert_at(key(&request), &nxdomain(&request, 3600, 60), now);
+
+        let response = cache
+            .get_at(&request, now + Duration::from_secs(59))
+            .unwrap();
+        assert_eq!(response.response_code(), 3);
+        assert_eq!(response.authority[0].ttl, 1);
+        assert!(cache
+            .get_at(&request, now + Duration::from_secs(60))
+            .is_none());
+
+        // Without an SOA record the answer is not cached
+        let mut response = nxdomain(&request, 3600, 60);
+        response.authority.clear();
+        cache.insert_at(key(&request), &response, now);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_ttl_clamps() {
+        let cache = DnsCache::new(16)
+            .with_min_ttl(60)
+            .with_max_ttl(600)
+            .with_max_negative_ttl(30);
+        let now = Instant::now();
+
+        let short = request("short.example.com", false);
+        cache.insert_at(key(&short), &answer(&short, 5), now);
+        let response = cache.get_at(&short, now).unwrap();
+        assert_eq!(response.answers[0].ttl, 60);
+
+        let long = request("long.example.com", false);
+        cache.insert_at(key(&long), &answer(&long, 86400), now);
+        assert!(cache
+            .get_at(&long, now + Duration::from_secs(600))
+            .is_none());
+
+        let negative = request("nope.example.com", false);
+        cache.insert_at(key(&negative), &nxdomain(&negative, 3600, 3600), now);
+        let response = cache.get_at(&negative, now).unwrap();
+        assert_eq!(response.authority[0].ttl, 60);
+    }
+
+    #[test]
+    fn test_keys_and_eviction() {
+        let cache = DnsCache::new(2);
+        let now = Instant::now();
+        let plain = request("www.example.com", false);
+        let dnssec = request("WWW.example.com", true);
+        cache.insert_at(key(&plain), &answer(&plain, 300), now);
+
+        // The DO bit selects another entry, the case of the name does not
+        assert!(cache.get_at(&dnssec, now).is_none());
+        let mut upper = plain.clone();
+        upper.questions[0].qname = labels_from_str("WWW.EXAMPLE.COM").unwrap();
+        assert!(cache.get_at(&upper, now).is_some());
+
+        let other = request("other.example.com", false);
+        let third = request("third.example.com", false);
+        cache.insert_at(key(&other), &answer(&other, 300), now);
+        cache.get_at(&plain, now);
+        cache.insert_at(key(&third), &answer(&third, 300), now);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_at(&other, now).is_none());
+        assert!(cache.get_at(&plain, now).is_some());
+    }
+
+    struct Counting(AtomicUsize);
+
+    impl DnsRequestHandler for Counting {
+        fn handle_request(
+            self: Arc<Self>,
+            request: DNSRequest,
+            _context: RequestContext,
+        ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(answer(&request, 300)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_caching_handler() {
+        let cache = Arc::new(DnsCache::new(16));
+        let handler = Arc::new(CacheLayer::new(cache.clone()).layer(Counting(AtomicUsize::new(0))));
+
+        for _ in 0..3 {
+            let context = RequestContext::local(Transport::Udp);
+            let response = handler
+                .clone()
+                .handle_request(request("www.example.com", false), context)
+                .await
+                .unwrap();
+            assert_eq!(response.answers.len(), 1);
+        }
+        assert_eq!(handler.inner.0.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_transfers_bypass_the_cache() {
+        let zone = Zone::parse(
+            "@ 3600 SOA ns hostmaster 1 7200 900 604800 300\n@ 3600 NS ns\nns 3600 A 192.0.2.53\n",
+            "example.com.",
+        )
+        .unwrap();
+        let origin = zone.origin().to_vec();
+        let allowed: IpAddr = "192.0.2.1".parse().unwrap();
+        let authority = AuthoritativeHandler::new()
+            .with_zone(zone)
+            .with_transfer_acl(&origin, Acl::new().with_address(allowed));
+        let cache = Arc::new(DnsCache::new(16));
+        let handler = Arc::new(CacheLayer::new(cache.clone()).layer(authority));
+        let mut axfr = request("example.com", false);
+        axfr.questions[0].qtype = ResourceRecordType::AXFR;
+        let server = "127.0.0.1:53".parse().unwrap();
+        let transfer = |client: IpAddr| {
+            let context =
+                RequestContext::new((client, 5300).into(), server, Transport::Tcp, vec![]);
+            handler.clone().handle_request(axfr.clone(), context)
+        };
+
+        let response = transfer(allowed).await.unwrap();
+        assert_eq!(response.answers.len(), 4);
+        let result = transfer("198.51.100.1".parse().unwrap()).await;
+        assert_eq!(result.unwrap_err(), DnsRequestError::Refused);
+        assert_eq!(cache.len(), 0);
+    }
+
+    // Fails every request, after `delay`
+    struct Failing(Duration);
+
+    impl DnsRequestHandler for Failing {
+        fn handle_request(
+            self: Arc<Self>,
+            _request: DNSRequest,
+            _context: RequestContext,
+        ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.0).await;
+                Err(DnsRequestError::ServFail)
+            })
+        }
+    }
+
+    fn expired_cache(request: &DNSRequest) -> Arc<DnsCache> {
+        let cache = DnsCache::new(16)
+            .with_serve_stale(Duration::from_secs(3600))
+            .with_stale_answer_timeout(Duration::from_millis(50));
+        let inserted = Instant::now() - Duration::from_secs(400);
+        cache.insert_at(key(request), &answer(request, 300), inserted);
+        Arc::new(cache)
+    }
+
+    #[tokio::test]
+    async fn test_serves_stale_when_resolution_fails() {
+        let request = request("www.example.com", true);
+        let cache = expired_cache(&request);
+        assert!(cache.get(&request).is_none());
+
+        for delay in [Duration::ZERO, Duration::from_secs(10)] {
+            let handler = Arc::new(CacheLayer::new(cache.clone()).layer(Failing(delay)));
+            let context = RequestContext::local(Transport::Udp);
+            let response = handler
+                .handle_request(request.clone(), context)
+                .await
+                .unwrap();
+
+            assert_eq!(response.answers[0].ttl, 30);
+            assert_eq!(
+                response.edns().unwrap().options,
+                vec![EdnsOption::ExtendedError {
+                    info_code: EDE_STALE_ANSWER,
+                    extra_text: String::new(),
+                }]
+            );
+        }
+
+        // Past the window the entry is gone
+        let later = Instant::now() + Duration::from_secs(3600);
+        assert!(cache.get_stale_at(&request, later).is_none());
+        assert!(cache.get_at(&request, later).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prefetches_once_near_expiry() {
+        let request = request("www.example.com", false);
+        let cache = DnsCache::new(16).with_prefetch(0.5);
+        let inserted = Instant::now() - Duration::from_secs(200);
+        cache.insert_at(key(&request), &answer(&request, 300), inserted);
+        let cache = Arc::new(cache);
+
+        let handler = Arc::new(CacheLayer::new(cache.clone()).layer(Counting(AtomicUsize::new(0))));
+        for _ in 0..5 {
+            let context = RequestContext::local(Transport::Udp);
+            let response = handler
+                .clone()
+                .handle_request(request.clone(), context)
+                .await
+                .unwrap();
+            assert_eq!(response.answers[0].ttl, 100);
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(handler.inner.0.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get(&request).unwrap().answers[0].ttl, 300);
+    }
+
+    #[test]
+    fn test_snapshot_restores_entries_with_elapsed_time() {
+        let cache = DnsCache::new(16);
+        let now = Instant::now();
+        let now_system = SystemTime::now();
+        let short = request("short.example.com", false);
+        let long = request("long.example.com", true);
+        let negative = request("nope.example.com", false);
+        cache.insert_at(key(&short), &answer(&short, 60), now);
+        cache.insert_at(key(&long), &answer(&long, 3600), now);
+        cache.insert_at(key(&negative), &nxdomain(&negative, 3600, 600), now);
+
+        let mut snapshot = Vec::new();
+        assert_eq!(
+            cache
+                .write_snapshot(&mut snapshot, now, now_system)
+                .unwrap(),
+            3
+        );
+
+        // Restored 100 seconds later, the short entry has expired in the meantime
+        let restored = DnsCache::new(16);
+        let later = now_system + Duration::from_secs(100);
+        assert_eq!(restored.read_snapshot(&snapshot, now, later).unwrap(), 2);
+        assert!(restored.get_at(&short, now).is_none());
+        assert_eq!(restored.get_at(&long, now).unwrap().answers[0].ttl, 3500);
+        let response = restored.get_at(&negative, now).unwrap();
+        assert_eq!(response.response_code(), 3);
+        assert_eq!(response.authority[0].ttl, 500);
+
+        // With serve-stale the expired entry is kept
+        let restored = DnsCache::new(16).with_serve_stale(Duration::from_secs(3600));
+        assert_eq!(restored.read_snapshot(&snapshot, now, later).unwrap(), 3);
+        assert!(restored.get_stale_at(&short, now).is_some());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("dns-cache-{}.snapshot", std::process::id()));
+        let cache = DnsCache::new(16);
+        let request = request("www.example.com", false);
+        cache.insert(key(&request), &answer(&request, 300));
+        assert_eq!(cache.save(&path).unwrap(), 1);
+
+        let restored = DnsCache::new(16);
+        assert_eq!(restored.load(&path).unwrap(), 1);
+        // Remaining lifetimes are rounded down to whole seconds
+        let restored = restored.get(&request).unwrap();
+        assert!((299..=300).contains(&restored.answers[0].ttl));
+        assert_eq!(
+            restored.answers[0].rdata,
+            answer(&request, 300).answers[0].rdata
+        );
+
+        std::fs::write(&path, b"garbage").unwrap();
+        assert!(DnsCache::new(16).load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_flush_and_stats() {
+        let cache = DnsCache::new(2);
+        let now = Instant::now();
+        let www = request("www.example.com", false);
+        let www_dnssec = request("www.example.com", true);
+        let apex = request("example.com", false);
+        cache.insert_at(key(&www), &answer(&www, 300), now);
+        cache.insert_at(key(&www_dnssec), &answer(&www_dnssec, 300), now);
+
+        let later = now + Duration::from_secs(100);
+        let entries = cache.lookup_info_at(&labels_from_str("WWW.example.com").unwrap(), later);
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.remaining_ttl == 200
+            && entry.records[0].ttl == 200
+            && !entry.stale));
+
+        cache.get_at(&www, now);
+        cache.get_at(&apex, now);
+        cache.insert_at(key(&apex), &answer(&apex, 300), now);
+        let stats = cache.stats();
+        assert_eq!(
+            (
+                stats.entries,
+                stats.hits,
+                stats.misses,
+                stats.insertions,
+                stats.evictions
+            ),
+            (2, 1, 1, 3, 1)
+        );
+
+        assert_eq!(
+            cache.flush_name(&labels_from_str("www.example.com").unwrap()),
+            1
+        );
+        cache.insert_at(key(&www), &answer(&www, 300), now);
+        assert_eq!(
+            cache.flush_subtree(&labels_from_str("example.com").unwrap()),
+            2
+        );
+        assert!(cache.is_empty());
+
+        cache.insert_at(key(&www), &answer(&www, 300), now);
+        assert_eq!(cache.flush_all(), 1);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use crate::{
+    context::RequestContext,
+    handler::{DnsRequestError, DnsRequestHandler},
+    label::labels_to_string,
+    layer::Layer,
+    request::DNSRequest,
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord, DnsResponse},
+};
+
+/// Answers the CHAOS-class TXT queries operators use to identify a server instance
+/// (`version.bind`, `hostname.bind`, `id.server`, see RFC 4892) and hands every other
+/// request to the wrapped handler.
+///
+/// Each identity is optional. Queries for an identity that is not configured are
+/// refused, so `ChaosHandler::new(inner)` without further configuration hides all of
+/// them.
+pub struct ChaosHandler<H: DnsRequestHandler> {
+    inner: Arc<H>,
+    identities: ChaosLayer,
+}
+
+/// The identities a [`ChaosHandler`] answers with, as a layer that wraps handlers in one
+#[derive(Debug, Clone, Default)]
+pub struct ChaosLayer {
+    version: Option<String>,
+    hostname: Option<String>,
+    server_id: Option<String>,
+}
+
+impl ChaosLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers `version.bind` and `version.server` with `version`.
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Answers `hostname.bind` with `hostname`.
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Answers `id.server` with `server_id`, usually the same identifier the server
+    /// sends as its NSID.
+    pub fn with_server_id(mut self, server_id: impl Into<String>) -> Self {
+        self.server_id = Some(server_id.into());
+        self
+    }
+
+    fn identity(&self, name: &str) -> Option<Option<&String>> {
+        match name {
+            "version.bind" | "version.server" => Some(self.version.as_ref()),
+            "hostname.bind" => Some(self.hostname.as_ref()),
+            "id.server" => Some(self.server_id.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl<H: DnsRequestHandler> Layer<H> for ChaosLayer {
+    type Handler = ChaosHandler<H>;
+
+    fn layer(&self, inner: H) -> ChaosHandler<H> {
+        ChaosHandler {
+            inner: Arc::new(inner),
+            identities: self.clone(),
+        }
+    }
+}
+
+impl<H: DnsRequestHandler> ChaosHandler<H> {
+    pub fn new(inner: H) -> Self {
+        ChaosLayer::new().layer(inner)
+    }
+
+    /// See [`ChaosLayer::with_version`].
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.identities = self.identities.with_version(version);
+        self
+    }
+
+    /// See [`ChaosLayer::with_hostname`].
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.identities = self.identities.with_hostname(hostname);
+        self
+    }
+
+    /// See [`ChaosLayer::with_server_id`].
+    pub fn with_server_id(mut self, server_id: impl Into<String>) -> Self {
+        self.identities = self.identities.with_server_id(server_id);
+        self
+    }
+
+    fn answer(&self, request: &DNSRequest) -> Option<Result<DnsResponse, DnsRequestError>> {
+        let question = match request.questions.as_slice() {
+            [question] if question.qclass == DnsClass::CH => question,
+            _ => return None,
+        };
+
+        let name = labels_to_string(&question.qname).to_lowercase();
+        let identity = match self.identities.identity(&name) {
+            Some(Some(identity)) => identity,
+            Some(None) => return Some(Err(DnsRequestError::Refused)),
+            None => return None,
+        };
+
+        let mut response = DnsResponse::reply_to(request);
+        response.header.flags |= 0x0400; // AA
+        if matches!(
+            question.qtype,
+            ResourceRecordType::TXT | ResourceRecordType::ANY
+        ) {
+            response.answers.push(DnsResourceRecord {
+                name: question.qname.clone(),
+                rtype: ResourceRecordType::TXT,
+                class: DnsClass::CH,
+                ttl: 0,
+                rdata: DnsRecordData::TXT(vec![identity.clone().into_bytes()]),
+            });
+        }
+        Some(Ok(response))
+    }
+}
+
+impl<H: DnsRequestHandler> DnsRequestHandler for ChaosHandler<H> {
+    fn handle_request(
+        self: Arc<Self>,
+        request: DNSRequest,
+        context: RequestContext,
+    ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+        match self.answer(&request) {
+            Some(result) => Box::pin(async move { result }),
+            None => self.inner.clone().handle_request(request, context),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Transport;
+
+    struct NotImplemented;
+
+    impl DnsRequestHandler for NotImplemented {
+        fn handle_request(
+            self: Arc<Self>,
+            _request: DNSRequest,
+            _context: RequestContext,
+        ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+            Box::pin(async { Err(DnsRequestError::NotImp) })
+        }
+    }
+
+    fn request(name: &str, qclass: DnsClass) -> DNSRequest {
+        DNSRequest::query(name, ResourceRecordType::TXT, qclass)
+    }
+
+    fn context() -> RequestContext {
+        RequestContext::local(Transport::Udp)
+    }
+
+    #[tokio::test]
+    async fn test_chaos_answers_configured_identities() {
+        let handler = Arc::new(
+            ChaosHandler::new(NotImplemented)
+                .with_version("dns 0.1.0")
+                .with_server_id("fra-1"),
+        );
+
+        let response = handler
+            .clone()
+            .handle_request(request("VERSION.BIND", DnsClass::CH), context())
+            .await
+            .unwrap();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].class, DnsClass::CH);
+        match &response.answers[0].rdata {
+            DnsRecordData::TXT(strings) => assert_eq!(strings, &vec![b"dns 0.1.0".to_vec()]),
+            rdata => panic!("unexpected rdata {:?}", rdata),
+        }
+
+        let response = handler
+            .clone()
+            .handle_request(request("id.server", DnsClass::CH), context())
+            .await
+            .unwrap();
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_chaos_refuses_hidden_identities_and_passes_through() {
+        let handler = Arc::new(ChaosHandler::new(NotImplemented));
+
+        let result = handler
+            .clone()
+            .handle_request(request("hostname.bind", DnsClass::CH), context())
+            .await;
+        assert!(matches!(result, Err(DnsRequestError::Refused)));
+
+        let result = handler
+            .clone()
+            .handle_request(request("version.bind", DnsClass::IN), context())
+            .await;
+        assert!(matches!(result, Err(DnsRequestError::NotImp)));
+    }
+}
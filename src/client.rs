@@ -0,0 +1,585 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::{
+    edns::Edns,
+    label::{labels_from_str, DNSLabel},
+    request::{DNSHeader, DNSQuestion, DNSRequest},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord, DnsResponse},
+    tsig::{TsigKey, TsigSession},
+};
+
+// Attempts at binding a random source port before leaving the choice to the OS
+const SOURCE_PORT_ATTEMPTS: usize = 8;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_RETRIES: usize = 2;
+// How long a server that rejected EDNS is queried without it before EDNS is tried again
+const EDNS_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A stub resolver that sends queries to a single server.
+///
+/// Queries go over UDP and are repeated over TCP when the reply is truncated. Every
+/// attempt uses a fresh ID and source port, and attempts that time out or fail are
+/// retried. Queries carry an OPT record unless EDNS is disabled; a server that answers
+/// one with FORMERR or NOTIMP and without an OPT record of its own is assumed not to
+/// support EDNS (RFC 6891, section 7), and is asked again without it. Later queries
+/// leave out EDNS too for a while, after which it is tried again.
+pub struct DnsClient {
+    server: SocketAddr,
+    timeout: Duration,
+    retries: usize,
+    tcp_only: bool,
+    recursion_desired: bool,
+    checking_disabled: bool,
+    edns: Option<Edns>,
+    // When the server last rejected a query for carrying EDNS
+    edns_rejected: Mutex<Option<Instant>>,
+}
+
+impl DnsClient {
+    pub fn new(server: SocketAddr) -> Self {
+        DnsClient {
+            server,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            tcp_only: false,
+            recursion_desired: true,
+            checking_disabled: false,
+            edns: Some(Edns::default()),
+            edns_rejected: Mutex::new(None),
+        }
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// How long to wait for each reply
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many more attempts to make after the first one failed
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sends all queries over TCP instead of trying UDP first.
+    pub fn with_tcp_only(mut self, tcp_only: bool) -> Self {
+        self.tcp_only = tcp_only;
+        self
+    }
+
+    /// Sets the RD bit of built queries, on by default. Iterative resolvers turn it off.
+    pub fn with_recursion_desired(mut self, recursion_desired: bool) -> Self {
+        self.recursion_desired = recursion_desired;
+        self
+    }
+
+    /// Sets the CD bit of built queries.
+    pub fn with_checking_disabled(mut self, checking_disabled: bool) -> Self {
+        self.checking_disabled = checking_disabled;
+        self
+    }
+
+    /// The EDNS parameters of built queries, or `None` to send them without an OPT
+    /// record
+    pub fn with_edns(mut self, edns: Option<Edns>) -> Self {
+        self.edns = edns;
+        self
+    }
+
+    /// Sets the DO bit of built queries, asking for DNSSEC records. Enables EDNS.
+    pub fn with_dnssec_ok(mut self, dnssec_ok: bool) -> Self {
+        self.edns.get_or_insert_with(Edns::default).dnssec_ok = dnssec_ok;
+        self
+    }
+
+    /// Builds a query for `name`, `qtype` and `qclass` with a random ID.
+    pub fn build_query(
+        &self,
+        name: &[DNSLabel],
+        qtype: ResourceRecordType,
+        qclass: DnsClass,
+    ) -> DNSRequest {
+        let mut flags = 0;
+        if self.recursion_desired {
+            flags |= 0x0100;
+        }
+        if self.checking_disabled {
+            flags |= 0x0010;
+        }
+        let additional: Vec<_> = self.edns.iter().map(Edns::to_record).collect();
+
+        DNSRequest {
+            header: DNSHeader {
+                id: rand::random(),
+                flags,
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: additional.len() as u16,
+            },
+            questions: vec![DNSQuestion {
+                qname: name.to_vec(),
+                qtype,
+                qclass,
+            }],
+            answers: vec![],
+            authority: vec![],
+            additional,
+        }
+    }
+
+    /// Looks up `name`, given in dotted form, and returns the server's response.
+    pub async fn query(
+        &self,
+        name: &str,
+        qtype: ResourceRecordType,
+        qclass: DnsClass,
+    ) -> io::Result<DnsResponse> {
+        let name = labels_from_str(name).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid name: {}", e))
+        })?;
+        self.send(&self.build_query(&name, qtype, qclass)).await
+    }
+
+    /// Sends `query` and returns the response, which carries the ID of `query` even if
+    /// a retry went out under another one.
+    pub async fn send(&self, query: &DNSRequest) -> io::Result<DnsResponse> {
+        let mut query = query.clone();
+        let rejected = *self.edns_rejected.lock().unwrap();
+        if rejected.is_some_and(|at| at.elapsed() < EDNS_RETRY_INTERVAL) {
+            strip_edns(&mut query);
+        }
+
+        let mut last_error = None;
+        let mut attempt = 0;
+        while attempt <= self.retries {
+            let mut attempt_query = query.clone();
+            if attempt > 0 {
+                attempt_query.header.id = rand::random();
+            }
+
+            match self.exchange(&attempt_query).await {
+                Ok(mut response) => {
+                    if query.edns().is_some()
+                        && response.edns().is_none()
+                        && matches!(response.response_code(), 1 | 4)
+                    {
+                        debug!(
+                            "{} rejected EDNS with RCODE {}, retrying without it",
+                            self.server,
+                            response.response_code()
+                        );
+                        *self.edns_rejected.lock().unwrap() = Some(Instant::now());
+                        strip_edns(&mut query);
+                        continue;
+                    }
+                    response.header.id = query.header.id;
+                    return Ok(response);
+                }
+                Err(e) => {
+                    debug!(
+                        "Query to {} failed (attempt {}): {}",
+                        self.server,
+                        attempt + 1,
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+            attempt += 1;
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no attempts")))
+    }
+
+    async fn exchange(&self, query: &DNSRequest) -> io::Result<DnsResponse> {
+        if self.tcp_only {
+            return exchange_tcp(self.server, query, self.timeout).await;
+        }
+        let response = exchange_udp(self.server, query, self.timeout).await?;
+        if response.header.flags & 0x0200 != 0 {
+            debug!(
+                "Reply from {} was truncated, retrying over TCP",
+                self.server
+            );
+            return exchange_tcp(self.server, query, self.timeout).await;
+        }
+        Ok(response)
+    }
+}
+
+fn strip_edns(query: &mut DNSRequest) {
+    query
+        .additional
+        .retain(|record| record.rtype != ResourceRecordType::OPT);
+    query.header.arcount = query.additional.len() as u16;
+}
+
+/// Whether `response` answers `query`: the QR bit is set and ID and question match.
+pub fn is_response_to(query: &DNSRequest, response: &DnsResponse) -> bool {
+    response.header.flags & 0x8000 != 0
+        && response.header.id == query.header.id
+        && response.questions == query.questions
+}
+
+/// Sends `query` over UDP from a random source port and waits up to `timeout` for the
+/// reply. Datagrams from other addresses and replies that do not match the query's ID
+/// and question are dropped, as they may be spoofed.
+pub async fn exchange_udp(
+    server: SocketAddr,
+    query: &DNSRequest,
+    timeout: Duration,
+) -> io::Result<DnsResponse> {
+    let socket = bind_random_port(server).await?;
+    socket.connect(server).await?;
+    socket.send(&query.to_bytes()?).await?;
+
+    let receive = async {
+        let mut buf = [0; 1 << 16];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            match DnsResponse::parse(&buf[..len]) {
+                Ok(response) if is_response_to(query, &response) => return Ok(response),
+                Ok(_) => debug!("Dropping mismatched reply from {}", server),
+                Err(e) => debug!("Dropping malformed reply from {}: {:?}", server, e),
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, receive)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "query timed out"))?
+}
+
+/// Sends `query` over a new TCP connection, framing messages with the two-byte length
+/// prefix of RFC 1035, section 4.2.2.
+pub async fn exchange_tcp(
+    server: SocketAddr,
+    query: &DNSRequest,
+    timeout: Duration,
+) -> io::Result<DnsResponse> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(server).await?;
+        write_tcp_message(&mut stream, &query.to_bytes()?).await?;
+
+        let response = DnsResponse::parse(&read_tcp_message(&mut stream).await?)?;
+        if !is_response_to(query, &response) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "reply does not match the query",
+            ));
+        }
+        Ok(response)
+    };
+
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "query timed out"))?
+}
+
+/// Transfers a zone over a new TCP connection: sends `query`, an AXFR or IXFR request
+/// signed with `key` if one is given, and collects the records of the response messages
+/// up to the SOA record that closes the transfer (RFC 5936 and RFC 1995). The TSIG
+/// records of the responses have to verify with the key. `timeout` applies to each
+/// message. See [`Transfer`](crate::journal::Transfer) for making sense of the records.
+pub async fn exchange_transfer(
+    server: SocketAddr,
+    query: &DNSRequest,
+    key: Option<&TsigKey>,
+    timeout: Duration,
+) -> io::Result<Vec<DnsResourceRecord>> {
+    let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "transfer timed out");
+    let mut stream = tokio::time::timeout(timeout, TcpStream::connect(server))
+        .await
+        .map_err(|_| timed_out())??;
+    let mut tsig = key.map(|key| TsigSession::new(key.clone()));
+    let mut message = query.to_bytes()?;
+    if let Some(tsig) = &mut tsig {
+        tsig.sign(&mut message)?;
+    }
+    write_tcp_message(&mut stream, &message).await?;
+
+    let mut records: Vec<DnsResourceRecord> = Vec::new();
+    loop {
+        let message = tokio::time::timeout(timeout, read_tcp_message(&mut stream))
+            .await
+            .map_err(|_| timed_out())??;
+        let mut response = DnsResponse::parse(&message)?;
+        if let Some(tsig) = &mut tsig {
+            tsig.verify(&message).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("TSIG verification failed: {}", e),
+                )
+            })?;
+        }
+        // Only the first message has to carry the question
+        if response.header.id != query.header.id
+            || (records.is_empty() && response.questions != query.questions)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "reply does not match the query",
+            ));
+        }
+        if response.response_code() != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("transfer failed with RCODE {}", response.response_code()),
+            ));
+        }
+        let first = records.is_empty();
+        records.append(&mut response.answers);
+        let ixfr = query.questions[0].qtype == ResourceRecordType::IXFR;
+        // A lone SOA record tells an IXFR client that it is up to date
+        if transfer_complete(&records, ixfr) || (ixfr && first && records.len() == 1) {
+            return Ok(records);
+        }
+        if response.header.ancount == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "transfer ended without the closing SOA record",
+            ));
+        }
+    }
+}
+
+// Whether the records of a transfer response are complete. They start and end with the
+// current SOA record of the zone, which an incremental IXFR response also has at the
+// start of its last difference.
+fn transfer_complete(records: &[DnsResourceRecord], ixfr: bool) -> bool {
+    let serial_of = |record: &DnsResourceRecord| match record.rdata {
+        DnsRecordData::SOA { serial, .. } => Some(serial),
+        _ => None,
+    };
+    let current = match records.first().and_then(serial_of) {
+        Some(serial) => serial,
+        None => return false,
+    };
+    let incremental = ixfr
+        && records
+            .get(1)
+            .and_then(serial_of)
+            .is_some_and(|serial| serial != current);
+    let closing = records
+        .iter()
+        .filter(|record| serial_of(record) == Some(current))
+        .count();
+    records.len() > 1
+        && records.last().and_then(serial_of) == Some(current)
+        && closing >= if incremental { 3 } else { 2 }
+}
+
+/// Builds a request to transfer the zone `origin`: IXFR from the version of `soa` if
+/// given, and AXFR otherwise.
+pub fn transfer_query(
+    origin: &[DNSLabel],
+    class: DnsClass,
+    soa: Option<&DnsResourceRecord>,
+) -> DNSRequest {
+    let qtype = match soa {
+        Some(_) => ResourceRecordType::IXFR,
+        None => ResourceRecordType::AXFR,
+    };
+    let authority: Vec<DnsResourceRecord> = soa.into_iter().cloned().collect();
+    DNSRequest {
+        header: DNSHeader {
+            id: rand::random(),
+            flags: 0,
+            qdcount: 1,
+            ancount: 0,
+            nscount: authority.len() as u16,
+            arcount: 0,
+        },
+        questions: vec![DNSQuestion {
+            qname: origin.to_vec(),
+            qtype,
+            qclass: class,
+        }],
+        answers: vec![],
+        authority,
+        additional: vec![],
+    }
+}
+
+/// Writes a message with its two-byte length prefix.
+pub async fn write_tcp_message<W>(stream: &mut W, message: &[u8]) -> io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    if message.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message too long for TCP",
+        ));
+    }
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await
+}
+
+/// Reads a message with its two-byte length prefix.
+pub async fn read_tcp_message<R>(stream: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let len = stream.read_u16().await? as usize;
+    let mut message = vec![0; len];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+// Binds a UDP socket to a random unprivileged port, so replies are harder to spoof
+async fn bind_random_port(server: SocketAddr) -> io::Result<UdpSocket> {
+    let ip = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    for _ in 0..SOURCE_PORT_ATTEMPTS {
+        let port = rand::thread_rng().gen_range(1024..=u16::MAX);
+        if let Ok(socket) = UdpSocket::bind(SocketAddr::new(ip, port)).await {
+            return Ok(socket);
+        }
+    }
+    UdpSocket::bind(SocketAddr::new(ip, 0)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use super::*;
+    use crate::{
+        authority::AuthoritativeHandler, response::DnsRecordData, server::DnsServer, zone::Zone,
+    };
+
+    const EXAMPLE_COM: &str = "
+$TTL 3600
+@       SOA ns hostmaster 1 7200 900 604800 300
+        NS  ns
+ns      A   192.0.2.53
+www     A   192.0.2.1
+";
+
+    async fn authoritative_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handler = AuthoritativeHandler::new()
+            .with_zone(Zone::parse(EXAMPLE_COM, "example.com.").unwrap());
+        let server = DnsServer::new(handler);
+        tokio::spawn(async move { server.serve(socket).await });
+        addr
+    }
+
+    // A server that ignores the first query and answers `rcode` to queries with EDNS
+    async fn picky_server(seen: Arc<Mutex<Vec<bool>>>, rcode: u8) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let mut first = true;
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let query = DNSRequest::parse(&buf[..len]).unwrap();
+                seen.lock().unwrap().push(query.edns().is_some());
+                if std::mem::take(&mut first) {
+                    continue;
+                }
+
+                let mut response = DnsResponse::reply_to(&query);
+                if query.edns().is_some() {
+                    response.set_response_code(rcode);
+                }
+                let bytes = response.to_bytes().unwrap();
+                socket.send_to(&bytes, peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let client = DnsClient::new(authoritative_server().await);
+
+        let response = client
+            .query("www.example.com", ResourceRecordType::A, DnsClass::IN)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), 0);
+        assert_eq!(
+            response.answers[0].rdata,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+
+        let response = client
+            .query("nope.example.com", ResourceRecordType::A, DnsClass::IN)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retries_and_falls_back_without_edns() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let client = DnsClient::new(picky_server(seen.clone(), 1).await)
+            .with_timeout(Duration::from_millis(200))
+            .with_retries(1);
+
+        let query = client.build_query(
+            &labels_from_str("example.com").unwrap(),
+            ResourceRecordType::A,
+            DnsClass::IN,
+        );
+        let response = client.send(&query).await.unwrap();
+        assert_eq!(response.response_code(), 0);
+        assert_eq!(response.header.id, query.header.id);
+        assert_eq!(*seen.lock().unwrap(), [true, true, false]);
+
+        // The server's lack of EDNS support is remembered for a while
+        client.send(&query).await.unwrap();
+        assert_eq!(seen.lock().unwrap().last(), Some(&false));
+        *client.edns_rejected.lock().unwrap() = Instant::now().checked_sub(EDNS_RETRY_INTERVAL);
+        client.send(&query).await.unwrap();
+        assert_eq!(seen.lock().unwrap()[4..], [true, false]);
+    }
+
+    #[tokio::test]
+    async fn test_keeps_edns_after_servfail() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let client = DnsClient::new(picky_server(seen.clone(), 2).await)
+            .with_timeout(Duration::from_millis(200))
+            .with_retries(1);
+
+        let query = client.build_query(
+            &labels_from_str("example.com").unwrap(),
+            ResourceRecordType::A,
+            DnsClass::IN,
+        );
+        let response = client.send(&query).await.unwrap();
+        assert_eq!(response.response_code(), 2);
+        assert_eq!(*seen.lock().unwrap(), [true, true]);
+
+        client.send(&query).await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), [true, true, true]);
+    }
+}
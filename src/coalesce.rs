@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    cache::CacheKey,
+    context::{RequestContext, Transport},
+    handler::{DnsRequestError, DnsRequestHandler},
+    layer::Layer,
+    request::DNSRequest,
+    response::DnsResponse,
+};
+
+// Requests that can share an answer: same question, DO and CD bits, RD bit and
+// transport
+type CoalescingKey = (CacheKey, bool, Transport);
+
+type Waiters = Vec<oneshot::Sender<Result<DnsResponse, DnsRequestError>>>;
+
+type InFlight = Arc<Mutex<HashMap<CoalescingKey, Waiters>>>;
+
+// The leader's claim on an in-flight entry. Dropping it removes the entry, so that a
+// resolution that panics leaves its waiters with SERVFAIL instead of stuck.
+struct Resolution {
+    in_flight: InFlight,
+    key: CoalescingKey,
+}
+
+impl Resolution {
+    fn finish(self, result: Result<DnsResponse, DnsRequestError>) {
+        for waiter in self.take_waiters() {
+            let _ = waiter.send(result.clone());
+        }
+    }
+
+    fn take_waiters(&self) -> Waiters {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.key)
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Resolution {
+    fn drop(&mut self) {
+        self.take_waiters();
+    }
+}
+
+/// A layer that wraps handlers in a [`CoalescingHandler`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CoalescingLayer;
+
+impl CoalescingLayer {
+    pub fn new() -> Self {
+        CoalescingLayer
+    }
+}
+
+impl<H: DnsRequestHandler> Layer<H> for CoalescingLayer {
+    type Handler = CoalescingHandler<H>;
+
+    fn layer(&self, inner: H) -> CoalescingHandler<H> {
+        CoalescingHandler::new(inner)
+    }
+}
+
+/// Collapses identical requests that arrive while one of them is being resolved into a
+/// single call of the wrapped handler. Every waiting request gets a copy of the outcome,
+/// under its own ID and with its own spelling of the question.
+///
+/// The resolution runs in a task of its own, so it completes for the remaining waiters
+/// even if the request that started it is dropped.
+///
+/// Only the context of the first request reaches the wrapped handler, so the layer is
+/// meant for handlers whose answers do not depend on who asks, such as resolvers.
+/// Requests verified with a TSIG key are passed through on their own.
+pub struct CoalescingHandler<H: DnsRequestHandler> {
+    inner: Arc<H>,
+    in_flight: InFlight,
+}
+
+impl<H: DnsRequestHandler> CoalescingHandler<H> {
+    pub fn new(inner: H) -> Self {
+        CoalescingHandler {
+            inner: Arc::new(inner),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The number of distinct requests currently being resolved
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+impl<H: DnsRequestHandler> DnsRequestHandler for CoalescingHandler<H> {
+    fn handle_request(
+        self: Arc<Self>,
+        request: DNSRequest,
+        context: RequestContext,
+    ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+        let key = match CacheKey::from_request(&request) {
+            Some(key) if context.tsig_key.is_none() => {
+                (key, request.header.flags & 0x0100 != 0, context.transport)
+            }
+            _ => return self.inner.clone().handle_request(request, context),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        let leader = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get_mut(&key) {
+                Some(waiters) => {
+                    waiters.push(sender);
+                    false
+                }
+                None => {
+                    in_flight.insert(key.clone(), vec![sender]);
+                    true
+                }
+            }
+        };
+
+        if leader {
+            let claim = Resolution {
+                in_flight: self.in_flight.clone(),
+                key,
+            };
+            let resolution = self.inner.clone().handle_request(request.clone(), context);
+            tokio::spawn(async move { claim.finish(resolution.await) });
+        }
+
+        Box::pin(async move {
+            let mut response = receiver.await.unwrap_or(Err(DnsRequestError::ServFail))?;
+            response.header.id = request.header.id;
+            response.questions = request.questions;
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        label::{labels_from_str, labels_to_string},
+        resourcerecord::{DnsClass, ResourceRecordType},
+        response::{DnsRecordData, DnsResourceRecord},
+    };
+
+    // Answers slowly and counts the requests that reach it
+    struct Slow(AtomicUsize);
+
+    impl DnsRequestHandler for Slow {
+        fn handle_request(
+            self: Arc<Self>,
+            request: DNSRequest,
+            _context: RequestContext,
+        ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let mut response = DnsResponse::reply_to(&request);
+                response.answers.push(DnsResourceRecord {
+                    name: request.questions[0].qname.clone(),
+                    rtype: ResourceRecordType::A,
+                    class: DnsClass::IN,
+                    ttl: 300,
+                    rdata: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                });
+                Ok(response)
+            })
+        }
+    }
+
+    // Panics while resolving
+    struct Panicking;
+
+    impl DnsRequestHandler for Panicking {
+        fn handle_request(
+            self: Arc<Self>,
+            _request: DNSRequest,
+            _context: RequestContext,
+        ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                panic!("resolution failed");
+            })
+        }
+    }
+
+    fn request(id: u16, name: &str) -> DNSRequest {
+        let mut request = DNSRequest::query(name, ResourceRecordType::A, DnsClass::IN);
+        request.header.id = id;
+        request
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_share_one_resolution() {
+        let handler = Arc::new(CoalescingLayer::new().layer(Slow(AtomicUsize::new(0))));
+
+        let name = |id: u16| {
+            if id.is_multiple_of(2) {
+                "www.example.com"
+            } else {
+                "WWW.example.com"
+            }
+        };
+        let requests = (0..10).map(|id| {
+            handler
+                .clone()
+                .handle_request(request(id, name(id)), RequestContext::local(Transport::Udp))
+        });
+        let other = handler.clone().handle_request(
+            request(99, "other.example.com"),
+            RequestContext::local(Transport::Udp),
+        );
+        // The same question over another transport, or with a TSIG key, is resolved
+        // on its own
+        let tcp = handler.clone().handle_request(
+            request(100, "www.example.com"),
+            RequestContext::local(Transport::Tcp),
+        );
+        let mut context = RequestContext::local(Transport::Udp);
+        context.tsig_key = Some(labels_from_str("key.example").unwrap());
+        let signed = handler
+            .clone()
+            .handle_request(request(101, "www.example.com"), context);
+        let (responses, other, tcp, signed) =
+            tokio::join!(futures::future::join_all(requests), other, tcp, signed);
+        assert_eq!(tcp.unwrap().header.id, 100);
+        assert_eq!(signed.unwrap().header.id, 101);
+
+        for (id, response) in responses.into_iter().enumerate() {
+            let response = response.unwrap();
+            let id = id as u16;
+            assert_eq!(response.header.id, id);
+            assert_eq!(labels_to_string(&response.questions[0].qname), name(id));
+            assert_eq!(response.answers.len(), 1);
+        }
+        assert_eq!(other.unwrap().header.id, 99);
+        assert_eq!(handler.inner.0.load(Ordering::SeqCst), 4);
+        assert_eq!(handler.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_waiters_get_servfail_when_the_resolution_panics() {
+        let handler = Arc::new(CoalescingLayer::new().layer(Panicking));
+
+        let requests = (0..3).map(|id| {
+            handler.clone().handle_request(
+                request(id, "www.example.com"),
+                RequestContext::local(Transport::Udp),
+            )
+        });
+        for response in futures::future::join_all(requests).await {
+            assert!(matches!(response, Err(DnsRequestError::ServFail)));
+        }
+        assert_eq!(handler.in_flight(), 0);
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use crate::label::DNSLabel;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The transport a request was received over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "UDP"),
+            Transport::Tcp => write!(f, "TCP"),
+            Transport::Tls => write!(f, "TLS"),
+            Transport::Https => write!(f, "HTTPS"),
+        }
+    }
+}
+
+/// What the server knows about a request besides its contents, passed to handlers
+/// alongside the parsed request
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// Identifies the request in logs, unique within the process
+    pub id: u64,
+    pub client_addr: SocketAddr,
+    /// The address of the listener that received the request
+    pub local_addr: SocketAddr,
+    pub transport: Transport,
+    pub received_at: SystemTime,
+    /// The message as received, before parsing
+    pub raw: Arc<[u8]>,
+    /// The name of the TSIG key the request was signed with, once the signature has
+    /// been verified
+    pub tsig_key: Option<Vec<DNSLabel>>,
+}
+
+impl RequestContext {
+    /// Creates the context of a request received just now, assigning it the next ID.
+    pub fn new(
+        client_addr: SocketAddr,
+        local_addr: SocketAddr,
+        transport: Transport,
+        raw: impl Into<Arc<[u8]>>,
+    ) -> Self {
+        RequestContext {
+            id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            client_addr,
+            local_addr,
+            transport,
+            received_at: SystemTime::now(),
+            raw: raw.into(),
+            tsig_key: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl RequestContext {
+    /// The context of a request over `transport` from and to 127.0.0.1:53
+    pub(crate) fn local(transport: Transport) -> Self {
+        let addr = "127.0.0.1:53".parse().unwrap();
+        RequestContext::new(addr, addr, transport, vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_ids_are_unique() {
+        let addr: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let first = RequestContext::new(addr, addr, Transport::Udp, vec![]);
+        let second = RequestContext::new(addr, addr, Transport::Tcp, vec![1, 2, 3]);
+
+        assert_ne!(first.id, second.id);
+        assert_eq!(&second.raw[..], &[1, 2, 3]);
+        assert_eq!(second.transport.to_string(), "TCP");
+    }
+}
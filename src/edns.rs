@@ -0,0 +1,202 @@
+use std::io::{self, Cursor, Read};
+
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord},
+};
+
+// Extension Mechanisms for DNS, see RFC 6891
+
+/// The UDP payload size advertised in OPT records this crate creates
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+const OPTION_NSID: u16 = 3;
+const OPTION_EXTENDED_ERROR: u16 = 15;
+
+/// Extended DNS Error info code for answers served from expired cache data (RFC 8914)
+pub const EDE_STALE_ANSWER: u16 = 3;
+
+/// An option in the RDATA of an OPT record
+#[derive(Debug, Clone, PartialEq)]
+pub enum EdnsOption {
+    /// Name Server Identifier (RFC 5001). Empty in queries, the server's identifier in
+    /// responses.
+    Nsid(Vec<u8>),
+    /// Extended DNS Error (RFC 8914): an info code and optional text that explain the
+    /// response
+    ExtendedError {
+        info_code: u16,
+        extra_text: String,
+    },
+    Unknown(u16, Vec<u8>),
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::Nsid(_) => OPTION_NSID,
+            EdnsOption::ExtendedError { .. } => OPTION_EXTENDED_ERROR,
+            EdnsOption::Unknown(code, _) => *code,
+        }
+    }
+
+    pub fn write(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let extended_error;
+        let data: &[u8] = match self {
+            EdnsOption::Nsid(nsid) => nsid,
+            EdnsOption::ExtendedError {
+                info_code,
+                extra_text,
+            } => {
+                extended_error = [&info_code.to_be_bytes()[..], extra_text.as_bytes()].concat();
+                &extended_error
+            }
+            EdnsOption::Unknown(_, data) => data,
+        };
+        if data.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "EDNS option too long",
+            ));
+        }
+        buffer.write_u16::<NetworkEndian>(self.code())?;
+        buffer.write_u16::<NetworkEndian>(data.len() as u16)?;
+        buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Reads all options of an OPT record's RDATA.
+    pub fn read_all(cursor: &mut Cursor<&[u8]>, rdlength: u16) -> io::Result<Vec<Self>> {
+        let end = cursor.position() + rdlength as u64;
+        let mut options = Vec::new();
+        while cursor.position() < end {
+            let code = cursor.read_u16::<NetworkEndian>()?;
+            let len = cursor.read_u16::<NetworkEndian>()? as usize;
+            let mut data = vec![0; len];
+            cursor.read_exact(&mut data)?;
+            options.push(match code {
+                OPTION_NSID => EdnsOption::Nsid(data),
+                OPTION_EXTENDED_ERROR if data.len() >= 2 => EdnsOption::ExtendedError {
+                    info_code: u16::from_be_bytes([data[0], data[1]]),
+                    extra_text: String::from_utf8_lossy(&data[2..]).into_owned(),
+                },
+                code => EdnsOption::Unknown(code, data),
+            });
+        }
+        if cursor.position() != end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "EDNS option overruns the OPT record",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// The decoded contents of an OPT pseudo-record
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Edns {
+            udp_payload_size: DEFAULT_UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl Edns {
+    /// Decodes an OPT record, returning `None` for records of any other type.
+    pub fn from_record(record: &DnsResourceRecord) -> Option<Self> {
+        let options = match &record.rdata {
+            DnsRecordData::OPT(options) => options.clone(),
+            _ => return None,
+        };
+        Some(Edns {
+            udp_payload_size: record.class.id(),
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: record.ttl & 0x8000 != 0,
+            options,
+        })
+    }
+
+    pub fn to_record(&self) -> DnsResourceRecord {
+        let mut ttl = (self.extended_rcode as u32) << 24 | (self.version as u32) << 16;
+        if self.dnssec_ok {
+            ttl |= 0x8000;
+        }
+        DnsResourceRecord {
+            name: Vec::new(),
+            rtype: ResourceRecordType::OPT,
+            class: DnsClass::from(self.udp_payload_size),
+            ttl,
+            rdata: DnsRecordData::OPT(self.options.clone()),
+        }
+    }
+
+    pub fn option(&self, code: u16) -> Option<&EdnsOption> {
+        self.options.iter().find(|option| option.code() == code)
+    }
+
+    /// Whether the sender asked for the server's NSID
+    pub fn requests_nsid(&self) -> bool {
+        self.option(OPTION_NSID).is_some()
+    }
+
+    /// Adds `option`, replacing an option with the same code.
+    pub fn set_option(&mut self, option: EdnsOption) {
+        self.options
+            .retain(|existing| existing.code() != option.code());
+        self.options.push(option);
+    }
+}
+
+/// Finds the OPT record in an additional section.
+pub fn find_edns(additional: &[DnsResourceRecord]) -> Option<Edns> {
+    additional.iter().find_map(Edns::from_record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edns_record_roundtrip() {
+        let edns = Edns {
+            udp_payload_size: 4096,
+            extended_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![
+                EdnsOption::Nsid(b"fra-1".to_vec()),
+                EdnsOption::ExtendedError {
+                    info_code: EDE_STALE_ANSWER,
+                    extra_text: "upstream timed out".into(),
+                },
+            ],
+        };
+
+        let record = edns.to_record();
+        assert_eq!(record.class, DnsClass::Unknown(4096));
+        assert_eq!(record.ttl, 0x0100_8000);
+
+        let mut buffer = Vec::new();
+        record.write(&mut buffer).unwrap();
+        let mut cursor = Cursor::new(&buffer[..]);
+        let read = DnsResourceRecord::read(&mut cursor).unwrap();
+        assert_eq!(Edns::from_record(&read), Some(edns));
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use rand::seq::SliceRandom;
+
+use crate::{
+    client::DnsClient,
+    context::RequestContext,
+    edns::Edns,
+    handler::{DnsRequestError, DnsRequestHandler},
+    request::{DNSHeader, DNSRequest},
+    response::DnsResponse,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_RETRIES: usize = 1;
+
+/// How a [`ForwardingHandler`] orders its upstreams for each query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamPolicy {
+    /// Starts with the next upstream in turn.
+    RoundRobin,
+    /// Tries the upstreams in random order.
+    Random,
+    /// Prefers the upstream with the lowest smoothed round-trip time.
+    LowestLatency,
+}
+
+struct Upstream {
+    addr: SocketAddr,
+    // Smoothed round-trip time in microseconds, 0 until the first measurement
+    srtt: AtomicU64,
+}
+
+impl Upstream {
+    fn record_rtt(&self, rtt: Duration) {
+        let sample = rtt.as_micros().min(u64::MAX as u128) as u64;
+        let srtt = self.srtt.load(Ordering::Relaxed);
+        let srtt = if srtt == 0 {
+            sample
+        } else {
+            (srtt * 7 + sample) / 8
+        };
+        self.srtt.store(srtt.max(1), Ordering::Relaxed);
+    }
+
+    fn record_failure(&self, timeout: Duration) {
+        let penalty = timeout.as_micros().min(u64::MAX as u128) as u64;
+        let srtt = self.srtt.load(Ordering::Relaxed);
+        self.srtt
+            .store(srtt.saturating_mul(2).max(penalty), Ordering::Relaxed);
+    }
+}
+
+/// Answers requests by forwarding them to upstream resolvers over UDP, falling back to
+/// TCP for truncated replies. Each upstream query gets a random ID and source port, and
+/// only replies that match its ID and question are accepted. An upstream that times out,
+/// fails or answers with SERVFAIL or REFUSED is skipped in favor of the next one; when
+/// all of them failed `retries` more times the request gets the last such answer, or
+/// fails with SERVFAIL. Replies fetched over TCP may not fit the client's UDP payload
+/// size; the server truncates them for UDP clients.
+pub struct ForwardingHandler {
+    upstreams: Vec<Upstream>,
+    policy: UpstreamPolicy,
+    timeout: Duration,
+    retries: usize,
+    next: AtomicUsize,
+}
+
+impl ForwardingHandler {
+    pub fn new(upstreams: impl IntoIterator<Item = SocketAddr>) -> Self {
+        ForwardingHandler {
+            upstreams: upstreams
+                .into_iter()
+                .map(|addr| Upstream {
+                    addr,
+                    srtt: AtomicU64::new(0),
+                })
+                .collect(),
+            policy: UpstreamPolicy::RoundRobin,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: UpstreamPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// How long to wait for each upstream reply
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many more rounds through the upstreams to make after the first one failed
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // The upstreams to try for one query, in order
+    fn order(&self) -> Vec<&Upstream> {
+        let mut order: Vec<&Upstream> = self.upstreams.iter().collect();
+        match self.policy {
+            UpstreamPolicy::RoundRobin if !order.is_empty() => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % order.len();
+                order.rotate_left(start);
+            }
+            UpstreamPolicy::RoundRobin => {}
+            UpstreamPolicy::Random => order.shuffle(&mut rand::thread_rng()),
+            UpstreamPolicy::LowestLatency => {
+                order.sort_by_key(|upstream| upstream.srtt.load(Ordering::Relaxed))
+            }
+        }
+        order
+    }
+
+    async fn query_upstream(
+        &self,
+        upstream: &Upstream,
+        query: &DNSRequest,
+    ) -> std::io::Result<DnsResponse> {
+        let started = Instant::now();
+        // Retries are up to the handler, which moves on to the next upstream first
+        let response = DnsClient::new(upstream.addr)
+            .with_timeout(self.timeout)
+            .with_retries(0)
+            .send(query)
+            .await?;
+        upstream.record_rtt(started.elapsed());
+        Ok(response)
+    }
+
+    async fn forward(&self, request: DNSRequest) -> Result<DnsResponse, DnsRequestError> {
+        if request.questions.len() != 1 {
+            return Err(DnsRequestError::FormErr);
+        }
+
+        let query = upstream_query(&request);
+        let mut last_failure = None;
+        for attempt in 0..=self.retries {
+            for upstream in self.order() {
+                let error = match self.query_upstream(upstream, &query).await {
+                    Ok(mut response) => {
+                        response.header.id = request.header.id;
+                        if !is_failure(&response) {
+                            return Ok(response);
+                        }
+                        let error = format!("answered with RCODE {}", response.response_code());
+                        last_failure = Some(response);
+                        error
+                    }
+                    Err(e) => e.to_string(),
+                };
+                warn!(
+                    "Upstream {} failed (attempt {}): {}",
+                    upstream.addr,
+                    attempt + 1,
+                    error
+                );
+                upstream.record_failure(self.timeout);
+            }
+        }
+
+        last_failure.ok_or(DnsRequestError::ServFail)
+    }
+}
+
+// The query sent upstream for `request`. Opcode, RD and CD are passed on, everything
+// else is set by this resolver, including the OPT record, which only keeps the client's
+// DO bit.
+fn upstream_query(request: &DNSRequest) -> DNSRequest {
+    let edns = request.edns().map(|edns| Edns {
+        dnssec_ok: edns.dnssec_ok,
+        ..Edns::default()
+    });
+    DNSRequest {
+        header: DNSHeader {
+            id: rand::random(),
+            flags: request.header.flags & 0x7910,
+            qdcount: 1,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        },
+        questions: request.questions.clone(),
+        answers: vec![],
+        authority: vec![],
+        additional: edns.iter().map(Edns::to_record).collect(),
+    }
+}
+
+// Whether an upstream reply says that the upstream could not answer
+fn is_failure(response: &DnsResponse) -> bool {
+    let rcode = response.response_code();
+    rcode == DnsRequestError::ServFail.to_response_code()
+        || rcode == DnsRequestError::Refused.to_response_code()
+}
+
+impl DnsRequestHandler for ForwardingHandler {
+    fn handle_request(
+        self: Arc<Self>,
+        request: DNSRequest,
+        _context: RequestContext,
+    ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+        Box::pin(async move { self.forward(request).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+    use crate::{
+        client::{read_tcp_message, write_tcp_message},
+        context::Transport,
+        edns::{EdnsOption, DEFAULT_UDP_PAYLOAD_SIZE},
+        resourcerecord::{DnsClass, ResourceRecordType},
+        response::{DnsRecordData, DnsResourceRecord},
+    };
+
+    fn answer(query: &DNSRequest) -> DnsResponse {
+        let mut response = DnsResponse::reply_to(query);
+        response.answers.push(DnsResourceRecord {
+            name: query.questions[0].qname.clone(),
+            rtype: ResourceRecordType::A,
+            class: DnsClass::IN,
+            ttl: 60,
+            rdata: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        });
+        response
+    }
+
+    // A stub upstream that answers over UDP, optionally truncating, and over TCP
+    async fn stub_upstream(truncate: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let query = DNSRequest::parse(&buf[..len]).unwrap();
+
+                // A spoofed reply with the wrong ID arrives first
+                let mut spoofed = answer(&query);
+                spoofed.header.id = query.header.id.wrapping_add(1);
+                spoofed.answers[0].rdata = DnsRecordData::A(Ipv4Addr::new(6, 6, 6, 6));
+                let bytes = spoofed.to_bytes().unwrap();
+                socket.send_to(&bytes, peer).await.unwrap();
+
+                let mut response = answer(&query);
+                if truncate {
+                    response.header.flags |= 0x0200;
+                    response.answers.clear();
+                }
+                let bytes = response.to_bytes().unwrap();
+                socket.send_to(&bytes, peer).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let query =
+                    DNSRequest::parse(&read_tcp_message(&mut stream).await.unwrap()).unwrap();
+                let mut response = answer(&query);
+                response.answers[0].rdata = DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 2));
+                let bytes = response.to_bytes().unwrap();
+                write_tcp_message(&mut stream, &bytes).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    // An upstream that answers every query with `error`
+    async fn failing_upstream(error: DnsRequestError) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let query = DNSRequest::parse(&buf[..len]).unwrap();
+                let bytes = DnsResponse::error_for(&query, &error).to_bytes().unwrap();
+                socket.send_to(&bytes, peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    // An upstream that never replies
+    async fn silent_upstream() -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    fn request() -> (DNSRequest, RequestContext) {
+        let mut request = DNSRequest::query("www.example.com", ResourceRecordType::A, DnsClass::IN);
+        request.header.id = 0x4242;
+        (request, RequestContext::local(Transport::Udp))
+    }
+
+    #[tokio::test]
+    async fn test_forwards_and_restores_id() {
+        let upstream = stub_upstream(false).await;
+        let handler = Arc::new(ForwardingHandler::new([upstream]));
+
+        let (request, context) = request();
+        let response = handler.handle_request(request, context).await.unwrap();
+
+        assert_eq!(response.header.id, 0x4242);
+        assert_eq!(
+            response.answers[0].rdata,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_tcp_when_truncated() {
+        let upstream = stub_upstream(true).await;
+        let handler = Arc::new(ForwardingHandler::new([upstream]));
+
+        let (request, context) = request();
+        let response = handler.handle_request(request, context).await.unwrap();
+
+        assert_eq!(
+            response.answers[0].rdata,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 2))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_to_next_upstream() {
+        let (_silent, silent_addr) = silent_upstream().await;
+        let upstream = stub_upstream(false).await;
+        let handler = Arc::new(
+            ForwardingHandler::new([silent_addr, upstream])
+                .with_policy(UpstreamPolicy::LowestLatency)
+                .with_timeout(Duration::from_millis(200))
+                .with_retries(0),
+        );
+
+        for _ in 0..3 {
+            let (request, context) = request();
+            let response = handler.clone().handle_request(request, context).await;
+            assert!(response.is_ok());
+        }
+
+        // The silent upstream was penalized, so the working one is now preferred
+        assert_eq!(handler.order()[0].addr, upstream);
+    }
+
+    #[tokio::test]
+    async fn test_servfail_when_all_upstreams_fail() {
+        let (_silent, silent_addr) = silent_upstream().await;
+        let handler = Arc::new(
+            ForwardingHandler::new([silent_addr])
+                .with_timeout(Duration::from_millis(100))
+                .with_retries(1),
+        );
+
+        let (request, context) = request();
+        let result = handler.handle_request(request, context).await;
+        assert!(matches!(result, Err(DnsRequestError::ServFail)));
+    }
+
+    #[tokio::test]
+    async fn test_servfail_and_refused_fail_over() {
+        let servfail = failing_upstream(DnsRequestError::ServFail).await;
+        let refused = failing_upstream(DnsRequestError::Refused).await;
+        let upstream = stub_upstream(false).await;
+        let handler =
+            Arc::new(ForwardingHandler::new([servfail, refused, upstream]).with_retries(0));
+
+        let (query, context) = request();
+        let response = handler.handle_request(query, context).await.unwrap();
+        assert_eq!(response.response_code(), 0);
+        assert_eq!(response.answers.len(), 1);
+
+        // Once every upstream has been tried, the last failure is passed on
+        let handler = Arc::new(ForwardingHandler::new([servfail, refused]).with_retries(0));
+        let (query, context) = request();
+        let response = handler.handle_request(query, context).await.unwrap();
+        assert_eq!(response.header.id, 0x4242);
+        assert_eq!(
+            response.response_code(),
+            DnsRequestError::Refused.to_response_code()
+        );
+    }
+
+    #[test]
+    fn test_upstream_query_has_its_own_opt_record() {
+        let (mut request, _) = request();
+        assert!(upstream_query(&request).edns().is_none());
+
+        let edns = Edns {
+            udp_payload_size: 4096,
+            dnssec_ok: true,
+            options: vec![EdnsOption::Nsid(vec![])],
+            ..Edns::default()
+        };
+        request.additional.push(edns.to_record());
+        request.header.arcount = 1;
+        let query = upstream_query(&request);
+        assert_eq!(
+            query.edns(),
+            Some(Edns {
+                dnssec_ok: true,
+                ..Edns::default()
+            })
+        );
+        assert_eq!(
+            query.edns().unwrap().udp_payload_size,
+            DEFAULT_UDP_PAYLOAD_SIZE
+        );
+    }
+
+    #[test]
+    fn test_round_robin_rotates() {
+        let a: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:53".parse().unwrap();
+        let handler = ForwardingHandler::new([a, b]);
+
+        assert_eq!(handler.order()[0].addr, a);
+        assert_eq!(handler.order()[0].addr, b);
+        assert_eq!(handler.order()[0].addr, a);
+    }
+}
@@ -1,8 +1,8 @@
-use std::{error::Error, future::Future, pin::Pin, sync::Arc};
+use std::{future::Future, pin::Pin, sync::Arc};
 
-use crate::{request::DNSRequest, response::DnsResponse};
+use crate::{context::RequestContext, request::DNSRequest, response::DnsResponse};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DnsRequestError {
     FormErr,
     ServFail,
@@ -90,8 +90,9 @@ impl From<Box<dyn std::error::Error>> for DnsRequestError {
 }
 
 pub trait DnsRequestHandler: Send + Sync + 'static {
-    fn handle_request<'a>(
+    fn handle_request(
         self: Arc<Self>,
         request: DNSRequest,
+        context: RequestContext,
     ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>>;
 }
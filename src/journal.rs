@@ -0,0 +1,419 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::{
+    label::labels_to_string,
+    resourcerecord::ResourceRecordType,
+    response::{DnsRecordData, DnsResourceRecord},
+    zone::{parse_master_file, Zone},
+};
+
+// Zone differences for incremental transfers, see RFC 1995
+
+// How many differences a journal keeps by default
+const DEFAULT_MAX_DIFFS: usize = 100;
+
+/// The changes that turn one version of a zone into the next
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneDiff {
+    pub old_soa: DnsResourceRecord,
+    pub new_soa: DnsResourceRecord,
+    /// The records of the old version that the new one lacks, without the SOA record
+    pub removed: Vec<DnsResourceRecord>,
+    /// The records of the new version that the old one lacks, without the SOA record
+    pub added: Vec<DnsResourceRecord>,
+}
+
+impl ZoneDiff {
+    /// The changes from `old` to `new`, or `None` unless both have SOA records and the
+    /// serial of `new` is greater
+    pub fn between(old: &Zone, new: &Zone) -> Option<Self> {
+        let (old_serial, new_serial) = (old.serial()?, new.serial()?);
+        if new_serial.wrapping_sub(old_serial) as i32 <= 0 {
+            return None;
+        }
+        let differing = |zone: &Zone, other: &Zone| -> Vec<DnsResourceRecord> {
+            zone.records()
+                .filter(|record| record.rtype != ResourceRecordType::SOA)
+                .filter(|record| {
+                    !other
+                        .node(&record.name)
+                        .unwrap_or_default()
+                        .contains(record)
+                })
+                .cloned()
+                .collect()
+        };
+        Some(ZoneDiff {
+            old_soa: old.soa()?.clone(),
+            new_soa: new.soa()?.clone(),
+            removed: differing(old, new),
+            added: differing(new, old),
+        })
+    }
+
+    pub fn old_serial(&self) -> u32 {
+        serial(&self.old_soa)
+    }
+
+    pub fn new_serial(&self) -> u32 {
+        serial(&self.new_soa)
+    }
+
+    /// Applies the changes to `zone`, which has to be at the old serial.
+    pub fn apply(&self, zone: &mut Zone) -> io::Result<()> {
+        if zone.serial() != Some(self.old_serial()) {
+            return Err(invalid(format!(
+                "cannot apply the changes from serial {} to zone {}. at serial {}",
+                self.old_serial(),
+                labels_to_string(zone.origin()),
+                zone.serial()
+                    .map_or("none".to_string(), |serial| serial.to_string())
+            )));
+        }
+        for record in &self.removed {
+            zone.remove(record);
+        }
+        zone.remove(&self.old_soa);
+        for record in self.added.iter().chain([&self.new_soa]) {
+            zone.insert(record.clone())?;
+        }
+        Ok(())
+    }
+
+    /// The records of the difference in an IXFR response: the old SOA record, the
+    /// removed records, the new SOA record and the added records
+    pub fn records(&self) -> impl Iterator<Item = &DnsResourceRecord> {
+        [&self.old_soa]
+            .into_iter()
+            .chain(&self.removed)
+            .chain([&self.new_soa])
+            .chain(&self.added)
+    }
+}
+
+/// What a transfer response tells a client about the zone
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transfer {
+    /// The client is up to date with this SOA record, or has to ask again over TCP
+    Current(DnsResourceRecord),
+    /// All records of the zone, as in an AXFR response
+    Full(Vec<DnsResourceRecord>),
+    /// The changes from the client's version to the current one
+    Incremental(Vec<ZoneDiff>),
+}
+
+impl Transfer {
+    /// Makes sense of the answer records of an AXFR or IXFR response (RFC 1995, section
+    /// 4), which start and end with the current SOA record.
+    pub fn from_records(records: Vec<DnsResourceRecord>) -> io::Result<Self> {
+        let current = match records.first() {
+            Some(soa) if soa.rtype == ResourceRecordType::SOA => soa.clone(),
+            _ => return Err(invalid("transfer does not start with an SOA record")),
+        };
+        if records.len() == 1 {
+            return Ok(Transfer::Current(current));
+        }
+        if records.last() != Some(&current) {
+            return Err(invalid("transfer does not end with its SOA record"));
+        }
+        let body = &records[1..records.len() - 1];
+        let incremental = body
+            .first()
+            .is_some_and(|record| record.rtype == ResourceRecordType::SOA && *record != current);
+        if !incremental {
+            return Ok(Transfer::Full(records[..records.len() - 1].to_vec()));
+        }
+
+        // Each difference is an old SOA record and the removed records, followed by a
+        // new SOA record and the added records
+        let mut diffs: Vec<ZoneDiff> = Vec::new();
+        let mut removing = false;
+        for record in body {
+            match (record.rtype == ResourceRecordType::SOA, diffs.last_mut()) {
+                (true, Some(diff)) if removing => diff.new_soa = record.clone(),
+                (true, _) => diffs.push(ZoneDiff {
+                    old_soa: record.clone(),
+                    new_soa: record.clone(),
+                    removed: vec![],
+                    added: vec![],
+                }),
+                (false, Some(diff)) if removing => diff.removed.push(record.clone()),
+                (false, Some(diff)) => diff.added.push(record.clone()),
+                (false, None) => unreachable!("the differences start with an SOA record"),
+            }
+            if record.rtype == ResourceRecordType::SOA {
+                removing = !removing;
+            }
+        }
+        let chained = diffs
+            .windows(2)
+            .all(|pair| pair[0].new_serial() == pair[1].old_serial());
+        if removing || !chained || diffs.last().map(ZoneDiff::new_serial) != Some(serial(&current))
+        {
+            return Err(invalid("incomplete or inconsistent incremental transfer"));
+        }
+        Ok(Transfer::Incremental(diffs))
+    }
+}
+
+/// The recent changes of a zone, which IXFR requests are answered from. The changes
+/// form a chain from the serial of the oldest to the current one; a change that does not
+/// continue the chain starts it over.
+///
+/// A journal opened from a file appends each change to it, so that the changes survive
+/// a restart.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    diffs: Vec<ZoneDiff>,
+    max_diffs: usize,
+    path: Option<PathBuf>,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Journal {
+            diffs: vec![],
+            max_diffs: DEFAULT_MAX_DIFFS,
+            path: None,
+        }
+    }
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the journal kept in the file at `path`, which need not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let diffs = match fs::read_to_string(&path) {
+            Ok(text) => {
+                parse_journal(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        Ok(Journal {
+            diffs,
+            path: Some(path),
+            ..Journal::default()
+        })
+    }
+
+    /// Keeps at most `max_diffs` changes, dropping the oldest ones, 100 by default
+    pub fn with_max_diffs(mut self, max_diffs: usize) -> Self {
+        self.max_diffs = max_diffs.max(1);
+        self
+    }
+
+    pub fn diffs(&self) -> &[ZoneDiff] {
+        &self.diffs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diffs.is_empty()
+    }
+
+    /// Records a change. Unless it starts at the serial the last one ended at, the
+    /// journal forgets the changes before it.
+    pub fn push(&mut self, diff: ZoneDiff) -> io::Result<()> {
+        let continues = self
+            .diffs
+            .last()
+            .is_none_or(|last| last.new_serial() == diff.old_serial());
+        if !continues {
+            warn!(
+                "Journal of {}. starts over at serial {}",
+                labels_to_string(&diff.old_soa.name),
+                diff.old_serial()
+            );
+            self.diffs.clear();
+        }
+        self.diffs.push(diff);
+        let excess = self.diffs.len().saturating_sub(self.max_diffs);
+        self.diffs.drain(..excess);
+
+        match (continues && excess == 0, &self.path) {
+            (true, Some(path)) => {
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                file.write_all(format_diff(self.diffs.last().unwrap()).as_bytes())
+            }
+            (false, Some(_)) => self.save(),
+            (_, None) => Ok(()),
+        }
+    }
+
+    /// Forgets all changes, say after the zone was replaced by an unrelated version.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.diffs.clear();
+        self.save()
+    }
+
+    /// The changes from `serial` to the current version, none if `serial` is the
+    /// current one, or `None` if the journal does not reach back that far
+    pub fn since(&self, serial: u32) -> Option<&[ZoneDiff]> {
+        if self.diffs.last()?.new_serial() == serial {
+            return Some(&[]);
+        }
+        let start = self
+            .diffs
+            .iter()
+            .position(|diff| diff.old_serial() == serial)?;
+        Some(&self.diffs[start..])
+    }
+
+    // Rewrites the journal file with the changes kept
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let text: String = self.diffs.iter().map(format_diff).collect();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, text)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+// A change in the journal file: the removed records after `del`, starting with the old
+// SOA record, and the added ones after `add`, starting with the new SOA record
+fn format_diff(diff: &ZoneDiff) -> String {
+    let mut text = format!("; serial {} to {}\n", diff.old_serial(), diff.new_serial());
+    for record in [&diff.old_soa].into_iter().chain(&diff.removed) {
+        text.push_str(&format!("del {}\n", record));
+    }
+    for record in [&diff.new_soa].into_iter().chain(&diff.added) {
+        text.push_str(&format!("add {}\n", record));
+    }
+    text
+}
+
+fn parse_journal(text: &str) -> io::Result<Vec<ZoneDiff>> {
+    let mut diffs: Vec<ZoneDiff> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let error = |message: &str| invalid(format!("line {}: {}", index + 1, message));
+        let (operation, record) = line
+            .split_once(' ')
+            .ok_or_else(|| error("missing record"))?;
+        let record = parse_master_file(record, &[])?
+            .pop()
+            .ok_or_else(|| error("missing record"))?;
+        let is_soa = record.rtype == ResourceRecordType::SOA;
+        match (operation, is_soa) {
+            ("del", true) => diffs.push(ZoneDiff {
+                old_soa: record.clone(),
+                new_soa: record,
+                removed: vec![],
+                added: vec![],
+            }),
+            ("del", false) => match diffs.last_mut() {
+                Some(diff) => diff.removed.push(record),
+                None => return Err(error("records before the first SOA record")),
+            },
+            ("add", true) => match diffs.last_mut() {
+                Some(diff) => diff.new_soa = record,
+                None => return Err(error("records before the first SOA record")),
+            },
+            ("add", false) => match diffs.last_mut() {
+                Some(diff) => diff.added.push(record),
+                None => return Err(error("records before the first SOA record")),
+            },
+            _ => return Err(error("expected del or add")),
+        }
+    }
+    Ok(diffs)
+}
+
+fn serial(soa: &DnsResourceRecord) -> u32 {
+    match soa.rdata {
+        DnsRecordData::SOA { serial, .. } => serial,
+        _ => 0,
+    }
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE_COM: &str = "
+$TTL 3600
+@       SOA ns hostmaster 1 7200 900 604800 300
+        NS  ns
+ns      A   192.0.2.53
+";
+
+    #[test]
+    fn test_records_and_replays_changes() {
+        let dir = std::env::temp_dir().join(format!("dns-journal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.jnl");
+
+        let versions = [
+            Zone::version(EXAMPLE_COM, 1, "www A 192.0.2.1\n"),
+            Zone::version(EXAMPLE_COM, 2, "www A 192.0.2.2\ntxt TXT \"a b\"\n"),
+            Zone::version(EXAMPLE_COM, 3, "www A 192.0.2.2\n"),
+        ];
+        let mut journal = Journal::open(&path).unwrap();
+        for pair in versions.windows(2) {
+            journal
+                .push(ZoneDiff::between(&pair[0], &pair[1]).unwrap())
+                .unwrap();
+        }
+        assert!(ZoneDiff::between(&versions[2], &versions[1]).is_none());
+        let diff = &journal.diffs()[0];
+        assert_eq!((diff.removed.len(), diff.added.len()), (1, 2));
+
+        // Replaying the changes from any serial leads to the current version
+        for (index, version) in versions.iter().enumerate() {
+            let mut zone = version.clone();
+            for diff in journal.since(index as u32 + 1).unwrap() {
+                diff.apply(&mut zone).unwrap();
+            }
+            assert_eq!(zone.to_master_file(), versions[2].to_master_file());
+        }
+        assert!(journal.since(7).is_none());
+        assert!(journal.diffs()[1].apply(&mut versions[0].clone()).is_err());
+
+        // The file holds the same changes, and a change that does not follow the last
+        // one starts the journal over
+        let reopened = Journal::open(&path).unwrap();
+        assert_eq!(reopened.diffs(), journal.diffs());
+        let mut journal = reopened.with_max_diffs(1);
+        journal
+            .push(
+                ZoneDiff::between(
+                    &versions[0],
+                    &Zone::version(EXAMPLE_COM, 9, "www A 192.0.2.9\n"),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(journal.diffs().len(), 1);
+        assert_eq!(Journal::open(&path).unwrap().diffs(), journal.diffs());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
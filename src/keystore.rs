@@ -0,0 +1,771 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::info;
+
+use crate::{
+    dnssec::{
+        self, format_timestamp, key_file, parse_timestamp, SigningKey, ALGORITHM_ECDSAP256SHA256,
+        DIGEST_SHA256, FLAG_SEP, FLAG_ZONE,
+    },
+    label::{labels_to_string, DNSLabel},
+    resourcerecord::ResourceRecordType,
+    response::{DnsRecordData, DnsResourceRecord},
+    signer::{self, Denial, OnlineDenial, OnlineSigner},
+    zone::Zone,
+};
+
+// Key management and rollovers, see RFC 6781 and RFC 7583
+
+/// Where a key stands in its life cycle (RFC 7583, section 3.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    /// The key exists, but its DNSKEY record is not published yet.
+    Generated,
+    /// The DNSKEY record is published ahead of the key signing anything.
+    Published,
+    /// The key signs the zone.
+    Active,
+    /// The key no longer signs, but its DNSKEY record stays until the signatures
+    /// made with it have expired from caches.
+    Retired,
+    /// The DNSKEY record is no longer published.
+    Removed,
+}
+
+/// When a key enters each state. Times that are not set lie in the indefinite future.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyTiming {
+    pub created: u32,
+    pub publish: Option<u32>,
+    pub activate: Option<u32>,
+    pub inactive: Option<u32>,
+    pub delete: Option<u32>,
+}
+
+impl KeyTiming {
+    /// Timing for a key that is published and signs from `now` on
+    pub fn active_from(now: u32) -> Self {
+        KeyTiming {
+            created: now,
+            publish: Some(now),
+            activate: Some(now),
+            ..Default::default()
+        }
+    }
+
+    /// The state at `now`, comparing times in serial number arithmetic
+    pub fn state(&self, now: u32) -> KeyState {
+        let reached =
+            |time: Option<u32>| time.is_some_and(|time| now.wrapping_sub(time) as i32 >= 0);
+        if reached(self.delete) {
+            KeyState::Removed
+        } else if reached(self.inactive) {
+            KeyState::Retired
+        } else if reached(self.activate) {
+            KeyState::Active
+        } else if reached(self.publish) {
+            KeyState::Published
+        } else {
+            KeyState::Generated
+        }
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        let mut timing = KeyTiming::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let (field, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("invalid timing line {:?}", line)))?;
+            let time = parse_timestamp(value.trim())?;
+            match field.trim() {
+                "Created" => timing.created = time,
+                "Publish" => timing.publish = Some(time),
+                "Activate" => timing.activate = Some(time),
+                "Inactive" => timing.inactive = Some(time),
+                "Delete" => timing.delete = Some(time),
+                field => return Err(invalid(format!("unknown timing field {:?}", field))),
+            }
+        }
+        Ok(timing)
+    }
+
+    fn to_text(self) -> String {
+        let mut text = format!("Created: {}\n", format_timestamp(self.created));
+        for (field, time) in [
+            ("Publish", self.publish),
+            ("Activate", self.activate),
+            ("Inactive", self.inactive),
+            ("Delete", self.delete),
+        ] {
+            if let Some(time) = time {
+                text.push_str(&format!("{}: {}\n", field, format_timestamp(time)));
+            }
+        }
+        text
+    }
+}
+
+/// How the keys of a zone are rolled over. Zone-signing keys use pre-publication: the
+/// successor is published ahead of time and takes over signing from its predecessor
+/// at once. Key-signing keys use double signatures: the successor signs the DNSKEY
+/// RRset along with its predecessor until the parent has replaced the DS record.
+/// (RFC 7583, sections 3.2 and 3.3)
+#[derive(Debug, Clone)]
+pub struct RolloverPolicy {
+    /// The algorithm of new keys
+    pub algorithm: u8,
+    /// How long a key-signing key signs, `None` to keep it indefinitely
+    pub ksk_lifetime: Option<Duration>,
+    /// How long a zone-signing key signs, `None` to keep it indefinitely
+    pub zsk_lifetime: Option<Duration>,
+    /// The TTL of the DNSKEY, CDS and CDNSKEY records
+    pub dnskey_ttl: u32,
+    /// The longest TTL of the signed records in the zone
+    pub max_zone_ttl: u32,
+    /// The TTL of the DS records at the parent
+    pub ds_ttl: u32,
+    /// How long changes to the zone take to reach all of its servers
+    pub propagation_delay: Duration,
+    /// How long the parent takes to pick up changed CDS records and serve the new DS
+    /// records from all of its servers
+    pub parent_propagation_delay: Duration,
+    /// How long signatures made with the keys are valid
+    pub signature_validity: Duration,
+    /// The digest type of the CDS records
+    pub cds_digest_type: u8,
+}
+
+impl Default for RolloverPolicy {
+    fn default() -> Self {
+        RolloverPolicy {
+            algorithm: ALGORITHM_ECDSAP256SHA256,
+            ksk_lifetime: None,
+            zsk_lifetime: Some(Duration::from_secs(90 * 86400)),
+            dnskey_ttl: 3600,
+            max_zone_ttl: 86400,
+            ds_ttl: 86400,
+            propagation_delay: Duration::from_secs(300),
+            parent_propagation_delay: Duration::from_secs(86400),
+            signature_validity: Duration::from_secs(14 * 86400),
+            cds_digest_type: DIGEST_SHA256,
+        }
+    }
+}
+
+impl RolloverPolicy {
+    // How long after publication a DNSKEY record is known to every resolver
+    fn publication_delay(&self) -> u32 {
+        self.dnskey_ttl
+            .saturating_add(self.propagation_delay.as_secs() as u32)
+    }
+}
+
+/// A key of a [`KeyStore`] along with its timing
+pub struct StoredKey {
+    path: PathBuf,
+    key: SigningKey,
+    timing: KeyTiming,
+}
+
+impl StoredKey {
+    pub fn key(&self) -> &SigningKey {
+        &self.key
+    }
+
+    pub fn timing(&self) -> &KeyTiming {
+        &self.timing
+    }
+
+    pub fn state(&self, now: u32) -> KeyState {
+        self.timing.state(now)
+    }
+
+    /// The path of the key files, without the extension
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn save_timing(&self) -> io::Result<()> {
+        fs::write(key_file(&self.path, "state"), self.timing.to_text())
+    }
+}
+
+/// The keys of one zone, kept in a directory: each key in the `.key` and `.private`
+/// files of [`SigningKey::save`], and its timing in a `.state` file next to them. Keys
+/// without a `.state` file count as published and active.
+///
+/// [`KeyStore::maintain`] generates keys and schedules rollovers after the
+/// [`RolloverPolicy`], and the zone is then signed with the keys in the state they are
+/// in, through [`KeyStore::sign_zone`] or [`KeyStore::online_signer`]. The zone
+/// publishes CDS and CDNSKEY records for the key-signing keys its parent should refer
+/// to (RFC 7344).
+pub struct KeyStore {
+    dir: PathBuf,
+    origin: Vec<DNSLabel>,
+    policy: RolloverPolicy,
+    keys: Vec<StoredKey>,
+}
+
+impl KeyStore {
+    /// Opens the key store of zone `origin` in `dir`, creating the directory if need be.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        origin: &[DNSLabel],
+        policy: RolloverPolicy,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let prefix = format!("K{}.+", labels_to_string(origin));
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let is_key = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.ends_with(".key"))
+                });
+            if !is_key {
+                continue;
+            }
+            let path = path.with_extension("");
+            let (owner, key) = SigningKey::load(&path)?;
+            if owner != origin {
+                continue;
+            }
+            let timing = match fs::read_to_string(key_file(&path, "state")) {
+                Ok(text) => KeyTiming::parse(&text)
+                    .map_err(|e| invalid(format!("{}.state: {}", path.display(), e)))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => KeyTiming::active_from(0),
+                Err(e) => return Err(e),
+            };
+            keys.push(StoredKey { path, key, timing });
+        }
+        // In the order they were generated in, key-signing keys first
+        keys.sort_by_key(|stored| {
+            (
+                stored.timing.created,
+                !stored.key.is_key_signing(),
+                stored.key.key_tag(),
+            )
+        });
+
+        Ok(KeyStore {
+            dir,
+            origin: origin.to_vec(),
+            policy,
+            keys,
+        })
+    }
+
+    pub fn origin(&self) -> &[DNSLabel] {
+        &self.origin
+    }
+
+    pub fn policy(&self) -> &RolloverPolicy {
+        &self.policy
+    }
+
+    pub fn keys(&self) -> &[StoredKey] {
+        &self.keys
+    }
+
+    /// Generates a key of the policy's algorithm with `flags` and `timing`, and saves
+    /// it to the store.
+    pub fn generate(&mut self, flags: u16, timing: KeyTiming) -> io::Result<&StoredKey> {
+        // Keys are named after their tag, which has to be unique within the store
+        let key = loop {
+            let key = SigningKey::generate(self.policy.algorithm, flags)?;
+            if !self
+                .keys
+                .iter()
+                .any(|stored| stored.key.key_tag() == key.key_tag())
+            {
+                break key;
+            }
+        };
+        let path = key.save(&self.dir, &self.origin)?;
+        let stored = StoredKey { path, key, timing };
+        stored.save_timing()?;
+        info!(
+            "generated {} key {} for {}.",
+            role(&stored.key),
+            stored.key.key_tag(),
+            labels_to_string(&self.origin)
+        );
+        self.keys.push(stored);
+        Ok(self.keys.last().unwrap())
+    }
+
+    /// Changes the timing of the key with tag `key_tag`.
+    pub fn set_timing(&mut self, key_tag: u16, timing: KeyTiming) -> io::Result<()> {
+        let stored = self
+            .keys
+            .iter_mut()
+            .find(|stored| stored.key.key_tag() == key_tag)
+            .ok_or_else(|| invalid(format!("no key with tag {}", key_tag)))?;
+        stored.timing = timing;
+        stored.save_timing()
+    }
+
+    /// Brings the keys in line with the policy at `now`: generates a key-signing and a
+    /// zone-signing key if there are none, and a successor for keys that come to the
+    /// end of their lifetime, scheduling when it takes over. Returns whether anything
+    /// changed.
+    pub fn maintain(&mut self, now: u32) -> io::Result<bool> {
+        let rolled_ksk = self.roll(true, now)?;
+        let rolled_zsk = self.roll(false, now)?;
+        Ok(rolled_ksk || rolled_zsk)
+    }
+
+    fn roll(&mut self, key_signing: bool, now: u32) -> io::Result<bool> {
+        let policy = self.policy.clone();
+        let flags = match key_signing {
+            true => FLAG_ZONE | FLAG_SEP,
+            false => FLAG_ZONE,
+        };
+
+        // The latest key of the role that is not scheduled to retire
+        let current = self
+            .keys
+            .iter()
+            .enumerate()
+            .filter(|(_, stored)| {
+                stored.key.is_key_signing() == key_signing
+                    && stored.key.algorithm() == policy.algorithm
+                    && stored.timing.activate.is_some()
+                    && stored.timing.inactive.is_none()
+            })
+            .max_by_key(|(_, stored)| stored.timing.activate)
+            .map(|(index, stored)| (index, stored.timing.activate.unwrap()));
+        let (index, activated) = match current {
+            Some(current) => current,
+            None => {
+                self.generate(flags, KeyTiming::active_from(now))?;
+                return Ok(true);
+            }
+        };
+        let lifetime = match if key_signing {
+            policy.ksk_lifetime
+        } else {
+            policy.zsk_lifetime
+        } {
+            Some(lifetime) => lifetime.as_secs().min(u32::MAX as u64) as u32,
+            None => return Ok(false),
+        };
+
+        let end = activated.wrapping_add(lifetime);
+        let mut successor = KeyTiming {
+            created: now,
+            publish: Some(now),
+            ..Default::default()
+        };
+        let mut timing = self.keys[index].timing;
+        if key_signing {
+            // Double signature: both keys sign the DNSKEY RRset until the DS record of
+            // the successor has replaced the old one in all caches
+            if (now.wrapping_sub(end) as i32) < 0 {
+                return Ok(false);
+            }
+            successor.activate = Some(now);
+            let retire = now
+                .wrapping_add(policy.publication_delay())
+                .wrapping_add(policy.parent_propagation_delay.as_secs() as u32)
+                .wrapping_add(policy.ds_ttl);
+            timing.inactive = Some(retire);
+            timing.delete = Some(retire);
+        } else {
+            // Pre-publication: the successor's DNSKEY record is cached everywhere by the
+            // time it signs, and the predecessor's stays until its signatures are gone
+            let publish = end.wrapping_sub(policy.publication_delay());
+            if (now.wrapping_sub(publish) as i32) < 0 {
+                return Ok(false);
+            }
+            let ready = now.wrapping_add(policy.publication_delay());
+            let activate = if ready.wrapping_sub(end) as i32 > 0 {
+                ready
+            } else {
+                end
+            };
+            successor.activate = Some(activate);
+            timing.inactive = Some(activate);
+            timing.delete = Some(
+                activate
+                    .wrapping_add(policy.propagation_delay.as_secs() as u32)
+                    .wrapping_add(policy.max_zone_ttl),
+            );
+        }
+
+        let tag = self.keys[index].key.key_tag();
+        self.set_timing(tag, timing)?;
+        let origin = labels_to_string(&self.origin);
+        let successor = self.generate(flags, successor)?;
+        info!(
+            "rolling {} key {} of {}. over to {}",
+            role(&successor.key),
+            tag,
+            origin,
+            successor.key.key_tag()
+        );
+        Ok(true)
+    }
+
+    /// The keys that sign at `now`
+    pub fn active_keys(&self, now: u32) -> Vec<SigningKey> {
+        self.keys
+            .iter()
+            .filter(|stored| stored.state(now) == KeyState::Active)
+            .map(|stored| stored.key.clone())
+            .collect()
+    }
+
+    /// The DNSKEY records published at `now`
+    pub fn dnskey_records(&self, now: u32) -> Vec<DnsResourceRecord> {
+        self.keys
+            .iter()
+            .filter(|stored| {
+                matches!(
+                    stored.state(now),
+                    KeyState::Published | KeyState::Active | KeyState::Retired
+                )
+            })
+            .map(|stored| {
+                stored
+                    .key
+                    .dnskey_record(&self.origin, self.policy.dnskey_ttl)
+            })
+            .collect()
+    }
+
+    // The key-signing keys the parent should refer to at `now`: the successor in a
+    // rollover once its DNSKEY record is known everywhere, the active keys otherwise
+    fn parent_keys(&self, now: u32) -> Vec<&StoredKey> {
+        let active: Vec<&StoredKey> = self
+            .keys
+            .iter()
+            .filter(|stored| stored.key.is_key_signing() && stored.state(now) == KeyState::Active)
+            .collect();
+        let known: Vec<&StoredKey> = active
+            .iter()
+            .copied()
+            .filter(|stored| {
+                stored.timing.publish.is_some_and(|publish| {
+                    now.wrapping_sub(publish.wrapping_add(self.policy.publication_delay())) as i32
+                        >= 0
+                })
+            })
+            .collect();
+        let candidates = if known.is_empty() { active } else { known };
+        let staying: Vec<&StoredKey> = candidates
+            .iter()
+            .copied()
+            .filter(|stored| stored.timing.inactive.is_none())
+            .collect();
+        if staying.is_empty() {
+            candidates
+        } else {
+            staying
+        }
+    }
+
+    /// The DS records the parent should hold at `now`, with digests of `digest_type`
+    pub fn ds_records(&self, now: u32, digest_type: u8) -> Vec<DnsResourceRecord> {
+        self.parent_keys(now)
+            .into_iter()
+            .filter_map(|stored| {
+                let dnskey = stored
+                    .key
+                    .dnskey_record(&self.origin, self.policy.dnskey_ttl);
+                dnssec::ds_record(&dnskey, digest_type)
+            })
+            .collect()
+    }
+
+    /// The CDS records at `now`
+    pub fn cds_records(&self, now: u32) -> Vec<DnsResourceRecord> {
+        self.ds_records(now, self.policy.cds_digest_type)
+            .into_iter()
+            .map(|mut cds| {
+                cds.rtype = ResourceRecordType::CDS;
+                cds.rdata = match cds.rdata {
+                    DnsRecordData::DS {
+                        key_tag,
+                        algorithm,
+                        digest_type,
+                        digest,
+                    } => DnsRecordData::CDS {
+                        key_tag,
+                        algorithm,
+                        digest_type,
+                        digest,
+                    },
+                    rdata => rdata,
+                };
+                cds
+            })
+            .collect()
+    }
+
+    /// The CDNSKEY records at `now`
+    pub fn cdnskey_records(&self, now: u32) -> Vec<DnsResourceRecord> {
+        self.parent_keys(now)
+            .into_iter()
+            .map(|stored| {
+                let mut cdnskey = stored
+                    .key
+                    .dnskey_record(&self.origin, self.policy.dnskey_ttl);
+                cdnskey.rtype = ResourceRecordType::CDNSKEY;
+                cdnskey.rdata = match cdnskey.rdata {
+                    DnsRecordData::DNSKEY {
+                        flags,
+                        protocol,
+                        algorithm,
+                        public_key,
+                    } => DnsRecordData::CDNSKEY {
+                        flags,
+                        protocol,
+                        algorithm,
+                        public_key,
+                    },
+                    rdata => rdata,
+                };
+                cdnskey
+            })
+            .collect()
+    }
+
+    /// The DNSKEY, CDS and CDNSKEY records of the zone apex at `now`
+    pub fn apex_records(&self, now: u32) -> Vec<DnsResourceRecord> {
+        let mut records = self.dnskey_records(now);
+        records.extend(self.cds_records(now));
+        records.extend(self.cdnskey_records(now));
+        records
+    }
+
+    /// Signs `zone` with the keys in their state at `now`, see [`signer::sign_zone`].
+    /// The DNSKEY, CDS and CDNSKEY records of the zone are replaced with those of the
+    /// store.
+    pub fn sign_zone(
+        &self,
+        zone: &Zone,
+        denial: &Denial,
+        now: u32,
+        inception: u32,
+        expiration: u32,
+    ) -> io::Result<Zone> {
+        if zone.origin() != self.origin {
+            return Err(invalid(format!(
+                "the keys are for {}., not {}.",
+                labels_to_string(&self.origin),
+                labels_to_string(zone.origin())
+            )));
+        }
+        let keys = self.active_keys(now);
+        if keys.is_empty() {
+            return Err(invalid("no key is active"));
+        }
+
+        let mut zone = zone.clone();
+        for rtype in [
+            ResourceRecordType::DNSKEY,
+            ResourceRecordType::CDS,
+            ResourceRecordType::CDNSKEY,
+        ] {
+            zone.remove_rrset(&self.origin, rtype);
+        }
+        for record in self.apex_records(now) {
+            zone.insert(record)?;
+        }
+        signer::sign_zone(&zone, &keys, denial, inception, expiration)
+    }
+
+    /// An online signer with the keys in their state at `now`. The signer has to be
+    /// replaced whenever a key changes state.
+    pub fn online_signer(&self, denial: OnlineDenial, now: u32) -> io::Result<OnlineSigner> {
+        let keys = self.active_keys(now);
+        if keys.is_empty() {
+            return Err(invalid("no key is active"));
+        }
+        Ok(OnlineSigner::new(keys, denial)
+            .with_validity(self.policy.signature_validity)
+            .with_published(self.apex_records(now)))
+    }
+
+    /// The next time after `now` at which a key changes state, if any is scheduled
+    pub fn next_event(&self, now: u32) -> Option<u32> {
+        self.keys
+            .iter()
+            .flat_map(|stored| {
+                let timing = stored.timing;
+                [
+                    timing.publish,
+                    timing.activate,
+                    timing.inactive,
+                    timing.delete,
+                ]
+            })
+            .flatten()
+            // CDS records change once a new key-signing key is known everywhere
+            .chain(
+                self.keys
+                    .iter()
+                    .filter(|stored| stored.key.is_key_signing())
+                    .filter_map(|stored| stored.timing.publish)
+                    .map(|publish| publish.wrapping_add(self.policy.publication_delay())),
+            )
+            .filter(|time| time.wrapping_sub(now) as i32 > 0)
+            .min_by_key(|time| time.wrapping_sub(now))
+    }
+}
+
+fn role(key: &SigningKey) -> &'static str {
+    match key.is_key_signing() {
+        true => "key-signing",
+        false => "zone-signing",
+    }
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dnssec::signatures_for, label::labels_from_str};
+
+    fn states(store: &KeyStore, now: u32) -> Vec<(bool, KeyState)> {
+        store
+            .keys()
+            .iter()
+            .map(|stored| (stored.key().is_key_signing(), stored.state(now)))
+            .collect()
+    }
+
+    fn tags(records: &[DnsResourceRecord]) -> Vec<u16> {
+        records
+            .iter()
+            .map(|record| match &record.rdata {
+                DnsRecordData::CDS { key_tag, .. } => *key_tag,
+                DnsRecordData::RRSIG(rrsig) => rrsig.key_tag,
+                rdata => dnssec::key_tag(rdata),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rolls_keys_over() {
+        let dir = std::env::temp_dir().join(format!("dns-keystore-{}", std::process::id()));
+        let origin = labels_from_str("example").unwrap();
+        let policy = RolloverPolicy {
+            zsk_lifetime: Some(Duration::from_secs(1000)),
+            dnskey_ttl: 100,
+            max_zone_ttl: 200,
+            ds_ttl: 200,
+            propagation_delay: Duration::ZERO,
+            parent_propagation_delay: Duration::from_secs(300),
+            ..Default::default()
+        };
+        let t0 = dnssec::now();
+        let mut store = KeyStore::open(&dir, &origin, policy.clone()).unwrap();
+        assert!(store.maintain(t0).unwrap());
+        assert!(!store.maintain(t0).unwrap());
+        let (ksk, zsk) = (
+            store.keys()[0].key().key_tag(),
+            store.keys()[1].key().key_tag(),
+        );
+        assert_eq!(
+            states(&store, t0),
+            [(true, KeyState::Active), (false, KeyState::Active)]
+        );
+        assert_eq!(tags(&store.cds_records(t0)), [ksk]);
+        assert_eq!(
+            store.cdnskey_records(t0)[0].rtype,
+            ResourceRecordType::CDNSKEY
+        );
+
+        // The successor of the zone-signing key is published a DNSKEY TTL ahead
+        assert!(!store.maintain(t0 + 899).unwrap());
+        assert!(store.maintain(t0 + 900).unwrap());
+        let successor = store.keys()[2].key().key_tag();
+        assert_eq!(store.keys()[2].state(t0 + 900), KeyState::Published);
+        assert_eq!(tags(&store.dnskey_records(t0 + 900)), [ksk, zsk, successor]);
+        assert_eq!(store.next_event(t0 + 900), Some(t0 + 1000));
+        let active = |store: &KeyStore, now| -> Vec<u16> {
+            store
+                .active_keys(now)
+                .iter()
+                .map(SigningKey::key_tag)
+                .collect()
+        };
+        assert_eq!(active(&store, t0 + 999), [ksk, zsk]);
+        assert_eq!(active(&store, t0 + 1000), [ksk, successor]);
+        assert_eq!(store.keys()[1].state(t0 + 1000), KeyState::Retired);
+        assert_eq!(tags(&store.dnskey_records(t0 + 1200)), [ksk, successor]);
+
+        // Reopened with a key-signing key lifetime, the store rolls that key over with
+        // double signatures and points the CDS records at the successor once its
+        // DNSKEY record has reached all caches
+        let mut store = KeyStore::open(
+            &dir,
+            &origin,
+            RolloverPolicy {
+                ksk_lifetime: Some(Duration::from_secs(5000)),
+                zsk_lifetime: None,
+                ..policy
+            },
+        )
+        .unwrap();
+        assert_eq!(store.keys().len(), 3);
+        assert_eq!(store.keys()[1].timing().delete, Some(t0 + 1200));
+        assert!(!store.maintain(t0 + 4999).unwrap());
+        assert!(store.maintain(t0 + 5000).unwrap());
+        let new_ksk = store.keys()[3].key().key_tag();
+        assert_eq!(active(&store, t0 + 5050), [ksk, successor, new_ksk]);
+        assert_eq!(tags(&store.cds_records(t0 + 5050)), [ksk]);
+        assert_eq!(tags(&store.cds_records(t0 + 5100)), [new_ksk]);
+        assert_eq!(
+            states(&store, t0 + 5600),
+            [
+                (true, KeyState::Removed),
+                (false, KeyState::Removed),
+                (false, KeyState::Active),
+                (true, KeyState::Active)
+            ]
+        );
+
+        // Both key-signing keys sign the keys and the CDS records during the rollover
+        let zone = Zone::parse(
+            "@ 60 SOA ns host 1 2 3 4 5\nwww 60 A 192.0.2.1\n",
+            "example.",
+        )
+        .unwrap();
+        let signed = store
+            .sign_zone(&zone, &Denial::Nsec, t0 + 5100, t0, t0 + 86400)
+            .unwrap();
+        let records: Vec<DnsResourceRecord> = signed.records().cloned().collect();
+        let signers = |owner: &[DNSLabel], rtype| tags(&signatures_for(&records, owner, rtype));
+        assert_eq!(signers(&origin, ResourceRecordType::DNSKEY), [ksk, new_ksk]);
+        assert_eq!(signers(&origin, ResourceRecordType::CDS), [ksk, new_ksk]);
+        assert_eq!(signers(&origin, ResourceRecordType::SOA), [successor]);
+        assert_eq!(
+            tags(&signed.rrset(&origin, ResourceRecordType::CDS)),
+            [new_ksk]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
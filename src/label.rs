@@ -1,4 +1,10 @@
 use arrayvec::{ArrayString, CapacityError};
+use byteorder::ReadBytesExt;
+use std::{
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+    io::{self, Cursor},
+};
 
 // See RFC 2181, section 11. Name syntax
 const MAX_LABEL_LENGTH: usize = 63;
@@ -13,6 +19,24 @@ impl DNSLabel {
     }
 }
 
+// Labels compare case-insensitively, see RFC 4343
+impl PartialEq for DNSLabel {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for DNSLabel {}
+
+impl Hash for DNSLabel {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in self.0.bytes() {
+            state.write_u8(b.to_ascii_lowercase());
+        }
+        state.write_u8(0xff);
+    }
+}
+
 impl From<String> for DNSLabel {
     fn from(value: String) -> Self {
         let mut array_string = ArrayString::<MAX_LABEL_LENGTH>::new();
@@ -28,6 +52,140 @@ impl From<DNSLabel> for String {
     }
 }
 
+/// Whether `name` equals `ancestor` or lies below it.
+pub fn is_subdomain(name: &[DNSLabel], ancestor: &[DNSLabel]) -> bool {
+    name.len() >= ancestor.len() && name[name.len() - ancestor.len()..] == *ancestor
+}
+
+/// A name that orders canonically (RFC 4034, section 6.1): label by label from the
+/// root, comparing lowercased label bytes. A name sorts directly before its subdomains.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CanonicalName(pub Vec<DNSLabel>);
+
+impl Ord for CanonicalName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lowercase = |label: &DNSLabel| label.0.to_ascii_lowercase();
+        self.0
+            .iter()
+            .rev()
+            .map(lowercase)
+            .cmp(other.0.iter().rev().map(lowercase))
+    }
+}
+
+impl PartialOrd for CanonicalName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Upper bound on compression pointers followed while reading a single name
+const MAX_COMPRESSION_POINTERS: usize = 64;
+
+/// Splits a dotted domain name into labels. A trailing dot is optional and both
+/// "" and "." denote the root name.
+pub fn labels_from_str(name: &str) -> Result<Vec<DNSLabel>, CapacityError<&str>> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    name.split('.').map(DNSLabel::new).collect()
+}
+
+/// Joins labels into a dotted domain name without a trailing dot. The root name is "".
+pub fn labels_to_string(labels: &[DNSLabel]) -> String {
+    labels
+        .iter()
+        .map(|label| label.0.as_str())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Writes a name in uncompressed wire format.
+pub fn write_labels(buffer: &mut Vec<u8>, labels: &[DNSLabel]) {
+    for label in labels {
+        buffer.push(label.0.len() as u8);
+        buffer.extend_from_slice(label.0.as_bytes());
+    }
+    buffer.push(0);
+}
+
+/// Writes a dotted domain name in uncompressed wire format.
+pub fn write_name(buffer: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    let labels = labels_from_str(name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid name: {}", e)))?;
+    write_labels(buffer, &labels);
+    Ok(())
+}
+
+/// Reads a name in wire format, following compression pointers (RFC 1035, section 4.1.4)
+/// relative to the start of the buffer the cursor is reading from.
+pub fn read_labels(cursor: &mut Cursor<&[u8]>) -> io::Result<Vec<DNSLabel>> {
+    let message = *cursor.get_ref();
+    let mut labels = Vec::new();
+    let mut position = cursor.position();
+    let mut resume_at = None;
+    let mut pointers = 0;
+
+    loop {
+        let mut reader = Cursor::new(message);
+        reader.set_position(position);
+        let len = reader.read_u8()?;
+
+        match len & 0xC0 {
+            0x00 => {
+                if len == 0 {
+                    position = reader.position();
+                    break;
+                }
+
+                let start = reader.position() as usize;
+                let end = start + len as usize;
+                if end > message.len() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                // Labels are kept as text, which holds bytes above 0x7F only as
+                // multi-byte characters, so such names are refused rather than altered
+                if !message[start..end].is_ascii() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "label with non-ASCII bytes",
+                    ));
+                }
+                let label_str: String = message[start..end].iter().map(|&b| b as char).collect();
+                let label = DNSLabel::new(&label_str)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "label too long"))?;
+                labels.push(label);
+                position = end as u64;
+            }
+            0xC0 => {
+                let low = reader.read_u8()?;
+                pointers += 1;
+                if pointers > MAX_COMPRESSION_POINTERS {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "too many compression pointers",
+                    ));
+                }
+                if resume_at.is_none() {
+                    resume_at = Some(reader.position());
+                }
+                position = (((len & 0x3F) as u64) << 8) | low as u64;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unsupported label type",
+                ))
+            }
+        }
+    }
+
+    cursor.set_position(resume_at.unwrap_or(position));
+    Ok(labels)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -43,14 +201,14 @@ mod tests {
 
     #[test]
     fn test_dns_label_too_long() {
-        let long_string: String = std::iter::repeat("a").take(MAX_LABEL_LENGTH + 1).collect();
+        let long_string = "a".repeat(MAX_LABEL_LENGTH + 1);
         let label = DNSLabel::new(&long_string);
         assert!(label.is_err());
     }
 
     #[test]
     fn test_dns_label_exact_length() {
-        let exact_length_string: String = std::iter::repeat("a").take(MAX_LABEL_LENGTH).collect();
+        let exact_length_string = "a".repeat(MAX_LABEL_LENGTH);
         let label = DNSLabel::new(&exact_length_string);
         assert!(label.is_ok());
 
@@ -58,4 +216,86 @@ mod tests {
         let label_str = binding.0.as_str();
         assert_eq!(label_str, &exact_length_string);
     }
+
+    #[test]
+    fn test_labels_from_str_and_back() {
+        let labels = labels_from_str("www.example.com.").unwrap();
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels_to_string(&labels), "www.example.com");
+
+        assert!(labels_from_str(".").unwrap().is_empty());
+        assert!(labels_from_str("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_labels_with_compression() {
+        // "example.com" at offset 0, then "www" followed by a pointer to offset 0
+        let mut message = Vec::new();
+        write_name(&mut message, "example.com").unwrap();
+        message.extend_from_slice(&[3, b'w', b'w', b'w', 0xC0, 0x00, 0xAA]);
+
+        let mut cursor = Cursor::new(&message[..]);
+        cursor.set_position(13);
+        let labels = read_labels(&mut cursor).unwrap();
+        assert_eq!(labels_to_string(&labels), "www.example.com");
+        assert_eq!(cursor.position(), 19);
+    }
+
+    #[test]
+    fn test_read_labels_rejects_non_ascii() {
+        let message = [2, b'a', 0xE9, 3, b'c', b'o', b'm', 0];
+        let mut cursor = Cursor::new(&message[..]);
+        let error = read_labels(&mut cursor).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        // The example of RFC 4034, section 6.1
+        let names = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        let mut canonical: Vec<CanonicalName> = names
+            .iter()
+            .rev()
+            .map(|name| CanonicalName(labels_from_str(name).unwrap()))
+            .collect();
+        canonical.sort();
+
+        // "*" (0x2a) sorts before "a" but after the name it is a child of
+        let sorted: Vec<String> = canonical.iter().map(|n| labels_to_string(&n.0)).collect();
+        assert_eq!(
+            sorted,
+            vec![
+                "example",
+                "a.example",
+                "yljkjljk.a.example",
+                "Z.a.example",
+                "zABC.a.EXAMPLE",
+                "z.example",
+                "*.z.example",
+            ]
+        );
+        assert!(is_subdomain(
+            &labels_from_str("www.Example.com").unwrap(),
+            &labels_from_str("example.COM").unwrap()
+        ));
+        assert!(!is_subdomain(
+            &labels_from_str("example.com").unwrap(),
+            &labels_from_str("www.example.com").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_read_labels_pointer_loop() {
+        let message = [0xC0, 0x00];
+        let mut cursor = Cursor::new(&message[..]);
+        assert!(read_labels(&mut cursor).is_err());
+    }
 }
@@ -0,0 +1,206 @@
+use crate::handler::DnsRequestHandler;
+
+/// Wraps a handler in another handler, in the style of tower's `Layer`. The wrapping
+/// handler sees every request before the inner handler and every response after it, so
+/// it can inspect or modify both, or answer without calling the inner handler at all.
+pub trait Layer<H: DnsRequestHandler> {
+    type Handler: DnsRequestHandler;
+
+    fn layer(&self, inner: H) -> Self::Handler;
+}
+
+/// A layer that returns the inner handler unchanged
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<H: DnsRequestHandler> Layer<H> for Identity {
+    type Handler = H;
+
+    fn layer(&self, inner: H) -> H {
+        inner
+    }
+}
+
+/// Two layers applied one after the other: `inner` wraps the handler first and `outer`
+/// wraps the result.
+#[derive(Debug, Clone)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<Inner, Outer> Stack<Inner, Outer> {
+    pub fn new(inner: Inner, outer: Outer) -> Self {
+        Stack { inner, outer }
+    }
+}
+
+impl<H, Inner, Outer> Layer<H> for Stack<Inner, Outer>
+where
+    H: DnsRequestHandler,
+    Inner: Layer<H>,
+    Outer: Layer<Inner::Handler>,
+{
+    type Handler = Outer::Handler;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// A layer built from a function that wraps a handler, see [`layer_fn`]
+#[derive(Debug, Clone, Copy)]
+pub struct LayerFn<F> {
+    f: F,
+}
+
+/// Creates a layer from a function that wraps a handler.
+pub fn layer_fn<F>(f: F) -> LayerFn<F> {
+    LayerFn { f }
+}
+
+impl<H, W, F> Layer<H> for LayerFn<F>
+where
+    H: DnsRequestHandler,
+    W: DnsRequestHandler,
+    F: Fn(H) -> W,
+{
+    type Handler = W;
+
+    fn layer(&self, inner: H) -> W {
+        (self.f)(inner)
+    }
+}
+
+/// Stacks layers in front of a terminal handler. Layers added first are outermost, so
+/// they see requests first and responses last:
+///
+/// ```text
+/// let handler = HandlerBuilder::new()
+///     .layer(logging)
+///     .layer(ChaosLayer::new().with_version("dns 0.1.0"))
+///     .handler(terminal);
+/// ```
+#[derive(Debug, Clone)]
+pub struct HandlerBuilder<L> {
+    layer: L,
+}
+
+impl Default for HandlerBuilder<Identity> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HandlerBuilder<Identity> {
+    pub fn new() -> Self {
+        HandlerBuilder { layer: Identity }
+    }
+}
+
+impl<L> HandlerBuilder<L> {
+    /// Adds a layer inside of the layers added so far.
+    pub fn layer<T>(self, layer: T) -> HandlerBuilder<Stack<T, L>> {
+        HandlerBuilder {
+            layer: Stack::new(layer, self.layer),
+        }
+    }
+
+    /// Wraps `handler` in all layers.
+    pub fn handler<H>(self, handler: H) -> L::Handler
+    where
+        H: DnsRequestHandler,
+        L: Layer<H>,
+    {
+        self.layer.layer(handler)
+    }
+
+    /// Returns the stacked layers as a single layer.
+    pub fn into_inner(self) -> L {
+        self.layer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{
+        context::{RequestContext, Transport},
+        handler::DnsRequestError,
+        request::DNSRequest,
+        resourcerecord::{DnsClass, ResourceRecordType},
+        response::DnsResponse,
+    };
+
+    struct Terminal;
+
+    impl DnsRequestHandler for Terminal {
+        fn handle_request(
+            self: Arc<Self>,
+            request: DNSRequest,
+            _context: RequestContext,
+        ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+            Box::pin(async move { Ok(DnsResponse::reply_to(&request)) })
+        }
+    }
+
+    // Records the order in which requests pass through, and marks responses with an RCODE
+    struct Tag<H> {
+        inner: Arc<H>,
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl<H: DnsRequestHandler> DnsRequestHandler for Tag<H> {
+        fn handle_request(
+            self: Arc<Self>,
+            request: DNSRequest,
+            context: RequestContext,
+        ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+            Box::pin(async move {
+                self.log.lock().unwrap().push(format!("> {}", self.name));
+                let mut response = self.inner.clone().handle_request(request, context).await?;
+                self.log.lock().unwrap().push(format!("< {}", self.name));
+                response.set_response_code(response.response_code() + 1);
+                Ok(response)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layers_wrap_in_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let log_outer = log.clone();
+        let log_inner = log.clone();
+        let handler = HandlerBuilder::new()
+            .layer(layer_fn(move |inner| Tag {
+                inner: Arc::new(inner),
+                name: "outer",
+                log: log_outer.clone(),
+            }))
+            .layer(layer_fn(move |inner| Tag {
+                inner: Arc::new(inner),
+                name: "inner",
+                log: log_inner.clone(),
+            }))
+            .handler(Terminal);
+
+        let request = DNSRequest::query("example.com", ResourceRecordType::A, DnsClass::IN);
+        let response = Arc::new(handler)
+            .handle_request(request, RequestContext::local(Transport::Udp))
+            .await
+            .unwrap();
+
+        assert_eq!(response.response_code(), 2);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["> outer", "> inner", "< inner", "< outer"]
+        );
+    }
+}
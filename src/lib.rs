@@ -0,0 +1,29 @@
+pub mod acl;
+pub mod anchors;
+pub mod authority;
+pub mod cache;
+pub mod chaos;
+pub mod client;
+pub mod coalesce;
+pub mod context;
+pub mod dnssec;
+pub mod edns;
+pub mod forwarder;
+pub mod handler;
+pub mod journal;
+pub mod keystore;
+pub mod label;
+pub mod layer;
+pub mod notify;
+pub mod recursor;
+pub mod request;
+pub mod resourcerecord;
+pub mod response;
+pub mod secondary;
+pub mod server;
+pub mod signer;
+pub mod svcb;
+pub mod tsig;
+pub mod update;
+pub mod validator;
+pub mod zone;
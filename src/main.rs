@@ -1,41 +1,24 @@
-use futures::{
-    task::{FutureObj, SpawnError},
-    FutureExt,
-};
-use handler::{DnsRequestError, DnsRequestHandler};
-// You can run this example from the root of the mio repo:
-// cargo run --example udp_server --features="os-poll net"
-use futures::task::Spawn;
-use log::{info, warn};
-use mio::{Events, Interest, Poll, Token};
-use response::{DnsRecordData, DnsResourceRecord, DnsResponse, DnsResponseHeader};
-use server::DnsServer;
-use std::{io, net::IpAddr, sync::Arc};
-use tokio::runtime::Runtime;
-
-use crate::request::DNSRequest;
-
-mod handler;
-mod label;
-mod request;
-mod resourcerecord;
-mod response;
-mod server;
-
-// A token to allow us to identify which event is for the `UdpSocket`.
-const UDP_SOCKET: Token = Token(0);
-const TCP_SOCKET: Token = Token(1);
+use dns::chaos::ChaosLayer;
+use dns::context::RequestContext;
+use dns::handler::{DnsRequestError, DnsRequestHandler};
+use dns::layer::HandlerBuilder;
+use dns::request::DNSRequest;
+use dns::response::{DnsRecordData, DnsResourceRecord, DnsResponse, DnsResponseHeader};
+use dns::server::DnsServer;
+use std::{io, sync::Arc};
 
 fn main() -> io::Result<()> {
     env_logger::init();
 
-    let mut runtime = tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap();
 
-    let handle = runtime.handle().clone();
-    let dns = DnsServer::new(HandlerImplementation);
+    let handler = HandlerBuilder::new()
+        .layer(ChaosLayer::new().with_version(concat!("dns ", env!("CARGO_PKG_VERSION"))))
+        .handler(HandlerImplementation);
+    let dns = DnsServer::new(handler);
     let future = dns.run();
     runtime.block_on(future)?;
 
@@ -48,6 +31,7 @@ impl DnsRequestHandler for HandlerImplementation {
     fn handle_request(
         self: Arc<Self>,
         request: DNSRequest,
+        _context: RequestContext,
     ) -> std::pin::Pin<Box<dyn futures::Future<Output = Result<DnsResponse, DnsRequestError>> + Send>>
     {
         Box::pin(async move {
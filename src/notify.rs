@@ -0,0 +1,369 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    authority::AuthoritativeHandler,
+    client::{exchange_udp, DnsClient},
+    label::{labels_from_str, labels_to_string, DNSLabel},
+    request::{DNSHeader, DNSQuestion, DNSRequest, OPCODE_NOTIFY},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord, DnsResponse},
+    zone::Zone,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_RETRIES: usize = 4;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Tells the secondaries of a zone that it changed, with NOTIFY messages (RFC 1996).
+///
+/// The secondaries are the targets of the zone's NS records, except the primary named
+/// in its SOA record, and the addresses configured for the zone with
+/// [`AuthoritativeHandler::with_also_notify`]. The addresses of NS targets come from the
+/// served zones, or from the resolver if none of them has the target. A NOTIFY that
+/// goes unanswered is sent again, waiting twice as long before each retry.
+pub struct Notifier {
+    timeout: Duration,
+    retries: usize,
+    backoff: Duration,
+    port: u16,
+    resolver: Option<DnsClient>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier {
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            port: 53,
+            resolver: None,
+        }
+    }
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait for each reply
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many more times to send a NOTIFY that went unanswered
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry; later ones wait twice as long as the
+    /// one before
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// The port NS targets are notified on, 53 by default
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Looks up the addresses of NS targets outside the served zones with `resolver`.
+    pub fn with_resolver(mut self, resolver: DnsClient) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// The addresses to notify of changes to `zone`: `also_notify` and the addresses of
+    /// its NS targets other than the primary
+    pub async fn targets(
+        &self,
+        handler: &AuthoritativeHandler,
+        zone: &Zone,
+        also_notify: &[SocketAddr],
+    ) -> Vec<SocketAddr> {
+        let primary = match zone.soa().map(|soa| &soa.rdata) {
+            Some(DnsRecordData::SOA { mname, .. }) => labels_from_str(mname).ok(),
+            _ => None,
+        };
+        let mut targets = also_notify.to_vec();
+        for ns in zone.rrset(zone.origin(), ResourceRecordType::NS) {
+            let name = match &ns.rdata {
+                DnsRecordData::NS(name) => match labels_from_str(name) {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            if primary.as_ref() == Some(&name) {
+                continue;
+            }
+            let addresses = self.addresses(handler, &name).await;
+            if addresses.is_empty() {
+                warn!(
+                    "Cannot notify {}. of changes to {}.: no address found",
+                    labels_to_string(&name),
+                    labels_to_string(zone.origin())
+                );
+            }
+            for address in addresses {
+                let target = SocketAddr::new(address, self.port);
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+        targets
+    }
+
+    // The addresses of `name` from the served zones, or else from the resolver
+    async fn addresses(&self, handler: &AuthoritativeHandler, name: &[DNSLabel]) -> Vec<IpAddr> {
+        let mut records = match handler.find_zone(name) {
+            Some(zone) => [ResourceRecordType::A, ResourceRecordType::AAAA]
+                .into_iter()
+                .flat_map(|rtype| zone.rrset(name, rtype))
+                .collect(),
+            None => vec![],
+        };
+        if let (true, Some(resolver)) = (records.is_empty(), &self.resolver) {
+            for rtype in [ResourceRecordType::A, ResourceRecordType::AAAA] {
+                let query = resolver.build_query(name, rtype, DnsClass::IN);
+                match resolver.send(&query).await {
+                    Ok(mut response) => records.append(&mut response.answers),
+                    Err(e) => debug!(
+                        "Failed to look up {}. {}: {}",
+                        labels_to_string(name),
+                        rtype,
+                        e
+                    ),
+                }
+            }
+        }
+        records
+            .iter()
+            .filter(|record| record.name == name)
+            .filter_map(|record| match record.rdata {
+                DnsRecordData::A(address) => Some(IpAddr::V4(address)),
+                DnsRecordData::AAAA(address) => Some(IpAddr::V6(address)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sends a NOTIFY for `zone` to `target`, retrying until it is answered or the
+    /// retries run out, and returns the reply.
+    pub async fn notify(&self, zone: &Zone, target: SocketAddr) -> io::Result<DnsResponse> {
+        let origin = labels_to_string(zone.origin());
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let request = notify_request(zone);
+            match exchange_udp(target, &request, self.timeout).await {
+                Ok(response) => {
+                    match response.response_code() {
+                        0 => info!("{} acknowledged the NOTIFY for {}.", target, origin),
+                        rcode => warn!(
+                            "{} answered the NOTIFY for {}. with RCODE {}",
+                            target, origin, rcode
+                        ),
+                    }
+                    return Ok(response);
+                }
+                Err(e) if attempt < self.retries => {
+                    debug!(
+                        "NOTIFY for {}. to {} failed (attempt {}): {}",
+                        origin,
+                        target,
+                        attempt + 1,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    warn!(
+                        "Giving up on the NOTIFY for {}. to {} after {} attempts: {}",
+                        origin,
+                        target,
+                        attempt + 1,
+                        e
+                    );
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+// A NOTIFY message for `zone`, which carries its current SOA record as a hint
+fn notify_request(zone: &Zone) -> DNSRequest {
+    let answers: Vec<DnsResourceRecord> = zone.soa().into_iter().cloned().collect();
+    DNSRequest {
+        header: DNSHeader {
+            id: rand::random(),
+            flags: (OPCODE_NOTIFY as u16) << 11 | 0x0400, // AA
+            qdcount: 1,
+            ancount: answers.len() as u16,
+            nscount: 0,
+            arcount: 0,
+        },
+        questions: vec![DNSQuestion {
+            qname: zone.origin().to_vec(),
+            qtype: ResourceRecordType::SOA,
+            qclass: answers.first().map_or(DnsClass::IN, |soa| soa.class),
+        }],
+        answers,
+        authority: vec![],
+        additional: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{net::UdpSocket, sync::mpsc};
+
+    use super::*;
+
+    const EXAMPLE_COM: &str = "
+$TTL 3600
+@    SOA ns0 hostmaster 1 3600 900 604800 300
+     NS  ns0
+     NS  ns1
+     NS  ns.example.net.
+ns0  A   192.0.2.53
+ns1  A   127.0.0.1
+";
+
+    // Reports the serial of each NOTIFY that arrives on `socket`, and answers those for
+    // which `answer` returns true given the serial and how many came before
+    fn secondary(
+        socket: UdpSocket,
+        answer: impl Fn(u32, usize) -> bool + Send + 'static,
+    ) -> mpsc::UnboundedReceiver<u32> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            for index in 0.. {
+                let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+                let request = DNSRequest::parse(&buf[..len]).unwrap();
+                assert_eq!(request.opcode(), OPCODE_NOTIFY);
+                assert_eq!(request.questions[0].qtype, ResourceRecordType::SOA);
+                let serial = match request.answers[0].rdata {
+                    DnsRecordData::SOA { serial, .. } => serial,
+                    _ => panic!("NOTIFY without an SOA record"),
+                };
+                if sender.send(serial).is_err() {
+                    break;
+                }
+                if answer(serial, index) {
+                    let response = DnsResponse::reply_to(&request);
+                    socket
+                        .send_to(&response.to_bytes().unwrap(), client)
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        receiver
+    }
+
+    async fn next(notifies: &mut mpsc::UnboundedReceiver<u32>) -> u32 {
+        tokio::time::timeout(Duration::from_secs(2), notifies.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_finds_the_secondaries_of_a_zone() {
+        let origin = labels_from_str("example.com").unwrap();
+        let example_net = Zone::parse(
+            "$TTL 3600
+@    SOA ns hostmaster 1 3600 900 604800 300
+     NS  ns
+ns   A   192.0.2.3
+",
+            "example.net.",
+        )
+        .unwrap();
+        let handler = AuthoritativeHandler::new()
+            .with_zone(Zone::version(EXAMPLE_COM, 1, ""))
+            .with_zone(example_net);
+        let also: SocketAddr = "192.0.2.4:5300".parse().unwrap();
+
+        // The NS targets of example.com other than its primary ns0, one of them in
+        // example.net, and the configured address
+        let zone = handler.zone(&origin).unwrap();
+        let mut targets = Notifier::new()
+            .with_port(5353)
+            .targets(&handler, &zone, &[also])
+            .await;
+        targets.sort();
+        let expected: Vec<SocketAddr> = vec![
+            "127.0.0.1:5353".parse().unwrap(),
+            "192.0.2.3:5353".parse().unwrap(),
+            also,
+        ];
+        assert_eq!(targets, expected);
+    }
+
+    #[tokio::test]
+    async fn test_notifies_secondaries_of_changes() {
+        let ns1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = ns1.local_addr().unwrap().port();
+        let also = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stale = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let also_notify = vec![also.local_addr().unwrap(), stale.local_addr().unwrap()];
+
+        let origin = labels_from_str("example.com").unwrap();
+        let handler = Arc::new(
+            AuthoritativeHandler::new()
+                .with_zone(Zone::version(EXAMPLE_COM, 1, ""))
+                .with_also_notify(&origin, also_notify),
+        );
+        let notifier = Notifier::new()
+            .with_port(port)
+            .with_timeout(Duration::from_millis(100))
+            .with_retries(3)
+            .with_backoff(Duration::from_millis(20));
+
+        // ns1 misses the first NOTIFY and gets it again, `stale` never answers one for
+        // serial 1. ns.example.net has no address and is left out.
+        let mut ns1 = secondary(ns1, |_, index| index > 0);
+        let mut also = secondary(also, |_, _| true);
+        let mut stale = secondary(stale, |serial, _| serial != 1);
+        tokio::spawn(handler.clone().send_notifies(notifier));
+
+        // example.com is notified as it was loaded, and again once it changes
+        assert_eq!([next(&mut ns1).await, next(&mut ns1).await], [1, 1]);
+        assert_eq!(next(&mut also).await, 1);
+        assert_eq!([next(&mut stale).await, next(&mut stale).await], [1, 1]);
+        handler.add_zone(Zone::version(EXAMPLE_COM, 2, ""));
+        // An unchanged serial is no reason to notify
+        handler.add_zone(Zone::version(EXAMPLE_COM, 2, ""));
+        assert_eq!(next(&mut ns1).await, 2);
+        assert_eq!(next(&mut also).await, 2);
+        // The NOTIFY for serial 1 still being retried gives way to the one for serial 2
+        while next(&mut stale).await == 1 {}
+
+        let quiet = |mut notifies: mpsc::UnboundedReceiver<u32>| async move {
+            tokio::time::timeout(Duration::from_millis(700), notifies.recv())
+                .await
+                .is_err()
+        };
+        let quiet = tokio::join!(quiet(ns1), quiet(also), quiet(stale));
+        assert_eq!(quiet, (true, true, true));
+    }
+}
@@ -0,0 +1,1532 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    anchors::{refresh_interval, TrustAnchors},
+    client::DnsClient,
+    context::RequestContext,
+    dnssec::{self, ds_matches, is_supported, label_count, signatures_for, verify_rrset},
+    edns::Edns,
+    handler::{DnsRequestError, DnsRequestHandler},
+    label::{is_subdomain, labels_from_str, labels_to_string, DNSLabel},
+    request::DNSRequest,
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord, DnsResponse},
+    validator::{
+        prove_nodata, prove_nxdomain, prove_wildcard_expansion, proves_unsigned_delegation,
+        Security,
+    },
+    zone::parse_master_file,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+// Upstream queries one client request may cause, including those for NS names
+const DEFAULT_MAX_QUERIES: usize = 100;
+// Upper bound on CNAME and DNAME records followed for one question
+const MAX_CHAIN_LENGTH: usize = 8;
+// How deeply the resolution of NS names without glue may nest
+const MAX_NS_DEPTH: usize = 4;
+// How long zones without usable keys are remembered as insecure or bogus
+const NEGATIVE_KEY_TTL: Duration = Duration::from_secs(60);
+// When to try again after fetching the keys of an anchored zone failed
+const ANCHOR_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The result of resolving a question: the RCODE, the answer records with every alias
+/// that led to them, and the authority records of a negative answer. When validating,
+/// the answers carry their signatures, the authority records the NSEC and NSEC3
+/// records that prove what does not exist, and `authenticated` tells whether a chain
+/// of signatures from a trust anchor covers all of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolution {
+    pub rcode: u8,
+    pub answers: Vec<DnsResourceRecord>,
+    pub authority: Vec<DnsResourceRecord>,
+    pub authenticated: bool,
+}
+
+// A resolution along with what validating it takes
+struct Resolved {
+    resolution: Resolution,
+    segments: Vec<Segment>,
+    // The name at the end of the aliases, which a negative answer is about
+    name: Vec<DNSLabel>,
+}
+
+// The records that the servers of one zone cut answered with
+struct Segment {
+    cut: Vec<DNSLabel>,
+    rcode: u8,
+    // Answer records without their signatures
+    records: Vec<DnsResourceRecord>,
+    // Signatures, NSEC and NSEC3 records, and the SOA record of a negative answer
+    dnssec: Vec<DnsResourceRecord>,
+}
+
+// The authenticated DNSKEY records of a zone, or why there are none
+#[derive(Debug, Clone)]
+enum ZoneKeys {
+    Secure(Vec<DnsResourceRecord>),
+    Insecure,
+    Bogus,
+}
+
+// The final response of the servers of a zone cut
+struct Final {
+    cut: Vec<DNSLabel>,
+    response: DnsResponse,
+}
+
+/// Resolves questions iteratively, starting at the root servers and following
+/// referrals down to the authoritative servers (RFC 1034, section 5.3.3).
+///
+/// Referrals are only followed if they lead strictly closer to the question, only glue
+/// from within the referring zone is trusted, and NS names without glue are resolved
+/// on the side. Aliases are followed across zones, but records outside of the zone that
+/// answered are looked up again from the root. Queries reveal no more of the question
+/// than each server needs to know (QNAME minimisation, RFC 9156). The number of queries
+/// a single request may cause is bounded.
+///
+/// With trust anchors configured the resolver validates DNSSEC (RFC 4035, section 5):
+/// it builds the chain of trust from the anchors down through DS and DNSKEY records,
+/// checks the signatures of the answers and the NSEC or NSEC3 proofs of negative
+/// answers, and fails bogus resolutions with SERVFAIL.
+pub struct RecursiveResolver {
+    root_servers: Vec<IpAddr>,
+    port: u16,
+    timeout: Duration,
+    max_queries: usize,
+    qname_minimisation: bool,
+    trust_anchors: Mutex<TrustAnchors>,
+    // Validated zone keys by zone, with the time they expire
+    keys: Mutex<HashMap<Vec<DNSLabel>, (ZoneKeys, Instant)>>,
+}
+
+impl RecursiveResolver {
+    pub fn new(root_servers: impl IntoIterator<Item = IpAddr>) -> Self {
+        RecursiveResolver {
+            root_servers: root_servers.into_iter().collect(),
+            port: 53,
+            timeout: DEFAULT_TIMEOUT,
+            max_queries: DEFAULT_MAX_QUERIES,
+            qname_minimisation: true,
+            trust_anchors: Mutex::new(TrustAnchors::new()),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a resolver from root hints in master file format, such as the
+    /// `named.root` file published by IANA: the NS records of the root zone and the
+    /// addresses of their targets.
+    pub fn from_root_hints(text: &str) -> io::Result<Self> {
+        let records = parse_master_file(text, &[])?;
+        let servers: Vec<Vec<DNSLabel>> = records
+            .iter()
+            .filter_map(|record| match &record.rdata {
+                DnsRecordData::NS(target) if record.name.is_empty() => labels_from_str(target).ok(),
+                _ => None,
+            })
+            .collect();
+        let addresses: Vec<IpAddr> = records
+            .iter()
+            .filter(|record| servers.contains(&record.name))
+            .filter_map(|record| match record.rdata {
+                DnsRecordData::A(addr) => Some(addr.into()),
+                DnsRecordData::AAAA(addr) => Some(addr.into()),
+                _ => None,
+            })
+            .collect();
+
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "root hints contain no root server addresses",
+            ));
+        }
+        Ok(Self::new(addresses))
+    }
+
+    /// Loads root hints from a file, see [`RecursiveResolver::from_root_hints`].
+    pub fn load_root_hints(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_root_hints(&std::fs::read_to_string(path)?)
+    }
+
+    /// The port name servers are contacted on, 53 unless testing
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// How long to wait for each name server's reply
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many queries resolving a single request may send
+    pub fn with_max_queries(mut self, max_queries: usize) -> Self {
+        self.max_queries = max_queries;
+        self
+    }
+
+    pub fn with_qname_minimisation(mut self, enabled: bool) -> Self {
+        self.qname_minimisation = enabled;
+        self
+    }
+
+    /// Validates DNSSEC from `anchor`, a DS or DNSKEY record of a zone. Several anchors
+    /// may be given, for different zones or for a key rollover. Records of other types
+    /// are ignored.
+    pub fn with_trust_anchor(mut self, anchor: DnsResourceRecord) -> Self {
+        if let Err(e) = self.trust_anchors.get_mut().unwrap().add(anchor) {
+            warn!("Ignoring trust anchor: {}", e);
+        }
+        self
+    }
+
+    /// Validates DNSSEC from `anchors`, replacing any anchors given before. Managed
+    /// anchors are kept up to date with [`RecursiveResolver::refresh_trust_anchors`].
+    pub fn with_trust_anchors(mut self, anchors: TrustAnchors) -> Self {
+        self.trust_anchors = Mutex::new(anchors);
+        self
+    }
+
+    /// Fetches the DNSKEY RRsets of the zones with managed trust anchors and updates the
+    /// anchors after RFC 5011. Returns when to refresh them next.
+    pub async fn refresh_trust_anchors(&self) -> Duration {
+        let zones = {
+            let anchors = self.trust_anchors.lock().unwrap();
+            match anchors.is_managed() {
+                true => anchors.zones(),
+                false => return ANCHOR_RETRY_INTERVAL,
+            }
+        };
+        let mut next = None;
+        for zone in zones {
+            let mut budget = self.max_queries;
+            let resolved = self
+                .resolve_with_budget(
+                    zone.clone(),
+                    ResourceRecordType::DNSKEY,
+                    DnsClass::IN,
+                    &mut budget,
+                    0,
+                )
+                .await;
+            let segment = match resolved {
+                Ok(resolved) => resolved.segments.into_iter().last(),
+                Err(e) => {
+                    warn!(
+                        "Fetching the keys of {}. failed: {:?}",
+                        labels_to_string(&zone),
+                        e
+                    );
+                    None
+                }
+            };
+            let interval = match segment {
+                Some(segment) => {
+                    let now = dnssec::now();
+                    let rrsigs = signatures_for(&segment.dnssec, &zone, ResourceRecordType::DNSKEY);
+                    let update = self.trust_anchors.lock().unwrap().update(
+                        &zone,
+                        &segment.records,
+                        &rrsigs,
+                        now,
+                    );
+                    match update {
+                        Ok(true) => {
+                            // Keys are authenticated against the new anchors from now on
+                            self.keys.lock().unwrap().clear();
+                        }
+                        Ok(false) => {}
+                        Err(e) => warn!("Saving the trust anchor state failed: {}", e),
+                    }
+                    refresh_interval(&segment.records, &rrsigs, now)
+                }
+                None => ANCHOR_RETRY_INTERVAL,
+            };
+            next = Some(next.map_or(interval, |next: Duration| next.min(interval)));
+        }
+        next.unwrap_or(ANCHOR_RETRY_INTERVAL)
+    }
+
+    /// Refreshes the managed trust anchors whenever they are due, see
+    /// [`RecursiveResolver::refresh_trust_anchors`]. Runs until the task is dropped.
+    pub async fn maintain_trust_anchors(self: Arc<Self>) {
+        loop {
+            let interval = self.refresh_trust_anchors().await;
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Resolves a question, validating the result if trust anchors are configured.
+    /// Bogus results fail with SERVFAIL.
+    pub async fn resolve(
+        &self,
+        qname: &[DNSLabel],
+        qtype: ResourceRecordType,
+        qclass: DnsClass,
+    ) -> Result<Resolution, DnsRequestError> {
+        self.resolve_checked(qname, qtype, qclass, true).await
+    }
+
+    /// Resolves a question without validating the result, as clients ask for with the
+    /// CD bit (RFC 4035, section 3.2.2)
+    pub async fn resolve_unchecked(
+        &self,
+        qname: &[DNSLabel],
+        qtype: ResourceRecordType,
+        qclass: DnsClass,
+    ) -> Result<Resolution, DnsRequestError> {
+        self.resolve_checked(qname, qtype, qclass, false).await
+    }
+
+    async fn resolve_checked(
+        &self,
+        qname: &[DNSLabel],
+        qtype: ResourceRecordType,
+        qclass: DnsClass,
+        validate: bool,
+    ) -> Result<Resolution, DnsRequestError> {
+        let mut budget = self.max_queries;
+        let resolved = self
+            .resolve_with_budget(qname.to_vec(), qtype, qclass, &mut budget, 0)
+            .await?;
+        if !validate || self.trust_anchors.lock().unwrap().is_empty() {
+            return Ok(resolved.resolution);
+        }
+
+        let security = self.validate(&resolved, qtype, &mut budget).await?;
+        debug!(
+            "Validated {} {}: {:?}",
+            labels_to_string(qname),
+            qtype,
+            security
+        );
+        let mut resolution = resolved.resolution;
+        match security {
+            Security::Secure => resolution.authenticated = true,
+            Security::Insecure => {}
+            Security::Bogus => return Err(DnsRequestError::ServFail),
+        }
+        Ok(resolution)
+    }
+
+    fn resolve_with_budget<'a>(
+        &'a self,
+        qname: Vec<DNSLabel>,
+        qtype: ResourceRecordType,
+        qclass: DnsClass,
+        budget: &'a mut usize,
+        depth: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Resolved, DnsRequestError>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut answers = Vec::new();
+            let mut authority = Vec::new();
+            let mut segments = Vec::new();
+            let mut name = qname;
+            let mut chain_length = 0;
+
+            loop {
+                let Final { cut, response } =
+                    self.iterate(&name, qtype, qclass, budget, depth).await?;
+                let mut segment = Segment {
+                    cut: cut.clone(),
+                    rcode: response.response_code(),
+                    records: vec![],
+                    dnssec: vec![],
+                };
+
+                // Collect the records for the name and the aliases it leads to, as far
+                // as the zone that answered is authoritative for them
+                let mut current = name.clone();
+                let mut answered = false;
+                loop {
+                    let in_zone = |record: &&DnsResourceRecord| is_subdomain(&record.name, &cut);
+                    let rrset: Vec<DnsResourceRecord> = response
+                        .answers
+                        .iter()
+                        .filter(in_zone)
+                        .filter(|record| record.name == current && record.rtype == qtype)
+                        .cloned()
+                        .collect();
+                    if !rrset.is_empty() {
+                        segment.records.extend(rrset);
+                        answered = true;
+                        break;
+                    }
+
+                    let (aliases, target) = match follow_alias(&response.answers, &current, &cut) {
+                        Some(alias) => alias,
+                        None => break,
+                    };
+                    chain_length += 1;
+                    if chain_length > MAX_CHAIN_LENGTH
+                        || answers.iter().chain(&segment.records).any(
+                            |record: &DnsResourceRecord| {
+                                record.rtype == ResourceRecordType::CNAME && record.name == target
+                            },
+                        )
+                    {
+                        debug!("Alias chain for {} is too long", labels_to_string(&name));
+                        return Err(DnsRequestError::ServFail);
+                    }
+                    segment.records.extend(aliases);
+                    current = target;
+                }
+
+                // The signatures of the records and the proofs that came along
+                let mut rrsets: Vec<(&[DNSLabel], ResourceRecordType)> = Vec::new();
+                for record in &segment.records {
+                    if !rrsets.contains(&(&record.name, record.rtype)) {
+                        rrsets.push((&record.name, record.rtype));
+                    }
+                }
+                for (owner, rtype) in rrsets {
+                    segment
+                        .dnssec
+                        .extend(signatures_for(&response.answers, owner, rtype));
+                }
+                let negative = !answered && current == name;
+                let proofs: Vec<DnsResourceRecord> = response
+                    .authority
+                    .iter()
+                    .filter(|record| is_subdomain(&record.name, &cut))
+                    .filter(|record| match record.rtype {
+                        ResourceRecordType::NSEC
+                        | ResourceRecordType::NSEC3
+                        | ResourceRecordType::RRSIG => true,
+                        ResourceRecordType::SOA => negative,
+                        _ => false,
+                    })
+                    .cloned()
+                    .collect();
+                segment.dnssec.extend(proofs.iter().cloned());
+
+                answers.extend(segment.records.iter().cloned());
+                answers.extend(
+                    segment
+                        .dnssec
+                        .iter()
+                        .filter(|record| !proofs.contains(record))
+                        .cloned(),
+                );
+                authority.extend(proofs);
+                segments.push(segment);
+
+                if !negative && !answered {
+                    // The chain leaves the zone, or the server did not follow it
+                    name = current;
+                    continue;
+                }
+                let rcode = match answered {
+                    true => 0,
+                    false => response.response_code(),
+                };
+                if answered {
+                    // Only the proofs of wildcard expansions stay in the authority section
+                    authority.retain(|record| record.rtype != ResourceRecordType::SOA);
+                }
+                return Ok(Resolved {
+                    resolution: Resolution {
+                        rcode,
+                        answers,
+                        authority,
+                        authenticated: false,
+                    },
+                    segments,
+                    name: current,
+                });
+            }
+        })
+    }
+
+    // Checks the chain of trust of every RRset in `resolved` and of its proofs of
+    // nonexistence
+    async fn validate(
+        &self,
+        resolved: &Resolved,
+        qtype: ResourceRecordType,
+        budget: &mut usize,
+    ) -> Result<Security, DnsRequestError> {
+        let now = dnssec::now();
+        let mut security = Security::Secure;
+
+        for segment in &resolved.segments {
+            let mut rrsets: Vec<Vec<DnsResourceRecord>> = Vec::new();
+            for record in &segment.records {
+                match rrsets
+                    .iter_mut()
+                    .find(|rrset| rrset[0].name == record.name && rrset[0].rtype == record.rtype)
+                {
+                    Some(rrset) => rrset.push(record.clone()),
+                    None => rrsets.push(vec![record.clone()]),
+                }
+            }
+
+            for rrset in &rrsets {
+                let (owner, rtype) = (&rrset[0].name, rrset[0].rtype);
+                // The CNAME that goes with a DNAME is synthesized rather than signed
+                let synthesized = rtype == ResourceRecordType::CNAME
+                    && segment.records.iter().any(|record| {
+                        record.rtype == ResourceRecordType::DNAME
+                            && record.name.len() < owner.len()
+                            && is_subdomain(owner, &record.name)
+                    });
+                if synthesized || rtype == ResourceRecordType::RRSIG {
+                    continue;
+                }
+
+                let rrsigs = signatures_for(&segment.dnssec, owner, rtype);
+                let signer = rrsigs.iter().find_map(|rrsig| match &rrsig.rdata {
+                    DnsRecordData::RRSIG(rrsig) => labels_from_str(&rrsig.signer_name).ok(),
+                    _ => None,
+                });
+                let signer = match signer {
+                    Some(signer) if is_subdomain(owner, &signer) => signer,
+                    Some(_) => return Ok(Security::Bogus),
+                    None => match self.zone_keys(segment.cut.clone(), budget).await? {
+                        ZoneKeys::Insecure => {
+                            security = Security::Insecure;
+                            continue;
+                        }
+                        _ => return Ok(Security::Bogus),
+                    },
+                };
+
+                let keys = match self.zone_keys(signer.clone(), budget).await? {
+                    ZoneKeys::Secure(keys) => keys,
+                    ZoneKeys::Insecure => {
+                        security = Security::Insecure;
+                        continue;
+                    }
+                    ZoneKeys::Bogus => return Ok(Security::Bogus),
+                };
+                let rrsig = match verify_rrset(rrset, &rrsigs, &keys, now) {
+                    Some(rrsig) => rrsig,
+                    None => return Ok(Security::Bogus),
+                };
+                if rrsig.labels < label_count(owner) {
+                    let proofs = verified_proofs(&segment.dnssec, &keys, now);
+                    match prove_wildcard_expansion(owner, rrsig.labels, &signer, &proofs) {
+                        Security::Secure => {}
+                        Security::Insecure => security = Security::Insecure,
+                        Security::Bogus => return Ok(Security::Bogus),
+                    }
+                }
+            }
+        }
+
+        let last = match resolved.segments.last() {
+            Some(last) => last,
+            None => return Ok(Security::Bogus),
+        };
+        let answered = last
+            .records
+            .iter()
+            .any(|record| record.name == resolved.name && record.rtype == qtype);
+        if answered {
+            return Ok(security);
+        }
+
+        // A negative answer is proven by the zone its SOA record names
+        let zone = match last
+            .dnssec
+            .iter()
+            .find(|record| record.rtype == ResourceRecordType::SOA)
+        {
+            Some(soa) if is_subdomain(&soa.name, &last.cut) => soa.name.clone(),
+            _ => last.cut.clone(),
+        };
+        let keys = match self.zone_keys(zone.clone(), budget).await? {
+            ZoneKeys::Secure(keys) => keys,
+            ZoneKeys::Insecure => return Ok(Security::Insecure),
+            ZoneKeys::Bogus => return Ok(Security::Bogus),
+        };
+        let proofs = verified_proofs(&last.dnssec, &keys, now);
+        if !proofs
+            .iter()
+            .any(|record| record.rtype == ResourceRecordType::SOA && record.name == zone)
+        {
+            return Ok(Security::Bogus);
+        }
+        let proven = match last.rcode {
+            3 => prove_nxdomain(&resolved.name, &zone, &proofs),
+            _ => prove_nodata(&resolved.name, qtype, &zone, &proofs),
+        };
+        Ok(match (security, proven) {
+            (_, Security::Bogus) => Security::Bogus,
+            (Security::Insecure, _) | (_, Security::Insecure) => Security::Insecure,
+            _ => Security::Secure,
+        })
+    }
+
+    // The authenticated keys of `zone`: the DNSKEY records that a trust anchor or the
+    // DS records of the parent zone lead to (RFC 4035, section 5.2)
+    fn zone_keys<'a>(
+        &'a self,
+        zone: Vec<DNSLabel>,
+        budget: &'a mut usize,
+    ) -> Pin<Box<dyn Future<Output = Result<ZoneKeys, DnsRequestError>> + Send + 'a>> {
+        Box::pin(async move {
+            if let Some((keys, expires)) = self.keys.lock().unwrap().get(&zone) {
+                if *expires > Instant::now() {
+                    return Ok(keys.clone());
+                }
+            }
+
+            let (anchored, anchors, covered) = {
+                let trust_anchors = self.trust_anchors.lock().unwrap();
+                (
+                    trust_anchors.zones().contains(&zone),
+                    trust_anchors.entry_points(&zone),
+                    trust_anchors.covers(&zone),
+                )
+            };
+            // A zone whose anchors have all been revoked has no keys to trust
+            let keys = if anchored {
+                self.authenticate_keys(&zone, &anchors, budget).await?
+            } else if !covered {
+                ZoneKeys::Insecure
+            } else {
+                match self.delegation_signer(&zone, budget).await? {
+                    Ok(ds) => self.authenticate_keys(&zone, &ds, budget).await?,
+                    Err(keys) => keys,
+                }
+            };
+
+            let ttl = match &keys {
+                ZoneKeys::Secure(keys) => {
+                    Duration::from_secs(keys.iter().map(|key| key.ttl).min().unwrap_or(0) as u64)
+                }
+                _ => NEGATIVE_KEY_TTL,
+            };
+            debug!("Keys of {}: {:?}", labels_to_string(&zone), keys);
+            self.keys
+                .lock()
+                .unwrap()
+                .insert(zone, (keys.clone(), Instant::now() + ttl));
+            Ok(keys)
+        })
+    }
+
+    // The authenticated DS records of `zone` with a supported algorithm and digest, or
+    // the keys of a zone without them
+    async fn delegation_signer(
+        &self,
+        zone: &[DNSLabel],
+        budget: &mut usize,
+    ) -> Result<Result<Vec<DnsRecordData>, ZoneKeys>, DnsRequestError> {
+        let now = dnssec::now();
+        let resolved = self
+            .resolve_with_budget(
+                zone.to_vec(),
+                ResourceRecordType::DS,
+                DnsClass::IN,
+                budget,
+                0,
+            )
+            .await?;
+        let segment = match resolved.segments.last() {
+            Some(segment) => segment,
+            None => return Ok(Err(ZoneKeys::Bogus)),
+        };
+        let ds: Vec<DnsResourceRecord> = segment
+            .records
+            .iter()
+            .filter(|record| record.name == zone && record.rtype == ResourceRecordType::DS)
+            .cloned()
+            .collect();
+
+        // The parent signs the DS records or the proof that there are none
+        let parent = segment
+            .dnssec
+            .iter()
+            .find_map(|record| match &record.rdata {
+                DnsRecordData::RRSIG(rrsig) => labels_from_str(&rrsig.signer_name).ok(),
+                _ => None,
+            })
+            .unwrap_or_else(|| segment.cut.clone());
+        if parent.len() >= zone.len() || !is_subdomain(zone, &parent) {
+            return Ok(Err(ZoneKeys::Bogus));
+        }
+        let keys = match self.zone_keys(parent.clone(), budget).await? {
+            ZoneKeys::Secure(keys) => keys,
+            keys => return Ok(Err(keys)),
+        };
+
+        if ds.is_empty() {
+            let proofs = verified_proofs(&segment.dnssec, &keys, now);
+            return Ok(Err(
+                match segment.rcode == 0 && proves_unsigned_delegation(zone, &parent, &proofs) {
+                    true => ZoneKeys::Insecure,
+                    false => ZoneKeys::Bogus,
+                },
+            ));
+        }
+        let rrsigs = signatures_for(&segment.dnssec, zone, ResourceRecordType::DS);
+        if verify_rrset(&ds, &rrsigs, &keys, now).is_none() {
+            return Ok(Err(ZoneKeys::Bogus));
+        }
+        let supported: Vec<DnsRecordData> = ds
+            .into_iter()
+            .map(|record| record.rdata)
+            .filter(|rdata| {
+                matches!(rdata, DnsRecordData::DS { algorithm, digest_type, .. }
+                    if is_supported(*algorithm, *digest_type))
+            })
+            .collect();
+        // A zone signed with algorithms we do not know is treated as unsigned
+        match supported.is_empty() {
+            true => Ok(Err(ZoneKeys::Insecure)),
+            false => Ok(Ok(supported)),
+        }
+    }
+
+    // Fetches the DNSKEY records of `zone` and checks that a key matching one of
+    // `entry_points`, DS records or trusted DNSKEY records, signs them
+    async fn authenticate_keys(
+        &self,
+        zone: &[DNSLabel],
+        entry_points: &[DnsRecordData],
+        budget: &mut usize,
+    ) -> Result<ZoneKeys, DnsRequestError> {
+        let resolved = self
+            .resolve_with_budget(
+                zone.to_vec(),
+                ResourceRecordType::DNSKEY,
+                DnsClass::IN,
+                budget,
+                0,
+            )
+            .await?;
+        let segment = match resolved.segments.last() {
+            Some(segment) => segment,
+            None => return Ok(ZoneKeys::Bogus),
+        };
+        let keys: Vec<DnsResourceRecord> = segment
+            .records
+            .iter()
+            .filter(|record| record.name == zone && record.rtype == ResourceRecordType::DNSKEY)
+            .cloned()
+            .collect();
+        let trusted: Vec<DnsResourceRecord> = keys
+            .iter()
+            .filter(|key| {
+                entry_points.iter().any(|entry_point| match entry_point {
+                    DnsRecordData::DS { .. } => ds_matches(entry_point, key),
+                    _ => *entry_point == key.rdata,
+                })
+            })
+            .cloned()
+            .collect();
+
+        let rrsigs = signatures_for(&segment.dnssec, zone, ResourceRecordType::DNSKEY);
+        Ok(
+            match verify_rrset(&keys, &rrsigs, &trusted, dnssec::now()) {
+                Some(_) => ZoneKeys::Secure(keys),
+                None => ZoneKeys::Bogus,
+            },
+        )
+    }
+
+    // Follows referrals from the root down to the servers that answer for `name`
+    async fn iterate(
+        &self,
+        name: &[DNSLabel],
+        qtype: ResourceRecordType,
+        qclass: DnsClass,
+        budget: &mut usize,
+        depth: usize,
+    ) -> Result<Final, DnsRequestError> {
+        let mut cut: Vec<DNSLabel> = Vec::new();
+        let mut servers = self.root_servers.clone();
+        // Labels of `name` known to exist without a zone cut
+        let mut known = 0;
+
+        loop {
+            let level = cut.len().max(known) + 1;
+            let minimised = self.qname_minimisation && level < name.len();
+            let (query_name, query_type) = if minimised {
+                // RFC 9156, section 2.1 recommends type A for the minimised queries
+                (&name[name.len() - level..], ResourceRecordType::A)
+            } else {
+                (name, qtype)
+            };
+
+            let response = self
+                .query_servers(&servers, query_name, query_type, qclass, budget)
+                .await?;
+
+            if let Some((owner, ns)) = referral(&response, query_name) {
+                if owner.len() <= cut.len() || !is_subdomain(&owner, &cut) {
+                    debug!(
+                        "Ignoring referral to {} at {}",
+                        labels_to_string(&owner),
+                        labels_to_string(&cut)
+                    );
+                    return Err(DnsRequestError::ServFail);
+                }
+                servers = self
+                    .server_addresses(&ns, &response, &cut, budget, depth)
+                    .await?;
+                cut = owner;
+                continue;
+            }
+
+            if !minimised {
+                return Ok(Final { cut, response });
+            }
+            if response.response_code() == DnsRequestError::NXDomain.to_response_code() {
+                // Nothing exists below a name that does not exist (RFC 8020)
+                return Ok(Final { cut, response });
+            }
+            known = level;
+        }
+    }
+
+    // The addresses of the servers a referral names, resolving NS names without glue
+    async fn server_addresses(
+        &self,
+        ns: &[Vec<DNSLabel>],
+        referral: &DnsResponse,
+        cut: &[DNSLabel],
+        budget: &mut usize,
+        depth: usize,
+    ) -> Result<Vec<IpAddr>, DnsRequestError> {
+        let glue: Vec<IpAddr> = referral
+            .additional
+            .iter()
+            .filter(|record| ns.contains(&record.name) && is_subdomain(&record.name, cut))
+            .filter_map(|record| match record.rdata {
+                DnsRecordData::A(addr) => Some(addr.into()),
+                DnsRecordData::AAAA(addr) => Some(addr.into()),
+                _ => None,
+            })
+            .collect();
+        if !glue.is_empty() {
+            return Ok(glue);
+        }
+
+        if depth >= MAX_NS_DEPTH {
+            debug!("Name server resolution nests too deeply");
+            return Err(DnsRequestError::ServFail);
+        }
+        for name in ns {
+            debug!("Resolving name server {}", labels_to_string(name));
+            let resolution = match self
+                .resolve_with_budget(
+                    name.clone(),
+                    ResourceRecordType::A,
+                    DnsClass::IN,
+                    budget,
+                    depth + 1,
+                )
+                .await
+            {
+                Ok(resolution) => resolution,
+                Err(_) if *budget > 0 => continue,
+                Err(e) => return Err(e),
+            };
+            let addresses: Vec<IpAddr> = resolution
+                .resolution
+                .answers
+                .iter()
+                .filter_map(|record| match record.rdata {
+                    DnsRecordData::A(addr) => Some(addr.into()),
+                    _ => None,
+                })
+                .collect();
+            if !addresses.is_empty() {
+                return Ok(addresses);
+            }
+        }
+        Err(DnsRequestError::ServFail)
+    }
+
+    // Asks the servers in turn until one gives a usable answer
+    async fn query_servers(
+        &self,
+        servers: &[IpAddr],
+        name: &[DNSLabel],
+        qtype: ResourceRecordType,
+        qclass: DnsClass,
+        budget: &mut usize,
+    ) -> Result<DnsResponse, DnsRequestError> {
+        for &server in servers {
+            if *budget == 0 {
+                debug!("Query budget exhausted");
+                return Err(DnsRequestError::ServFail);
+            }
+            *budget -= 1;
+
+            let client = DnsClient::new(SocketAddr::new(server, self.port))
+                .with_timeout(self.timeout)
+                .with_retries(0)
+                .with_recursion_desired(false);
+            debug!(
+                "Asking {} for {} {}",
+                client.server(),
+                labels_to_string(name),
+                qtype
+            );
+            let client = match self.trust_anchors.lock().unwrap().is_empty() {
+                true => client,
+                false => client.with_dnssec_ok(true),
+            };
+            let response = client.send(&client.build_query(name, qtype, qclass)).await;
+            match response {
+                Ok(response) if matches!(response.response_code(), 0 | 3) => return Ok(response),
+                Ok(response) => debug!(
+                    "{} answered with RCODE {}",
+                    client.server(),
+                    response.response_code()
+                ),
+                Err(e) => debug!("{} failed: {}", client.server(), e),
+            }
+        }
+        Err(DnsRequestError::ServFail)
+    }
+}
+
+// The SOA, NSEC and NSEC3 records among `records` whose signatures by `keys` check out
+fn verified_proofs(
+    records: &[DnsResourceRecord],
+    keys: &[DnsResourceRecord],
+    now: u32,
+) -> Vec<DnsResourceRecord> {
+    let mut proofs: Vec<DnsResourceRecord> = Vec::new();
+    for record in records {
+        let proof = matches!(
+            record.rtype,
+            ResourceRecordType::SOA | ResourceRecordType::NSEC | ResourceRecordType::NSEC3
+        );
+        if !proof || proofs.contains(record) {
+            continue;
+        }
+        let rrset: Vec<DnsResourceRecord> = records
+            .iter()
+            .filter(|other| other.name == record.name && other.rtype == record.rtype)
+            .cloned()
+            .collect();
+        let rrsigs = signatures_for(records, &record.name, record.rtype);
+        if verify_rrset(&rrset, &rrsigs, keys, now).is_some() {
+            proofs.extend(rrset);
+        }
+    }
+    proofs
+}
+
+// The zone cut and NS names of a referral for `name`
+fn referral(
+    response: &DnsResponse,
+    name: &[DNSLabel],
+) -> Option<(Vec<DNSLabel>, Vec<Vec<DNSLabel>>)> {
+    if response.header.flags & 0x0400 != 0
+        || !response.answers.is_empty()
+        || response.response_code() != 0
+    {
+        return None;
+    }
+
+    let owner = response
+        .authority
+        .iter()
+        .find(|record| record.rtype == ResourceRecordType::NS)?
+        .name
+        .clone();
+    if !is_subdomain(name, &owner) {
+        return None;
+    }
+    let ns = response
+        .authority
+        .iter()
+        .filter(|record| record.name == owner)
+        .filter_map(|record| match &record.rdata {
+            DnsRecordData::NS(target) => labels_from_str(target).ok(),
+            _ => None,
+        })
+        .collect();
+    Some((owner, ns))
+}
+
+// The records that alias `name` within `cut` and the name they lead to
+fn follow_alias(
+    answers: &[DnsResourceRecord],
+    name: &[DNSLabel],
+    cut: &[DNSLabel],
+) -> Option<(Vec<DnsResourceRecord>, Vec<DNSLabel>)> {
+    // A DNAME replaces its owner at the end of the name (RFC 6672). The CNAME servers
+    // send along with it is synthesized again rather than trusted.
+    let dname = answers.iter().find(|record| {
+        record.rtype == ResourceRecordType::DNAME
+            && record.name.len() < name.len()
+            && is_subdomain(name, &record.name)
+            && is_subdomain(&record.name, cut)
+    });
+    if let Some(
+        dname @ DnsResourceRecord {
+            rdata: DnsRecordData::DNAME(target),
+            ..
+        },
+    ) = dname
+    {
+        let mut synthesized = name[..name.len() - dname.name.len()].to_vec();
+        synthesized.extend(labels_from_str(target).ok()?);
+        let cname = DnsResourceRecord {
+            name: name.to_vec(),
+            rtype: ResourceRecordType::CNAME,
+            class: dname.class,
+            ttl: dname.ttl,
+            rdata: DnsRecordData::CNAME(labels_to_string(&synthesized)),
+        };
+        return Some((vec![dname.clone(), cname], synthesized));
+    }
+
+    let cname = answers.iter().find(|record| {
+        record.rtype == ResourceRecordType::CNAME
+            && record.name == name
+            && is_subdomain(&record.name, cut)
+    })?;
+    match &cname.rdata {
+        DnsRecordData::CNAME(target) => Some((vec![cname.clone()], labels_from_str(target).ok()?)),
+        _ => None,
+    }
+}
+
+impl DnsRequestHandler for RecursiveResolver {
+    fn handle_request(
+        self: Arc<Self>,
+        request: DNSRequest,
+        _context: RequestContext,
+    ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+        Box::pin(async move {
+            let question = match request.questions.as_slice() {
+                [question] => question,
+                _ => return Err(DnsRequestError::FormErr),
+            };
+            if request.header.flags & 0x7800 != 0 {
+                return Err(DnsRequestError::NotImp);
+            }
+
+            let checking_disabled = request.header.flags & 0x0010 != 0;
+            let dnssec_ok = request.edns().is_some_and(|edns| edns.dnssec_ok);
+
+            let resolution = match checking_disabled {
+                true => {
+                    self.resolve_unchecked(&question.qname, question.qtype, question.qclass)
+                        .await?
+                }
+                false => {
+                    self.resolve(&question.qname, question.qtype, question.qclass)
+                        .await?
+                }
+            };
+
+            let mut response = DnsResponse::reply_to(&request);
+            response.header.flags |= 0x0080; // RA
+            if checking_disabled {
+                response.header.flags |= 0x0010;
+            }
+            // RFC 6840, section 5.8: AD is also set for clients that ask with AD
+            if resolution.authenticated && (dnssec_ok || request.header.flags & 0x0020 != 0) {
+                response.header.flags |= 0x0020;
+            }
+            response.set_response_code(resolution.rcode);
+            response.answers = resolution.answers;
+            response.authority = resolution.authority;
+            if dnssec_ok {
+                response.set_edns(Edns {
+                    dnssec_ok: true,
+                    ..Edns::default()
+                });
+            } else {
+                // DNSSEC records only go to clients that set the DO bit
+                let qtype = question.qtype;
+                let dnssec = |record: &DnsResourceRecord| {
+                    record.rtype != qtype
+                        && matches!(
+                            record.rtype,
+                            ResourceRecordType::RRSIG
+                                | ResourceRecordType::NSEC
+                                | ResourceRecordType::NSEC3
+                        )
+                };
+                response.answers.retain(|record| !dnssec(record));
+                response.authority.retain(|record| !dnssec(record));
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Mutex};
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::{
+        authority::AuthoritativeHandler,
+        dnssec::{
+            ds_record, SigningKey, ALGORITHM_ECDSAP256SHA256, ALGORITHM_ED25519, DIGEST_SHA256,
+            FLAG_SEP, FLAG_ZONE,
+        },
+        server::DnsServer,
+        signer::{sign_zone, Denial},
+        zone::Zone,
+    };
+
+    const ROOT: &str = "
+$TTL 86400
+.               SOA a.root. hostmaster.root. 1 1800 900 604800 86400
+.               NS  a.root.
+a.root.         A   127.0.0.1
+com.            NS  ns.com.
+ns.com.         A   127.0.0.2
+net.            NS  ns.net.
+ns.net.         A   127.0.0.3
+";
+
+    // example.com is served from a name server without glue, and loop.com is delegated
+    // back to the com server itself
+    const COM: &str = "
+$TTL 86400
+@               SOA ns hostmaster 1 1800 900 604800 86400
+                NS  ns
+ns              A   127.0.0.2
+example         NS  ns1.example.net.
+loop            NS  ns.loop
+ns.loop         A   127.0.0.2
+";
+
+    const NET: &str = "
+$TTL 86400
+@               SOA ns hostmaster 1 1800 900 604800 86400
+                NS  ns
+ns              A   127.0.0.3
+example         NS  ns.example
+ns.example      A   127.0.0.4
+";
+
+    const EXAMPLE_COM: &str = "
+$TTL 3600
+@               SOA ns1.example.net. hostmaster 1 1800 900 604800 300
+                NS  ns1.example.net.
+www             A   192.0.2.1
+alias           CNAME www.example.net.
+old             DNAME example.net.
+*.wild          A   192.0.2.9
+";
+
+    const EXAMPLE_NET: &str = "
+$TTL 3600
+@               SOA ns hostmaster 1 1800 900 604800 300
+                NS  ns
+ns              A   127.0.0.4
+ns1             A   127.0.0.4
+www             A   192.0.2.2
+";
+
+    // Records the questions a handler receives
+    struct Recorder<H> {
+        inner: Arc<H>,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl<H: DnsRequestHandler> DnsRequestHandler for Recorder<H> {
+        fn handle_request(
+            self: Arc<Self>,
+            request: DNSRequest,
+            context: RequestContext,
+        ) -> Pin<Box<dyn Future<Output = Result<DnsResponse, DnsRequestError>> + Send>> {
+            for question in &request.questions {
+                self.log
+                    .lock()
+                    .unwrap()
+                    .push(labels_to_string(&question.qname));
+            }
+            self.inner.clone().handle_request(request, context)
+        }
+    }
+
+    // Binds 127.0.0.1 to 127.0.0.4 on a common port
+    async fn bind_servers() -> (u16, Vec<UdpSocket>) {
+        'retry: loop {
+            let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let port = first.local_addr().unwrap().port();
+            let mut sockets = vec![first];
+            for host in 2..=4 {
+                match UdpSocket::bind((Ipv4Addr::new(127, 0, 0, host), port)).await {
+                    Ok(socket) => sockets.push(socket),
+                    Err(_) => continue 'retry,
+                }
+            }
+            return (port, sockets);
+        }
+    }
+
+    // Runs the simulated hierarchy and returns its port and the questions the root saw
+    async fn hierarchy() -> (u16, Arc<Mutex<Vec<String>>>) {
+        let zone = |text, origin| Zone::parse(text, origin).unwrap();
+        serve([
+            vec![zone(ROOT, ".")],
+            vec![zone(COM, "com.")],
+            vec![zone(NET, "net.")],
+            vec![
+                zone(EXAMPLE_COM, "example.com."),
+                zone(EXAMPLE_NET, "example.net."),
+            ],
+        ])
+        .await
+    }
+
+    // Signs example.com with NSEC, com with NSEC3 and the root with NSEC, leaving net
+    // unsigned, and returns the trust anchor of the root along with the port. A
+    // tampered hierarchy changes the address of www.example.com after signing.
+    async fn signed_hierarchy(tampered: bool) -> (u16, DnsResourceRecord) {
+        let now = dnssec::now();
+        let sign = |zone: Zone, key: &SigningKey, denial: Denial| {
+            sign_zone(
+                &zone,
+                std::slice::from_ref(key),
+                &denial,
+                now - 3600,
+                now + 86400,
+            )
+            .unwrap()
+        };
+        // The DS record of `child` added to `parent`
+        let delegate = |parent: Zone, child: &Zone| {
+            let dnskey = child
+                .rrset(child.origin(), ResourceRecordType::DNSKEY)
+                .pop()
+                .unwrap();
+            let records = parent.records().cloned();
+            let ds = ds_record(&dnskey, DIGEST_SHA256).unwrap();
+            Zone::from_records(parent.origin().to_vec(), records.chain([ds])).unwrap()
+        };
+        let key = |algorithm| SigningKey::generate(algorithm, FLAG_ZONE | FLAG_SEP).unwrap();
+
+        let mut example_com = sign(
+            Zone::parse(EXAMPLE_COM, "example.com.").unwrap(),
+            &key(ALGORITHM_ED25519),
+            Denial::Nsec,
+        );
+        if tampered {
+            let records = example_com.records().cloned().map(|mut record| {
+                if record.rtype == ResourceRecordType::A && record.name == name("www.example.com") {
+                    record.rdata = DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 66));
+                }
+                record
+            });
+            example_com = Zone::from_records(name("example.com"), records).unwrap();
+        }
+        let com = sign(
+            delegate(Zone::parse(COM, "com.").unwrap(), &example_com),
+            &key(ALGORITHM_ECDSAP256SHA256),
+            Denial::Nsec3 {
+                iterations: 0,
+                salt: vec![0xab, 0xcd],
+                opt_out: false,
+            },
+        );
+        let root = sign(
+            delegate(Zone::parse(ROOT, ".").unwrap(), &com),
+            &key(ALGORITHM_ED25519),
+            Denial::Nsec,
+        );
+        let anchor = root.rrset(&[], ResourceRecordType::DNSKEY).pop().unwrap();
+
+        let (port, _) = serve([
+            vec![root],
+            vec![com],
+            vec![Zone::parse(NET, "net.").unwrap()],
+            vec![
+                example_com,
+                Zone::parse(EXAMPLE_NET, "example.net.").unwrap(),
+            ],
+        ])
+        .await;
+        (port, anchor)
+    }
+
+    async fn serve(zones: [Vec<Zone>; 4]) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let (port, sockets) = bind_servers().await;
+        let root_log = Arc::new(Mutex::new(Vec::new()));
+
+        for (index, (socket, zones)) in sockets.into_iter().zip(zones).enumerate() {
+            let mut handler = AuthoritativeHandler::new();
+            for zone in zones {
+                handler = handler.with_zone(zone);
+            }
+            if index == 0 {
+                let server = DnsServer::new(Recorder {
+                    inner: Arc::new(handler),
+                    log: root_log.clone(),
+                });
+                tokio::spawn(async move { server.serve(socket).await });
+            } else {
+                let server = DnsServer::new(handler);
+                tokio::spawn(async move { server.serve(socket).await });
+            }
+        }
+        (port, root_log)
+    }
+
+    fn resolver(port: u16) -> RecursiveResolver {
+        RecursiveResolver::from_root_hints(".  3600000 NS a.root.\na.root. 3600000 A 127.0.0.1\n")
+            .unwrap()
+            .with_port(port)
+            .with_timeout(Duration::from_millis(500))
+    }
+
+    fn name(name: &str) -> Vec<DNSLabel> {
+        labels_from_str(name).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resolves_through_ns_without_glue_with_minimised_queries() {
+        let (port, root_log) = hierarchy().await;
+        let resolution = resolver(port)
+            .resolve(
+                &name("www.example.com"),
+                ResourceRecordType::A,
+                DnsClass::IN,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(resolution.rcode, 0);
+        assert_eq!(
+            resolution.answers[0].rdata,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        // The root servers only learned the top-level domains
+        let root_log = root_log.lock().unwrap();
+        assert!(!root_log.is_empty());
+        assert!(root_log
+            .iter()
+            .all(|qname| qname == "com" || qname == "net"));
+    }
+
+    #[tokio::test]
+    async fn test_follows_cname_and_dname_across_zones() {
+        let (port, _) = hierarchy().await;
+        let resolver = resolver(port);
+
+        let resolution = resolver
+            .resolve(
+                &name("alias.example.com"),
+                ResourceRecordType::A,
+                DnsClass::IN,
+            )
+            .await
+            .unwrap();
+        let types: Vec<_> = resolution.answers.iter().map(|r| r.rtype).collect();
+        assert_eq!(types, [ResourceRecordType::CNAME, ResourceRecordType::A]);
+        assert_eq!(
+            resolution.answers[1].rdata,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 2))
+        );
+
+        let resolution = resolver
+            .resolve(
+                &name("www.old.example.com"),
+                ResourceRecordType::A,
+                DnsClass::IN,
+            )
+            .await
+            .unwrap();
+        let types: Vec<_> = resolution.answers.iter().map(|r| r.rtype).collect();
+        assert_eq!(
+            types,
+            [
+                ResourceRecordType::DNAME,
+                ResourceRecordType::CNAME,
+                ResourceRecordType::A
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nxdomain_carries_soa() {
+        let (port, _) = hierarchy().await;
+        let resolution = resolver(port)
+            .resolve(
+                &name("a.nope.example.com"),
+                ResourceRecordType::A,
+                DnsClass::IN,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(resolution.rcode, 3);
+        assert!(resolution.answers.is_empty());
+        assert_eq!(resolution.authority[0].rtype, ResourceRecordType::SOA);
+    }
+
+    #[tokio::test]
+    async fn test_referral_loop_and_query_budget() {
+        let (port, _) = hierarchy().await;
+
+        let result = resolver(port)
+            .resolve(&name("www.loop.com"), ResourceRecordType::A, DnsClass::IN)
+            .await;
+        assert!(matches!(result, Err(DnsRequestError::ServFail)));
+
+        let result = resolver(port)
+            .with_max_queries(2)
+            .resolve(
+                &name("www.example.com"),
+                ResourceRecordType::A,
+                DnsClass::IN,
+            )
+            .await;
+        assert!(matches!(result, Err(DnsRequestError::ServFail)));
+    }
+
+    #[tokio::test]
+    async fn test_validates_signed_answers_and_denials() {
+        let (port, anchor) = signed_hierarchy(false).await;
+        let resolver = resolver(port).with_trust_anchor(anchor);
+        let resolve = |qname: &'static str, qtype| {
+            let resolver = &resolver;
+            async move {
+                resolver
+                    .resolve(&name(qname), qtype, DnsClass::IN)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let resolution = resolve("www.example.com", ResourceRecordType::A).await;
+        assert!(resolution.authenticated);
+        assert!(resolution
+            .answers
+            .iter()
+            .any(|record| record.rtype == ResourceRecordType::RRSIG));
+
+        // A wildcard expansion, and the NSEC proofs of example.com
+        assert!(
+            resolve("a.wild.example.com", ResourceRecordType::A)
+                .await
+                .authenticated
+        );
+        let resolution = resolve("nope.example.com", ResourceRecordType::A).await;
+        assert_eq!(resolution.rcode, 3);
+        assert!(resolution.authenticated);
+        let resolution = resolve("www.example.com", ResourceRecordType::TXT).await;
+        assert_eq!((resolution.rcode, resolution.answers.len()), (0, 0));
+        assert!(resolution.authenticated);
+
+        // The NSEC3 proofs of com
+        let resolution = resolve("nope.com", ResourceRecordType::A).await;
+        assert_eq!(resolution.rcode, 3);
+        assert!(resolution.authenticated);
+        assert!(resolve("com", ResourceRecordType::TXT).await.authenticated);
+
+        // net has no DS records, and neither do aliases into it count as secure
+        let resolution = resolve("www.example.net", ResourceRecordType::A).await;
+        assert_eq!(resolution.answers.len(), 1);
+        assert!(!resolution.authenticated);
+        assert!(
+            !resolve("alias.example.com", ResourceRecordType::A)
+                .await
+                .authenticated
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manages_trust_anchors_from_a_ds_file() {
+        let (port, anchor) = signed_hierarchy(false).await;
+        let dir = std::env::temp_dir().join(format!("dns-recursor-anchors-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let ds = ds_record(&anchor, DIGEST_SHA256).unwrap();
+        std::fs::write(dir.join("root.anchor"), format!("{}\n", ds)).unwrap();
+
+        let anchors = TrustAnchors::load(dir.join("root.anchor"))
+            .unwrap()
+            .managed(dir.join("root.state"))
+            .unwrap();
+        let resolver = resolver(port).with_trust_anchors(anchors);
+        let interval = resolver.refresh_trust_anchors().await;
+        assert!(interval >= Duration::from_secs(3600));
+
+        // The root key the DS refers to is now a managed anchor
+        let state = std::fs::read_to_string(dir.join("root.state")).unwrap();
+        assert!(state.contains(". VALID "));
+        let resolution = resolver
+            .resolve(
+                &name("www.example.com"),
+                ResourceRecordType::A,
+                DnsClass::IN,
+            )
+            .await
+            .unwrap();
+        assert!(resolution.authenticated);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bogus_answers_fail_unless_checking_is_disabled() {
+        let (port, anchor) = signed_hierarchy(true).await;
+        let resolver = resolver(port).with_trust_anchor(anchor);
+
+        let result = resolver
+            .resolve(
+                &name("www.example.com"),
+                ResourceRecordType::A,
+                DnsClass::IN,
+            )
+            .await;
+        assert!(matches!(result, Err(DnsRequestError::ServFail)));
+
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let resolver = DnsServer::new(resolver);
+        tokio::spawn(async move { resolver.serve(server).await });
+
+        let client = DnsClient::new(addr).with_dnssec_ok(true);
+        let query = |client: &DnsClient, qname| {
+            client.build_query(&name(qname), ResourceRecordType::A, DnsClass::IN)
+        };
+        let response = client
+            .send(&query(&client, "www.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), 2);
+
+        let unchecked = DnsClient::new(addr)
+            .with_dnssec_ok(true)
+            .with_checking_disabled(true);
+        let response = unchecked
+            .send(&query(&unchecked, "www.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), 0);
+        assert_eq!(response.header.flags & 0x0030, 0x0010);
+        assert_eq!(
+            response.answers[0].rdata,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 66))
+        );
+
+        // Secure answers carry AD
+        let response = client.send(&query(&client, "nope.com")).await.unwrap();
+        assert_eq!(response.response_code(), 3);
+        assert_ne!(response.header.flags & 0x0020, 0);
+    }
+}
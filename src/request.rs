@@ -1,7 +1,17 @@
 use byteorder::{NetworkEndian, ReadBytesExt};
 use std::io::Cursor;
 
-use crate::label::DNSLabel;
+use crate::{
+    edns::{find_edns, Edns},
+    label::{read_labels, DNSLabel},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{write_message, DnsResourceRecord},
+};
+
+/// The opcode of a NOTIFY message, which tells secondaries of a zone change (RFC 1996)
+pub const OPCODE_NOTIFY: u8 = 4;
+/// The opcode of an UPDATE message, which changes the records of a zone (RFC 2136)
+pub const OPCODE_UPDATE: u8 = 5;
 
 #[derive(Debug, Copy, Clone)]
 pub struct DNSHeader {
@@ -13,17 +23,20 @@ pub struct DNSHeader {
     pub arcount: u16,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct DNSQuestion {
     pub qname: Vec<DNSLabel>,
-    pub qtype: u16,
-    pub qclass: u16,
+    pub qtype: ResourceRecordType,
+    pub qclass: DnsClass,
 }
 
 #[derive(Debug, Clone)]
 pub struct DNSRequest {
     pub header: DNSHeader,
     pub questions: Vec<DNSQuestion>,
+    pub answers: Vec<DnsResourceRecord>,
+    pub authority: Vec<DnsResourceRecord>,
+    pub additional: Vec<DnsResourceRecord>,
 }
 
 impl DNSRequest {
@@ -41,25 +54,9 @@ impl DNSRequest {
 
         let mut questions = Vec::new();
         for _ in 0..header.qdcount {
-            let mut qname = Vec::new();
-            loop {
-                let len = cursor.read_u8()?;
-                if len == 0 {
-                    break;
-                }
-
-                let mut label_str = String::new();
-                for _ in 0..len {
-                    let c = cursor.read_u8()? as char;
-                    label_str.push(c);
-                }
-
-                let label = label_str.into();
-                qname.push(label);
-            }
-
-            let qtype = cursor.read_u16::<NetworkEndian>()?;
-            let qclass = cursor.read_u16::<NetworkEndian>()?;
+            let qname = read_labels(&mut cursor)?;
+            let qtype = cursor.read_u16::<NetworkEndian>()?.into();
+            let qclass = cursor.read_u16::<NetworkEndian>()?.into();
 
             questions.push(DNSQuestion {
                 qname,
@@ -68,7 +65,66 @@ impl DNSRequest {
             });
         }
 
-        Ok(DNSRequest { header, questions })
+        let mut read_section = |count: u16| {
+            (0..count)
+                .map(|_| DnsResourceRecord::read(&mut cursor))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let answers = read_section(header.ancount)?;
+        let authority = read_section(header.nscount)?;
+        let additional = read_section(header.arcount)?;
+
+        Ok(DNSRequest {
+            header,
+            questions,
+            answers,
+            authority,
+            additional,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, std::io::Error> {
+        write_message(
+            self.header.id,
+            self.header.flags,
+            &self.questions,
+            [&self.answers, &self.authority, &self.additional],
+        )
+    }
+
+    /// The EDNS parameters of the OPT record in the additional section, if any
+    pub fn edns(&self) -> Option<Edns> {
+        find_edns(&self.additional)
+    }
+
+    /// The kind of message, 0 for a standard query
+    pub fn opcode(&self) -> u8 {
+        ((self.header.flags >> 11) & 0x0f) as u8
+    }
+}
+
+#[cfg(test)]
+impl DNSRequest {
+    /// A query for `name` with ID 7 and the RD bit set, without EDNS
+    pub(crate) fn query(name: &str, qtype: ResourceRecordType, qclass: DnsClass) -> Self {
+        DNSRequest {
+            header: DNSHeader {
+                id: 7,
+                flags: 0x0100,
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![DNSQuestion {
+                qname: crate::label::labels_from_str(name).unwrap(),
+                qtype,
+                qclass,
+            }],
+            answers: vec![],
+            authority: vec![],
+            additional: vec![],
+        }
     }
 }
 
@@ -117,8 +173,8 @@ mod tests {
         assert_eq!(Into::<String>::into(question.qname[0].clone()), "www");
         assert_eq!(Into::<String>::into(question.qname[1].clone()), "example");
         assert_eq!(Into::<String>::into(question.qname[2].clone()), "com");
-        assert_eq!(question.qtype, 1);
-        assert_eq!(question.qclass, 1);
+        assert_eq!(question.qtype, ResourceRecordType::A);
+        assert_eq!(question.qclass, DnsClass::IN);
 
         Ok(())
     }
@@ -1,5 +1,9 @@
-#[derive(Copy, Clone, Debug, PartialEq)]
-#[repr(u16)]
+use std::{fmt, io, str::FromStr};
+
+/// The TYPE of a resource record or QTYPE of a question. Codes without a variant of
+/// their own are kept as `Unknown`, so converting from and to `u16` is lossless.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
 pub enum ResourceRecordType {
     A,
     NS,
@@ -90,6 +94,7 @@ pub enum ResourceRecordType {
     AMTRELAY,
     TA,
     DLV,
+    Unknown(u16),
 }
 
 impl ResourceRecordType {
@@ -184,98 +189,503 @@ impl ResourceRecordType {
             ResourceRecordType::AMTRELAY => 260,
             ResourceRecordType::TA => 32768,
             ResourceRecordType::DLV => 32769,
+            ResourceRecordType::Unknown(id) => id,
         }
     }
 
+    /// Returns the type for `id`, or `None` if the code has no variant of its own.
     pub fn try_from_id(id: u16) -> Option<Self> {
+        match Self::from(id) {
+            ResourceRecordType::Unknown(_) => None,
+            rtype => Some(rtype),
+        }
+    }
+
+    /// The IANA mnemonic of the type, or `None` for `Unknown` codes
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        match *self {
+            ResourceRecordType::A => Some("A"),
+            ResourceRecordType::NS => Some("NS"),
+            ResourceRecordType::MD => Some("MD"),
+            ResourceRecordType::MF => Some("MF"),
+            ResourceRecordType::CNAME => Some("CNAME"),
+            ResourceRecordType::SOA => Some("SOA"),
+            ResourceRecordType::MB => Some("MB"),
+            ResourceRecordType::MG => Some("MG"),
+            ResourceRecordType::MR => Some("MR"),
+            ResourceRecordType::NULL => Some("NULL"),
+            ResourceRecordType::WKS => Some("WKS"),
+            ResourceRecordType::PTR => Some("PTR"),
+            ResourceRecordType::HINFO => Some("HINFO"),
+            ResourceRecordType::MINFO => Some("MINFO"),
+            ResourceRecordType::MX => Some("MX"),
+            ResourceRecordType::TXT => Some("TXT"),
+            ResourceRecordType::RP => Some("RP"),
+            ResourceRecordType::AFSDB => Some("AFSDB"),
+            ResourceRecordType::X25 => Some("X25"),
+            ResourceRecordType::ISDN => Some("ISDN"),
+            ResourceRecordType::RT => Some("RT"),
+            ResourceRecordType::NSAP => Some("NSAP"),
+            ResourceRecordType::NSAP_PTR => Some("NSAP-PTR"),
+            ResourceRecordType::SIG => Some("SIG"),
+            ResourceRecordType::KEY => Some("KEY"),
+            ResourceRecordType::PX => Some("PX"),
+            ResourceRecordType::GPOS => Some("GPOS"),
+            ResourceRecordType::AAAA => Some("AAAA"),
+            ResourceRecordType::LOC => Some("LOC"),
+            ResourceRecordType::NXT => Some("NXT"),
+            ResourceRecordType::EID => Some("EID"),
+            ResourceRecordType::NIMLOC => Some("NIMLOC"),
+            ResourceRecordType::SRV => Some("SRV"),
+            ResourceRecordType::ATMA => Some("ATMA"),
+            ResourceRecordType::NAPTR => Some("NAPTR"),
+            ResourceRecordType::KX => Some("KX"),
+            ResourceRecordType::CERT => Some("CERT"),
+            ResourceRecordType::A6 => Some("A6"),
+            ResourceRecordType::DNAME => Some("DNAME"),
+            ResourceRecordType::SINK => Some("SINK"),
+            ResourceRecordType::OPT => Some("OPT"),
+            ResourceRecordType::APL => Some("APL"),
+            ResourceRecordType::DS => Some("DS"),
+            ResourceRecordType::SSHFP => Some("SSHFP"),
+            ResourceRecordType::IPSECKEY => Some("IPSECKEY"),
+            ResourceRecordType::RRSIG => Some("RRSIG"),
+            ResourceRecordType::NSEC => Some("NSEC"),
+            ResourceRecordType::DNSKEY => Some("DNSKEY"),
+            ResourceRecordType::DHCID => Some("DHCID"),
+            ResourceRecordType::NSEC3 => Some("NSEC3"),
+            ResourceRecordType::NSEC3PARAM => Some("NSEC3PARAM"),
+            ResourceRecordType::TLSA => Some("TLSA"),
+            ResourceRecordType::SMIMEA => Some("SMIMEA"),
+            ResourceRecordType::HIP => Some("HIP"),
+            ResourceRecordType::NINFO => Some("NINFO"),
+            ResourceRecordType::RKEY => Some("RKEY"),
+            ResourceRecordType::TALINK => Some("TALINK"),
+            ResourceRecordType::CDS => Some("CDS"),
+            ResourceRecordType::CDNSKEY => Some("CDNSKEY"),
+            ResourceRecordType::OPENPGPKEY => Some("OPENPGPKEY"),
+            ResourceRecordType::CSYNC => Some("CSYNC"),
+            ResourceRecordType::ZONEMD => Some("ZONEMD"),
+            ResourceRecordType::SVCB => Some("SVCB"),
+            ResourceRecordType::HTTPS => Some("HTTPS"),
+            ResourceRecordType::SPF => Some("SPF"),
+            ResourceRecordType::UINFO => Some("UINFO"),
+            ResourceRecordType::UID => Some("UID"),
+            ResourceRecordType::GID => Some("GID"),
+            ResourceRecordType::UNSPEC => Some("UNSPEC"),
+            ResourceRecordType::NID => Some("NID"),
+            ResourceRecordType::L32 => Some("L32"),
+            ResourceRecordType::L64 => Some("L64"),
+            ResourceRecordType::LP => Some("LP"),
+            ResourceRecordType::EUI48 => Some("EUI48"),
+            ResourceRecordType::EUI64 => Some("EUI64"),
+            ResourceRecordType::TKEY => Some("TKEY"),
+            ResourceRecordType::TSIG => Some("TSIG"),
+            ResourceRecordType::IXFR => Some("IXFR"),
+            ResourceRecordType::AXFR => Some("AXFR"),
+            ResourceRecordType::MAILB => Some("MAILB"),
+            ResourceRecordType::MAILA => Some("MAILA"),
+            ResourceRecordType::ANY => Some("ANY"),
+            ResourceRecordType::URI => Some("URI"),
+            ResourceRecordType::CAA => Some("CAA"),
+            ResourceRecordType::AVC => Some("AVC"),
+            ResourceRecordType::DOA => Some("DOA"),
+            ResourceRecordType::AMTRELAY => Some("AMTRELAY"),
+            ResourceRecordType::TA => Some("TA"),
+            ResourceRecordType::DLV => Some("DLV"),
+            ResourceRecordType::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<u16> for ResourceRecordType {
+    fn from(id: u16) -> Self {
         match id {
-            1 => Some(ResourceRecordType::A),
-            2 => Some(ResourceRecordType::NS),
-            3 => Some(ResourceRecordType::MD),
-            4 => Some(ResourceRecordType::MF),
-            5 => Some(ResourceRecordType::CNAME),
-            6 => Some(ResourceRecordType::SOA),
-            7 => Some(ResourceRecordType::MB),
-            8 => Some(ResourceRecordType::MG),
-            9 => Some(ResourceRecordType::MR),
-            10 => Some(ResourceRecordType::NULL),
-            11 => Some(ResourceRecordType::WKS),
-            12 => Some(ResourceRecordType::PTR),
-            13 => Some(ResourceRecordType::HINFO),
-            14 => Some(ResourceRecordType::MINFO),
-            15 => Some(ResourceRecordType::MX),
-            16 => Some(ResourceRecordType::TXT),
-            17 => Some(ResourceRecordType::RP),
-            18 => Some(ResourceRecordType::AFSDB),
-            19 => Some(ResourceRecordType::X25),
-            20 => Some(ResourceRecordType::ISDN),
-            21 => Some(ResourceRecordType::RT),
-            22 => Some(ResourceRecordType::NSAP),
-            23 => Some(ResourceRecordType::NSAP_PTR),
-            24 => Some(ResourceRecordType::SIG),
-            25 => Some(ResourceRecordType::KEY),
-            26 => Some(ResourceRecordType::PX),
-            27 => Some(ResourceRecordType::GPOS),
-            28 => Some(ResourceRecordType::AAAA),
-            29 => Some(ResourceRecordType::LOC),
-            30 => Some(ResourceRecordType::NXT),
-            31 => Some(ResourceRecordType::EID),
-            32 => Some(ResourceRecordType::NIMLOC),
-            33 => Some(ResourceRecordType::SRV),
-            34 => Some(ResourceRecordType::ATMA),
-            35 => Some(ResourceRecordType::NAPTR),
-            36 => Some(ResourceRecordType::KX),
-            37 => Some(ResourceRecordType::CERT),
-            38 => Some(ResourceRecordType::A6),
-            39 => Some(ResourceRecordType::DNAME),
-            40 => Some(ResourceRecordType::SINK),
-            41 => Some(ResourceRecordType::OPT),
-            42 => Some(ResourceRecordType::APL),
-            43 => Some(ResourceRecordType::DS),
-            44 => Some(ResourceRecordType::SSHFP),
-            45 => Some(ResourceRecordType::IPSECKEY),
-            46 => Some(ResourceRecordType::RRSIG),
-            47 => Some(ResourceRecordType::NSEC),
-            48 => Some(ResourceRecordType::DNSKEY),
-            49 => Some(ResourceRecordType::DHCID),
-            50 => Some(ResourceRecordType::NSEC3),
-            51 => Some(ResourceRecordType::NSEC3PARAM),
-            52 => Some(ResourceRecordType::TLSA),
-            53 => Some(ResourceRecordType::SMIMEA),
-            55 => Some(ResourceRecordType::HIP),
-            56 => Some(ResourceRecordType::NINFO),
-            57 => Some(ResourceRecordType::RKEY),
-            58 => Some(ResourceRecordType::TALINK),
-            59 => Some(ResourceRecordType::CDS),
-            60 => Some(ResourceRecordType::CDNSKEY),
-            61 => Some(ResourceRecordType::OPENPGPKEY),
-            62 => Some(ResourceRecordType::CSYNC),
-            99 => Some(ResourceRecordType::SPF),
-            100 => Some(ResourceRecordType::UINFO),
-            101 => Some(ResourceRecordType::UID),
-            102 => Some(ResourceRecordType::GID),
-            103 => Some(ResourceRecordType::UNSPEC),
-            104 => Some(ResourceRecordType::NID),
-            105 => Some(ResourceRecordType::L32),
-            106 => Some(ResourceRecordType::L64),
-            107 => Some(ResourceRecordType::LP),
-            108 => Some(ResourceRecordType::EUI48),
-            109 => Some(ResourceRecordType::EUI64),
-            249 => Some(ResourceRecordType::TKEY),
-            250 => Some(ResourceRecordType::TSIG),
-            251 => Some(ResourceRecordType::IXFR),
-            252 => Some(ResourceRecordType::AXFR),
-            253 => Some(ResourceRecordType::MAILB),
-            254 => Some(ResourceRecordType::MAILA),
-            255 => Some(ResourceRecordType::ANY),
-            256 => Some(ResourceRecordType::URI),
-            257 => Some(ResourceRecordType::CAA),
-            258 => Some(ResourceRecordType::AVC),
-            259 => Some(ResourceRecordType::DOA),
-            260 => Some(ResourceRecordType::AMTRELAY),
-            32768 => Some(ResourceRecordType::TA),
-            32769 => Some(ResourceRecordType::DLV),
-            _ => None,
+            1 => ResourceRecordType::A,
+            2 => ResourceRecordType::NS,
+            3 => ResourceRecordType::MD,
+            4 => ResourceRecordType::MF,
+            5 => ResourceRecordType::CNAME,
+            6 => ResourceRecordType::SOA,
+            7 => ResourceRecordType::MB,
+            8 => ResourceRecordType::MG,
+            9 => ResourceRecordType::MR,
+            10 => ResourceRecordType::NULL,
+            11 => ResourceRecordType::WKS,
+            12 => ResourceRecordType::PTR,
+            13 => ResourceRecordType::HINFO,
+            14 => ResourceRecordType::MINFO,
+            15 => ResourceRecordType::MX,
+            16 => ResourceRecordType::TXT,
+            17 => ResourceRecordType::RP,
+            18 => ResourceRecordType::AFSDB,
+            19 => ResourceRecordType::X25,
+            20 => ResourceRecordType::ISDN,
+            21 => ResourceRecordType::RT,
+            22 => ResourceRecordType::NSAP,
+            23 => ResourceRecordType::NSAP_PTR,
+            24 => ResourceRecordType::SIG,
+            25 => ResourceRecordType::KEY,
+            26 => ResourceRecordType::PX,
+            27 => ResourceRecordType::GPOS,
+            28 => ResourceRecordType::AAAA,
+            29 => ResourceRecordType::LOC,
+            30 => ResourceRecordType::NXT,
+            31 => ResourceRecordType::EID,
+            32 => ResourceRecordType::NIMLOC,
+            33 => ResourceRecordType::SRV,
+            34 => ResourceRecordType::ATMA,
+            35 => ResourceRecordType::NAPTR,
+            36 => ResourceRecordType::KX,
+            37 => ResourceRecordType::CERT,
+            38 => ResourceRecordType::A6,
+            39 => ResourceRecordType::DNAME,
+            40 => ResourceRecordType::SINK,
+            41 => ResourceRecordType::OPT,
+            42 => ResourceRecordType::APL,
+            43 => ResourceRecordType::DS,
+            44 => ResourceRecordType::SSHFP,
+            45 => ResourceRecordType::IPSECKEY,
+            46 => ResourceRecordType::RRSIG,
+            47 => ResourceRecordType::NSEC,
+            48 => ResourceRecordType::DNSKEY,
+            49 => ResourceRecordType::DHCID,
+            50 => ResourceRecordType::NSEC3,
+            51 => ResourceRecordType::NSEC3PARAM,
+            52 => ResourceRecordType::TLSA,
+            53 => ResourceRecordType::SMIMEA,
+            55 => ResourceRecordType::HIP,
+            56 => ResourceRecordType::NINFO,
+            57 => ResourceRecordType::RKEY,
+            58 => ResourceRecordType::TALINK,
+            59 => ResourceRecordType::CDS,
+            60 => ResourceRecordType::CDNSKEY,
+            61 => ResourceRecordType::OPENPGPKEY,
+            62 => ResourceRecordType::CSYNC,
+            63 => ResourceRecordType::ZONEMD,
+            64 => ResourceRecordType::SVCB,
+            65 => ResourceRecordType::HTTPS,
+            99 => ResourceRecordType::SPF,
+            100 => ResourceRecordType::UINFO,
+            101 => ResourceRecordType::UID,
+            102 => ResourceRecordType::GID,
+            103 => ResourceRecordType::UNSPEC,
+            104 => ResourceRecordType::NID,
+            105 => ResourceRecordType::L32,
+            106 => ResourceRecordType::L64,
+            107 => ResourceRecordType::LP,
+            108 => ResourceRecordType::EUI48,
+            109 => ResourceRecordType::EUI64,
+            249 => ResourceRecordType::TKEY,
+            250 => ResourceRecordType::TSIG,
+            251 => ResourceRecordType::IXFR,
+            252 => ResourceRecordType::AXFR,
+            253 => ResourceRecordType::MAILB,
+            254 => ResourceRecordType::MAILA,
+            255 => ResourceRecordType::ANY,
+            256 => ResourceRecordType::URI,
+            257 => ResourceRecordType::CAA,
+            258 => ResourceRecordType::AVC,
+            259 => ResourceRecordType::DOA,
+            260 => ResourceRecordType::AMTRELAY,
+            32768 => ResourceRecordType::TA,
+            32769 => ResourceRecordType::DLV,
+            id => ResourceRecordType::Unknown(id),
+        }
+    }
+}
+
+impl From<ResourceRecordType> for u16 {
+    fn from(rtype: ResourceRecordType) -> Self {
+        rtype.id()
+    }
+}
+
+/// Formats the mnemonic, or the generic `TYPE1234` syntax of RFC 3597 for unknown codes
+impl fmt::Display for ResourceRecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mnemonic() {
+            Some(mnemonic) => write!(f, "{}", mnemonic),
+            None => write!(f, "TYPE{}", self.id()),
+        }
+    }
+}
+
+/// Parses a mnemonic or the generic `TYPE1234` syntax, ignoring case
+impl FromStr for ResourceRecordType {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        if let Some(id) = parse_generic(&upper, "TYPE") {
+            return Ok(Self::from(id));
         }
+
+        match upper.as_str() {
+            "A" => Ok(ResourceRecordType::A),
+            "NS" => Ok(ResourceRecordType::NS),
+            "MD" => Ok(ResourceRecordType::MD),
+            "MF" => Ok(ResourceRecordType::MF),
+            "CNAME" => Ok(ResourceRecordType::CNAME),
+            "SOA" => Ok(ResourceRecordType::SOA),
+            "MB" => Ok(ResourceRecordType::MB),
+            "MG" => Ok(ResourceRecordType::MG),
+            "MR" => Ok(ResourceRecordType::MR),
+            "NULL" => Ok(ResourceRecordType::NULL),
+            "WKS" => Ok(ResourceRecordType::WKS),
+            "PTR" => Ok(ResourceRecordType::PTR),
+            "HINFO" => Ok(ResourceRecordType::HINFO),
+            "MINFO" => Ok(ResourceRecordType::MINFO),
+            "MX" => Ok(ResourceRecordType::MX),
+            "TXT" => Ok(ResourceRecordType::TXT),
+            "RP" => Ok(ResourceRecordType::RP),
+            "AFSDB" => Ok(ResourceRecordType::AFSDB),
+            "X25" => Ok(ResourceRecordType::X25),
+            "ISDN" => Ok(ResourceRecordType::ISDN),
+            "RT" => Ok(ResourceRecordType::RT),
+            "NSAP" => Ok(ResourceRecordType::NSAP),
+            "NSAP-PTR" => Ok(ResourceRecordType::NSAP_PTR),
+            "SIG" => Ok(ResourceRecordType::SIG),
+            "KEY" => Ok(ResourceRecordType::KEY),
+            "PX" => Ok(ResourceRecordType::PX),
+            "GPOS" => Ok(ResourceRecordType::GPOS),
+            "AAAA" => Ok(ResourceRecordType::AAAA),
+            "LOC" => Ok(ResourceRecordType::LOC),
+            "NXT" => Ok(ResourceRecordType::NXT),
+            "EID" => Ok(ResourceRecordType::EID),
+            "NIMLOC" => Ok(ResourceRecordType::NIMLOC),
+            "SRV" => Ok(ResourceRecordType::SRV),
+            "ATMA" => Ok(ResourceRecordType::ATMA),
+            "NAPTR" => Ok(ResourceRecordType::NAPTR),
+            "KX" => Ok(ResourceRecordType::KX),
+            "CERT" => Ok(ResourceRecordType::CERT),
+            "A6" => Ok(ResourceRecordType::A6),
+            "DNAME" => Ok(ResourceRecordType::DNAME),
+            "SINK" => Ok(ResourceRecordType::SINK),
+            "OPT" => Ok(ResourceRecordType::OPT),
+            "APL" => Ok(ResourceRecordType::APL),
+            "DS" => Ok(ResourceRecordType::DS),
+            "SSHFP" => Ok(ResourceRecordType::SSHFP),
+            "IPSECKEY" => Ok(ResourceRecordType::IPSECKEY),
+            "RRSIG" => Ok(ResourceRecordType::RRSIG),
+            "NSEC" => Ok(ResourceRecordType::NSEC),
+            "DNSKEY" => Ok(ResourceRecordType::DNSKEY),
+            "DHCID" => Ok(ResourceRecordType::DHCID),
+            "NSEC3" => Ok(ResourceRecordType::NSEC3),
+            "NSEC3PARAM" => Ok(ResourceRecordType::NSEC3PARAM),
+            "TLSA" => Ok(ResourceRecordType::TLSA),
+            "SMIMEA" => Ok(ResourceRecordType::SMIMEA),
+            "HIP" => Ok(ResourceRecordType::HIP),
+            "NINFO" => Ok(ResourceRecordType::NINFO),
+            "RKEY" => Ok(ResourceRecordType::RKEY),
+            "TALINK" => Ok(ResourceRecordType::TALINK),
+            "CDS" => Ok(ResourceRecordType::CDS),
+            "CDNSKEY" => Ok(ResourceRecordType::CDNSKEY),
+            "OPENPGPKEY" => Ok(ResourceRecordType::OPENPGPKEY),
+            "CSYNC" => Ok(ResourceRecordType::CSYNC),
+            "ZONEMD" => Ok(ResourceRecordType::ZONEMD),
+            "SVCB" => Ok(ResourceRecordType::SVCB),
+            "HTTPS" => Ok(ResourceRecordType::HTTPS),
+            "SPF" => Ok(ResourceRecordType::SPF),
+            "UINFO" => Ok(ResourceRecordType::UINFO),
+            "UID" => Ok(ResourceRecordType::UID),
+            "GID" => Ok(ResourceRecordType::GID),
+            "UNSPEC" => Ok(ResourceRecordType::UNSPEC),
+            "NID" => Ok(ResourceRecordType::NID),
+            "L32" => Ok(ResourceRecordType::L32),
+            "L64" => Ok(ResourceRecordType::L64),
+            "LP" => Ok(ResourceRecordType::LP),
+            "EUI48" => Ok(ResourceRecordType::EUI48),
+            "EUI64" => Ok(ResourceRecordType::EUI64),
+            "TKEY" => Ok(ResourceRecordType::TKEY),
+            "TSIG" => Ok(ResourceRecordType::TSIG),
+            "IXFR" => Ok(ResourceRecordType::IXFR),
+            "AXFR" => Ok(ResourceRecordType::AXFR),
+            "MAILB" => Ok(ResourceRecordType::MAILB),
+            "MAILA" => Ok(ResourceRecordType::MAILA),
+            "ANY" => Ok(ResourceRecordType::ANY),
+            "URI" => Ok(ResourceRecordType::URI),
+            "CAA" => Ok(ResourceRecordType::CAA),
+            "AVC" => Ok(ResourceRecordType::AVC),
+            "DOA" => Ok(ResourceRecordType::DOA),
+            "AMTRELAY" => Ok(ResourceRecordType::AMTRELAY),
+            "TA" => Ok(ResourceRecordType::TA),
+            "DLV" => Ok(ResourceRecordType::DLV),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record type {:?}", s),
+            )),
+        }
+    }
+}
+
+impl TryFrom<&str> for ResourceRecordType {
+    type Error = io::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The CLASS of a resource record or QCLASS of a question
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum DnsClass {
+    IN,
+    CH,
+    HS,
+    NONE,
+    ANY,
+    Unknown(u16),
+}
+
+impl DnsClass {
+    pub fn id(&self) -> u16 {
+        match *self {
+            DnsClass::IN => 1,
+            DnsClass::CH => 3,
+            DnsClass::HS => 4,
+            DnsClass::NONE => 254,
+            DnsClass::ANY => 255,
+            DnsClass::Unknown(id) => id,
+        }
+    }
+
+    /// The IANA mnemonic of the class, or `None` for `Unknown` codes
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        match *self {
+            DnsClass::IN => Some("IN"),
+            DnsClass::CH => Some("CH"),
+            DnsClass::HS => Some("HS"),
+            DnsClass::NONE => Some("NONE"),
+            DnsClass::ANY => Some("ANY"),
+            DnsClass::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<u16> for DnsClass {
+    fn from(id: u16) -> Self {
+        match id {
+            1 => DnsClass::IN,
+            3 => DnsClass::CH,
+            4 => DnsClass::HS,
+            254 => DnsClass::NONE,
+            255 => DnsClass::ANY,
+            id => DnsClass::Unknown(id),
+        }
+    }
+}
+
+impl From<DnsClass> for u16 {
+    fn from(class: DnsClass) -> Self {
+        class.id()
+    }
+}
+
+/// Formats the mnemonic, or the generic `CLASS1234` syntax of RFC 3597 for unknown codes
+impl fmt::Display for DnsClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mnemonic() {
+            Some(mnemonic) => write!(f, "{}", mnemonic),
+            None => write!(f, "CLASS{}", self.id()),
+        }
+    }
+}
+
+/// Parses a mnemonic or the generic `CLASS1234` syntax, ignoring case
+impl FromStr for DnsClass {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        if let Some(id) = parse_generic(&upper, "CLASS") {
+            return Ok(Self::from(id));
+        }
+
+        match upper.as_str() {
+            "IN" => Ok(DnsClass::IN),
+            "CH" => Ok(DnsClass::CH),
+            "HS" => Ok(DnsClass::HS),
+            "NONE" => Ok(DnsClass::NONE),
+            "ANY" => Ok(DnsClass::ANY),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record class {:?}", s),
+            )),
+        }
+    }
+}
+
+impl TryFrom<&str> for DnsClass {
+    type Error = io::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+// Parses the decimal code of RFC 3597's TYPE1234 and CLASS1234 syntax
+fn parse_generic(s: &str, prefix: &str) -> Option<u16> {
+    s.strip_prefix(prefix)
+        .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|id| id.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_type_lossless() {
+        for id in [1, 63, 64, 65, 54, 1234, 65535] {
+            assert_eq!(u16::from(ResourceRecordType::from(id)), id);
+        }
+        assert_eq!(ResourceRecordType::from(64), ResourceRecordType::SVCB);
+        assert_eq!(
+            ResourceRecordType::from(1234),
+            ResourceRecordType::Unknown(1234)
+        );
+        assert_eq!(
+            ResourceRecordType::try_from_id(63),
+            Some(ResourceRecordType::ZONEMD)
+        );
+        assert_eq!(ResourceRecordType::try_from_id(1234), None);
+    }
+
+    #[test]
+    fn test_record_type_presentation() {
+        assert_eq!(
+            "https".parse::<ResourceRecordType>().unwrap(),
+            ResourceRecordType::HTTPS
+        );
+        assert_eq!(
+            "NSAP-PTR".parse::<ResourceRecordType>().unwrap(),
+            ResourceRecordType::NSAP_PTR
+        );
+        assert_eq!(
+            "TYPE1".parse::<ResourceRecordType>().unwrap(),
+            ResourceRecordType::A
+        );
+        assert_eq!(
+            ResourceRecordType::try_from("type1234").unwrap(),
+            ResourceRecordType::Unknown(1234)
+        );
+        assert!("TYPE".parse::<ResourceRecordType>().is_err());
+        assert!("BOGUS".parse::<ResourceRecordType>().is_err());
+
+        assert_eq!(ResourceRecordType::AAAA.to_string(), "AAAA");
+        assert_eq!(ResourceRecordType::Unknown(1234).to_string(), "TYPE1234");
+    }
+
+    #[test]
+    fn test_class_conversions() {
+        assert_eq!(DnsClass::from(3), DnsClass::CH);
+        assert_eq!(u16::from(DnsClass::NONE), 254);
+        assert_eq!(DnsClass::from(4096), DnsClass::Unknown(4096));
+        assert_eq!("ch".parse::<DnsClass>().unwrap(), DnsClass::CH);
+        assert_eq!("CLASS255".parse::<DnsClass>().unwrap(), DnsClass::ANY);
+        assert_eq!(DnsClass::Unknown(42).to_string(), "CLASS42");
+        assert_eq!(DnsClass::IN.to_string(), "IN");
     }
 }
@@ -1,13 +1,30 @@
 use std::{
-    io::Write,
+    collections::HashSet,
+    fmt,
+    io::{Cursor, Read, Write},
     net::{Ipv4Addr, Ipv6Addr},
 };
 
-use crate::{label::DNSLabel, request::DNSQuestion, resourcerecord::ResourceRecordType};
-use byteorder::{NetworkEndian, WriteBytesExt};
+use crate::{
+    dnssec::{base32hex_encode, read_type_bitmap, write_type_bitmap, RrsigData},
+    edns::{find_edns, Edns, EdnsOption},
+    handler::DnsRequestError,
+    label::{labels_from_str, labels_to_string, read_labels, write_labels, write_name, DNSLabel},
+    request::{DNSQuestion, DNSRequest},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    svcb::SvcbData,
+    tsig::TsigData,
+    zone::escape,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+// Upper bound on AliasMode records followed while filling the additional section
+const MAX_SVCB_CHAIN: usize = 8;
 
 // Die verschiedenen Typen von Ressourcendatensätzen, die in einer DNS-Antwort enthalten sein können
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DnsRecordData {
     A(Ipv4Addr),
     AAAA(Ipv6Addr),
@@ -20,6 +37,7 @@ pub enum DnsRecordData {
     MG(String),
     MR(String),
     PTR(String),
+    DNAME(String),
     SOA {
         mname: String,
         rname: String,
@@ -35,7 +53,58 @@ pub enum DnsRecordData {
         port: u16,
         target: String,
     },
-    TXT(Vec<String>),
+    TXT(Vec<Vec<u8>>),
+    SVCB(SvcbData),
+    HTTPS(SvcbData),
+    DS {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    DNSKEY {
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    /// The DS records a child zone wants at its parent (RFC 7344)
+    CDS {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    /// The DNSKEY records a child zone wants its parent to refer to (RFC 7344)
+    CDNSKEY {
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    RRSIG(RrsigData),
+    NSEC {
+        next_domain: String,
+        types: Vec<ResourceRecordType>,
+    },
+    NSEC3 {
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<ResourceRecordType>,
+    },
+    NSEC3PARAM {
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+    },
+    OPT(Vec<EdnsOption>),
+    TSIG(TsigData),
+    /// RDATA of a type without a variant of its own, kept in wire format (RFC 3597)
+    Unknown(ResourceRecordType, Vec<u8>),
 }
 
 impl DnsRecordData {
@@ -52,6 +121,7 @@ impl DnsRecordData {
             DnsRecordData::MG(_) => ResourceRecordType::MG,
             DnsRecordData::MR(_) => ResourceRecordType::MR,
             DnsRecordData::PTR(_) => ResourceRecordType::PTR,
+            DnsRecordData::DNAME(_) => ResourceRecordType::DNAME,
             DnsRecordData::SOA {
                 mname: _,
                 rname: _,
@@ -68,20 +138,488 @@ impl DnsRecordData {
                 target: _,
             } => ResourceRecordType::SRV,
             DnsRecordData::TXT(_) => ResourceRecordType::TXT,
+            DnsRecordData::SVCB(_) => ResourceRecordType::SVCB,
+            DnsRecordData::HTTPS(_) => ResourceRecordType::HTTPS,
+            DnsRecordData::DS { .. } => ResourceRecordType::DS,
+            DnsRecordData::DNSKEY { .. } => ResourceRecordType::DNSKEY,
+            DnsRecordData::CDS { .. } => ResourceRecordType::CDS,
+            DnsRecordData::CDNSKEY { .. } => ResourceRecordType::CDNSKEY,
+            DnsRecordData::RRSIG(_) => ResourceRecordType::RRSIG,
+            DnsRecordData::NSEC { .. } => ResourceRecordType::NSEC,
+            DnsRecordData::NSEC3 { .. } => ResourceRecordType::NSEC3,
+            DnsRecordData::NSEC3PARAM { .. } => ResourceRecordType::NSEC3PARAM,
+            DnsRecordData::OPT(_) => ResourceRecordType::OPT,
+            DnsRecordData::TSIG(_) => ResourceRecordType::TSIG,
+            DnsRecordData::Unknown(rtype, _) => *rtype,
+        }
+    }
+
+    /// Reads `rdlength` bytes of RDATA of type `rtype`. The cursor has to read from the
+    /// whole message so that compressed names can be resolved.
+    pub fn read(
+        rtype: ResourceRecordType,
+        cursor: &mut Cursor<&[u8]>,
+        rdlength: u16,
+    ) -> Result<Self, std::io::Error> {
+        let end = cursor.position() + rdlength as u64;
+        if end > cursor.get_ref().len() as u64 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        // Empty RDATA, as in the prerequisites and deletions of UPDATE messages (RFC 2136)
+        if rdlength == 0 && rtype != ResourceRecordType::OPT {
+            return Ok(DnsRecordData::Unknown(rtype, vec![]));
+        }
+        let read_name = |cursor: &mut Cursor<&[u8]>| -> Result<String, std::io::Error> {
+            Ok(labels_to_string(&read_labels(cursor)?))
+        };
+        let read_rest = |cursor: &mut Cursor<&[u8]>| -> Result<Vec<u8>, std::io::Error> {
+            let mut data = vec![0; end.saturating_sub(cursor.position()) as usize];
+            cursor.read_exact(&mut data)?;
+            Ok(data)
+        };
+        let read_string = |cursor: &mut Cursor<&[u8]>| -> Result<Vec<u8>, std::io::Error> {
+            let mut data = vec![0; cursor.read_u8()? as usize];
+            cursor.read_exact(&mut data)?;
+            Ok(data)
+        };
+
+        let rdata = match rtype {
+            ResourceRecordType::A => {
+                DnsRecordData::A(Ipv4Addr::from(cursor.read_u32::<NetworkEndian>()?))
+            }
+            ResourceRecordType::AAAA => {
+                DnsRecordData::AAAA(Ipv6Addr::from(cursor.read_u128::<NetworkEndian>()?))
+            }
+            ResourceRecordType::CNAME => DnsRecordData::CNAME(read_name(cursor)?),
+            ResourceRecordType::NS => DnsRecordData::NS(read_name(cursor)?),
+            ResourceRecordType::MD => DnsRecordData::MD(read_name(cursor)?),
+            ResourceRecordType::MF => DnsRecordData::MF(read_name(cursor)?),
+            ResourceRecordType::MB => DnsRecordData::MB(read_name(cursor)?),
+            ResourceRecordType::MG => DnsRecordData::MG(read_name(cursor)?),
+            ResourceRecordType::MR => DnsRecordData::MR(read_name(cursor)?),
+            ResourceRecordType::PTR => DnsRecordData::PTR(read_name(cursor)?),
+            ResourceRecordType::DNAME => DnsRecordData::DNAME(read_name(cursor)?),
+            ResourceRecordType::MX => {
+                let preference = cursor.read_u16::<NetworkEndian>()?;
+                DnsRecordData::MX(preference, read_name(cursor)?)
+            }
+            ResourceRecordType::SOA => DnsRecordData::SOA {
+                mname: read_name(cursor)?,
+                rname: read_name(cursor)?,
+                serial: cursor.read_u32::<NetworkEndian>()?,
+                refresh: cursor.read_u32::<NetworkEndian>()?,
+                retry: cursor.read_u32::<NetworkEndian>()?,
+                expire: cursor.read_u32::<NetworkEndian>()?,
+                minimum: cursor.read_u32::<NetworkEndian>()?,
+            },
+            ResourceRecordType::SRV => DnsRecordData::SRV {
+                priority: cursor.read_u16::<NetworkEndian>()?,
+                weight: cursor.read_u16::<NetworkEndian>()?,
+                port: cursor.read_u16::<NetworkEndian>()?,
+                target: read_name(cursor)?,
+            },
+            ResourceRecordType::TXT => {
+                let mut strings = Vec::new();
+                while cursor.position() < end {
+                    let len = cursor.read_u8()? as usize;
+                    let mut string = vec![0; len];
+                    cursor.read_exact(&mut string)?;
+                    strings.push(string);
+                }
+                DnsRecordData::TXT(strings)
+            }
+            ResourceRecordType::SVCB => DnsRecordData::SVCB(SvcbData::read(cursor, rdlength)?),
+            ResourceRecordType::HTTPS => DnsRecordData::HTTPS(SvcbData::read(cursor, rdlength)?),
+            ResourceRecordType::DS => DnsRecordData::DS {
+                key_tag: cursor.read_u16::<NetworkEndian>()?,
+                algorithm: cursor.read_u8()?,
+                digest_type: cursor.read_u8()?,
+                digest: read_rest(cursor)?,
+            },
+            ResourceRecordType::DNSKEY => DnsRecordData::DNSKEY {
+                flags: cursor.read_u16::<NetworkEndian>()?,
+                protocol: cursor.read_u8()?,
+                algorithm: cursor.read_u8()?,
+                public_key: read_rest(cursor)?,
+            },
+            ResourceRecordType::CDS => DnsRecordData::CDS {
+                key_tag: cursor.read_u16::<NetworkEndian>()?,
+                algorithm: cursor.read_u8()?,
+                digest_type: cursor.read_u8()?,
+                digest: read_rest(cursor)?,
+            },
+            ResourceRecordType::CDNSKEY => DnsRecordData::CDNSKEY {
+                flags: cursor.read_u16::<NetworkEndian>()?,
+                protocol: cursor.read_u8()?,
+                algorithm: cursor.read_u8()?,
+                public_key: read_rest(cursor)?,
+            },
+            ResourceRecordType::RRSIG => DnsRecordData::RRSIG(RrsigData::read(cursor, rdlength)?),
+            ResourceRecordType::NSEC => DnsRecordData::NSEC {
+                next_domain: read_name(cursor)?,
+                types: read_type_bitmap(&read_rest(cursor)?)?,
+            },
+            ResourceRecordType::NSEC3 => DnsRecordData::NSEC3 {
+                hash_algorithm: cursor.read_u8()?,
+                flags: cursor.read_u8()?,
+                iterations: cursor.read_u16::<NetworkEndian>()?,
+                salt: read_string(cursor)?,
+                next_hashed: read_string(cursor)?,
+                types: read_type_bitmap(&read_rest(cursor)?)?,
+            },
+            ResourceRecordType::NSEC3PARAM => DnsRecordData::NSEC3PARAM {
+                hash_algorithm: cursor.read_u8()?,
+                flags: cursor.read_u8()?,
+                iterations: cursor.read_u16::<NetworkEndian>()?,
+                salt: read_string(cursor)?,
+            },
+            ResourceRecordType::OPT => DnsRecordData::OPT(EdnsOption::read_all(cursor, rdlength)?),
+            ResourceRecordType::TSIG => DnsRecordData::TSIG(TsigData::read(cursor, rdlength)?),
+            rtype => {
+                let mut data = vec![0; rdlength as usize];
+                cursor.read_exact(&mut data)?;
+                DnsRecordData::Unknown(rtype, data)
+            }
+        };
+
+        if cursor.position() != end {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("RDATA of {} record does not match its length", rtype),
+            ));
         }
+        Ok(rdata)
+    }
+
+    /// Writes the RDATA in wire format, without the RDLENGTH prefix
+    pub fn write(&self, buffer: &mut Vec<u8>) -> Result<(), std::io::Error> {
+        match self {
+            DnsRecordData::A(addr) => buffer.write_u32::<NetworkEndian>(u32::from(*addr))?,
+            DnsRecordData::AAAA(addr) => buffer.write_all(&addr.octets())?,
+            DnsRecordData::CNAME(name)
+            | DnsRecordData::NS(name)
+            | DnsRecordData::MD(name)
+            | DnsRecordData::MF(name)
+            | DnsRecordData::MB(name)
+            | DnsRecordData::MG(name)
+            | DnsRecordData::MR(name)
+            | DnsRecordData::PTR(name)
+            | DnsRecordData::DNAME(name) => write_name(buffer, name)?,
+            DnsRecordData::MX(preference, exchange) => {
+                buffer.write_u16::<NetworkEndian>(*preference)?;
+                write_name(buffer, exchange)?;
+            }
+            DnsRecordData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                write_name(buffer, mname)?;
+                write_name(buffer, rname)?;
+                buffer.write_u32::<NetworkEndian>(*serial)?;
+                buffer.write_u32::<NetworkEndian>(*refresh)?;
+                buffer.write_u32::<NetworkEndian>(*retry)?;
+                buffer.write_u32::<NetworkEndian>(*expire)?;
+                buffer.write_u32::<NetworkEndian>(*minimum)?;
+            }
+            DnsRecordData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                buffer.write_u16::<NetworkEndian>(*priority)?;
+                buffer.write_u16::<NetworkEndian>(*weight)?;
+                buffer.write_u16::<NetworkEndian>(*port)?;
+                write_name(buffer, target)?;
+            }
+            DnsRecordData::TXT(strings) => {
+                for string in strings {
+                    if string.len() > 255 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "TXT character-string longer than 255 bytes",
+                        ));
+                    }
+                    buffer.push(string.len() as u8);
+                    buffer.write_all(string)?;
+                }
+            }
+            DnsRecordData::SVCB(data) | DnsRecordData::HTTPS(data) => data.write(buffer)?,
+            DnsRecordData::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            }
+            | DnsRecordData::CDS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => {
+                buffer.write_u16::<NetworkEndian>(*key_tag)?;
+                buffer.push(*algorithm);
+                buffer.push(*digest_type);
+                buffer.write_all(digest)?;
+            }
+            DnsRecordData::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            }
+            | DnsRecordData::CDNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => {
+                buffer.write_u16::<NetworkEndian>(*flags)?;
+                buffer.push(*protocol);
+                buffer.push(*algorithm);
+                buffer.write_all(public_key)?;
+            }
+            DnsRecordData::RRSIG(rrsig) => rrsig.write(buffer)?,
+            DnsRecordData::TSIG(tsig) => tsig.write(buffer)?,
+            DnsRecordData::NSEC { next_domain, types } => {
+                write_name(buffer, next_domain)?;
+                write_type_bitmap(buffer, types);
+            }
+            DnsRecordData::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+            } => {
+                buffer.push(*hash_algorithm);
+                buffer.push(*flags);
+                buffer.write_u16::<NetworkEndian>(*iterations)?;
+                write_string(buffer, salt)?;
+                write_string(buffer, next_hashed)?;
+                write_type_bitmap(buffer, types);
+            }
+            DnsRecordData::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+            } => {
+                buffer.push(*hash_algorithm);
+                buffer.push(*flags);
+                buffer.write_u16::<NetworkEndian>(*iterations)?;
+                write_string(buffer, salt)?;
+            }
+            DnsRecordData::OPT(options) => {
+                for option in options {
+                    option.write(buffer)?;
+                }
+            }
+            DnsRecordData::Unknown(_, data) => buffer.write_all(data)?,
+        }
+        Ok(())
     }
 }
 
+/// Formats the RDATA in the presentation format of zone files, with absolute names.
+/// Types without a presentation format of their own use the generic `\#` form of
+/// RFC 3597.
+impl fmt::Display for DnsRecordData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsRecordData::A(addr) => write!(f, "{}", addr),
+            DnsRecordData::AAAA(addr) => write!(f, "{}", addr),
+            DnsRecordData::CNAME(name)
+            | DnsRecordData::NS(name)
+            | DnsRecordData::MD(name)
+            | DnsRecordData::MF(name)
+            | DnsRecordData::MB(name)
+            | DnsRecordData::MG(name)
+            | DnsRecordData::MR(name)
+            | DnsRecordData::PTR(name)
+            | DnsRecordData::DNAME(name) => write!(f, "{}.", name),
+            DnsRecordData::MX(preference, exchange) => write!(f, "{} {}.", preference, exchange),
+            DnsRecordData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{}. {}. {} {} {} {} {}",
+                mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            DnsRecordData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}.", priority, weight, port, target),
+            DnsRecordData::TXT(strings) => {
+                let strings: Vec<String> = strings
+                    .iter()
+                    .map(|string| format!("\"{}\"", escape(string)))
+                    .collect();
+                write!(f, "{}", strings.join(" "))
+            }
+            DnsRecordData::SVCB(data) | DnsRecordData::HTTPS(data) => write!(f, "{}", data),
+            DnsRecordData::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            }
+            | DnsRecordData::CDS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                hex(digest)
+            ),
+            DnsRecordData::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            }
+            | DnsRecordData::CDNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                BASE64.encode(public_key)
+            ),
+            DnsRecordData::RRSIG(rrsig) => write!(f, "{}", rrsig),
+            DnsRecordData::TSIG(tsig) => write!(f, "{}", tsig),
+            DnsRecordData::NSEC { next_domain, types } => {
+                write!(f, "{}.", next_domain)?;
+                write_types(f, types)
+            }
+            DnsRecordData::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+            } => {
+                write!(
+                    f,
+                    "{} {} {} {} {}",
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt_text(salt),
+                    base32hex_encode(next_hashed).to_ascii_uppercase()
+                )?;
+                write_types(f, types)
+            }
+            DnsRecordData::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                salt_text(salt)
+            ),
+            DnsRecordData::OPT(_) | DnsRecordData::Unknown(_, _) => {
+                let mut data = Vec::new();
+                self.write(&mut data).map_err(|_| fmt::Error)?;
+                match data.is_empty() {
+                    true => write!(f, "\\# 0"),
+                    false => write!(f, "\\# {} {}", data.len(), hex(&data)),
+                }
+            }
+        }
+    }
+}
+
+// The type list of NSEC and NSEC3 records, in the order of the type bitmap
+fn write_types(f: &mut fmt::Formatter<'_>, types: &[ResourceRecordType]) -> fmt::Result {
+    let mut types = types.to_vec();
+    types.sort_by_key(|rtype| rtype.id());
+    types.dedup();
+    for rtype in types {
+        write!(f, " {}", rtype)?;
+    }
+    Ok(())
+}
+
+fn salt_text(salt: &[u8]) -> String {
+    match salt.is_empty() {
+        true => String::from("-"),
+        false => hex(salt),
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+// Writes a length-prefixed string of bytes, such as an NSEC3 salt
+fn write_string(buffer: &mut Vec<u8>, data: &[u8]) -> Result<(), std::io::Error> {
+    if data.len() > 255 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "string longer than 255 bytes",
+        ));
+    }
+    buffer.push(data.len() as u8);
+    buffer.extend_from_slice(data);
+    Ok(())
+}
+
 // Ein DNS-Ressourcendatensatz, der in der Antwortsektion einer DNS-Antwort enthalten ist
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DnsResourceRecord {
     pub name: Vec<DNSLabel>,
-    pub rtype: u16,
-    pub class: u16,
+    pub rtype: ResourceRecordType,
+    pub class: DnsClass,
     pub ttl: u32,
     pub rdata: DnsRecordData,
 }
 
+/// Formats the record as a line of a zone file: owner, TTL, class, type and RDATA
+impl fmt::Display for DnsResourceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}. {} {} {} {}",
+            labels_to_string(&self.name),
+            self.ttl,
+            self.class,
+            self.rtype,
+            self.rdata
+        )
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct DnsResponseHeader {
     pub id: u16,
@@ -93,7 +631,7 @@ pub struct DnsResponseHeader {
 }
 
 // Die DNS-Antwortstruktur
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DnsResponse {
     pub header: DnsResponseHeader,
     pub questions: Vec<DNSQuestion>,
@@ -103,85 +641,222 @@ pub struct DnsResponse {
 }
 
 impl DnsResponse {
-    pub fn to_bytes(&self) -> Result<Vec<u8>, std::io::Error> {
-        let mut buffer = Vec::new();
-        // Header
-        buffer.write_u16::<NetworkEndian>(self.header.id)?;
-        buffer.write_u16::<NetworkEndian>(self.header.flags)?;
-        buffer.write_u16::<NetworkEndian>(self.header.qdcount)?;
-        buffer.write_u16::<NetworkEndian>(self.header.ancount)?;
-        buffer.write_u16::<NetworkEndian>(self.header.nscount)?;
-        buffer.write_u16::<NetworkEndian>(self.header.arcount)?;
-
-        // Questions
-        for question in &self.questions {
-            for label in &question.qname {
-                buffer.push(label.0.len() as u8);
-                buffer.extend_from_slice(label.0.as_bytes());
-            }
-            buffer.push(0); // End of QNAME
-            buffer.write_u16::<NetworkEndian>(question.qtype)?;
-            buffer.write_u16::<NetworkEndian>(question.qclass)?;
+    /// Creates an empty response to `request` that echoes its ID, opcode, RD bit and
+    /// questions.
+    pub fn reply_to(request: &DNSRequest) -> Self {
+        DnsResponse {
+            header: DnsResponseHeader {
+                id: request.header.id,
+                flags: 0x8000 | (request.header.flags & 0x7900),
+                qdcount: request.questions.len() as u16,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: request.questions.clone(),
+            answers: vec![],
+            authority: vec![],
+            additional: vec![],
         }
+    }
+
+    /// Creates a response to `request` that carries the RCODE of `error`.
+    pub fn error_for(request: &DNSRequest, error: &DnsRequestError) -> Self {
+        let mut response = Self::reply_to(request);
+        // RCODEs above 15 need the extended RCODE of an OPT record
+        let rcode = match error.to_response_code() {
+            rcode @ 0..=15 => rcode,
+            _ => DnsRequestError::ServFail.to_response_code(),
+        };
+        response.set_response_code(rcode);
+        response
+    }
+
+    /// The EDNS parameters of the OPT record in the additional section, if any
+    pub fn edns(&self) -> Option<Edns> {
+        find_edns(&self.additional)
+    }
+
+    /// Adds an OPT record carrying `edns`, replacing an existing one.
+    pub fn set_edns(&mut self, edns: Edns) {
+        self.additional
+            .retain(|record| record.rtype != ResourceRecordType::OPT);
+        self.additional.push(edns.to_record());
+    }
+
+    pub fn response_code(&self) -> u8 {
+        (self.header.flags & 0x000F) as u8
+    }
+
+    pub fn set_response_code(&mut self, rcode: u8) {
+        self.header.flags = (self.header.flags & !0x000F) | (rcode as u16 & 0x000F);
+    }
+
+    /// Parses a response received from another server.
+    pub fn parse(data: &[u8]) -> Result<Self, std::io::Error> {
+        let message = DNSRequest::parse(data)?;
+        Ok(DnsResponse {
+            header: DnsResponseHeader {
+                id: message.header.id,
+                flags: message.header.flags,
+                qdcount: message.header.qdcount,
+                ancount: message.header.ancount,
+                nscount: message.header.nscount,
+                arcount: message.header.arcount,
+            },
+            questions: message.questions,
+            answers: message.answers,
+            authority: message.authority,
+            additional: message.additional,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, std::io::Error> {
+        write_message(
+            self.header.id,
+            self.header.flags,
+            &self.questions,
+            [&self.answers, &self.authority, &self.additional],
+        )
+    }
+
+    /// Adds the records a client needs to follow the SVCB and HTTPS records of the answer
+    /// section to the additional section (RFC 9460, section 4). AliasMode targets are
+    /// followed to their own SVCB/HTTPS records, and address records are added for
+    /// ServiceMode targets. `lookup` returns the records the server holds for a name and
+    /// type.
+    pub fn add_svcb_additionals<F>(&mut self, mut lookup: F)
+    where
+        F: FnMut(&[DNSLabel], ResourceRecordType) -> Vec<DnsResourceRecord>,
+    {
+        let mut seen: HashSet<(String, ResourceRecordType)> = self
+            .answers
+            .iter()
+            .chain(&self.additional)
+            .map(|record| (labels_to_string(&record.name).to_lowercase(), record.rtype))
+            .collect();
 
-        // Answers
-        for answer in &self.answers {
-            for label in &answer.name {
-                buffer.push(label.0.len() as u8);
-                buffer.extend_from_slice(label.0.as_bytes());
-            }
-            buffer.push(0); // End of NAME
-            buffer.write_u16::<NetworkEndian>(answer.rtype)?;
-            buffer.write_u16::<NetworkEndian>(answer.class)?;
-            buffer.write_u32::<NetworkEndian>(answer.ttl)?;
-            match &answer.rdata {
-                DnsRecordData::A(addr) => {
-                    buffer.write_u16::<NetworkEndian>(4)?; // RDATA length for IPv4 address
-                    buffer.write_u32::<NetworkEndian>(u32::from(*addr))?;
+        let mut pending: Vec<(Vec<DNSLabel>, ResourceRecordType, SvcbData)> = self
+            .answers
+            .iter()
+            .filter_map(|record| match &record.rdata {
+                DnsRecordData::SVCB(data) => {
+                    Some((record.name.clone(), ResourceRecordType::SVCB, data.clone()))
                 }
-                DnsRecordData::AAAA(addr) => {
-                    buffer.write_u16::<NetworkEndian>(16)?; // RDATA length for IPv6 address
-                    for segment in &addr.segments() {
-                        buffer.write_u16::<NetworkEndian>(*segment)?;
-                    }
+                DnsRecordData::HTTPS(data) => {
+                    Some((record.name.clone(), ResourceRecordType::HTTPS, data.clone()))
                 }
-                DnsRecordData::CNAME(cname) => {
-                    buffer.write_u16::<NetworkEndian>(cname.len() as u16)?; // RDATA length for CNAME
-                    buffer.write_all(cname.as_bytes())?;
+                _ => None,
+            })
+            .collect();
+
+        let mut followed = 0;
+        while let Some((owner, rtype, data)) = pending.pop() {
+            if data.target.is_empty() && data.is_alias() {
+                // AliasMode to the root name: the service is not available
+                continue;
+            }
+            let target = if data.target.is_empty() {
+                owner
+            } else {
+                match labels_from_str(&data.target) {
+                    Ok(target) => target,
+                    Err(_) => continue,
                 }
-                DnsRecordData::MX(preference, exchange) => {
-                    buffer.write_u16::<NetworkEndian>(2 + exchange.len() as u16)?; // RDATA length for MX
-                    buffer.write_u16::<NetworkEndian>(*preference)?;
-                    buffer.write_all(exchange.as_bytes())?;
+            };
+            let target_key = labels_to_string(&target).to_lowercase();
+
+            let wanted: &[ResourceRecordType] = if data.is_alias() {
+                &[rtype, ResourceRecordType::A, ResourceRecordType::AAAA]
+            } else {
+                &[ResourceRecordType::A, ResourceRecordType::AAAA]
+            };
+            for wanted_type in wanted {
+                if !seen.insert((target_key.clone(), *wanted_type)) {
+                    continue;
+                }
+                for record in lookup(&target, *wanted_type) {
+                    match &record.rdata {
+                        DnsRecordData::SVCB(next) | DnsRecordData::HTTPS(next)
+                            if followed < MAX_SVCB_CHAIN =>
+                        {
+                            followed += 1;
+                            pending.push((record.name.clone(), rtype, next.clone()));
+                        }
+                        _ => {}
+                    }
+                    self.additional.push(record);
                 }
-                DnsRecordData::NS(_) => todo!(),
-                DnsRecordData::MD(_) => todo!(),
-                DnsRecordData::MF(_) => todo!(),
-                DnsRecordData::MB(_) => todo!(),
-                DnsRecordData::MG(_) => todo!(),
-                DnsRecordData::MR(_) => todo!(),
-                DnsRecordData::PTR(_) => todo!(),
-                DnsRecordData::SOA {
-                    mname,
-                    rname,
-                    serial,
-                    refresh,
-                    retry,
-                    expire,
-                    minimum,
-                } => todo!(),
-                DnsRecordData::SRV {
-                    priority,
-                    weight,
-                    port,
-                    target,
-                } => todo!(),
-                DnsRecordData::TXT(_) => todo!(),
             }
         }
+    }
+}
+
+/// Writes a message, with the section counts of the header taken from the sections.
+pub(crate) fn write_message(
+    id: u16,
+    flags: u16,
+    questions: &[DNSQuestion],
+    sections: [&[DnsResourceRecord]; 3],
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut buffer = Vec::new();
+    // Header
+    buffer.write_u16::<NetworkEndian>(id)?;
+    buffer.write_u16::<NetworkEndian>(flags)?;
+    buffer.write_u16::<NetworkEndian>(questions.len() as u16)?;
+    for section in sections {
+        buffer.write_u16::<NetworkEndian>(section.len() as u16)?;
+    }
 
-        // For this simple example, we ignore the Authority and Additional sections
+    // Questions
+    for question in questions {
+        write_labels(&mut buffer, &question.qname);
+        buffer.write_u16::<NetworkEndian>(question.qtype.id())?;
+        buffer.write_u16::<NetworkEndian>(question.qclass.id())?;
+    }
 
-        Ok(buffer)
+    for record in sections.into_iter().flatten() {
+        record.write(&mut buffer)?;
+    }
+
+    Ok(buffer)
+}
+
+impl DnsResourceRecord {
+    /// Reads a resource record from a message.
+    pub fn read(cursor: &mut Cursor<&[u8]>) -> Result<Self, std::io::Error> {
+        let name = read_labels(cursor)?;
+        let rtype = ResourceRecordType::from(cursor.read_u16::<NetworkEndian>()?);
+        let class = DnsClass::from(cursor.read_u16::<NetworkEndian>()?);
+        let ttl = cursor.read_u32::<NetworkEndian>()?;
+        let rdlength = cursor.read_u16::<NetworkEndian>()?;
+        let rdata = DnsRecordData::read(rtype, cursor, rdlength)?;
+
+        Ok(DnsResourceRecord {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut Vec<u8>) -> Result<(), std::io::Error> {
+        write_labels(buffer, &self.name);
+        buffer.write_u16::<NetworkEndian>(self.rtype.id())?;
+        buffer.write_u16::<NetworkEndian>(self.class.id())?;
+        buffer.write_u32::<NetworkEndian>(self.ttl)?;
+
+        let mut rdata = Vec::new();
+        self.rdata.write(&mut rdata)?;
+        if rdata.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "RDATA too long",
+            ));
+        }
+        buffer.write_u16::<NetworkEndian>(rdata.len() as u16)?;
+        buffer.extend_from_slice(&rdata);
+        Ok(())
     }
 }
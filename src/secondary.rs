@@ -0,0 +1,360 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    authority::AuthoritativeHandler,
+    client::{exchange_transfer, transfer_query, DnsClient},
+    context::RequestContext,
+    journal::Transfer,
+    label::{labels_to_string, DNSLabel},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord},
+    tsig::TsigKey,
+    zone::Zone,
+};
+
+// How often to try to load a zone that has not been transferred yet
+const INITIAL_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// The shortest wait between refreshes, whatever the SOA record says, so that a zone
+// with a refresh or retry interval of 0 is not queried in a tight loop
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A zone this server copies from its primary and serves as a secondary (RFC 1034,
+/// section 4.3.5).
+///
+/// The zone checks the serial of the primary's SOA record every refresh interval of
+/// its SOA record, and when the primary sends a NOTIFY (RFC 1996). A newer version is
+/// transferred with IXFR, or AXFR for the first one. After a failed check, it tries
+/// again every retry interval, and once checks have failed for the expire interval,
+/// the zone is no longer served until a check succeeds again.
+pub struct SecondaryZone {
+    origin: Vec<DNSLabel>,
+    class: DnsClass,
+    primary: SocketAddr,
+    key: Option<TsigKey>,
+    timeout: Duration,
+    notify: Notify,
+    state: Mutex<SecondaryState>,
+}
+
+#[derive(Default)]
+struct SecondaryState {
+    // The SOA record of the last version loaded, whose timers apply
+    soa: Option<DnsResourceRecord>,
+    // When the primary last confirmed the version
+    refreshed: Option<Instant>,
+}
+
+impl SecondaryZone {
+    pub fn new(origin: Vec<DNSLabel>, primary: SocketAddr) -> Self {
+        SecondaryZone {
+            origin,
+            class: DnsClass::IN,
+            primary,
+            key: None,
+            timeout: DEFAULT_TIMEOUT,
+            notify: Notify::new(),
+            state: Mutex::new(SecondaryState::default()),
+        }
+    }
+
+    pub fn with_class(mut self, class: DnsClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    /// Signs the transfer requests with `key`, and accepts NOTIFY messages signed with
+    /// it from any address.
+    pub fn with_tsig_key(mut self, key: TsigKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// How long to wait for each reply from the primary
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn origin(&self) -> &[DNSLabel] {
+        &self.origin
+    }
+
+    pub fn primary(&self) -> SocketAddr {
+        self.primary
+    }
+
+    /// Whether a NOTIFY for the zone comes from its primary: from its address, or
+    /// signed with the zone's TSIG key
+    pub fn accepts_notify(&self, context: &RequestContext) -> bool {
+        let signed = match (&self.key, &context.tsig_key) {
+            (Some(key), Some(name)) => key.name() == name.as_slice(),
+            _ => false,
+        };
+        signed || context.client_addr.ip().to_canonical() == self.primary.ip().to_canonical()
+    }
+
+    /// Makes [`SecondaryZone::maintain`] check the primary right away.
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Checks the primary for a newer version of the zone and loads it into `handler`.
+    /// Returns how long to wait until the next check: the refresh interval after a
+    /// successful check and the retry interval after a failed one. A zone whose
+    /// primary has not confirmed it for the expire interval is removed from `handler`.
+    pub async fn refresh(&self, handler: &AuthoritativeHandler) -> Duration {
+        let result = self.update(handler).await;
+        let mut state = self.state.lock().unwrap();
+        if let Some(soa) = handler
+            .zone(&self.origin)
+            .and_then(|zone| zone.soa().cloned())
+        {
+            state.soa = Some(soa);
+        }
+        if result.is_ok() {
+            state.refreshed = Some(Instant::now());
+        }
+        let soa = state.soa.as_ref();
+        match result {
+            Ok(()) => timer(soa, |refresh, _, _| refresh),
+            Err(e) => {
+                let zone = labels_to_string(&self.origin);
+                warn!("Failed to refresh {}. from {}: {}", zone, self.primary, e);
+                let expire = timer(soa, |_, _, expire| expire);
+                let expired = state
+                    .refreshed
+                    .is_some_and(|refreshed| refreshed.elapsed() >= expire);
+                if expired && handler.remove_zone(&self.origin).is_some() {
+                    warn!("{}. has expired and is no longer served", zone);
+                }
+                timer(soa, |_, retry, _| retry)
+            }
+        }
+    }
+
+    /// Refreshes the zone whenever it is due or notified, see
+    /// [`SecondaryZone::refresh`]. Runs until the task is dropped.
+    pub async fn maintain(self: Arc<Self>, handler: Arc<AuthoritativeHandler>) {
+        loop {
+            let interval = self.refresh(&handler).await;
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = self.notify.notified() => {
+                    debug!("{}. was notified", labels_to_string(&self.origin));
+                }
+            }
+        }
+    }
+
+    // Transfers the zone if the primary has a newer version than `handler` serves
+    async fn update(&self, handler: &AuthoritativeHandler) -> io::Result<()> {
+        let current = handler.zone(&self.origin);
+        let client = DnsClient::new(self.primary)
+            .with_timeout(self.timeout)
+            .with_recursion_desired(false);
+        let query = client.build_query(&self.origin, ResourceRecordType::SOA, self.class);
+        let response = client.send(&query).await?;
+        if response.response_code() != 0 {
+            return Err(invalid(format!(
+                "SOA query failed with RCODE {}",
+                response.response_code()
+            )));
+        }
+        let serial = response
+            .answers
+            .iter()
+            .find(|record| record.name == self.origin)
+            .and_then(|record| match record.rdata {
+                DnsRecordData::SOA { serial, .. } => Some(serial),
+                _ => None,
+            })
+            .ok_or_else(|| invalid("the primary did not answer with the SOA record"))?;
+        if let Some(ours) = current.as_ref().and_then(|zone| zone.serial()) {
+            if serial.wrapping_sub(ours) as i32 <= 0 {
+                debug!(
+                    "{}. is up to date at serial {}",
+                    labels_to_string(&self.origin),
+                    ours
+                );
+                return Ok(());
+            }
+        }
+
+        let query = transfer_query(
+            &self.origin,
+            self.class,
+            current.as_ref().and_then(|zone| zone.soa()),
+        );
+        let records =
+            exchange_transfer(self.primary, &query, self.key.as_ref(), self.timeout).await?;
+        let zone = match (Transfer::from_records(records)?, current) {
+            (Transfer::Current(_), Some(_)) => return Ok(()),
+            (Transfer::Full(records), _) => Zone::from_records(self.origin.clone(), records)?,
+            (Transfer::Incremental(diffs), Some(current)) => {
+                let mut zone = Zone::clone(&current);
+                for diff in &diffs {
+                    diff.apply(&mut zone)?;
+                }
+                zone
+            }
+            _ => return Err(invalid("the primary did not send the zone")),
+        };
+        info!(
+            "Transferred {}. with serial {} from {}",
+            labels_to_string(&self.origin),
+            zone.serial().unwrap_or_default(),
+            self.primary
+        );
+        handler.add_zone(zone);
+        Ok(())
+    }
+}
+
+// One of the refresh, retry and expire intervals of `soa`, but at least `MIN_INTERVAL`,
+// or the initial retry interval without one
+fn timer(soa: Option<&DnsResourceRecord>, pick: impl Fn(u32, u32, u32) -> u32) -> Duration {
+    match soa.map(|soa| &soa.rdata) {
+        Some(DnsRecordData::SOA {
+            refresh,
+            retry,
+            expire,
+            ..
+        }) => Duration::from_secs(pick(*refresh, *retry, *expire) as u64).max(MIN_INTERVAL),
+        _ => INITIAL_RETRY_INTERVAL,
+    }
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::{
+        acl::Acl,
+        context::Transport,
+        handler::{DnsRequestError, DnsRequestHandler},
+        journal::Journal,
+        label::labels_from_str,
+        request::{DNSRequest, OPCODE_NOTIFY},
+        server::DnsServer,
+    };
+
+    const EXAMPLE_COM: &str = "
+$TTL 3600
+@    SOA ns hostmaster 1 3600 1 1 300
+     NS  ns
+ns   A   192.0.2.53
+";
+
+    #[test]
+    fn test_timers_have_a_minimum() {
+        let zone = Zone::parse("@ 60 SOA ns hostmaster 1 0 0 300 60\n", "example.com.").unwrap();
+        assert_eq!(timer(zone.soa(), |refresh, _, _| refresh), MIN_INTERVAL);
+        assert_eq!(timer(zone.soa(), |_, retry, _| retry), MIN_INTERVAL);
+        assert_eq!(
+            timer(zone.soa(), |_, _, expire| expire),
+            Duration::from_secs(300)
+        );
+        assert_eq!(timer(None, |refresh, _, _| refresh), INITIAL_RETRY_INTERVAL);
+    }
+
+    fn notify(client: &str) -> (DNSRequest, RequestContext) {
+        let mut request = DNSRequest::query("Example.com", ResourceRecordType::SOA, DnsClass::IN);
+        request.header.flags = (OPCODE_NOTIFY as u16) << 11 | 0x0400;
+        let context = RequestContext::new(
+            client.parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+            vec![],
+        );
+        (request, context)
+    }
+
+    #[tokio::test]
+    async fn test_follows_the_primary() {
+        let origin = labels_from_str("example.com").unwrap();
+        let primary = AuthoritativeHandler::new()
+            .with_zone(Zone::version(EXAMPLE_COM, 1, ""))
+            .with_journal(&origin, Journal::new())
+            .with_transfer_acl(
+                &origin,
+                Acl::new().with_address(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            );
+        let server = Arc::new(DnsServer::new(primary));
+        let primary = server.handler();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = tokio::net::UdpSocket::bind(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp_server = server.clone();
+        let tcp_task = tokio::spawn(async move { tcp_server.serve_tcp(listener).await });
+        let udp_task = tokio::spawn(async move { server.serve(socket).await });
+
+        let handler = Arc::new(AuthoritativeHandler::new().with_secondary(
+            SecondaryZone::new(origin.clone(), addr).with_timeout(Duration::from_millis(300)),
+        ));
+        let secondary = handler.secondary(&origin).unwrap();
+        assert_eq!(secondary.refresh(&handler).await, Duration::from_secs(3600));
+        assert_eq!(handler.zone(&origin).unwrap().serial(), Some(1));
+
+        // A NOTIFY from the primary makes the zone catch up
+        let maintain_task = tokio::spawn(handler.clone().maintain_secondaries());
+        let v2 = Zone::version(EXAMPLE_COM, 2, "www A 192.0.2.1\n");
+        primary.add_zone(v2.clone());
+        let (request, context) = notify("127.0.0.1:5300");
+        let response = handler
+            .clone()
+            .handle_request(request, context)
+            .await
+            .unwrap();
+        assert_eq!(response.header.flags & 0x7c0f, 0x2400);
+        for _ in 0..100 {
+            if handler.zone(&origin).unwrap().serial() == Some(2) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            handler.zone(&origin).unwrap().to_master_file(),
+            v2.to_master_file()
+        );
+
+        // From anyone else, and for other zones, it is refused
+        let (request, context) = notify("192.0.2.1:5300");
+        let result = handler.clone().handle_request(request, context).await;
+        assert_eq!(result.unwrap_err(), DnsRequestError::Refused);
+        let (mut request, context) = notify("127.0.0.1:5300");
+        request.questions[0].qname = labels_from_str("example.net").unwrap();
+        let result = handler.clone().handle_request(request, context).await;
+        assert_eq!(result.unwrap_err(), DnsRequestError::NotAuth);
+
+        // Without the primary, the zone expires after a second
+        maintain_task.abort();
+        tcp_task.abort();
+        udp_task.abort();
+        assert_eq!(secondary.refresh(&handler).await, Duration::from_secs(1));
+        assert!(handler.zone(&origin).is_some());
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_millis(1100)).await;
+        assert_eq!(secondary.refresh(&handler).await, Duration::from_secs(1));
+        assert!(handler.zone(&origin).is_none());
+    }
+}
@@ -1,162 +1,490 @@
-use log::{error, info, warn};
-use tokio::{net::UdpSocket, runtime::Runtime, sync::mpsc};
+use log::{debug, error, info};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+};
 
-use crate::{handler::DnsRequestHandler, request::DNSRequest};
-use futures::task::{Spawn, SpawnExt};
-use std::{io, net::SocketAddr, sync::Arc};
+use crate::{
+    client::{read_tcp_message, write_tcp_message},
+    context::{RequestContext, Transport},
+    edns::EdnsOption,
+    handler::{DnsRequestError, DnsRequestHandler},
+    request::DNSRequest,
+    resourcerecord::ResourceRecordType,
+    response::DnsResponse,
+    tsig::{verify_request, TsigKey},
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+// How long a TCP connection may sit idle between requests (RFC 7766, section 6.2.3)
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// Upper bound on the messages of a zone transfer, leaving room for a TSIG record
+const MAX_TRANSFER_MESSAGE: usize = u16::MAX as usize - 512;
+// The size of UDP responses to clients without EDNS (RFC 1035, section 4.2.1)
+const MAX_UDP_MESSAGE: usize = 512;
 
 pub struct DnsServer<H: DnsRequestHandler> {
     handler: Arc<H>,
+    nsid: Option<Arc<[u8]>>,
+    tsig_keys: Arc<[TsigKey]>,
 }
 
 impl<H: DnsRequestHandler> DnsServer<H> {
     pub fn new(handler: H) -> DnsServer<H> {
         DnsServer {
             handler: Arc::new(handler),
+            nsid: None,
+            tsig_keys: Arc::new([]),
         }
     }
 
+    pub fn handler(&self) -> Arc<H> {
+        self.handler.clone()
+    }
+
+    /// Sends `nsid` to clients that ask for the server's identifier with an empty NSID
+    /// option (RFC 5001), whichever handler produced the response.
+    pub fn with_nsid(mut self, nsid: impl Into<Vec<u8>>) -> Self {
+        self.nsid = Some(nsid.into().into());
+        self
+    }
+
+    /// Verifies requests signed with `key` and signs their responses (RFC 8945). The
+    /// handler learns the key from [`RequestContext::tsig_key`]; requests that fail
+    /// verification are answered with NOTAUTH and do not reach it.
+    pub fn with_tsig_key(mut self, key: TsigKey) -> Self {
+        let mut keys = self.tsig_keys.to_vec();
+        keys.push(key);
+        self.tsig_keys = keys.into();
+        self
+    }
+
     pub async fn run(&self) -> tokio::io::Result<()> {
         let socket = UdpSocket::bind("0.0.0.0:54").await?;
+        let listener = TcpListener::bind("0.0.0.0:54").await?;
+        tokio::try_join!(self.serve(socket), self.serve_tcp(listener))?;
+        Ok(())
+    }
+
+    /// Answers the requests arriving on an already bound socket.
+    pub async fn serve(&self, socket: UdpSocket) -> tokio::io::Result<()> {
+        let local_addr = socket.local_addr()?;
         let r = Arc::new(socket);
         let s = r.clone();
         let (tx, mut rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(1_000);
 
         let handler = self.handler.clone();
+        let nsid = self.nsid.clone();
+        let tsig_keys = self.tsig_keys.clone();
         tokio::spawn(async move {
             while let Some((buf_data, peer_address)) = rx.recv().await {
-                let dns_request = DNSRequest::parse(&buf_data);
-                match dns_request {
-                    Ok(dns_request) => {
-                        info!("Received DNS request: {:?}", dns_request);
-
-                        let handler_clone = handler.clone();
-                        let handler_response = handler_clone.handle_request(dns_request).await;
-
-                        match handler_response {
-                            Ok(response) => {
-                                info!("Sending DNS response: {:?}", response);
-                                let response_bytes = response.to_bytes();
-                                match response_bytes {
-                                    Ok(response_bytes) => {
-                                        s.send_to(&response_bytes[..], peer_address)
-                                            .await
-                                            .expect("Failed to send response");
-                                    }
-                                    Err(e) => {
-                                        error!("Error converting response to bytes: {:?}", e);
-                                    }
-                                }
-                            }
-                            Err(e) => error!("Error handling request: {:?}", e),
+                let handler = handler.clone();
+                let nsid = nsid.clone();
+                let tsig_keys = tsig_keys.clone();
+                let s = s.clone();
+                // Requests are answered concurrently, so a slow handler only delays its
+                // own client
+                tokio::spawn(async move {
+                    let context =
+                        RequestContext::new(peer_address, local_addr, Transport::Udp, buf_data);
+                    let messages = respond(handler, nsid.as_deref(), &tsig_keys, context).await;
+                    for message in messages {
+                        if let Err(e) = s.send_to(&message[..], peer_address).await {
+                            error!("Error sending response to {}: {}", peer_address, e);
                         }
                     }
-                    Err(e) => {
-                        info!("Error parsing DNS request: {:?}", e);
-                    }
-                }
+                });
             }
         });
 
         let mut buf = [0; 1 << 16];
         loop {
             let (len, addr) = r.recv_from(&mut buf).await?;
-            println!("{:?} bytes received from {:?}", len, addr);
+            debug!("{} bytes received from {}", len, addr);
             tx.send((buf[..len].to_vec(), addr)).await.unwrap();
         }
+    }
 
-        Ok(())
+    /// Answers the requests arriving over TCP connections to an already bound listener.
+    /// Each connection may carry several requests, which are answered in turn.
+    pub async fn serve_tcp(&self, listener: TcpListener) -> tokio::io::Result<()> {
+        let local_addr = listener.local_addr()?;
+        loop {
+            let (stream, peer_address) = listener.accept().await?;
+            let handler = self.handler.clone();
+            let nsid = self.nsid.clone();
+            let tsig_keys = self.tsig_keys.clone();
+            tokio::spawn(async move {
+                let served = serve_connection(
+                    stream,
+                    peer_address,
+                    local_addr,
+                    handler,
+                    nsid.as_deref(),
+                    &tsig_keys,
+                )
+                .await;
+                if let Err(e) = served {
+                    debug!("TCP connection from {} failed: {}", peer_address, e);
+                }
+            });
+        }
+    }
+}
+
+async fn serve_connection<H: DnsRequestHandler>(
+    mut stream: TcpStream,
+    peer_address: SocketAddr,
+    local_addr: SocketAddr,
+    handler: Arc<H>,
+    nsid: Option<&[u8]>,
+    tsig_keys: &[TsigKey],
+) -> tokio::io::Result<()> {
+    loop {
+        let message =
+            match tokio::time::timeout(TCP_IDLE_TIMEOUT, read_tcp_message(&mut stream)).await {
+                Ok(Ok(message)) => message,
+                // The client closed the connection or kept it idle for too long
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            };
+        let context = RequestContext::new(peer_address, local_addr, Transport::Tcp, message);
+        for message in respond(handler.clone(), nsid, tsig_keys, context).await {
+            write_tcp_message(&mut stream, &message).await?;
+        }
     }
+}
 
-    /*pub async fn run(&self, executor: &mut dyn Spawn) -> io::Result<()> {
-        let mut poll = Poll::new()?;
+// Answers the request in `context` with messages in wire format: one, or several for a
+// zone transfer over TCP, each signed if the request was. Requests that cannot be parsed
+// are not answered.
+async fn respond<H: DnsRequestHandler>(
+    handler: Arc<H>,
+    nsid: Option<&[u8]>,
+    tsig_keys: &[TsigKey],
+    mut context: RequestContext,
+) -> Vec<Vec<u8>> {
+    let request = match DNSRequest::parse(&context.raw) {
+        Ok(request) => request,
+        Err(e) => {
+            info!("Error parsing DNS request: {:?}", e);
+            return vec![];
+        }
+    };
 
-        // Create storage for events. Since we will only register a single socket, a
-        // capacity of 1 will do.
-        let mut events = Events::with_capacity(1);
-        let addr = "0.0.0.0:54".parse().unwrap();
-        let mut socket = UdpSocket::bind(addr)?;
+    // Requests rejected before they reach the handler still get the server's NSID
+    let error_for = |error: &DnsRequestError| {
+        let mut response = DnsResponse::error_for(&request, error);
+        if let Some(nsid) = nsid {
+            add_nsid(&request, &mut response, nsid);
+        }
+        response
+    };
+    let mut tsig = match verify_request(tsig_keys, &context.raw) {
+        Ok(tsig) => tsig,
+        Err(e) => {
+            info!("Malformed TSIG record in request #{}", context.id);
+            return encode(vec![error_for(&e)]);
+        }
+    };
+    let transport = context.transport;
+    let mut responses = match &tsig {
+        Some(session) if session.error().is_some() => {
+            info!(
+                "TSIG verification of request #{} failed: {:?}",
+                context.id,
+                session.error()
+            );
+            vec![error_for(&DnsRequestError::NotAuth)]
+        }
+        _ => {
+            context.tsig_key = tsig.as_ref().map(|session| session.key().name().to_vec());
+            let response = process_request(handler, nsid, request.clone(), context).await;
+            match is_transfer(&request) && transport != Transport::Udp {
+                true => split_transfer(response),
+                false => vec![response],
+            }
+        }
+    };
 
-        // Register our socket with the token defined above and an interest in being
-        // `READABLE`.
-        poll.registry()
-            .register(&mut socket, UDP_SOCKET, Interest::READABLE)?;
+    if transport == Transport::Udp {
+        let limit = udp_limit(&request);
+        for response in &mut responses {
+            truncate(response, limit);
+        }
+    }
 
-        // Initialize a buffer for the UDP packet. We use the maximum size of a UDP
-        // packet, which is the maximum value of 16 a bit integer.
-        let mut buf = [0; 1 << 16];
+    let mut messages = encode(responses);
+    if let Some(session) = &mut tsig {
+        for message in &mut messages {
+            if let Err(e) = session.sign(message) {
+                error!("Error signing response: {:?}", e);
+            }
+        }
+    }
+    messages
+}
 
-        loop {
-            // Poll to check if we have events waiting for us.
-            poll.poll(&mut events, None)?;
-
-            // Process each event.
-            for event in events.iter() {
-                // Validate the token we registered our socket with,
-                // in this example it will only ever be one but we
-                // make sure it's valid none the less.
-                match event.token() {
-                    UDP_SOCKET => loop {
-                        // In this loop we receive all packets queued for the socket.
-                        match socket.recv_from(&mut buf) {
-                            Ok((packet_size, source_address)) => {
-                                let buf_data = &buf[..packet_size];
-                                let dns_request = DNSRequest::parse(buf_data);
-
-                                match dns_request {
-                                    Ok(dns_request) => {
-                                        info!("Received DNS request: {:?}", dns_request);
-                                        let handler_response =
-                                            self.handler.handle_request(&dns_request).await;
-
-                                        match handler_response {
-                                            Ok(response) => {
-                                                info!("Sending DNS response: {:?}", response);
-                                                let response_bytes = response.to_bytes();
-                                                match response_bytes {
-                                                    Ok(response_bytes) => {
-                                                        socket.send_to(
-                                                            &response_bytes[..],
-                                                            source_address,
-                                                        )?;
-                                                    }
-                                                    Err(e) => {
-                                                        error!("Error converting response to bytes: {:?}", e);
-                                                    }
-                                                }
-                                            }
-                                            Err(e) => error!("Error handling request: {:?}", e),
-                                        }
-                                    }
-                                    Err(e) => {
-                                        info!("Error parsing DNS request: {:?}", e);
-                                    }
-                                }
-                                println!("Received packet from: {}", source_address);
-
-                                socket.send_to(&buf[..packet_size], source_address)?;
-                            }
-                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                // If we get a `WouldBlock` error we know our socket
-                                // has no more packets queued, so we can return to
-                                // polling and wait for some more.
-                                break;
-                            }
-                            Err(e) => {
-                                // If it was any other kind of error, something went
-                                // wrong and we terminate with an error.
-                                return Err(e);
-                            }
-                        }
-                    },
-                    _ => {
-                        // This should never happen as we only registered our
-                        // `UdpSocket` using the `UDP_SOCKET` token, but if it ever
-                        // does we'll log it.
-                        warn!("Got event for unexpected token: {:?}", event);
-                    }
-                }
+fn encode(responses: Vec<DnsResponse>) -> Vec<Vec<u8>> {
+    responses
+        .iter()
+        .filter_map(|response| match response.to_bytes() {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                error!("Error converting response to bytes: {:?}", e);
+                None
             }
+        })
+        .collect()
+}
+
+// The largest UDP response the client accepts (RFC 6891, section 6.2.5)
+fn udp_limit(request: &DNSRequest) -> usize {
+    request.edns().map_or(MAX_UDP_MESSAGE, |edns| {
+        (edns.udp_payload_size as usize).max(MAX_UDP_MESSAGE)
+    })
+}
+
+// Fits a response into `limit` bytes: first the additional section goes, except for the
+// OPT record, then the answers, with the TC bit telling the client to retry over TCP
+// (RFC 2181, section 9)
+fn truncate(response: &mut DnsResponse, limit: usize) {
+    let fits = |response: &DnsResponse| response.to_bytes().is_ok_and(|bytes| bytes.len() <= limit);
+    if fits(response) {
+        return;
+    }
+    response
+        .additional
+        .retain(|record| record.rtype == ResourceRecordType::OPT);
+    if fits(response) {
+        return;
+    }
+    response.header.flags |= 0x0200;
+    response.answers.clear();
+    response.authority.clear();
+}
+
+fn is_transfer(request: &DNSRequest) -> bool {
+    request.questions.iter().any(|question| {
+        matches!(
+            question.qtype,
+            ResourceRecordType::AXFR | ResourceRecordType::IXFR
+        )
+    })
+}
+
+// Spreads the answers of a zone transfer over as many messages as they need. Only the
+// first message repeats the question (RFC 5936, section 2.2).
+fn split_transfer(response: DnsResponse) -> Vec<DnsResponse> {
+    let mut messages: Vec<DnsResponse> = vec![];
+    let mut current = DnsResponse {
+        answers: vec![],
+        ..response.clone()
+    };
+    let mut size = current.to_bytes().map_or(0, |bytes| bytes.len());
+    for record in response.answers {
+        let mut buffer = Vec::new();
+        let record_size = match record.write(&mut buffer) {
+            Ok(()) => buffer.len(),
+            Err(_) => 0,
+        };
+        if size + record_size > MAX_TRANSFER_MESSAGE && !current.answers.is_empty() {
+            let mut next = DnsResponse {
+                questions: vec![],
+                answers: vec![],
+                authority: vec![],
+                additional: vec![],
+                ..current.clone()
+            };
+            next.header.qdcount = 0;
+            size = next.to_bytes().map_or(0, |bytes| bytes.len());
+            messages.push(std::mem::replace(&mut current, next));
+        }
+        size += record_size;
+        current.answers.push(record);
+    }
+    messages.push(current);
+    messages
+}
+
+// Runs a request through the handler and applies the server-wide response options
+async fn process_request<H: DnsRequestHandler>(
+    handler: Arc<H>,
+    nsid: Option<&[u8]>,
+    request: DNSRequest,
+    context: RequestContext,
+) -> DnsResponse {
+    info!(
+        "Received DNS request #{} from {}: {:?}",
+        context.id, context.client_addr, request
+    );
+    let request_id = context.id;
+
+    let mut response = match handler.handle_request(request.clone(), context).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Error handling request #{}: {:?}", request_id, e);
+            DnsResponse::error_for(&request, &e)
+        }
+    };
+    if let Some(nsid) = nsid {
+        add_nsid(&request, &mut response, nsid);
+    }
+
+    info!("Sending DNS response #{}: {:?}", request_id, response);
+    response
+}
+
+// Answers an NSID request with `nsid`, adding an OPT record if the handler did not
+fn add_nsid(request: &DNSRequest, response: &mut DnsResponse, nsid: &[u8]) {
+    if !request.edns().is_some_and(|edns| edns.requests_nsid()) {
+        return;
+    }
+
+    let mut edns = response.edns().unwrap_or_default();
+    edns.set_option(EdnsOption::Nsid(nsid.to_vec()));
+    response.set_edns(edns);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        authority::AuthoritativeHandler,
+        edns::Edns,
+        label::labels_from_str,
+        resourcerecord::DnsClass,
+        response::{DnsRecordData, DnsResourceRecord},
+        tsig::{TsigAlgorithm, TsigSession},
+    };
+
+    fn request_with_options(options: Option<Vec<EdnsOption>>) -> DNSRequest {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        if let Some(options) = options {
+            packet[11] = 1;
+            let edns = Edns {
+                options,
+                ..Edns::default()
+            };
+            edns.to_record().write(&mut packet).unwrap();
+        }
+        DNSRequest::parse(&packet).unwrap()
+    }
+
+    #[test]
+    fn test_nsid_added_when_requested() {
+        let request = request_with_options(Some(vec![EdnsOption::Nsid(vec![])]));
+        let mut response = DnsResponse::reply_to(&request);
+        add_nsid(&request, &mut response, b"fra-1");
+
+        let edns = response.edns().unwrap();
+        assert_eq!(edns.options, vec![EdnsOption::Nsid(b"fra-1".to_vec())]);
+        assert_eq!(response.additional.len(), 1);
+    }
+
+    #[test]
+    fn test_nsid_not_added_unless_requested() {
+        for request in [
+            request_with_options(None),
+            request_with_options(Some(vec![])),
+        ] {
+            let mut response = DnsResponse::reply_to(&request);
+            add_nsid(&request, &mut response, b"fra-1");
+            assert!(response.edns().is_none());
+        }
+    }
+
+    fn txt_record(request: &DNSRequest) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: request.questions[0].qname.clone(),
+            rtype: ResourceRecordType::TXT,
+            class: DnsClass::IN,
+            ttl: 3600,
+            rdata: DnsRecordData::TXT(vec![vec![b'x'; 200]]),
+        }
+    }
+
+    #[test]
+    fn test_udp_responses_are_truncated() {
+        // Without EDNS, three answers exceed 512 bytes
+        let request = request_with_options(None);
+        let mut response = DnsResponse::reply_to(&request);
+        response.answers = vec![txt_record(&request); 3];
+        truncate(&mut response, udp_limit(&request));
+        assert_ne!(response.header.flags & 0x0200, 0);
+        assert!(response.answers.is_empty());
+        assert!(response.to_bytes().unwrap().len() <= 512);
+
+        // With EDNS they fit once the additional section is gone, but for the OPT record
+        let request = request_with_options(Some(vec![]));
+        let mut response = DnsResponse::reply_to(&request);
+        response.answers = vec![txt_record(&request); 3];
+        response.additional = vec![txt_record(&request); 3];
+        response.set_edns(Edns::default());
+        truncate(&mut response, udp_limit(&request));
+        assert_eq!(response.header.flags & 0x0200, 0);
+        assert_eq!(response.answers.len(), 3);
+        assert_eq!(response.additional.len(), 1);
+        assert!(response.edns().is_some());
+
+        // Responses that fit are left alone
+        let mut response = DnsResponse::reply_to(&request);
+        response.answers = vec![txt_record(&request)];
+        response.additional = vec![txt_record(&request)];
+        truncate(&mut response, udp_limit(&request));
+        assert_eq!(response.additional.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_nsid_added_to_tsig_failures() {
+        let key = |secret: &str| {
+            TsigKey::new(
+                labels_from_str("key.example").unwrap(),
+                TsigAlgorithm::HmacSha256,
+                secret,
+            )
+        };
+        let request = request_with_options(Some(vec![EdnsOption::Nsid(vec![])]));
+        let mut message = request.to_bytes().unwrap();
+        TsigSession::new(key("other")).sign(&mut message).unwrap();
+
+        let context = RequestContext::new(
+            "127.0.0.1:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+            message,
+        );
+        let handler = Arc::new(AuthoritativeHandler::new());
+        let messages = respond(handler, Some(b"fra-1"), &[key("secret")], context).await;
+        let response = DnsResponse::parse(&messages[0]).unwrap();
+        assert_eq!(
+            response.response_code(),
+            DnsRequestError::NotAuth.to_response_code()
+        );
+        assert_eq!(
+            response.edns().unwrap().options,
+            vec![EdnsOption::Nsid(b"fra-1".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_transfers_are_split_into_messages() {
+        let request = request_with_options(None);
+        let mut response = DnsResponse::reply_to(&request);
+        response.answers = vec![txt_record(&request); 1000];
+
+        let messages = split_transfer(response);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].questions.len(), 1);
+        assert!(messages[1..]
+            .iter()
+            .all(|message| message.questions.is_empty()));
+        let total: usize = messages.iter().map(|message| message.answers.len()).sum();
+        assert_eq!(total, 1000);
+        for message in messages {
+            assert!(message.to_bytes().unwrap().len() <= MAX_TRANSFER_MESSAGE);
         }
-    }*/
+    }
 }
@@ -0,0 +1,611 @@
+use std::{collections::BTreeMap, io, num::NonZeroUsize, path::Path, sync::Mutex, time::Duration};
+
+use lru::LruCache;
+
+use crate::{
+    dnssec::{self, base32hex_encode, nsec3_hash, SigningKey, NSEC3_HASH_SHA1, NSEC3_OPT_OUT},
+    label::{is_subdomain, labels_to_string, CanonicalName, DNSLabel},
+    resourcerecord::ResourceRecordType,
+    response::{DnsRecordData, DnsResourceRecord},
+    zone::Zone,
+};
+
+/// How a signed zone proves that names and types do not exist
+#[derive(Debug, Clone, PartialEq)]
+pub enum Denial {
+    /// A chain of NSEC records between the names of the zone (RFC 4034)
+    Nsec,
+    /// A chain of NSEC3 records between hashed names (RFC 5155). With `opt_out`, the
+    /// chain leaves out unsigned delegations and flags every span as possibly
+    /// containing some.
+    Nsec3 {
+        iterations: u16,
+        salt: Vec<u8>,
+        opt_out: bool,
+    },
+}
+
+/// Signs `zone` with `keys`: adds the DNSKEY records of the keys and an NSEC or NSEC3
+/// chain, and signs every RRset the zone is authoritative for. Given both key-signing
+/// and zone-signing keys, the former sign the DNSKEY, CDS and CDNSKEY RRsets and the
+/// latter everything else; otherwise every key signs every RRset. The signatures are valid from
+/// `inception` to `expiration`.
+///
+/// DNSSEC records already in the zone are replaced, apart from DNSKEY records, so that
+/// keys published ahead of a rollover stay in place.
+pub fn sign_zone(
+    zone: &Zone,
+    keys: &[SigningKey],
+    denial: &Denial,
+    inception: u32,
+    expiration: u32,
+) -> io::Result<Zone> {
+    let origin = zone.origin().to_vec();
+    let soa = zone
+        .soa()
+        .ok_or_else(|| invalid("zone has no SOA record"))?;
+    let negative_ttl = match soa.rdata {
+        DnsRecordData::SOA { minimum, .. } => soa.ttl.min(minimum),
+        _ => soa.ttl,
+    };
+
+    let mut records: Vec<DnsResourceRecord> = zone
+        .records()
+        .filter(|record| {
+            !matches!(
+                record.rtype,
+                ResourceRecordType::RRSIG
+                    | ResourceRecordType::NSEC
+                    | ResourceRecordType::NSEC3
+                    | ResourceRecordType::NSEC3PARAM
+            )
+        })
+        .cloned()
+        .collect();
+    for key in keys {
+        let dnskey = key.dnskey_record(&origin, soa.ttl);
+        if !records.iter().any(|record| {
+            record.name == origin && record.rtype == dnskey.rtype && record.rdata == dnskey.rdata
+        }) {
+            records.push(dnskey);
+        }
+    }
+    if let Denial::Nsec3 {
+        iterations, salt, ..
+    } = denial
+    {
+        records.push(DnsResourceRecord {
+            name: origin.clone(),
+            rtype: ResourceRecordType::NSEC3PARAM,
+            class: soa.class,
+            ttl: 0,
+            rdata: DnsRecordData::NSEC3PARAM {
+                hash_algorithm: NSEC3_HASH_SHA1,
+                flags: 0,
+                iterations: *iterations,
+                salt: salt.clone(),
+            },
+        });
+    }
+
+    // The types at each name the zone is authoritative for, leaving out glue
+    let cuts: Vec<Vec<DNSLabel>> = records
+        .iter()
+        .filter(|record| record.rtype == ResourceRecordType::NS && record.name != origin)
+        .map(|record| record.name.clone())
+        .collect();
+    let below_cut = |name: &[DNSLabel]| {
+        cuts.iter()
+            .any(|cut| name.len() > cut.len() && is_subdomain(name, cut))
+    };
+    let mut nodes: BTreeMap<CanonicalName, Vec<ResourceRecordType>> = BTreeMap::new();
+    for record in &records {
+        if !below_cut(&record.name) {
+            let types = nodes.entry(CanonicalName(record.name.clone())).or_default();
+            if !types.contains(&record.rtype) {
+                types.push(record.rtype);
+            }
+        }
+    }
+
+    match denial {
+        Denial::Nsec => {
+            let names: Vec<&CanonicalName> = nodes.keys().collect();
+            for (index, name) in names.iter().enumerate() {
+                let next = names.get(index + 1).map_or(&origin, |next| &next.0);
+                let mut types = nodes[name].clone();
+                types.extend([ResourceRecordType::RRSIG, ResourceRecordType::NSEC]);
+                records.push(DnsResourceRecord {
+                    name: name.0.clone(),
+                    rtype: ResourceRecordType::NSEC,
+                    class: soa.class,
+                    ttl: negative_ttl,
+                    rdata: DnsRecordData::NSEC {
+                        next_domain: labels_to_string(next),
+                        types,
+                    },
+                });
+            }
+        }
+        Denial::Nsec3 {
+            iterations,
+            salt,
+            opt_out,
+        } => {
+            // Empty non-terminals get NSEC3 records of their own, unless they only lead
+            // to delegations that are opted out
+            let mut hashed: BTreeMap<Vec<u8>, Vec<ResourceRecordType>> = BTreeMap::new();
+            for (name, types) in &nodes {
+                let unsigned_delegation = name.0 != origin
+                    && types.contains(&ResourceRecordType::NS)
+                    && !types.contains(&ResourceRecordType::DS);
+                if unsigned_delegation && *opt_out {
+                    continue;
+                }
+                for depth in origin.len()..name.0.len() {
+                    let ancestor = &name.0[name.0.len() - depth..];
+                    hashed
+                        .entry(nsec3_hash(ancestor, salt, *iterations))
+                        .or_default();
+                }
+                let mut types = types.clone();
+                if !unsigned_delegation {
+                    types.push(ResourceRecordType::RRSIG);
+                }
+                hashed.insert(nsec3_hash(&name.0, salt, *iterations), types);
+            }
+
+            let hashes: Vec<&Vec<u8>> = hashed.keys().collect();
+            for (index, hash) in hashes.iter().enumerate() {
+                let next = hashes[(index + 1) % hashes.len()];
+                let mut name = vec![DNSLabel::new(&base32hex_encode(hash)).unwrap()];
+                name.extend_from_slice(&origin);
+                records.push(DnsResourceRecord {
+                    name,
+                    rtype: ResourceRecordType::NSEC3,
+                    class: soa.class,
+                    ttl: negative_ttl,
+                    rdata: DnsRecordData::NSEC3 {
+                        hash_algorithm: NSEC3_HASH_SHA1,
+                        flags: if *opt_out { NSEC3_OPT_OUT } else { 0 },
+                        iterations: *iterations,
+                        salt: salt.clone(),
+                        next_hashed: next.clone(),
+                        types: hashed[*hash].clone(),
+                    },
+                });
+            }
+        }
+    }
+
+    // Every authoritative RRset but the NS records at delegations is signed
+    let mut rrsets: BTreeMap<(CanonicalName, u16), Vec<DnsResourceRecord>> = BTreeMap::new();
+    for record in &records {
+        let delegation = record.name != origin && cuts.contains(&record.name);
+        if below_cut(&record.name)
+            || (delegation
+                && record.rtype != ResourceRecordType::DS
+                && record.rtype != ResourceRecordType::NSEC)
+        {
+            continue;
+        }
+        rrsets
+            .entry((CanonicalName(record.name.clone()), record.rtype.id()))
+            .or_default()
+            .push(record.clone());
+    }
+    for ((_, rtype), rrset) in &rrsets {
+        for key in signing_keys(keys, ResourceRecordType::from(*rtype)) {
+            records.push(key.sign_rrset(rrset, &origin, inception, expiration)?);
+        }
+    }
+
+    Zone::from_records(origin, records)
+}
+
+/// The DS records for the parent of zone `origin`: one per key-signing key, or per key
+/// if there are none, with a digest of type `digest_type`.
+pub fn ds_records(
+    origin: &[DNSLabel],
+    keys: &[SigningKey],
+    ttl: u32,
+    digest_type: u8,
+) -> Vec<DnsResourceRecord> {
+    let key_signing = keys.iter().any(SigningKey::is_key_signing);
+    keys.iter()
+        .filter(|key| !key_signing || key.is_key_signing())
+        .filter_map(|key| dnssec::ds_record(&key.dnskey_record(origin, ttl), digest_type))
+        .collect()
+}
+
+// How long online signatures are valid by default
+const DEFAULT_VALIDITY: Duration = Duration::from_secs(7 * 86400);
+// Online signatures start this long before they are made, for clocks that lag behind
+const INCEPTION_OFFSET: u32 = 3600;
+const DEFAULT_SIGNATURE_CACHE_SIZE: usize = 10_000;
+
+/// How an online signer proves that names and types do not exist
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnlineDenial {
+    /// NSEC records that cover nothing but the name in question ("white lies", RFC 4470)
+    Nsec,
+    /// NSEC3 records whose hashes lie just around the hash of the name in question
+    Nsec3 { iterations: u16, salt: Vec<u8> },
+    /// Compact denial of existence (RFC 9824): a name that does not exist gets an NSEC
+    /// record of its own, as if it existed without any records, and a NOERROR RCODE.
+    Compact,
+}
+
+/// Signs the records of a zone as they are served, with the keys of the zone. The
+/// zone itself stays unsigned: the signatures of its RRsets are made when they are
+/// first asked for and cached until they come close to expiring, and the NSEC or
+/// NSEC3 records of negative answers are made up for each answer.
+pub struct OnlineSigner {
+    keys: Vec<SigningKey>,
+    // Apex records published besides the DNSKEY records of the keys
+    published: Vec<DnsResourceRecord>,
+    denial: OnlineDenial,
+    validity: u32,
+    // Signatures by owner and type, along with the RRset they sign
+    cache: Mutex<LruCache<(Vec<DNSLabel>, ResourceRecordType), CachedSignatures>>,
+}
+
+struct CachedSignatures {
+    rrset: Vec<DnsResourceRecord>,
+    rrsigs: Vec<DnsResourceRecord>,
+    expiration: u32,
+}
+
+impl OnlineSigner {
+    pub fn new(keys: Vec<SigningKey>, denial: OnlineDenial) -> Self {
+        OnlineSigner {
+            keys,
+            published: vec![],
+            denial,
+            validity: DEFAULT_VALIDITY.as_secs() as u32,
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(DEFAULT_SIGNATURE_CACHE_SIZE).unwrap(),
+            )),
+        }
+    }
+
+    /// Loads the keys of the zone `origin` from key files, see [`SigningKey::load`].
+    pub fn load(
+        origin: &[DNSLabel],
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+        denial: OnlineDenial,
+    ) -> io::Result<Self> {
+        let mut keys = Vec::new();
+        for path in paths {
+            let (owner, key) = SigningKey::load(&path)?;
+            if owner != origin {
+                return Err(invalid(format!(
+                    "{} is a key of {}., not {}.",
+                    path.as_ref().display(),
+                    labels_to_string(&owner),
+                    labels_to_string(origin)
+                )));
+            }
+            keys.push(key);
+        }
+        Ok(Self::new(keys, denial))
+    }
+
+    /// How long signatures are valid after they are made. They are made again once
+    /// less than a quarter of this is left.
+    pub fn with_validity(mut self, validity: Duration) -> Self {
+        self.validity = validity.as_secs().min(u32::MAX as u64) as u32;
+        self
+    }
+
+    /// How many RRsets to keep signatures for
+    pub fn with_cache_size(self, size: usize) -> Self {
+        let size = NonZeroUsize::new(size).unwrap_or(NonZeroUsize::MIN);
+        self.cache.lock().unwrap().resize(size);
+        self
+    }
+
+    /// Publishes `records` at the zone apex along with the DNSKEY records of the keys,
+    /// such as the DNSKEY records of keys that do not sign (yet or any longer), or CDS
+    /// and CDNSKEY records.
+    pub fn with_published(mut self, records: Vec<DnsResourceRecord>) -> Self {
+        self.published = records;
+        self
+    }
+
+    pub fn keys(&self) -> &[SigningKey] {
+        &self.keys
+    }
+
+    pub fn denial(&self) -> &OnlineDenial {
+        &self.denial
+    }
+
+    /// The DNSKEY records of the keys for the zone `origin`
+    pub fn dnskey_records(&self, origin: &[DNSLabel], ttl: u32) -> Vec<DnsResourceRecord> {
+        self.keys
+            .iter()
+            .map(|key| key.dnskey_record(origin, ttl))
+            .collect()
+    }
+
+    /// The records the signer publishes at the apex of zone `origin`: the DNSKEY records
+    /// of the keys, with `ttl` unless a published DNSKEY record sets another, and the
+    /// records given to [`OnlineSigner::with_published`].
+    pub fn apex_records(&self, origin: &[DNSLabel], ttl: u32) -> Vec<DnsResourceRecord> {
+        let ttl = self
+            .published
+            .iter()
+            .find(|record| record.rtype == ResourceRecordType::DNSKEY)
+            .map_or(ttl, |dnskey| dnskey.ttl);
+        let mut records = self.dnskey_records(origin, ttl);
+        for record in &self.published {
+            if !records
+                .iter()
+                .any(|existing| existing.rtype == record.rtype && existing.rdata == record.rdata)
+            {
+                let mut record = record.clone();
+                record.name = origin.to_vec();
+                records.push(record);
+            }
+        }
+        records
+    }
+
+    /// The RRSIG records over `rrset` for the zone `origin`
+    pub fn sign(
+        &self,
+        rrset: &[DnsResourceRecord],
+        origin: &[DNSLabel],
+    ) -> io::Result<Vec<DnsResourceRecord>> {
+        let first = rrset
+            .first()
+            .ok_or_else(|| invalid("cannot sign an empty RRset"))?;
+        let key = (first.name.clone(), first.rtype);
+        let now = dnssec::now();
+
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            let remaining = cached.expiration.wrapping_sub(now) as i32;
+            if cached.rrset == rrset && remaining > (self.validity / 4) as i32 {
+                return Ok(cached.rrsigs.clone());
+            }
+        }
+
+        let inception = now.wrapping_sub(INCEPTION_OFFSET);
+        let expiration = now.wrapping_add(self.validity);
+        let rrsigs = signing_keys(&self.keys, first.rtype)
+            .map(|key| key.sign_rrset(rrset, origin, inception, expiration))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.cache.lock().unwrap().put(
+            key,
+            CachedSignatures {
+                rrset: rrset.to_vec(),
+                rrsigs: rrsigs.clone(),
+                expiration,
+            },
+        );
+        Ok(rrsigs)
+    }
+}
+
+// The keys that sign RRsets of type `rtype`: with both key-signing and zone-signing
+// keys at hand, the former sign the DNSKEY RRset, along with the CDS and CDNSKEY
+// RRsets that refer to it (RFC 7344, section 4.1), and the latter everything else
+fn signing_keys(
+    keys: &[SigningKey],
+    rtype: ResourceRecordType,
+) -> impl Iterator<Item = &SigningKey> {
+    let split =
+        keys.iter().any(SigningKey::is_key_signing) && keys.iter().any(|key| !key.is_key_signing());
+    let key_rrset = matches!(
+        rtype,
+        ResourceRecordType::DNSKEY | ResourceRecordType::CDS | ResourceRecordType::CDNSKEY
+    );
+    keys.iter()
+        .filter(move |key| !split || key.is_key_signing() == key_rrset)
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dnssec::{
+            ds_matches, signatures_for, verify_rrset, ALGORITHM_ECDSAP256SHA256, ALGORITHM_ED25519,
+            DIGEST_SHA256, FLAG_SEP, FLAG_ZONE,
+        },
+        label::labels_from_str,
+        validator::{prove_nxdomain, proves_unsigned_delegation, Security},
+    };
+
+    const EXAMPLE: &str = r#"
+$ORIGIN example.
+$TTL 3600
+@           SOA ns hostmaster 1 7200 900 1209600 300
+            NS  ns
+ns          A   192.0.2.53
+www         A   192.0.2.1
+a.deep      A   192.0.2.2
+secure      NS  ns.secure
+            DS  12345 13 2 0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef
+ns.secure   A   192.0.2.54
+x.insecure  NS  ns.x.insecure
+ns.x.insecure A 192.0.2.55
+"#;
+
+    fn name(name: &str) -> Vec<DNSLabel> {
+        labels_from_str(name).unwrap()
+    }
+
+    #[test]
+    fn test_signs_with_split_keys_and_opt_out() {
+        let zone = Zone::parse(EXAMPLE, "example.").unwrap();
+        let keys = [
+            SigningKey::generate(ALGORITHM_ED25519, FLAG_ZONE | FLAG_SEP).unwrap(),
+            SigningKey::generate(ALGORITHM_ECDSAP256SHA256, FLAG_ZONE).unwrap(),
+        ];
+        let now = dnssec::now();
+        let denial = Denial::Nsec3 {
+            iterations: 0,
+            salt: vec![0xab],
+            opt_out: true,
+        };
+        let signed = sign_zone(&zone, &keys, &denial, now - 3600, now + 86400).unwrap();
+        let records: Vec<DnsResourceRecord> = signed.records().cloned().collect();
+        let origin = name("example");
+        let dnskeys = signed.rrset(&origin, ResourceRecordType::DNSKEY);
+
+        // The key-signing key signs the keys, the zone-signing key everything else
+        let signers = |owner: &str, rtype| -> Vec<u16> {
+            signatures_for(&records, &name(owner), rtype)
+                .iter()
+                .map(|rrsig| match &rrsig.rdata {
+                    DnsRecordData::RRSIG(rrsig) => rrsig.key_tag,
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        assert_eq!(
+            signers("example", ResourceRecordType::DNSKEY),
+            [keys[0].key_tag()]
+        );
+        assert_eq!(
+            signers("www.example", ResourceRecordType::A),
+            [keys[1].key_tag()]
+        );
+        assert_eq!(
+            signers("secure.example", ResourceRecordType::DS),
+            [keys[1].key_tag()]
+        );
+        assert!(signers("x.insecure.example", ResourceRecordType::NS).is_empty());
+        let www = signed.rrset(&name("www.example"), ResourceRecordType::A);
+        let rrsigs = signatures_for(&records, &name("www.example"), ResourceRecordType::A);
+        assert!(verify_rrset(&www, &rrsigs, &dnskeys, now).is_some());
+
+        // The opted-out delegation and the empty non-terminal above it are left out
+        let nsec3s: Vec<DnsResourceRecord> = records
+            .iter()
+            .filter(|record| record.rtype == ResourceRecordType::NSEC3)
+            .cloned()
+            .collect();
+        assert_eq!(nsec3s.len(), 6);
+        let hashed = |owner: &str| {
+            let hash = base32hex_encode(&nsec3_hash(&name(owner), &[0xab], 0));
+            nsec3s.iter().any(|nsec3| nsec3.name[0].0.as_str() == hash)
+        };
+        assert!(hashed("deep.example") && hashed("secure.example"));
+        assert!(!hashed("insecure.example") && !hashed("x.insecure.example"));
+        assert!(nsec3s.iter().all(|nsec3| matches!(
+            nsec3.rdata,
+            DnsRecordData::NSEC3 {
+                flags: NSEC3_OPT_OUT,
+                ..
+            }
+        )));
+        assert!(proves_unsigned_delegation(
+            &name("x.insecure.example"),
+            &origin,
+            &nsec3s
+        ));
+
+        let ds = ds_records(&origin, &keys, 3600, DIGEST_SHA256);
+        assert_eq!(ds.len(), 1);
+        assert!(ds_matches(
+            &ds[0].rdata,
+            &keys[0].dnskey_record(&origin, 3600)
+        ));
+
+        // The signed zone survives being written out and read back in
+        let reparsed = Zone::parse(&signed.to_master_file(), "example.").unwrap();
+        let text = |zone: &Zone| zone.records().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(text(&signed), text(&reparsed));
+    }
+
+    #[test]
+    fn test_signs_with_an_nsec_chain() {
+        let zone = Zone::parse(EXAMPLE, "example.").unwrap();
+        let keys = [SigningKey::generate(ALGORITHM_ECDSAP256SHA256, FLAG_ZONE | FLAG_SEP).unwrap()];
+        let now = dnssec::now();
+        let signed = sign_zone(&zone, &keys, &Denial::Nsec, now - 3600, now + 86400).unwrap();
+        let records: Vec<DnsResourceRecord> = signed.records().cloned().collect();
+        let origin = name("example");
+        let dnskeys = signed.rrset(&origin, ResourceRecordType::DNSKEY);
+
+        // One NSEC record per authoritative name and delegation in canonical order, the
+        // last one leading back to the apex. Glue and empty non-terminals have none.
+        let nsecs: Vec<DnsResourceRecord> = records
+            .iter()
+            .filter(|record| record.rtype == ResourceRecordType::NSEC)
+            .cloned()
+            .collect();
+        let chain: Vec<(String, String)> = nsecs
+            .iter()
+            .map(|nsec| match &nsec.rdata {
+                DnsRecordData::NSEC { next_domain, .. } => {
+                    (labels_to_string(&nsec.name), next_domain.clone())
+                }
+                _ => unreachable!(),
+            })
+            .collect();
+        let expected = [
+            ("example", "a.deep.example"),
+            ("a.deep.example", "x.insecure.example"),
+            ("x.insecure.example", "ns.example"),
+            ("ns.example", "secure.example"),
+            ("secure.example", "www.example"),
+            ("www.example", "example"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(owner, next)| (owner.to_string(), next.to_string()))
+            .collect();
+        assert_eq!(chain, expected);
+        assert!(nsecs.iter().all(|nsec| nsec.ttl == 300));
+
+        // Each lists the types at its name, and is signed
+        let types =
+            |owner: &str| match &signed.rrset(&name(owner), ResourceRecordType::NSEC)[0].rdata {
+                DnsRecordData::NSEC { types, .. } => types.clone(),
+                _ => unreachable!(),
+            };
+        let mut secure = types("secure.example");
+        secure.sort_by_key(|rtype| rtype.id());
+        assert_eq!(
+            secure,
+            [
+                ResourceRecordType::NS,
+                ResourceRecordType::DS,
+                ResourceRecordType::RRSIG,
+                ResourceRecordType::NSEC
+            ]
+        );
+        assert!(types("example").contains(&ResourceRecordType::DNSKEY));
+        for nsec in &nsecs {
+            let rrset = signed.rrset(&nsec.name, ResourceRecordType::NSEC);
+            let rrsigs = signatures_for(&records, &nsec.name, ResourceRecordType::NSEC);
+            assert!(verify_rrset(&rrset, &rrsigs, &dnskeys, now).is_some());
+        }
+
+        // The chain proves names absent and the delegation without DS records unsigned
+        assert_eq!(
+            prove_nxdomain(&name("nope.example"), &origin, &nsecs),
+            Security::Secure
+        );
+        assert!(proves_unsigned_delegation(
+            &name("x.insecure.example"),
+            &origin,
+            &nsecs
+        ));
+        assert!(!proves_unsigned_delegation(
+            &name("secure.example"),
+            &origin,
+            &nsecs
+        ));
+    }
+}
@@ -0,0 +1,712 @@
+use std::{
+    fmt,
+    io::{self, Cursor, Read},
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    label::{labels_from_str, labels_to_string, read_labels, write_name},
+    zone::{escape, unescape},
+};
+
+// Service Binding records, see RFC 9460
+
+/// The key of a SvcParam, see RFC 9460, section 14.3.2
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SvcParamKey {
+    Mandatory,
+    Alpn,
+    NoDefaultAlpn,
+    Port,
+    Ipv4Hint,
+    Ech,
+    Ipv6Hint,
+    Key(u16),
+}
+
+impl SvcParamKey {
+    pub fn id(&self) -> u16 {
+        match *self {
+            SvcParamKey::Mandatory => 0,
+            SvcParamKey::Alpn => 1,
+            SvcParamKey::NoDefaultAlpn => 2,
+            SvcParamKey::Port => 3,
+            SvcParamKey::Ipv4Hint => 4,
+            SvcParamKey::Ech => 5,
+            SvcParamKey::Ipv6Hint => 6,
+            SvcParamKey::Key(id) => id,
+        }
+    }
+
+    pub fn from_id(id: u16) -> Self {
+        match id {
+            0 => SvcParamKey::Mandatory,
+            1 => SvcParamKey::Alpn,
+            2 => SvcParamKey::NoDefaultAlpn,
+            3 => SvcParamKey::Port,
+            4 => SvcParamKey::Ipv4Hint,
+            5 => SvcParamKey::Ech,
+            6 => SvcParamKey::Ipv6Hint,
+            id => SvcParamKey::Key(id),
+        }
+    }
+}
+
+impl fmt::Display for SvcParamKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SvcParamKey::Mandatory => write!(f, "mandatory"),
+            SvcParamKey::Alpn => write!(f, "alpn"),
+            SvcParamKey::NoDefaultAlpn => write!(f, "no-default-alpn"),
+            SvcParamKey::Port => write!(f, "port"),
+            SvcParamKey::Ipv4Hint => write!(f, "ipv4hint"),
+            SvcParamKey::Ech => write!(f, "ech"),
+            SvcParamKey::Ipv6Hint => write!(f, "ipv6hint"),
+            SvcParamKey::Key(id) => write!(f, "key{}", id),
+        }
+    }
+}
+
+impl FromStr for SvcParamKey {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mandatory" => Ok(SvcParamKey::Mandatory),
+            "alpn" => Ok(SvcParamKey::Alpn),
+            "no-default-alpn" => Ok(SvcParamKey::NoDefaultAlpn),
+            "port" => Ok(SvcParamKey::Port),
+            "ipv4hint" => Ok(SvcParamKey::Ipv4Hint),
+            "ech" => Ok(SvcParamKey::Ech),
+            "ipv6hint" => Ok(SvcParamKey::Ipv6Hint),
+            _ => s
+                .strip_prefix("key")
+                .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|id| id.parse::<u16>().ok())
+                .map(SvcParamKey::from_id)
+                .ok_or_else(|| invalid(format!("unknown SvcParamKey {:?}", s))),
+        }
+    }
+}
+
+/// A single SvcParam with its typed value
+#[derive(Debug, Clone, PartialEq)]
+pub enum SvcParam {
+    Mandatory(Vec<SvcParamKey>),
+    Alpn(Vec<Vec<u8>>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    Unknown(u16, Vec<u8>),
+}
+
+impl SvcParam {
+    pub fn key(&self) -> SvcParamKey {
+        match self {
+            SvcParam::Mandatory(_) => SvcParamKey::Mandatory,
+            SvcParam::Alpn(_) => SvcParamKey::Alpn,
+            SvcParam::NoDefaultAlpn => SvcParamKey::NoDefaultAlpn,
+            SvcParam::Port(_) => SvcParamKey::Port,
+            SvcParam::Ipv4Hint(_) => SvcParamKey::Ipv4Hint,
+            SvcParam::Ech(_) => SvcParamKey::Ech,
+            SvcParam::Ipv6Hint(_) => SvcParamKey::Ipv6Hint,
+            SvcParam::Unknown(id, _) => SvcParamKey::from_id(*id),
+        }
+    }
+
+    fn write_value(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            SvcParam::Mandatory(keys) => {
+                let mut ids: Vec<u16> = keys.iter().map(|key| key.id()).collect();
+                ids.sort_unstable();
+                for id in ids {
+                    buffer.write_u16::<NetworkEndian>(id)?;
+                }
+            }
+            SvcParam::Alpn(protocols) => {
+                for protocol in protocols {
+                    buffer.push(protocol.len() as u8);
+                    buffer.extend_from_slice(protocol);
+                }
+            }
+            SvcParam::NoDefaultAlpn => {}
+            SvcParam::Port(port) => buffer.write_u16::<NetworkEndian>(*port)?,
+            SvcParam::Ipv4Hint(addrs) => {
+                for addr in addrs {
+                    buffer.extend_from_slice(&addr.octets());
+                }
+            }
+            SvcParam::Ech(config) => buffer.extend_from_slice(config),
+            SvcParam::Ipv6Hint(addrs) => {
+                for addr in addrs {
+                    buffer.extend_from_slice(&addr.octets());
+                }
+            }
+            SvcParam::Unknown(_, value) => buffer.extend_from_slice(value),
+        }
+        Ok(())
+    }
+
+    fn read_value(key: SvcParamKey, value: &[u8]) -> io::Result<Self> {
+        let mut cursor = Cursor::new(value);
+        let param = match key {
+            SvcParamKey::Mandatory => {
+                if value.is_empty() || !value.len().is_multiple_of(2) {
+                    return Err(invalid("malformed mandatory SvcParam"));
+                }
+                let mut keys = Vec::new();
+                while (cursor.position() as usize) < value.len() {
+                    keys.push(SvcParamKey::from_id(cursor.read_u16::<NetworkEndian>()?));
+                }
+                SvcParam::Mandatory(keys)
+            }
+            SvcParamKey::Alpn => {
+                let mut protocols = Vec::new();
+                while (cursor.position() as usize) < value.len() {
+                    let len = cursor.read_u8()? as usize;
+                    let mut protocol = vec![0; len];
+                    cursor.read_exact(&mut protocol)?;
+                    protocols.push(protocol);
+                }
+                SvcParam::Alpn(protocols)
+            }
+            SvcParamKey::NoDefaultAlpn => {
+                if !value.is_empty() {
+                    return Err(invalid("no-default-alpn must not have a value"));
+                }
+                SvcParam::NoDefaultAlpn
+            }
+            SvcParamKey::Port => {
+                if value.len() != 2 {
+                    return Err(invalid("malformed port SvcParam"));
+                }
+                SvcParam::Port(cursor.read_u16::<NetworkEndian>()?)
+            }
+            SvcParamKey::Ipv4Hint => {
+                if value.is_empty() || !value.len().is_multiple_of(4) {
+                    return Err(invalid("malformed ipv4hint SvcParam"));
+                }
+                let addrs = value
+                    .chunks(4)
+                    .map(|octets| Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+                    .collect();
+                SvcParam::Ipv4Hint(addrs)
+            }
+            SvcParamKey::Ech => SvcParam::Ech(value.to_vec()),
+            SvcParamKey::Ipv6Hint => {
+                if value.is_empty() || !value.len().is_multiple_of(16) {
+                    return Err(invalid("malformed ipv6hint SvcParam"));
+                }
+                let addrs = value
+                    .chunks(16)
+                    .map(|octets| {
+                        let mut addr = [0; 16];
+                        addr.copy_from_slice(octets);
+                        Ipv6Addr::from(addr)
+                    })
+                    .collect();
+                SvcParam::Ipv6Hint(addrs)
+            }
+            SvcParamKey::Key(id) => SvcParam::Unknown(id, value.to_vec()),
+        };
+        Ok(param)
+    }
+
+    fn parse_value(key: SvcParamKey, value: Option<&str>) -> io::Result<Self> {
+        let require = || {
+            value
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| invalid(format!("SvcParam {} requires a value", key)))
+        };
+
+        let param = match key {
+            SvcParamKey::Mandatory => SvcParam::Mandatory(
+                split_value_list(&unescape(require()?)?)?
+                    .iter()
+                    .map(|key| String::from_utf8_lossy(key).parse())
+                    .collect::<io::Result<_>>()?,
+            ),
+            SvcParamKey::Alpn => SvcParam::Alpn(split_value_list(&unescape(require()?)?)?),
+            SvcParamKey::NoDefaultAlpn => {
+                if value.is_some() {
+                    return Err(invalid("no-default-alpn must not have a value"));
+                }
+                SvcParam::NoDefaultAlpn
+            }
+            SvcParamKey::Port => SvcParam::Port(
+                require()?
+                    .parse()
+                    .map_err(|_| invalid("invalid port SvcParam"))?,
+            ),
+            SvcParamKey::Ipv4Hint => SvcParam::Ipv4Hint(
+                require()?
+                    .split(',')
+                    .map(|addr| addr.parse().map_err(|_| invalid("invalid ipv4hint")))
+                    .collect::<io::Result<_>>()?,
+            ),
+            SvcParamKey::Ech => SvcParam::Ech(
+                BASE64
+                    .decode(require()?)
+                    .map_err(|_| invalid("invalid base64 in ech SvcParam"))?,
+            ),
+            SvcParamKey::Ipv6Hint => SvcParam::Ipv6Hint(
+                require()?
+                    .split(',')
+                    .map(|addr| addr.parse().map_err(|_| invalid("invalid ipv6hint")))
+                    .collect::<io::Result<_>>()?,
+            ),
+            SvcParamKey::Key(id) => SvcParam::Unknown(id, unescape(value.unwrap_or_default())?),
+        };
+        Ok(param)
+    }
+}
+
+impl fmt::Display for SvcParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.key())?;
+        match self {
+            SvcParam::Mandatory(keys) => {
+                let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+                write!(f, "={}", keys.join(","))
+            }
+            SvcParam::Alpn(protocols) => {
+                let protocols: Vec<String> = protocols
+                    .iter()
+                    .map(|protocol| escape(&escape_list_item(protocol)))
+                    .collect();
+                write!(f, "={}", protocols.join(","))
+            }
+            SvcParam::NoDefaultAlpn => Ok(()),
+            SvcParam::Port(port) => write!(f, "={}", port),
+            SvcParam::Ipv4Hint(addrs) => {
+                let addrs: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+                write!(f, "={}", addrs.join(","))
+            }
+            SvcParam::Ech(config) => write!(f, "={}", BASE64.encode(config)),
+            SvcParam::Ipv6Hint(addrs) => {
+                let addrs: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+                write!(f, "={}", addrs.join(","))
+            }
+            SvcParam::Unknown(_, value) if value.is_empty() => Ok(()),
+            SvcParam::Unknown(_, value) => write!(f, "=\"{}\"", escape(value)),
+        }
+    }
+}
+
+/// RDATA of SVCB and HTTPS records. A priority of 0 denotes AliasMode, every other
+/// priority ServiceMode. A target of "" is the root name, which in ServiceMode stands
+/// for the owner name of the record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SvcbData {
+    pub priority: u16,
+    pub target: String,
+    pub params: Vec<SvcParam>,
+}
+
+impl SvcbData {
+    pub fn is_alias(&self) -> bool {
+        self.priority == 0
+    }
+
+    pub fn param(&self, key: SvcParamKey) -> Option<&SvcParam> {
+        self.params.iter().find(|param| param.key() == key)
+    }
+
+    /// Checks the constraints RFC 9460 places on the SvcParams of a single record.
+    pub fn validate(&self) -> io::Result<()> {
+        let mut ids: Vec<u16> = self.params.iter().map(|param| param.key().id()).collect();
+        ids.sort_unstable();
+        if ids.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(invalid("duplicate SvcParamKey"));
+        }
+
+        if let Some(SvcParam::Mandatory(keys)) = self.param(SvcParamKey::Mandatory) {
+            let mut mandatory: Vec<u16> = keys.iter().map(|key| key.id()).collect();
+            mandatory.sort_unstable();
+            if mandatory.windows(2).any(|pair| pair[0] == pair[1]) {
+                return Err(invalid("duplicate key in mandatory SvcParam"));
+            }
+            for key in keys {
+                if *key == SvcParamKey::Mandatory {
+                    return Err(invalid("mandatory SvcParam must not list itself"));
+                }
+                if self.param(*key).is_none() {
+                    return Err(invalid(format!("mandatory key {} is missing", key)));
+                }
+            }
+        }
+
+        if let Some(SvcParam::Alpn(protocols)) = self.param(SvcParamKey::Alpn) {
+            if protocols
+                .iter()
+                .any(|protocol| protocol.is_empty() || protocol.len() > 255)
+            {
+                return Err(invalid("alpn protocol ids must be 1 to 255 octets long"));
+            }
+        }
+
+        if self.param(SvcParamKey::NoDefaultAlpn).is_some()
+            && self.param(SvcParamKey::Alpn).is_none()
+        {
+            return Err(invalid("no-default-alpn requires alpn"));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the RDATA in wire format with the SvcParams in increasing key order.
+    pub fn write(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        self.validate()?;
+
+        buffer.write_u16::<NetworkEndian>(self.priority)?;
+        write_name(buffer, &self.target)?;
+
+        let mut params: Vec<&SvcParam> = self.params.iter().collect();
+        params.sort_by_key(|param| param.key().id());
+        for param in params {
+            let mut value = Vec::new();
+            param.write_value(&mut value)?;
+            if value.len() > u16::MAX as usize {
+                return Err(invalid("SvcParam value too long"));
+            }
+            buffer.write_u16::<NetworkEndian>(param.key().id())?;
+            buffer.write_u16::<NetworkEndian>(value.len() as u16)?;
+            buffer.extend_from_slice(&value);
+        }
+        Ok(())
+    }
+
+    /// Reads `rdlength` bytes of RDATA in wire format.
+    pub fn read(cursor: &mut Cursor<&[u8]>, rdlength: u16) -> io::Result<Self> {
+        let end = cursor.position() + rdlength as u64;
+        let priority = cursor.read_u16::<NetworkEndian>()?;
+        let target = labels_to_string(&read_labels(cursor)?);
+
+        let mut params = Vec::new();
+        let mut last_key = None;
+        while cursor.position() < end {
+            let key = cursor.read_u16::<NetworkEndian>()?;
+            if last_key.is_some_and(|last| key <= last) {
+                return Err(invalid("SvcParamKeys are not in strictly increasing order"));
+            }
+            last_key = Some(key);
+
+            let len = cursor.read_u16::<NetworkEndian>()? as usize;
+            let mut value = vec![0; len];
+            cursor.read_exact(&mut value)?;
+            params.push(SvcParam::read_value(SvcParamKey::from_id(key), &value)?);
+        }
+        if cursor.position() != end {
+            return Err(invalid("SVCB RDATA overruns its length"));
+        }
+
+        let data = SvcbData {
+            priority,
+            target,
+            params,
+        };
+        data.validate()?;
+        Ok(data)
+    }
+}
+
+impl fmt::Display for SvcbData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}.", self.priority, self.target)?;
+        let mut params: Vec<&SvcParam> = self.params.iter().collect();
+        params.sort_by_key(|param| param.key().id());
+        for param in params {
+            write!(f, " {}", param)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the presentation format used in zone files, e.g.
+/// `1 svc.example.com. alpn=h2,h3 port=8443 ipv4hint=192.0.2.1`.
+impl FromStr for SvcbData {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut tokens = tokens.iter();
+
+        let priority = tokens
+            .next()
+            .and_then(|priority| priority.parse().ok())
+            .ok_or_else(|| invalid("missing or invalid SvcPriority"))?;
+        let target = tokens.next().ok_or_else(|| invalid("missing TargetName"))?;
+        let target =
+            labels_to_string(&labels_from_str(target).map_err(|_| invalid("invalid TargetName"))?);
+
+        let mut params = Vec::new();
+        for token in tokens {
+            let (key, value) = match token.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (token.as_str(), None),
+            };
+            params.push(SvcParam::parse_value(key.parse()?, value)?);
+        }
+
+        let data = SvcbData {
+            priority,
+            target,
+            params,
+        };
+        data.validate()?;
+        Ok(data)
+    }
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+// Splits presentation RDATA on whitespace, removing the quotes around quoted values
+fn tokenize(s: &str) -> io::Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut in_token = false;
+    let mut quoted = false;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_token = true;
+            }
+            '\\' => {
+                token.push(c);
+                token.push(chars.next().ok_or_else(|| invalid("dangling escape"))?);
+                in_token = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            c => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(invalid("unterminated quoted string"));
+    }
+    if in_token {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// Splits a comma-separated value list after character-string escapes have been resolved,
+// honoring the "\," and "\\" escapes of RFC 9460, appendix A.1
+fn split_value_list(value: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut items = Vec::new();
+    let mut item = Vec::new();
+    let mut bytes = value.iter();
+
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'\\' => item.push(*bytes.next().ok_or_else(|| invalid("dangling escape"))?),
+            b',' => items.push(std::mem::take(&mut item)),
+            b => item.push(b),
+        }
+    }
+    items.push(item);
+
+    if items.iter().any(|item| item.is_empty()) {
+        return Err(invalid("empty item in value list"));
+    }
+    Ok(items)
+}
+
+fn escape_list_item(item: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(item.len());
+    for &b in item {
+        if b == b'\\' || b == b',' {
+            escaped.push(b'\\');
+        }
+        escaped.push(b);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_svcb_parse_presentation() {
+        let data: SvcbData = "1 svc.example.com. alpn=h2,h3 port=8443 ipv4hint=192.0.2.1,192.0.2.2 ipv6hint=2001:db8::1 ech=AEX+DQ=="
+            .parse()
+            .unwrap();
+
+        assert_eq!(data.priority, 1);
+        assert_eq!(data.target, "svc.example.com");
+        assert!(!data.is_alias());
+        assert_eq!(
+            data.param(SvcParamKey::Alpn),
+            Some(&SvcParam::Alpn(vec![b"h2".to_vec(), b"h3".to_vec()]))
+        );
+        assert_eq!(data.param(SvcParamKey::Port), Some(&SvcParam::Port(8443)));
+        assert_eq!(
+            data.param(SvcParamKey::Ipv4Hint),
+            Some(&SvcParam::Ipv4Hint(vec![
+                Ipv4Addr::new(192, 0, 2, 1),
+                Ipv4Addr::new(192, 0, 2, 2)
+            ]))
+        );
+        assert_eq!(
+            data.param(SvcParamKey::Ech),
+            Some(&SvcParam::Ech(vec![0x00, 0x45, 0xfe, 0x0d]))
+        );
+    }
+
+    #[test]
+    fn test_svcb_wire_key_order() {
+        // Parameters given out of order must be written in increasing key order
+        let data: SvcbData = "16 foo.example.org. port=53 mandatory=alpn alpn=h3 key667=hello"
+            .parse()
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        data.write(&mut buffer).unwrap();
+
+        let mut expected = vec![0x00, 0x10];
+        expected.extend_from_slice(b"\x03foo\x07example\x03org\x00");
+        expected.extend_from_slice(&[0x00, 0x00, 0x00, 0x02, 0x00, 0x01]); // mandatory=alpn
+        expected.extend_from_slice(&[0x00, 0x01, 0x00, 0x03, 0x02, b'h', b'3']); // alpn=h3
+        expected.extend_from_slice(&[0x00, 0x03, 0x00, 0x02, 0x00, 0x35]); // port=53
+        expected.extend_from_slice(&[0x02, 0x9b, 0x00, 0x05]); // key667
+        expected.extend_from_slice(b"hello");
+        assert_eq!(buffer, expected);
+
+        let mut cursor = Cursor::new(&buffer[..]);
+        let read = SvcbData::read(&mut cursor, buffer.len() as u16).unwrap();
+        assert_eq!(read.params.len(), 4);
+        assert_eq!(read.param(SvcParamKey::Port), Some(&SvcParam::Port(53)));
+        assert_eq!(read.target, "foo.example.org");
+    }
+
+    #[test]
+    fn test_svcb_read_rejects_unordered_keys() {
+        let mut buffer = vec![0x00, 0x01, 0x00];
+        buffer.extend_from_slice(&[0x00, 0x03, 0x00, 0x02, 0x00, 0x35]); // port
+        buffer.extend_from_slice(&[0x00, 0x01, 0x00, 0x03, 0x02, b'h', b'2']); // alpn
+
+        let mut cursor = Cursor::new(&buffer[..]);
+        assert!(SvcbData::read(&mut cursor, buffer.len() as u16).is_err());
+    }
+
+    #[test]
+    fn test_svcb_validation() {
+        assert!("1 . mandatory=port".parse::<SvcbData>().is_err());
+        assert!("1 . mandatory=mandatory".parse::<SvcbData>().is_err());
+        assert!("1 . no-default-alpn".parse::<SvcbData>().is_err());
+        assert!("1 . port=1 port=2".parse::<SvcbData>().is_err());
+        assert!("1 . alpn=h2 no-default-alpn".parse::<SvcbData>().is_ok());
+    }
+
+    #[test]
+    fn test_svcb_alias_and_escaped_alpn() {
+        let alias: SvcbData = "0 pool.svc.example.".parse().unwrap();
+        assert!(alias.is_alias());
+        assert_eq!(alias.to_string(), "0 pool.svc.example.");
+
+        let data: SvcbData = r#"1 . alpn="f\\\\oo\\,bar,h2""#.parse().unwrap();
+        assert_eq!(
+            data.param(SvcParamKey::Alpn),
+            Some(&SvcParam::Alpn(vec![br"f\oo,bar".to_vec(), b"h2".to_vec()]))
+        );
+        assert_eq!(data.to_string(), r"1 . alpn=f\\\\oo\\,bar,h2");
+        assert_eq!(data.to_string().parse::<SvcbData>().unwrap(), data);
+    }
+
+    #[test]
+    fn test_svcb_alpn_keeps_bytes_above_0x7f() {
+        let protocol: Vec<u8> = (0x80..=0xFF).collect();
+        let data = SvcbData {
+            priority: 1,
+            target: String::new(),
+            params: vec![SvcParam::Alpn(vec![protocol, b"h2".to_vec()])],
+        };
+
+        let mut buffer = Vec::new();
+        data.write(&mut buffer).unwrap();
+        let mut cursor = Cursor::new(&buffer[..]);
+        assert_eq!(
+            SvcbData::read(&mut cursor, buffer.len() as u16).unwrap(),
+            data
+        );
+        assert_eq!(data.to_string().parse::<SvcbData>().unwrap(), data);
+    }
+
+    #[test]
+    fn test_svcb_additional_section() {
+        use crate::label::labels_from_str;
+        use crate::resourcerecord::{DnsClass, ResourceRecordType};
+        use crate::response::{DnsRecordData, DnsResourceRecord, DnsResponse, DnsResponseHeader};
+
+        let record = |name: &str, rdata: DnsRecordData| DnsResourceRecord {
+            name: labels_from_str(name).unwrap(),
+            rtype: rdata.to_type(),
+            class: DnsClass::IN,
+            ttl: 300,
+            rdata,
+        };
+
+        let mut response = DnsResponse {
+            header: DnsResponseHeader {
+                id: 1,
+                flags: 0x8400,
+                qdcount: 0,
+                ancount: 1,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![],
+            answers: vec![record(
+                "example.com",
+                DnsRecordData::HTTPS("0 pool.example.net.".parse().unwrap()),
+            )],
+            authority: vec![],
+            additional: vec![],
+        };
+
+        response.add_svcb_additionals(|name, rtype| {
+            match (labels_to_string(name).as_str(), rtype) {
+                ("pool.example.net", ResourceRecordType::HTTPS) => vec![record(
+                    "pool.example.net",
+                    DnsRecordData::HTTPS("1 . alpn=h2".parse().unwrap()),
+                )],
+                ("pool.example.net", ResourceRecordType::A) => vec![record(
+                    "pool.example.net",
+                    DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 7)),
+                )],
+                _ => vec![],
+            }
+        });
+
+        let types: Vec<ResourceRecordType> = response.additional.iter().map(|r| r.rtype).collect();
+        assert_eq!(
+            types,
+            vec![ResourceRecordType::HTTPS, ResourceRecordType::A]
+        );
+
+        let bytes = response.to_bytes().unwrap();
+        assert_eq!(&bytes[10..12], &[0, 2]); // ARCOUNT
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    fmt,
+    io::{self, Cursor, Read},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use ring::hmac;
+
+use crate::{
+    dnssec::canonical_name,
+    handler::DnsRequestError,
+    label::{labels_from_str, labels_to_string, read_labels, write_labels, write_name, DNSLabel},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord},
+};
+
+// Transaction signatures, see RFC 8945
+
+// How far the clocks of signer and verifier may drift apart, in seconds
+const DEFAULT_FUDGE: u16 = 300;
+
+/// The MAC algorithms of TSIG keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    HmacSha1,
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    /// The name of the algorithm in TSIG records, such as `hmac-sha256`
+    pub fn name(self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha1 => "hmac-sha1",
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha384 => "hmac-sha384",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        [
+            TsigAlgorithm::HmacSha1,
+            TsigAlgorithm::HmacSha256,
+            TsigAlgorithm::HmacSha384,
+            TsigAlgorithm::HmacSha512,
+        ]
+        .into_iter()
+        .find(|algorithm| algorithm.name() == name)
+    }
+
+    fn hmac(self) -> hmac::Algorithm {
+        match self {
+            TsigAlgorithm::HmacSha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            TsigAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+            TsigAlgorithm::HmacSha384 => hmac::HMAC_SHA384,
+            TsigAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+        }
+    }
+}
+
+/// The RDATA of a TSIG record (RFC 8945, section 4.2)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TsigData {
+    pub algorithm: String,
+    /// Seconds since the epoch, 48 bits wide
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+}
+
+impl TsigData {
+    pub fn read(cursor: &mut Cursor<&[u8]>, rdlength: u16) -> io::Result<Self> {
+        let end = cursor.position() + rdlength as u64;
+        let algorithm = labels_to_string(&read_labels(cursor)?);
+        let time_signed = cursor.read_u48::<NetworkEndian>()?;
+        let fudge = cursor.read_u16::<NetworkEndian>()?;
+        let mut mac = vec![0; cursor.read_u16::<NetworkEndian>()? as usize];
+        cursor.read_exact(&mut mac)?;
+        let original_id = cursor.read_u16::<NetworkEndian>()?;
+        let error = cursor.read_u16::<NetworkEndian>()?;
+        let mut other = vec![0; cursor.read_u16::<NetworkEndian>()? as usize];
+        cursor.read_exact(&mut other)?;
+        if cursor.position() != end {
+            return Err(invalid("TSIG record does not match its length"));
+        }
+
+        Ok(TsigData {
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        write_name(buffer, &self.algorithm)?;
+        buffer.write_u48::<NetworkEndian>(self.time_signed)?;
+        buffer.write_u16::<NetworkEndian>(self.fudge)?;
+        buffer.write_u16::<NetworkEndian>(self.mac.len() as u16)?;
+        buffer.extend_from_slice(&self.mac);
+        buffer.write_u16::<NetworkEndian>(self.original_id)?;
+        buffer.write_u16::<NetworkEndian>(self.error)?;
+        buffer.write_u16::<NetworkEndian>(self.other.len() as u16)?;
+        buffer.extend_from_slice(&self.other);
+        Ok(())
+    }
+}
+
+impl fmt::Display for TsigData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}. {} {} {} {} {} {} {}",
+            self.algorithm,
+            self.time_signed,
+            self.fudge,
+            self.mac.len(),
+            BASE64.encode(&self.mac),
+            self.original_id,
+            self.error,
+            self.other.len()
+        )?;
+        if !self.other.is_empty() {
+            write!(f, " {}", BASE64.encode(&self.other))?;
+        }
+        Ok(())
+    }
+}
+
+/// A secret shared between a client and a server to sign their messages with
+#[derive(Clone)]
+pub struct TsigKey {
+    name: Vec<DNSLabel>,
+    algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn new(name: Vec<DNSLabel>, algorithm: TsigAlgorithm, secret: impl Into<Vec<u8>>) -> Self {
+        TsigKey {
+            name,
+            algorithm,
+            secret: secret.into(),
+        }
+    }
+
+    /// Creates a key from its name, the name of its algorithm and its secret in base64,
+    /// as in the key statements of BIND's configuration.
+    pub fn from_base64(name: &str, algorithm: &str, secret: &str) -> io::Result<Self> {
+        let name = labels_from_str(name.trim_end_matches('.'))
+            .map_err(|e| invalid(format!("invalid key name: {}", e)))?;
+        let algorithm = TsigAlgorithm::from_name(algorithm)
+            .ok_or_else(|| invalid(format!("unsupported TSIG algorithm {:?}", algorithm)))?;
+        let secret = BASE64
+            .decode(secret.trim())
+            .map_err(|_| invalid("invalid base64 in TSIG secret"))?;
+        Ok(Self::new(name, algorithm, secret))
+    }
+
+    pub fn name(&self) -> &[DNSLabel] {
+        &self.name
+    }
+
+    pub fn algorithm(&self) -> TsigAlgorithm {
+        self.algorithm
+    }
+
+    // Whether a TSIG record with this owner and algorithm refers to the key
+    fn matches(&self, name: &[DNSLabel], algorithm: &str) -> bool {
+        canonical_name(name) == canonical_name(&self.name)
+            && TsigAlgorithm::from_name(algorithm) == Some(self.algorithm)
+    }
+}
+
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &labels_to_string(&self.name))
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Signs and verifies the messages of one exchange with a key: a request and its
+/// response, or a request and the messages of a zone transfer. Each MAC covers the one
+/// of the message before it (RFC 8945, section 5.3).
+#[derive(Debug)]
+pub struct TsigSession {
+    key: TsigKey,
+    previous_mac: Option<Vec<u8>>,
+    messages: usize,
+    // The error a request that failed verification is answered with
+    error: Option<DnsRequestError>,
+}
+
+impl TsigSession {
+    pub fn new(key: TsigKey) -> Self {
+        TsigSession {
+            key,
+            previous_mac: None,
+            messages: 0,
+            error: None,
+        }
+    }
+
+    pub fn key(&self) -> &TsigKey {
+        &self.key
+    }
+
+    /// Why the request of the session failed verification: BADKEY, BADSIG or BADTIME
+    pub fn error(&self) -> Option<DnsRequestError> {
+        self.error
+    }
+
+    /// Signs `message`, in wire format, by appending a TSIG record. After a request
+    /// that failed verification, the record carries the error instead, and a MAC only
+    /// for BADTIME (RFC 8945, section 5.3.2).
+    pub fn sign(&mut self, message: &mut Vec<u8>) -> io::Result<()> {
+        if message.len() < 12 {
+            return Err(invalid("message too short to sign"));
+        }
+        let now = unix_time();
+        let mut tsig = TsigData {
+            algorithm: self.key.algorithm.name().to_string(),
+            time_signed: now,
+            fudge: DEFAULT_FUDGE,
+            mac: vec![],
+            original_id: u16::from_be_bytes([message[0], message[1]]),
+            error: self
+                .error
+                .map_or(0, |error| error.to_response_code() as u16),
+            other: vec![],
+        };
+        match self.error {
+            Some(DnsRequestError::BadKey | DnsRequestError::BadVersOrSig) => {}
+            error => {
+                if error == Some(DnsRequestError::BadTime) {
+                    // The server's time, so that the client can tell the offset
+                    tsig.other = now.to_be_bytes()[2..].to_vec();
+                }
+                let key = hmac::Key::new(self.key.algorithm.hmac(), &self.key.secret);
+                tsig.mac = hmac::sign(&key, &self.signed_data(message, &tsig)?)
+                    .as_ref()
+                    .to_vec();
+                self.previous_mac = Some(tsig.mac.clone());
+                self.messages += 1;
+            }
+        }
+
+        DnsResourceRecord {
+            name: self.key.name.clone(),
+            rtype: ResourceRecordType::TSIG,
+            class: DnsClass::ANY,
+            ttl: 0,
+            rdata: DnsRecordData::TSIG(tsig),
+        }
+        .write(message)?;
+        let arcount = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&arcount.to_be_bytes());
+        Ok(())
+    }
+
+    /// Verifies the TSIG record that ends `message`, the next message of the exchange.
+    /// Fails with BADSIG if there is none, and with the error of the record if the
+    /// other side reports one.
+    pub fn verify(&mut self, message: &[u8]) -> Result<(), DnsRequestError> {
+        let (start, record) = find_tsig(message)
+            .map_err(|_| DnsRequestError::FormErr)?
+            .ok_or(DnsRequestError::BadVersOrSig)?;
+        let tsig = match record.rdata {
+            DnsRecordData::TSIG(tsig) => tsig,
+            _ => return Err(DnsRequestError::FormErr),
+        };
+        match tsig.error {
+            0 => {}
+            16 => return Err(DnsRequestError::BadVersOrSig),
+            17 => return Err(DnsRequestError::BadKey),
+            18 => return Err(DnsRequestError::BadTime),
+            _ => return Err(DnsRequestError::NotAuth),
+        }
+        if !self.key.matches(&record.name, &tsig.algorithm) {
+            return Err(DnsRequestError::BadKey);
+        }
+
+        // The message as it was signed: with its original ID and without the TSIG record
+        let mut unsigned = message[..start].to_vec();
+        unsigned[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        let arcount = u16::from_be_bytes([unsigned[10], unsigned[11]]) - 1;
+        unsigned[10..12].copy_from_slice(&arcount.to_be_bytes());
+        let data = self
+            .signed_data(&unsigned, &tsig)
+            .map_err(|_| DnsRequestError::FormErr)?;
+        let key = hmac::Key::new(self.key.algorithm.hmac(), &self.key.secret);
+        hmac::verify(&key, &data, &tsig.mac).map_err(|_| DnsRequestError::BadVersOrSig)?;
+        self.previous_mac = Some(tsig.mac);
+        self.messages += 1;
+
+        if unix_time().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            return Err(DnsRequestError::BadTime);
+        }
+        Ok(())
+    }
+
+    // What the MAC of `message` covers: the MAC before it, the message and the TSIG
+    // variables, of which messages after the first response only include the times
+    fn signed_data(&self, message: &[u8], tsig: &TsigData) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        if let Some(previous_mac) = &self.previous_mac {
+            data.write_u16::<NetworkEndian>(previous_mac.len() as u16)?;
+            data.extend_from_slice(previous_mac);
+        }
+        data.extend_from_slice(message);
+        if self.messages < 2 {
+            write_labels(&mut data, &canonical_name(&self.key.name));
+            data.write_u16::<NetworkEndian>(DnsClass::ANY.id())?;
+            data.write_u32::<NetworkEndian>(0)?;
+            write_name(&mut data, &tsig.algorithm.to_ascii_lowercase())?;
+            data.write_u48::<NetworkEndian>(tsig.time_signed)?;
+            data.write_u16::<NetworkEndian>(tsig.fudge)?;
+            data.write_u16::<NetworkEndian>(tsig.error)?;
+            data.write_u16::<NetworkEndian>(tsig.other.len() as u16)?;
+            data.extend_from_slice(&tsig.other);
+        } else {
+            data.write_u48::<NetworkEndian>(tsig.time_signed)?;
+            data.write_u16::<NetworkEndian>(tsig.fudge)?;
+        }
+        Ok(data)
+    }
+}
+
+/// Verifies the TSIG record of a request with the key among `keys` it names. Returns
+/// `None` if the request is not signed, and otherwise the session to sign the response
+/// with, whose [`TsigSession::error`] tells whether verification failed.
+pub fn verify_request(
+    keys: &[TsigKey],
+    message: &[u8],
+) -> Result<Option<TsigSession>, DnsRequestError> {
+    let record = match find_tsig(message).map_err(|_| DnsRequestError::FormErr)? {
+        Some((_, record)) => record,
+        None => return Ok(None),
+    };
+    let algorithm = match &record.rdata {
+        DnsRecordData::TSIG(tsig) => tsig.algorithm.clone(),
+        _ => return Err(DnsRequestError::FormErr),
+    };
+    let mut session = match keys
+        .iter()
+        .find(|key| key.matches(&record.name, &algorithm))
+    {
+        Some(key) => TsigSession::new(key.clone()),
+        None => {
+            // Answered without a MAC, so the key only has to name itself
+            let algorithm =
+                TsigAlgorithm::from_name(&algorithm).unwrap_or(TsigAlgorithm::HmacSha256);
+            let mut session = TsigSession::new(TsigKey::new(record.name, algorithm, vec![]));
+            session.error = Some(DnsRequestError::BadKey);
+            return Ok(Some(session));
+        }
+    };
+    if let Err(error) = session.verify(message) {
+        session.error = Some(error);
+    }
+    Ok(Some(session))
+}
+
+/// The TSIG record of a message in wire format along with its offset, which has to be
+/// the last record of the message
+pub fn find_tsig(message: &[u8]) -> io::Result<Option<(usize, DnsResourceRecord)>> {
+    let mut cursor = Cursor::new(message);
+    cursor.set_position(4);
+    let qdcount = cursor.read_u16::<NetworkEndian>()?;
+    let mut records = 0;
+    for _ in 0..3 {
+        records += cursor.read_u16::<NetworkEndian>()? as usize;
+    }
+    let arcount = u16::from_be_bytes([message[10], message[11]]);
+    for _ in 0..qdcount {
+        read_labels(&mut cursor)?;
+        cursor.set_position(cursor.position() + 4);
+    }
+
+    let mut tsig = None;
+    for index in 0..records {
+        let start = cursor.position() as usize;
+        let record = DnsResourceRecord::read(&mut cursor)?;
+        if record.rtype == ResourceRecordType::TSIG {
+            if index + 1 != records || arcount == 0 {
+                return Err(invalid("TSIG record is not the last record"));
+            }
+            tsig = Some((start, record));
+        }
+    }
+    Ok(tsig)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::DnsClient;
+    use crate::request::DNSRequest;
+    use crate::response::DnsResponse;
+
+    fn key(secret: &[u8]) -> TsigKey {
+        TsigKey::new(
+            labels_from_str("transfer.example").unwrap(),
+            TsigAlgorithm::HmacSha256,
+            secret,
+        )
+    }
+
+    #[test]
+    fn test_signs_and_verifies_exchanges() {
+        let query = DnsClient::new("127.0.0.1:53".parse().unwrap()).build_query(
+            &labels_from_str("example.com").unwrap(),
+            ResourceRecordType::AXFR,
+            DnsClass::IN,
+        );
+        let mut client = TsigSession::new(key(b"secret"));
+        let mut message = query.to_bytes().unwrap();
+        client.sign(&mut message).unwrap();
+        let request = DNSRequest::parse(&message).unwrap();
+        assert_eq!(
+            request.additional.last().unwrap().rtype,
+            ResourceRecordType::TSIG
+        );
+
+        // Keys are told apart by name and algorithm, and then have to match the MAC
+        let other = TsigKey::new(key(b"secret").name, TsigAlgorithm::HmacSha512, "secret");
+        let server = verify_request(&[other], &message).unwrap().unwrap();
+        assert_eq!(server.error(), Some(DnsRequestError::BadKey));
+        let server = verify_request(&[key(b"other")], &message).unwrap().unwrap();
+        assert_eq!(server.error(), Some(DnsRequestError::BadVersOrSig));
+
+        // The server answers with several messages, each covering the one before
+        let mut server = verify_request(&[key(b"secret")], &message)
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.error(), None);
+        let mut responses = vec![];
+        for _ in 0..3 {
+            let mut response = DnsResponse::reply_to(&request).to_bytes().unwrap();
+            server.sign(&mut response).unwrap();
+            responses.push(response);
+        }
+        for response in &responses {
+            client.verify(response).unwrap();
+        }
+
+        // Out of order or tampered messages fail
+        let mut client = TsigSession::new(key(b"secret"));
+        client.sign(&mut query.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            client.verify(&responses[1]),
+            Err(DnsRequestError::BadVersOrSig)
+        );
+        let mut tampered = message.clone();
+        tampered[14] = b'X';
+        let session = verify_request(&[key(b"secret")], &tampered)
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.error(), Some(DnsRequestError::BadVersOrSig));
+
+        // Unsigned requests have no session
+        let unsigned = query.to_bytes().unwrap();
+        assert!(verify_request(&[key(b"secret")], &unsigned)
+            .unwrap()
+            .is_none());
+    }
+}
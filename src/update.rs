@@ -0,0 +1,525 @@
+use std::{collections::HashMap, io, str::FromStr};
+
+use crate::{
+    handler::DnsRequestError,
+    label::{is_subdomain, labels_from_str, DNSLabel},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord},
+    zone::Zone,
+};
+
+// Dynamic updates of zones, see RFC 2136
+
+/// Decides which changes the clients of a zone may make with UPDATE messages: each rule
+/// lets the clients that sign their requests with a TSIG key change the records of a
+/// name, or of the names below it as well, optionally only of some types. Unsigned
+/// updates are never allowed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpdatePolicy {
+    rules: Vec<UpdateRule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct UpdateRule {
+    key: Vec<DNSLabel>,
+    name: Vec<DNSLabel>,
+    subdomains: bool,
+    // Any type if empty
+    types: Vec<ResourceRecordType>,
+}
+
+impl UpdatePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets the holders of `key` change the records of `name` of the given types, or
+    /// of any type if `types` is empty.
+    pub fn with_name(
+        self,
+        key: &[DNSLabel],
+        name: &[DNSLabel],
+        types: &[ResourceRecordType],
+    ) -> Self {
+        self.with_rule(key, name, false, types)
+    }
+
+    /// Lets the holders of `key` change the records of `name` and of all names below
+    /// it, of the given types or of any type if `types` is empty.
+    pub fn with_subdomain(
+        self,
+        key: &[DNSLabel],
+        name: &[DNSLabel],
+        types: &[ResourceRecordType],
+    ) -> Self {
+        self.with_rule(key, name, true, types)
+    }
+
+    fn with_rule(
+        mut self,
+        key: &[DNSLabel],
+        name: &[DNSLabel],
+        subdomains: bool,
+        types: &[ResourceRecordType],
+    ) -> Self {
+        self.rules.push(UpdateRule {
+            key: key.to_vec(),
+            name: name.to_vec(),
+            subdomains,
+            types: types.to_vec(),
+        });
+        self
+    }
+
+    /// Whether a client whose request was verified with `key` may change the records
+    /// of `name` and type `rtype`. Type ANY stands for all records of the name, which
+    /// only a rule for any type allows.
+    pub fn allows(
+        &self,
+        key: Option<&[DNSLabel]>,
+        name: &[DNSLabel],
+        rtype: ResourceRecordType,
+    ) -> bool {
+        let key = match key {
+            Some(key) => key,
+            None => return false,
+        };
+        self.rules.iter().any(|rule| {
+            rule.key == key
+                && match rule.subdomains {
+                    true => is_subdomain(name, &rule.name),
+                    false => name == rule.name,
+                }
+                && (rule.types.is_empty() || rule.types.contains(&rtype))
+        })
+    }
+}
+
+/// Parses rules separated by semicolons, each `grant <key> name|subdomain <name>`
+/// followed by the types it is limited to, if any, such as
+/// `grant dhcp.example subdomain dyn.example.com A AAAA`.
+impl FromStr for UpdatePolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = |text: &str| {
+            labels_from_str(text.trim_end_matches('.'))
+                .map_err(|e| invalid(format!("invalid name {:?}: {}", text, e)))
+        };
+        let mut policy = UpdatePolicy::new();
+        for rule in s.split(';').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let fields: Vec<&str> = rule.split_whitespace().collect();
+            let (key, kind, owner, types) = match fields.as_slice() {
+                ["grant", key, kind, owner, types @ ..] => (name(key)?, *kind, name(owner)?, types),
+                _ => return Err(invalid(format!("invalid update rule {:?}", rule))),
+            };
+            let types = types
+                .iter()
+                .map(|rtype| {
+                    rtype
+                        .parse()
+                        .map_err(|_| invalid(format!("unknown type {:?}", rtype)))
+                })
+                .collect::<io::Result<Vec<ResourceRecordType>>>()?;
+            policy = match kind {
+                "name" => policy.with_name(&key, &owner, &types),
+                "subdomain" => policy.with_subdomain(&key, &owner, &types),
+                _ => return Err(invalid(format!("invalid update rule {:?}", rule))),
+            };
+        }
+        Ok(policy)
+    }
+}
+
+/// Checks the prerequisites of an UPDATE message against `zone` (RFC 2136, section
+/// 3.2), failing with the RCODE of the first one that does not hold.
+pub fn check_prerequisites(
+    zone: &Zone,
+    prerequisites: &[DnsResourceRecord],
+) -> Result<(), DnsRequestError> {
+    let class = zone_class(zone);
+    // The RRsets that have to exist with exactly these records
+    let mut rrsets: HashMap<(Vec<DNSLabel>, ResourceRecordType), Vec<&DnsRecordData>> =
+        HashMap::new();
+    for record in prerequisites {
+        if record.ttl != 0 {
+            return Err(DnsRequestError::FormErr);
+        }
+        if !is_subdomain(&record.name, zone.origin()) {
+            return Err(DnsRequestError::NotZone);
+        }
+        let exists = |rtype| match rtype {
+            ResourceRecordType::ANY => zone.node(&record.name).is_some(),
+            rtype => !zone.rrset(&record.name, rtype).is_empty(),
+        };
+        match record.class {
+            DnsClass::ANY if is_empty(record) => {
+                if !exists(record.rtype) {
+                    return Err(match record.rtype {
+                        ResourceRecordType::ANY => DnsRequestError::NXDomain,
+                        _ => DnsRequestError::NXRRSet,
+                    });
+                }
+            }
+            DnsClass::NONE if is_empty(record) => {
+                if exists(record.rtype) {
+                    return Err(match record.rtype {
+                        ResourceRecordType::ANY => DnsRequestError::YXDomain,
+                        _ => DnsRequestError::YXRRSet,
+                    });
+                }
+            }
+            record_class if Some(record_class) == class && !is_meta(record.rtype) => rrsets
+                .entry((record.name.clone(), record.rtype))
+                .or_default()
+                .push(&record.rdata),
+            _ => return Err(DnsRequestError::FormErr),
+        }
+    }
+
+    for ((name, rtype), records) in rrsets {
+        let rrset = zone.rrset(&name, rtype);
+        let mut expected: Vec<&DnsRecordData> = vec![];
+        for rdata in records {
+            if !expected.contains(&rdata) {
+                expected.push(rdata);
+            }
+        }
+        let matches = rrset.len() == expected.len()
+            && rrset.iter().all(|record| expected.contains(&&record.rdata));
+        if !matches {
+            return Err(DnsRequestError::NXRRSet);
+        }
+    }
+    Ok(())
+}
+
+/// Checks the update section of an UPDATE message (RFC 2136, section 3.4.1), failing
+/// with FORMERR or NOTZONE if any change is malformed.
+pub fn check_updates(zone: &Zone, updates: &[DnsResourceRecord]) -> Result<(), DnsRequestError> {
+    for record in updates {
+        if !is_subdomain(&record.name, zone.origin()) {
+            return Err(DnsRequestError::NotZone);
+        }
+        let valid = match record.class {
+            DnsClass::ANY => {
+                record.ttl == 0
+                    && is_empty(record)
+                    && (record.rtype == ResourceRecordType::ANY || !is_meta(record.rtype))
+            }
+            DnsClass::NONE => record.ttl == 0 && !is_meta(record.rtype),
+            class => Some(class) == zone_class(zone) && !is_meta(record.rtype),
+        };
+        if !valid {
+            return Err(DnsRequestError::FormErr);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the update section of an UPDATE message to `zone` (RFC 2136, section
+/// 3.4.2), after [`check_updates`] has passed it. Changes that RFC 2136 says to ignore,
+/// such as deleting the SOA record or the NS records of the apex, are skipped. Returns
+/// whether the zone changed.
+pub fn apply_updates(zone: &mut Zone, updates: &[DnsResourceRecord]) -> bool {
+    let origin = zone.origin().to_vec();
+    let mut changed = false;
+    for record in updates {
+        let apex = record.name == origin;
+        match record.class {
+            DnsClass::ANY => {
+                let types: Vec<ResourceRecordType> = match record.rtype {
+                    ResourceRecordType::ANY => zone
+                        .node(&record.name)
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|record| record.rtype)
+                        .collect(),
+                    rtype => vec![rtype],
+                };
+                for rtype in types {
+                    let kept =
+                        apex && matches!(rtype, ResourceRecordType::SOA | ResourceRecordType::NS);
+                    if !kept {
+                        changed |= !zone.remove_rrset(&record.name, rtype).is_empty();
+                    }
+                }
+            }
+            DnsClass::NONE => {
+                let last_ns = apex
+                    && record.rtype == ResourceRecordType::NS
+                    && zone.rrset(&origin, ResourceRecordType::NS).len() == 1;
+                if record.rtype != ResourceRecordType::SOA && !last_ns {
+                    changed |= zone.remove(record);
+                }
+            }
+            _ => changed |= add_record(zone, record),
+        }
+    }
+    changed
+}
+
+// Adds `record` unless it would put a CNAME next to other data, replacing an SOA record
+// with an older serial, a CNAME record, and a record with the same data and another TTL
+fn add_record(zone: &mut Zone, record: &DnsResourceRecord) -> bool {
+    let node = zone.node(&record.name).unwrap_or_default();
+    let is_cname = |rtype| rtype == ResourceRecordType::CNAME;
+    // Records that may stand next to a CNAME (RFC 4035, section 2.5)
+    let is_dnssec = |rtype| {
+        matches!(
+            rtype,
+            ResourceRecordType::RRSIG | ResourceRecordType::NSEC | ResourceRecordType::NSEC3
+        )
+    };
+    if node.iter().any(|existing| {
+        !is_dnssec(existing.rtype)
+            && !is_dnssec(record.rtype)
+            && is_cname(existing.rtype) != is_cname(record.rtype)
+    }) {
+        return false;
+    }
+    if node.contains(record) {
+        return false;
+    }
+    match record.rtype {
+        ResourceRecordType::SOA => {
+            let newer = match (&record.rdata, zone.serial()) {
+                (DnsRecordData::SOA { serial, .. }, Some(current)) => {
+                    serial.wrapping_sub(current) as i32 > 0
+                }
+                _ => false,
+            };
+            if record.name != zone.origin() || !newer {
+                return false;
+            }
+            zone.remove_rrset(&record.name, ResourceRecordType::SOA);
+        }
+        ResourceRecordType::CNAME => {
+            zone.remove_rrset(&record.name, ResourceRecordType::CNAME);
+        }
+        _ => {
+            zone.remove(record);
+        }
+    }
+    zone.insert(record.clone()).is_ok()
+}
+
+/// Increments the serial of the SOA record of `zone`.
+pub fn bump_serial(zone: &mut Zone) {
+    let mut soa = match zone.soa() {
+        Some(soa) => soa.clone(),
+        None => return,
+    };
+    if let DnsRecordData::SOA { serial, .. } = &mut soa.rdata {
+        *serial = serial.wrapping_add(1);
+    }
+    let apex = soa.name.clone();
+    zone.remove_rrset(&apex, ResourceRecordType::SOA);
+    // The SOA record lies at the apex of the zone
+    zone.insert(soa).expect("SOA record within the zone");
+}
+
+fn zone_class(zone: &Zone) -> Option<DnsClass> {
+    zone.soa().map(|soa| soa.class)
+}
+
+// Whether the RDATA of a prerequisite or deletion is empty
+fn is_empty(record: &DnsResourceRecord) -> bool {
+    matches!(&record.rdata, DnsRecordData::Unknown(_, data) if data.is_empty())
+}
+
+// Types that only appear in queries and transactions, not in zones
+fn is_meta(rtype: ResourceRecordType) -> bool {
+    matches!(
+        rtype,
+        ResourceRecordType::ANY
+            | ResourceRecordType::AXFR
+            | ResourceRecordType::IXFR
+            | ResourceRecordType::MAILA
+            | ResourceRecordType::MAILB
+            | ResourceRecordType::OPT
+            | ResourceRecordType::TSIG
+            | ResourceRecordType::TKEY
+    )
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const ZONE: &str = "
+$TTL 3600
+@       SOA ns hostmaster 1 7200 900 604800 300
+        NS  ns
+ns      A   192.0.2.53
+www     A   192.0.2.1
+        A   192.0.2.2
+alias   CNAME www
+";
+
+    fn record(name: &str, class: DnsClass, ttl: u32, rdata: DnsRecordData) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: labels_from_str(name).unwrap(),
+            rtype: rdata.to_type(),
+            class,
+            ttl,
+            rdata,
+        }
+    }
+
+    fn empty(name: &str, class: DnsClass, rtype: ResourceRecordType) -> DnsResourceRecord {
+        record(name, class, 0, DnsRecordData::Unknown(rtype, vec![]))
+    }
+
+    fn a(name: &str, class: DnsClass, ttl: u32, address: [u8; 4]) -> DnsResourceRecord {
+        record(name, class, ttl, DnsRecordData::A(Ipv4Addr::from(address)))
+    }
+
+    #[test]
+    fn test_checks_prerequisites() {
+        let zone = Zone::parse(ZONE, "example.com.").unwrap();
+        let check = |prerequisites: &[DnsResourceRecord]| check_prerequisites(&zone, prerequisites);
+        use DnsClass::{ANY, IN, NONE};
+        use ResourceRecordType::{A, AAAA};
+
+        assert_eq!(
+            check(&[empty("www.example.com", ANY, ResourceRecordType::ANY)]),
+            Ok(())
+        );
+        assert_eq!(
+            check(&[empty("new.example.com", ANY, ResourceRecordType::ANY)]),
+            Err(DnsRequestError::NXDomain)
+        );
+        assert_eq!(
+            check(&[empty("www.example.com", ANY, AAAA)]),
+            Err(DnsRequestError::NXRRSet)
+        );
+        assert_eq!(
+            check(&[empty("www.example.com", NONE, ResourceRecordType::ANY)]),
+            Err(DnsRequestError::YXDomain)
+        );
+        assert_eq!(
+            check(&[empty("www.example.com", NONE, A)]),
+            Err(DnsRequestError::YXRRSet)
+        );
+        assert_eq!(check(&[empty("www.example.com", NONE, AAAA)]), Ok(()));
+
+        // Value-dependent prerequisites have to match the whole RRset
+        let first = a("www.example.com", IN, 0, [192, 0, 2, 1]);
+        let second = a("www.example.com", IN, 0, [192, 0, 2, 2]);
+        assert_eq!(check(&[first.clone(), second]), Ok(()));
+        assert_eq!(check(&[first]), Err(DnsRequestError::NXRRSet));
+
+        assert_eq!(
+            check(&[empty("www.example.net", ANY, A)]),
+            Err(DnsRequestError::NotZone)
+        );
+        assert_eq!(
+            check(&[a("www.example.com", IN, 300, [192, 0, 2, 1])]),
+            Err(DnsRequestError::FormErr)
+        );
+    }
+
+    #[test]
+    fn test_applies_updates() {
+        let mut zone = Zone::parse(ZONE, "example.com.").unwrap();
+        use DnsClass::{ANY, IN, NONE};
+        use ResourceRecordType::{A, NS};
+
+        let updates = [
+            a("new.example.com", IN, 300, [192, 0, 2, 3]),
+            empty("www.example.com", ANY, A),
+            // Ignored: the apex keeps its SOA and NS records, and its last NS record,
+            // and a CNAME cannot stand next to other data
+            empty("example.com", ANY, ResourceRecordType::ANY),
+            record(
+                "example.com",
+                NONE,
+                0,
+                DnsRecordData::NS("ns.example.com".into()),
+            ),
+            a("alias.example.com", IN, 300, [192, 0, 2, 4]),
+        ];
+        assert_eq!(check_updates(&zone, &updates), Ok(()));
+        assert!(apply_updates(&mut zone, &updates));
+        assert_eq!(
+            zone.rrset(&labels_from_str("new.example.com").unwrap(), A)
+                .len(),
+            1
+        );
+        assert!(zone
+            .node(&labels_from_str("www.example.com").unwrap())
+            .is_none());
+        assert!(zone.soa().is_some());
+        assert_eq!(zone.rrset(zone.origin(), NS).len(), 1);
+        assert_eq!(
+            zone.rrset(&labels_from_str("alias.example.com").unwrap(), A),
+            vec![]
+        );
+
+        // Nothing to change
+        assert!(!apply_updates(&mut zone, &updates));
+        bump_serial(&mut zone);
+        assert_eq!(zone.serial(), Some(2));
+
+        assert_eq!(
+            check_updates(&zone, &[a("www.example.com", ANY, 0, [192, 0, 2, 1])]),
+            Err(DnsRequestError::FormErr)
+        );
+        assert_eq!(
+            check_updates(
+                &zone,
+                &[empty("www.example.com", IN, ResourceRecordType::ANY)]
+            ),
+            Err(DnsRequestError::FormErr)
+        );
+    }
+
+    #[test]
+    fn test_policy_allows_keys_names_and_types() {
+        let policy: UpdatePolicy =
+            "grant dhcp.example subdomain dyn.example.com A AAAA; grant admin.example name example.com"
+                .parse()
+                .unwrap();
+        let dhcp = labels_from_str("dhcp.example").unwrap();
+        let admin = labels_from_str("admin.example").unwrap();
+        let name = |name: &str| labels_from_str(name).unwrap();
+
+        assert!(policy.allows(
+            Some(&dhcp),
+            &name("host.dyn.example.com"),
+            ResourceRecordType::A
+        ));
+        assert!(!policy.allows(
+            Some(&dhcp),
+            &name("host.dyn.example.com"),
+            ResourceRecordType::TXT
+        ));
+        assert!(!policy.allows(
+            Some(&dhcp),
+            &name("host.dyn.example.com"),
+            ResourceRecordType::ANY
+        ));
+        assert!(!policy.allows(Some(&dhcp), &name("www.example.com"), ResourceRecordType::A));
+        assert!(!policy.allows(None, &name("host.dyn.example.com"), ResourceRecordType::A));
+        assert!(policy.allows(Some(&admin), &name("example.com"), ResourceRecordType::ANY));
+        assert!(!policy.allows(
+            Some(&admin),
+            &name("www.example.com"),
+            ResourceRecordType::A
+        ));
+
+        assert!("grant dhcp.example everywhere example.com"
+            .parse::<UpdatePolicy>()
+            .is_err());
+    }
+}
@@ -0,0 +1,746 @@
+use crate::{
+    dnssec::{base32hex_decode, nsec3_hash, NSEC3_HASH_SHA1, NSEC3_OPT_OUT},
+    label::{is_subdomain, labels_from_str, CanonicalName, DNSLabel},
+    resourcerecord::ResourceRecordType,
+    response::{DnsRecordData, DnsResourceRecord},
+};
+
+// Proofs of nonexistence, see RFC 4035, section 5.4, and RFC 5155, section 8
+
+// NSEC3 chains with more iterations are treated as insecure (RFC 9276, section 3.2)
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// The security status of validated data (RFC 4033, section 5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Security {
+    /// A chain of signatures from a trust anchor covers the data.
+    Secure,
+    /// The data lies below a provably unsigned delegation, or is covered by an NSEC3
+    /// opt-out span.
+    Insecure,
+    /// Signatures or proofs are missing or do not check out.
+    Bogus,
+}
+
+struct Nsec<'a> {
+    owner: &'a [DNSLabel],
+    next: Vec<DNSLabel>,
+    types: &'a [ResourceRecordType],
+}
+
+impl Nsec<'_> {
+    // Whether `name` sorts between the owner and the next name; the last record of a
+    // chain wraps around to the apex
+    fn covers(&self, name: &[DNSLabel]) -> bool {
+        let owner = CanonicalName(self.owner.to_vec());
+        let next = CanonicalName(self.next.clone());
+        let name = CanonicalName(name.to_vec());
+        if owner < next {
+            owner < name && name < next
+        } else {
+            owner < name || name < next
+        }
+    }
+
+    // Whether the owner is a zone cut or DNAME above `name`. Such a record only proves
+    // names of the parent zone, not the ones below it (RFC 6840, section 4.1).
+    fn is_cut_above(&self, name: &[DNSLabel]) -> bool {
+        self.owner.len() < name.len()
+            && is_subdomain(name, self.owner)
+            && (is_delegation(self.types) || self.types.contains(&ResourceRecordType::DNAME))
+    }
+}
+
+struct Nsec3<'a> {
+    hash: Vec<u8>,
+    next: &'a [u8],
+    flags: u8,
+    types: &'a [ResourceRecordType],
+}
+
+impl Nsec3<'_> {
+    fn covers(&self, hash: &[u8]) -> bool {
+        if self.hash.as_slice() < self.next {
+            self.hash.as_slice() < hash && hash < self.next
+        } else {
+            self.hash.as_slice() < hash || hash < self.next
+        }
+    }
+
+    fn opt_out(&self) -> bool {
+        self.flags & NSEC3_OPT_OUT != 0
+    }
+}
+
+// The NSEC3 records of a zone that share its hash parameters
+struct Nsec3Chain<'a> {
+    salt: &'a [u8],
+    iterations: u16,
+    records: Vec<Nsec3<'a>>,
+}
+
+impl<'a> Nsec3Chain<'a> {
+    fn new(zone: &[DNSLabel], records: &'a [DnsResourceRecord]) -> Option<Self> {
+        let mut chain: Option<Nsec3Chain> = None;
+        for record in records {
+            let (flags, iterations, salt, next, types) = match &record.rdata {
+                DnsRecordData::NSEC3 {
+                    hash_algorithm: NSEC3_HASH_SHA1,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    types,
+                } => (*flags, *iterations, salt, next_hashed, types),
+                _ => continue,
+            };
+            if record.name.len() != zone.len() + 1 || !is_subdomain(&record.name, zone) {
+                continue;
+            }
+            let hash = match base32hex_decode(&record.name[0].0) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let chain = chain.get_or_insert_with(|| Nsec3Chain {
+                salt,
+                iterations,
+                records: vec![],
+            });
+            if chain.salt == salt.as_slice() && chain.iterations == iterations {
+                chain.records.push(Nsec3 {
+                    hash,
+                    next,
+                    flags,
+                    types,
+                });
+            }
+        }
+        chain
+    }
+
+    fn hash(&self, name: &[DNSLabel]) -> Vec<u8> {
+        nsec3_hash(name, self.salt, self.iterations)
+    }
+
+    fn matching(&self, name: &[DNSLabel]) -> Option<&Nsec3<'a>> {
+        let hash = self.hash(name);
+        self.records.iter().find(|record| record.hash == hash)
+    }
+
+    fn covering(&self, name: &[DNSLabel]) -> Option<&Nsec3<'a>> {
+        let hash = self.hash(name);
+        self.records.iter().find(|record| record.covers(&hash))
+    }
+
+    // The closest encloser proof of RFC 5155, section 8.3: the closest encloser of
+    // `name` and the record that covers the next closer name
+    fn closest_encloser(
+        &self,
+        name: &[DNSLabel],
+        zone: &[DNSLabel],
+    ) -> Option<(Vec<DNSLabel>, &Nsec3<'a>)> {
+        for depth in (zone.len()..name.len()).rev() {
+            let ancestor = &name[name.len() - depth..];
+            let encloser = match self.matching(ancestor) {
+                Some(encloser) => encloser,
+                None => continue,
+            };
+            // Nothing below a delegation or DNAME is proven by the zone
+            if is_delegation(encloser.types) || encloser.types.contains(&ResourceRecordType::DNAME)
+            {
+                return None;
+            }
+            let next_closer = &name[name.len() - depth - 1..];
+            return Some((ancestor.to_vec(), self.covering(next_closer)?));
+        }
+        None
+    }
+}
+
+fn nsec_records<'a>(zone: &[DNSLabel], records: &'a [DnsResourceRecord]) -> Vec<Nsec<'a>> {
+    records
+        .iter()
+        .filter(|record| is_subdomain(&record.name, zone))
+        .filter_map(|record| match &record.rdata {
+            DnsRecordData::NSEC { next_domain, types } => Some(Nsec {
+                owner: &record.name,
+                next: labels_from_str(next_domain).ok()?,
+                types,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+// The record that covers `name`, skipping those owned by cuts above it
+fn find_covering<'a, 'b>(nsecs: &'b [Nsec<'a>], name: &[DNSLabel]) -> Option<&'b Nsec<'a>> {
+    nsecs
+        .iter()
+        .find(|nsec| nsec.covers(name) && !nsec.is_cut_above(name))
+}
+
+// The parent side of a zone cut, which owns NS but not SOA records
+fn is_delegation(types: &[ResourceRecordType]) -> bool {
+    types.contains(&ResourceRecordType::NS) && !types.contains(&ResourceRecordType::SOA)
+}
+
+// Whether a type bitmap proves that `name` has no `qtype` records
+fn lacks_type(types: &[ResourceRecordType], qtype: ResourceRecordType, at_root: bool) -> bool {
+    if types.contains(&qtype) || types.contains(&ResourceRecordType::CNAME) {
+        return false;
+    }
+    if qtype == ResourceRecordType::DS {
+        // DS records are denied by the parent, not by the apex of the child. Only the
+        // root has no parent.
+        at_root || !types.contains(&ResourceRecordType::SOA)
+    } else {
+        !is_delegation(types)
+    }
+}
+
+fn wildcard(encloser: &[DNSLabel]) -> Vec<DNSLabel> {
+    let mut wildcard = vec![DNSLabel::new("*").unwrap()];
+    wildcard.extend_from_slice(encloser);
+    wildcard
+}
+
+// The closest encloser that an NSEC covering `name` implies: the longest ancestor that
+// `name` shares with the owner or the next name
+fn nsec_closest_encloser(name: &[DNSLabel], nsec: &Nsec) -> Vec<DNSLabel> {
+    let shared = |other: &[DNSLabel]| {
+        name.iter()
+            .rev()
+            .zip(other.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count()
+    };
+    // A name that does not exist is enclosed by a proper ancestor, even when the next
+    // name lies right below it as with minimally covering NSEC records
+    let depth = shared(nsec.owner)
+        .max(shared(&nsec.next))
+        .min(name.len().saturating_sub(1));
+    name[name.len() - depth..].to_vec()
+}
+
+/// Checks the proof in `records`, whose signatures have been verified, that `name` does
+/// not exist in `zone`.
+pub fn prove_nxdomain(
+    name: &[DNSLabel],
+    zone: &[DNSLabel],
+    records: &[DnsResourceRecord],
+) -> Security {
+    let nsecs = nsec_records(zone, records);
+    if !nsecs.is_empty() {
+        let covering = match find_covering(&nsecs, name) {
+            Some(covering) => covering,
+            None => return Security::Bogus,
+        };
+        let wildcard = wildcard(&nsec_closest_encloser(name, covering));
+        return if find_covering(&nsecs, &wildcard).is_some() {
+            Security::Secure
+        } else {
+            Security::Bogus
+        };
+    }
+
+    let chain = match Nsec3Chain::new(zone, records) {
+        Some(chain) => chain,
+        None => return Security::Bogus,
+    };
+    if chain.iterations > MAX_NSEC3_ITERATIONS {
+        return Security::Insecure;
+    }
+    if chain.matching(name).is_some() {
+        return Security::Bogus;
+    }
+    let (encloser, next_closer) = match chain.closest_encloser(name, zone) {
+        Some(proof) => proof,
+        None => return Security::Bogus,
+    };
+    if chain.covering(&wildcard(&encloser)).is_none() {
+        return Security::Bogus;
+    }
+    if next_closer.opt_out() {
+        Security::Insecure
+    } else {
+        Security::Secure
+    }
+}
+
+/// Checks the proof in `records`, whose signatures have been verified, that `name`
+/// exists in `zone` but has no records of type `qtype`.
+pub fn prove_nodata(
+    name: &[DNSLabel],
+    qtype: ResourceRecordType,
+    zone: &[DNSLabel],
+    records: &[DnsResourceRecord],
+) -> Security {
+    let at_root = name.is_empty();
+    let nsecs = nsec_records(zone, records);
+    if !nsecs.is_empty() {
+        if let Some(nsec) = nsecs.iter().find(|nsec| nsec.owner == name) {
+            return match lacks_type(nsec.types, qtype, at_root) {
+                true => Security::Secure,
+                false => Security::Bogus,
+            };
+        }
+        let covering = match find_covering(&nsecs, name) {
+            Some(covering) => covering,
+            None => return Security::Bogus,
+        };
+        // An empty non-terminal sorts directly before its descendants
+        if is_subdomain(&covering.next, name) {
+            return Security::Secure;
+        }
+        // Or the name matches a wildcard without records of the type
+        let wildcard = wildcard(&nsec_closest_encloser(name, covering));
+        return match nsecs.iter().find(|nsec| nsec.owner == wildcard) {
+            Some(nsec) if lacks_type(nsec.types, qtype, false) => Security::Secure,
+            _ => Security::Bogus,
+        };
+    }
+
+    let chain = match Nsec3Chain::new(zone, records) {
+        Some(chain) => chain,
+        None => return Security::Bogus,
+    };
+    if chain.iterations > MAX_NSEC3_ITERATIONS {
+        return Security::Insecure;
+    }
+    if let Some(nsec3) = chain.matching(name) {
+        return match lacks_type(nsec3.types, qtype, at_root) {
+            true => Security::Secure,
+            false => Security::Bogus,
+        };
+    }
+    let (encloser, next_closer) = match chain.closest_encloser(name, zone) {
+        Some(proof) => proof,
+        None => return Security::Bogus,
+    };
+    // No DS below an opt-out span (RFC 5155, section 8.6)
+    if qtype == ResourceRecordType::DS && next_closer.opt_out() {
+        return Security::Insecure;
+    }
+    match chain.matching(&wildcard(&encloser)) {
+        Some(nsec3) if lacks_type(nsec3.types, qtype, false) => Security::Secure,
+        _ => Security::Bogus,
+    }
+}
+
+/// Checks that `records`, whose signatures have been verified, prove that `zone` has no
+/// DS records at its delegation in `parent`, so that it is unsigned.
+pub fn proves_unsigned_delegation(
+    zone: &[DNSLabel],
+    parent: &[DNSLabel],
+    records: &[DnsResourceRecord],
+) -> bool {
+    let nsecs = nsec_records(parent, records);
+    if !nsecs.is_empty() {
+        return nsecs.iter().any(|nsec| {
+            nsec.owner == zone
+                && is_delegation(nsec.types)
+                && !nsec.types.contains(&ResourceRecordType::DS)
+        });
+    }
+
+    let chain = match Nsec3Chain::new(parent, records) {
+        Some(chain) => chain,
+        None => return false,
+    };
+    if chain.iterations > MAX_NSEC3_ITERATIONS {
+        return true;
+    }
+    match chain.matching(zone) {
+        Some(nsec3) => is_delegation(nsec3.types) && !nsec3.types.contains(&ResourceRecordType::DS),
+        None => chain
+            .closest_encloser(zone, parent)
+            .is_some_and(|(_, next_closer)| next_closer.opt_out()),
+    }
+}
+
+/// Checks the proof that `name`, answered from a wildcard with `labels` labels, has no
+/// records of its own (RFC 4035, section 5.3.4, and RFC 5155, section 8.8).
+pub fn prove_wildcard_expansion(
+    name: &[DNSLabel],
+    labels: u8,
+    zone: &[DNSLabel],
+    records: &[DnsResourceRecord],
+) -> Security {
+    let nsecs = nsec_records(zone, records);
+    if !nsecs.is_empty() {
+        return match find_covering(&nsecs, name) {
+            Some(_) => Security::Secure,
+            None => Security::Bogus,
+        };
+    }
+
+    let chain = match Nsec3Chain::new(zone, records) {
+        Some(chain) => chain,
+        None => return Security::Bogus,
+    };
+    if chain.iterations > MAX_NSEC3_ITERATIONS {
+        return Security::Insecure;
+    }
+    let labels = labels as usize;
+    if labels >= name.len() {
+        return Security::Bogus;
+    }
+    match chain.covering(&name[name.len() - labels - 1..]) {
+        Some(nsec3) if nsec3.opt_out() => Security::Insecure,
+        Some(_) => Security::Secure,
+        None => Security::Bogus,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dnssec::base32hex_encode,
+        resourcerecord::{DnsClass, ResourceRecordType::*},
+    };
+    use Security::{Bogus, Insecure, Secure};
+
+    // The names of example. with their types, including the empty non-terminals c and w
+    // that only NSEC3 chains list. sub is an unsigned delegation.
+    const EXAMPLE: &[(&str, &[ResourceRecordType])] = &[
+        ("example", &[SOA, NS, RRSIG, DNSKEY]),
+        ("a.example", &[A, RRSIG]),
+        ("c.example", &[]),
+        ("b.c.example", &[A, RRSIG]),
+        ("sub.example", &[NS]),
+        ("w.example", &[]),
+        ("*.w.example", &[TXT, RRSIG]),
+    ];
+
+    const NSEC: Denial = Denial::Nsec;
+    const NSEC3: Denial = Denial::Nsec3 {
+        flags: 0,
+        iterations: 1,
+    };
+    const OPT_OUT: Denial = Denial::Nsec3 {
+        flags: NSEC3_OPT_OUT,
+        iterations: 1,
+    };
+    const AT_CAP: Denial = Denial::Nsec3 {
+        flags: 0,
+        iterations: MAX_NSEC3_ITERATIONS,
+    };
+    const OVER_CAP: Denial = Denial::Nsec3 {
+        flags: 0,
+        iterations: MAX_NSEC3_ITERATIONS + 1,
+    };
+
+    // A row of a table test: the denial chain, the names whose records match, the names
+    // whose records cover, and the expected result
+    type Case = (
+        Denial,
+        &'static [&'static str],
+        &'static [&'static str],
+        Security,
+    );
+
+    fn name(name: &str) -> Vec<DNSLabel> {
+        labels_from_str(name).unwrap()
+    }
+
+    fn record(owner: Vec<DNSLabel>, rdata: DnsRecordData) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: owner,
+            rtype: rdata.to_type(),
+            class: DnsClass::IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[ResourceRecordType]) -> DnsResourceRecord {
+        let rdata = DnsRecordData::NSEC {
+            next_domain: next.to_string(),
+            types: types.to_vec(),
+        };
+        record(name(owner), rdata)
+    }
+
+    #[derive(Clone, Copy)]
+    enum Denial {
+        Nsec,
+        Nsec3 { flags: u8, iterations: u16 },
+    }
+
+    impl Denial {
+        // The chain as (key, owner, types), in the order of its keys
+        fn chain(self) -> Vec<(Vec<u8>, &'static str, &'static [ResourceRecordType])> {
+            let mut chain: Vec<_> = EXAMPLE
+                .iter()
+                .filter(|(_, types)| matches!(self, Denial::Nsec3 { .. }) || !types.is_empty())
+                .map(|&(owner, types)| (self.key(owner), owner, types))
+                .collect();
+            chain.sort_by(|a, b| a.0.cmp(&b.0));
+            chain
+        }
+
+        // Where a name sorts in the chain: its canonical form, or its hash
+        fn key(self, owner: &str) -> Vec<u8> {
+            match self {
+                Denial::Nsec => {
+                    let mut key = vec![];
+                    for label in name(owner).iter().rev() {
+                        key.extend(label.0.to_ascii_lowercase().bytes());
+                        key.push(0);
+                    }
+                    key
+                }
+                Denial::Nsec3 { iterations, .. } => nsec3_hash(&name(owner), b"", iterations),
+            }
+        }
+
+        // The record of the chain at `index`
+        fn record(self, index: usize) -> DnsResourceRecord {
+            let chain = self.chain();
+            let (key, owner, types) = &chain[index];
+            let (next, next_owner, _) = &chain[(index + 1) % chain.len()];
+            match self {
+                Denial::Nsec => nsec(owner, next_owner, types),
+                Denial::Nsec3 { flags, iterations } => {
+                    let mut owner = name("example");
+                    owner.insert(0, DNSLabel::from(base32hex_encode(key)));
+                    let rdata = DnsRecordData::NSEC3 {
+                        hash_algorithm: NSEC3_HASH_SHA1,
+                        flags,
+                        iterations,
+                        salt: vec![],
+                        next_hashed: next.clone(),
+                        types: types.to_vec(),
+                    };
+                    record(owner, rdata)
+                }
+            }
+        }
+
+        // The record owned by `owner`
+        fn matching(self, owner: &str) -> DnsResourceRecord {
+            let index = self.chain().iter().position(|entry| entry.1 == owner);
+            self.record(index.unwrap_or_else(|| panic!("{} is not in the chain", owner)))
+        }
+
+        // The record whose span covers `name`, which must not be in the chain
+        fn covering(self, name: &str) -> DnsResourceRecord {
+            let key = self.key(name);
+            let chain = self.chain();
+            assert!(chain.iter().all(|entry| entry.0 != key));
+            let index = chain.iter().rposition(|entry| entry.0 < key);
+            self.record(index.unwrap_or(chain.len() - 1))
+        }
+
+        fn proof(self, matching: &[&str], covering: &[&str]) -> Vec<DnsResourceRecord> {
+            let matching = matching.iter().map(|owner| self.matching(owner));
+            let covering = covering.iter().map(|name| self.covering(name));
+            matching.chain(covering).collect()
+        }
+    }
+
+    fn check(cases: &[Case], prove: impl Fn(&[DnsResourceRecord]) -> Security) {
+        for (index, &(denial, matching, covering, expected)) in cases.iter().enumerate() {
+            let records = denial.proof(matching, covering);
+            assert_eq!(prove(&records), expected, "case {}", index);
+        }
+    }
+
+    #[test]
+    fn test_nxdomain() {
+        let cases: &[Case] = &[
+            (NSEC, &[], &["x.example", "*.example"], Secure),
+            (NSEC3, &["example"], &["x.example", "*.example"], Secure),
+            (OVER_CAP, &[], &["x.example"], Insecure),
+            // No proof that the wildcard does not exist
+            (NSEC, &[], &["x.example"], Bogus),
+            (NSEC3, &["example"], &["x.example"], Bogus),
+        ];
+        check(cases, |records| {
+            prove_nxdomain(&name("x.example"), &name("example"), records)
+        });
+
+        // The name exists
+        let records = NSEC3.proof(&["example", "a.example"], &["*.example"]);
+        assert_eq!(
+            prove_nxdomain(&name("a.example"), &name("example"), &records),
+            Bogus
+        );
+
+        // The NSEC of the delegation to sub covers names below it, but says nothing about
+        // what the child zone holds
+        let records = NSEC.proof(&[], &["www.sub.example", "*.example"]);
+        assert_eq!(
+            prove_nxdomain(&name("www.sub.example"), &name("example"), &records),
+            Bogus
+        );
+        let dname = [nsec("d.example", "example", &[DNAME, RRSIG])];
+        assert_eq!(
+            prove_nxdomain(&name("www.d.example"), &name("example"), &dname),
+            Bogus
+        );
+    }
+
+    #[test]
+    fn test_nodata() {
+        let cases: &[Case] = &[
+            (NSEC, &["a.example"], &[], Secure),
+            (NSEC3, &["a.example"], &[], Secure),
+            (OVER_CAP, &["a.example"], &[], Insecure),
+            // The type exists
+            (NSEC, &["b.c.example"], &[], Bogus),
+            (NSEC3, &["b.c.example"], &[], Bogus),
+        ];
+        check(&cases[..3], |records| {
+            prove_nodata(&name("a.example"), AAAA, &name("example"), records)
+        });
+        check(&cases[3..], |records| {
+            prove_nodata(&name("b.c.example"), A, &name("example"), records)
+        });
+
+        // A delegation without DS is proven by the parent, but not by the apex of the child
+        let ds = |records: &[DnsResourceRecord]| {
+            prove_nodata(&name("sub.example"), DS, &name("example"), records)
+        };
+        assert_eq!(ds(&NSEC.proof(&["sub.example"], &[])), Secure);
+        assert_eq!(ds(&NSEC3.proof(&["sub.example"], &[])), Secure);
+        let child_apex = [nsec("sub.example", "www.sub.example", &[SOA, NS, RRSIG])];
+        assert_eq!(
+            prove_nodata(&name("sub.example"), DS, &name("sub.example"), &child_apex),
+            Bogus
+        );
+        let root = [nsec(".", "com.", &[SOA, NS, RRSIG, DNSKEY])];
+        assert_eq!(prove_nodata(&[], DS, &[], &root), Secure);
+
+        // A delegation only proves that the parent has no data other than NS and DS
+        let records = NSEC.proof(&["sub.example"], &[]);
+        assert_eq!(
+            prove_nodata(&name("sub.example"), A, &name("example"), &records),
+            Bogus
+        );
+    }
+
+    #[test]
+    fn test_empty_non_terminal() {
+        let cases: &[Case] = &[
+            (NSEC, &[], &["c.example"], Secure),
+            (NSEC3, &["c.example"], &[], Secure),
+            (OVER_CAP, &["c.example"], &[], Insecure),
+            // The record does not cover the name
+            (NSEC, &["example"], &[], Bogus),
+            // No record for the name itself
+            (NSEC3, &["a.example"], &[], Bogus),
+        ];
+        check(cases, |records| {
+            prove_nodata(&name("c.example"), A, &name("example"), records)
+        });
+    }
+
+    #[test]
+    fn test_wildcard_nodata() {
+        let cases: &[Case] = &[
+            (NSEC, &["*.w.example"], &["v.w.example"], Secure),
+            (
+                NSEC3,
+                &["w.example", "*.w.example"],
+                &["v.w.example"],
+                Secure,
+            ),
+            (OVER_CAP, &["*.w.example"], &[], Insecure),
+            // The wildcard has the type
+            (NSEC, &["*.w.example"], &["v.w.example"], Bogus),
+            (
+                NSEC3,
+                &["w.example", "*.w.example"],
+                &["v.w.example"],
+                Bogus,
+            ),
+        ];
+        check(&cases[..3], |records| {
+            prove_nodata(&name("v.w.example"), A, &name("example"), records)
+        });
+        check(&cases[3..], |records| {
+            prove_nodata(&name("v.w.example"), TXT, &name("example"), records)
+        });
+    }
+
+    #[test]
+    fn test_wildcard_expansion() {
+        let cases: &[Case] = &[
+            (NSEC, &[], &["v.w.example"], Secure),
+            (NSEC3, &[], &["v.w.example"], Secure),
+            (OPT_OUT, &[], &["v.w.example"], Insecure),
+            (OVER_CAP, &["a.example"], &[], Insecure),
+            // Nothing covers the name
+            (NSEC, &["a.example"], &[], Bogus),
+            (NSEC3, &["a.example"], &[], Bogus),
+        ];
+        check(cases, |records| {
+            prove_wildcard_expansion(&name("v.w.example"), 2, &name("example"), records)
+        });
+
+        // The wildcard cannot have more labels than the name
+        let records = NSEC3.proof(&[], &["v.w.example"]);
+        assert_eq!(
+            prove_wildcard_expansion(&name("v.w.example"), 3, &name("example"), &records),
+            Bogus
+        );
+        // Nor can records below a delegation be synthesized from a wildcard of the parent
+        let records = NSEC.proof(&[], &["v.sub.example"]);
+        assert_eq!(
+            prove_wildcard_expansion(&name("v.sub.example"), 1, &name("example"), &records),
+            Bogus
+        );
+    }
+
+    #[test]
+    fn test_nsec3_opt_out() {
+        let cases: &[Case] = &[
+            (NSEC3, &["example"], &["x.example", "*.example"], Secure),
+            (OPT_OUT, &["example"], &["x.example", "*.example"], Insecure),
+            // Opt-out does not excuse a missing wildcard proof
+            (OPT_OUT, &["example"], &["x.example"], Bogus),
+        ];
+        check(cases, |records| {
+            prove_nxdomain(&name("x.example"), &name("example"), records)
+        });
+
+        // An unsigned delegation may be left out of an opt-out span
+        let records = OPT_OUT.proof(&["example"], &["x.example"]);
+        assert_eq!(
+            prove_nodata(&name("x.example"), DS, &name("example"), &records),
+            Insecure
+        );
+        assert!(proves_unsigned_delegation(
+            &name("x.example"),
+            &name("example"),
+            &records
+        ));
+        let records = NSEC3.proof(&["example"], &["x.example"]);
+        assert!(!proves_unsigned_delegation(
+            &name("x.example"),
+            &name("example"),
+            &records
+        ));
+    }
+
+    #[test]
+    fn test_nsec3_iteration_cap() {
+        let cases: &[Case] = &[
+            (AT_CAP, &["example"], &["x.example", "*.example"], Secure),
+            (
+                OVER_CAP,
+                &["example"],
+                &["x.example", "*.example"],
+                Insecure,
+            ),
+            (OVER_CAP, &["a.example"], &[], Insecure),
+            // Only the next closer name is covered
+            (AT_CAP, &[], &["x.example"], Bogus),
+        ];
+        check(cases, |records| {
+            prove_nxdomain(&name("x.example"), &name("example"), records)
+        });
+    }
+}
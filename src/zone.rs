@@ -0,0 +1,1166 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Cursor},
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use crate::{
+    dnssec::{base32hex_decode, parse_timestamp, RrsigData},
+    label::{is_subdomain, labels_from_str, labels_to_string, CanonicalName, DNSLabel},
+    resourcerecord::{DnsClass, ResourceRecordType},
+    response::{DnsRecordData, DnsResourceRecord},
+    svcb::SvcbData,
+};
+
+/// The outcome of looking up a name and type in a [`Zone`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    /// The records of the requested type, synthesized from a wildcard if the name has
+    /// no records of its own
+    Answer(Vec<DnsResourceRecord>),
+    /// The name is an alias; the lookup continues at the CNAME's target.
+    Cname(DnsResourceRecord),
+    /// A DNAME above the name redirects it, with the CNAME synthesized from it
+    Dname {
+        dname: DnsResourceRecord,
+        cname: DnsResourceRecord,
+    },
+    /// The name lies in a delegated child zone.
+    Referral {
+        ns: Vec<DnsResourceRecord>,
+        glue: Vec<DnsResourceRecord>,
+    },
+    NoData,
+    NxDomain,
+}
+
+/// The records of one zone, ordered canonically by owner name
+#[derive(Debug, Clone)]
+pub struct Zone {
+    origin: Vec<DNSLabel>,
+    nodes: BTreeMap<CanonicalName, Vec<DnsResourceRecord>>,
+}
+
+impl Zone {
+    pub fn new(origin: Vec<DNSLabel>) -> Self {
+        Zone {
+            origin,
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Builds a zone from `records`, which have to lie within `origin` and include the
+    /// SOA record of the zone apex.
+    pub fn from_records(
+        origin: Vec<DNSLabel>,
+        records: impl IntoIterator<Item = DnsResourceRecord>,
+    ) -> io::Result<Self> {
+        let mut zone = Zone::new(origin);
+        for record in records {
+            zone.insert(record)?;
+        }
+        if zone.soa().is_none() {
+            return Err(invalid(format!(
+                "zone {}. has no SOA record",
+                labels_to_string(&zone.origin)
+            )));
+        }
+        Ok(zone)
+    }
+
+    /// Parses a zone from master file text (RFC 1035, section 5).
+    pub fn parse(text: &str, origin: &str) -> io::Result<Self> {
+        let origin = labels_from_str(origin).map_err(|_| invalid("invalid zone origin"))?;
+        let records = parse_master_file(text, &origin)?;
+        Zone::from_records(origin, records)
+    }
+
+    /// Loads a zone from a master file.
+    pub fn load(path: impl AsRef<Path>, origin: &str) -> io::Result<Self> {
+        Zone::parse(&std::fs::read_to_string(path)?, origin)
+    }
+
+    /// Writes the zone as master file text with absolute names, the SOA record first.
+    pub fn to_master_file(&self) -> String {
+        let mut text = String::new();
+        let soa = self.soa();
+        for record in soa
+            .into_iter()
+            .chain(self.records().filter(|record| Some(*record) != soa))
+        {
+            text.push_str(&record.to_string());
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Saves the zone to a master file, see [`Zone::to_master_file`].
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_master_file())
+    }
+
+    pub fn origin(&self) -> &[DNSLabel] {
+        &self.origin
+    }
+
+    pub fn soa(&self) -> Option<&DnsResourceRecord> {
+        self.node(&self.origin)?
+            .iter()
+            .find(|record| record.rtype == ResourceRecordType::SOA)
+    }
+
+    pub fn serial(&self) -> Option<u32> {
+        match self.soa()?.rdata {
+            DnsRecordData::SOA { serial, .. } => Some(serial),
+            _ => None,
+        }
+    }
+
+    /// Adds a record, ignoring exact duplicates of records already in the zone.
+    pub fn insert(&mut self, record: DnsResourceRecord) -> io::Result<()> {
+        if !is_subdomain(&record.name, &self.origin) {
+            return Err(invalid(format!(
+                "{}. is outside of zone {}.",
+                labels_to_string(&record.name),
+                labels_to_string(&self.origin)
+            )));
+        }
+
+        let node = self
+            .nodes
+            .entry(CanonicalName(record.name.clone()))
+            .or_default();
+        if !node
+            .iter()
+            .any(|existing| existing.rtype == record.rtype && existing.rdata == record.rdata)
+        {
+            node.push(record);
+        }
+        Ok(())
+    }
+
+    /// Removes the records of type `rtype` owned by `name` and returns them.
+    pub fn remove_rrset(
+        &mut self,
+        name: &[DNSLabel],
+        rtype: ResourceRecordType,
+    ) -> Vec<DnsResourceRecord> {
+        let key = CanonicalName(name.to_vec());
+        let node = match self.nodes.get_mut(&key) {
+            Some(node) => node,
+            None => return vec![],
+        };
+        let (removed, kept) = std::mem::take(node)
+            .into_iter()
+            .partition(|record| record.rtype == rtype);
+        *node = kept;
+        if node.is_empty() {
+            self.nodes.remove(&key);
+        }
+        removed
+    }
+
+    /// Removes the record with the owner, type and data of `record`, whatever its TTL.
+    /// Returns whether there was one.
+    pub fn remove(&mut self, record: &DnsResourceRecord) -> bool {
+        let key = CanonicalName(record.name.clone());
+        let node = match self.nodes.get_mut(&key) {
+            Some(node) => node,
+            None => return false,
+        };
+        let before = node.len();
+        node.retain(|existing| existing.rtype != record.rtype || existing.rdata != record.rdata);
+        let removed = node.len() != before;
+        if node.is_empty() {
+            self.nodes.remove(&key);
+        }
+        removed
+    }
+
+    /// All records of the zone in canonical order of their owner names
+    pub fn records(&self) -> impl Iterator<Item = &DnsResourceRecord> {
+        self.nodes.values().flatten()
+    }
+
+    /// The records owned by exactly `name`
+    pub fn node(&self, name: &[DNSLabel]) -> Option<&[DnsResourceRecord]> {
+        self.nodes
+            .get(&CanonicalName(name.to_vec()))
+            .map(|records| records.as_slice())
+    }
+
+    pub fn rrset(&self, name: &[DNSLabel], rtype: ResourceRecordType) -> Vec<DnsResourceRecord> {
+        self.node(name)
+            .unwrap_or_default()
+            .iter()
+            .filter(|record| record.rtype == rtype)
+            .cloned()
+            .collect()
+    }
+
+    /// The last record of type `rtype` whose owner sorts canonically at or before
+    /// `name`, wrapping around to the last one in the zone. This finds the NSEC or
+    /// NSEC3 record that matches or covers a name.
+    pub fn preceding(
+        &self,
+        name: &[DNSLabel],
+        rtype: ResourceRecordType,
+    ) -> Option<&DnsResourceRecord> {
+        fn find(
+            records: &[DnsResourceRecord],
+            rtype: ResourceRecordType,
+        ) -> Option<&DnsResourceRecord> {
+            records.iter().find(|record| record.rtype == rtype)
+        }
+        self.nodes
+            .range(..=CanonicalName(name.to_vec()))
+            .rev()
+            .find_map(|(_, records)| find(records, rtype))
+            .or_else(|| {
+                self.nodes
+                    .values()
+                    .rev()
+                    .find_map(|records| find(records, rtype))
+            })
+    }
+
+    /// The last owner name that sorts canonically before `name`
+    pub fn previous_name(&self, name: &[DNSLabel]) -> Option<&[DNSLabel]> {
+        self.nodes
+            .range(..CanonicalName(name.to_vec()))
+            .next_back()
+            .map(|(previous, _)| previous.0.as_slice())
+    }
+
+    /// Whether `name` owns records or is an empty non-terminal above names that do
+    pub fn name_exists(&self, name: &[DNSLabel]) -> bool {
+        // Subdomains sort directly after their ancestor
+        self.nodes
+            .range(CanonicalName(name.to_vec())..)
+            .next()
+            .is_some_and(|(next, _)| is_subdomain(&next.0, name))
+    }
+
+    /// The longest ancestor of `name`, or `name` itself, that exists in the zone
+    pub fn closest_encloser<'a>(&'a self, name: &'a [DNSLabel]) -> &'a [DNSLabel] {
+        (self.origin.len()..=name.len())
+            .rev()
+            .map(|depth| &name[name.len() - depth..])
+            .find(|ancestor| self.name_exists(ancestor))
+            .unwrap_or(&self.origin)
+    }
+
+    /// Looks up `name` and `rtype`, following the rules of RFC 1034, section 4.3.2 for
+    /// delegations, aliases and wildcards.
+    pub fn lookup(&self, name: &[DNSLabel], rtype: ResourceRecordType) -> Lookup {
+        if !is_subdomain(name, &self.origin) {
+            return Lookup::NxDomain;
+        }
+
+        // Delegations and DNAMEs between the apex and the name
+        for depth in self.origin.len()..=name.len() {
+            let ancestor = &name[name.len() - depth..];
+            let at_name = depth == name.len();
+
+            if depth > self.origin.len() && !(at_name && rtype == ResourceRecordType::DS) {
+                let ns = self.rrset(ancestor, ResourceRecordType::NS);
+                if !ns.is_empty() {
+                    let glue = self.glue(&ns);
+                    return Lookup::Referral { ns, glue };
+                }
+            }
+
+            if !at_name {
+                if let Some(dname) = self.rrset(ancestor, ResourceRecordType::DNAME).pop() {
+                    return synthesize_cname(name, ancestor, dname);
+                }
+            }
+        }
+
+        if let Some(records) = self.node(name) {
+            return answer_from(records, name, rtype);
+        }
+        if self.name_exists(name) {
+            return Lookup::NoData;
+        }
+
+        // Wildcard at the closest encloser (RFC 4592)
+        let mut wildcard = vec![DNSLabel::new("*").unwrap()];
+        wildcard.extend_from_slice(self.closest_encloser(name));
+        match self.node(&wildcard) {
+            Some(records) => answer_from(records, name, rtype),
+            None => Lookup::NxDomain,
+        }
+    }
+
+    // Address records for name servers inside the zone
+    fn glue(&self, ns: &[DnsResourceRecord]) -> Vec<DnsResourceRecord> {
+        let mut glue = Vec::new();
+        for record in ns {
+            let target = match &record.rdata {
+                DnsRecordData::NS(target) => labels_from_str(target).unwrap_or_default(),
+                _ => continue,
+            };
+            if is_subdomain(&target, &self.origin) {
+                glue.extend(self.rrset(&target, ResourceRecordType::A));
+                glue.extend(self.rrset(&target, ResourceRecordType::AAAA));
+            }
+        }
+        glue
+    }
+}
+
+#[cfg(test)]
+impl Zone {
+    /// The zone example.com. from `text` and the records in `extra`, with `serial` as
+    /// its serial, for tests that go through several versions of a zone
+    pub(crate) fn version(text: &str, serial: u32, extra: &str) -> Zone {
+        let mut zone = Zone::parse(&format!("{}{}", text, extra), "example.com.").unwrap();
+        let apex = CanonicalName(zone.origin.clone());
+        for record in zone.nodes.get_mut(&apex).into_iter().flatten() {
+            if let DnsRecordData::SOA {
+                serial: current, ..
+            } = &mut record.rdata
+            {
+                *current = serial;
+            }
+        }
+        zone
+    }
+}
+
+// Answers from the records of a node, renaming them to `name` for wildcard matches
+fn answer_from(
+    records: &[DnsResourceRecord],
+    name: &[DNSLabel],
+    rtype: ResourceRecordType,
+) -> Lookup {
+    let rename = |record: &DnsResourceRecord| DnsResourceRecord {
+        name: name.to_vec(),
+        ..record.clone()
+    };
+
+    let matching: Vec<DnsResourceRecord> = records
+        .iter()
+        .filter(|record| rtype == ResourceRecordType::ANY || record.rtype == rtype)
+        .map(rename)
+        .collect();
+    if !matching.is_empty() {
+        return Lookup::Answer(matching);
+    }
+
+    match records
+        .iter()
+        .find(|record| record.rtype == ResourceRecordType::CNAME)
+    {
+        Some(cname) => Lookup::Cname(rename(cname)),
+        None => Lookup::NoData,
+    }
+}
+
+// Replaces the DNAME owner at the end of `name` with the DNAME target (RFC 6672)
+fn synthesize_cname(name: &[DNSLabel], owner: &[DNSLabel], dname: DnsResourceRecord) -> Lookup {
+    let target = match &dname.rdata {
+        DnsRecordData::DNAME(target) => labels_from_str(target).unwrap_or_default(),
+        _ => return Lookup::NoData,
+    };
+    let mut synthesized = name[..name.len() - owner.len()].to_vec();
+    synthesized.extend(target);
+
+    let cname = DnsResourceRecord {
+        name: name.to_vec(),
+        rtype: ResourceRecordType::CNAME,
+        class: dname.class,
+        ttl: dname.ttl,
+        rdata: DnsRecordData::CNAME(labels_to_string(&synthesized)),
+    };
+    Lookup::Dname { dname, cname }
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+// One logical line of a master file, with parentheses joined
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+fn entries(text: &str) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut in_token = false;
+    let mut quoted = false;
+    let mut whole_quoted = false;
+    let mut depth = 0;
+    let mut line = 1;
+    let mut entry_line = 1;
+    let mut blank_owner = false;
+    let mut at_line_start = true;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if at_line_start && depth == 0 && tokens.is_empty() && !in_token {
+            blank_owner = c == ' ' || c == '\t';
+            entry_line = line;
+        }
+        at_line_start = false;
+
+        match c {
+            // A quote inside a token, as in `alpn="h2,h3"`, is kept as part of it
+            '"' => {
+                if quoted && whole_quoted {
+                    tokens.push(Token {
+                        text: std::mem::take(&mut token),
+                        quoted: true,
+                    });
+                    in_token = false;
+                } else if quoted || in_token {
+                    token.push(c);
+                    whole_quoted = false;
+                } else {
+                    in_token = true;
+                    whole_quoted = true;
+                }
+                quoted = !quoted;
+            }
+            '\\' => {
+                token.push(c);
+                match chars.next() {
+                    Some('\n') | None => {
+                        return Err(invalid(format!("line {}: dangling escape", line)))
+                    }
+                    Some(escaped) => token.push(escaped),
+                }
+                in_token = true;
+            }
+            _ if quoted => {
+                if c == '\n' {
+                    return Err(invalid(format!("line {}: unterminated string", line)));
+                }
+                token.push(c);
+            }
+            ';' => {
+                while chars.peek().is_some_and(|&next| next != '\n') {
+                    chars.next();
+                }
+            }
+            '(' | ')' | ' ' | '\t' | '\r' | '\n' => {
+                if in_token {
+                    tokens.push(Token {
+                        text: std::mem::take(&mut token),
+                        quoted: false,
+                    });
+                    in_token = false;
+                }
+                match c {
+                    '(' => depth += 1,
+                    ')' if depth == 0 => {
+                        return Err(invalid(format!("line {}: unbalanced parenthesis", line)))
+                    }
+                    ')' => depth -= 1,
+                    '\n' => {
+                        line += 1;
+                        at_line_start = true;
+                        if depth == 0 && !tokens.is_empty() {
+                            entries.push(Entry {
+                                line: entry_line,
+                                blank_owner,
+                                tokens: std::mem::take(&mut tokens),
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            c => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if quoted {
+        return Err(invalid(format!("line {}: unterminated string", line)));
+    }
+    if depth != 0 {
+        return Err(invalid(format!("line {}: unbalanced parenthesis", line)));
+    }
+    if in_token {
+        tokens.push(Token {
+            text: token,
+            quoted: false,
+        });
+    }
+    if !tokens.is_empty() {
+        entries.push(Entry {
+            line: entry_line,
+            blank_owner,
+            tokens,
+        });
+    }
+    Ok(entries)
+}
+
+/// Parses the records of a master file (RFC 1035, section 5), supporting `$ORIGIN`,
+/// `$TTL`, `@`, relative names, omitted owners, TTLs and classes, and TTL units such as
+/// `1h30m`. Unknown types can be given in the generic `\# length hex` form of RFC 3597.
+pub fn parse_master_file(text: &str, origin: &[DNSLabel]) -> io::Result<Vec<DnsResourceRecord>> {
+    let mut origin = origin.to_vec();
+    let mut default_ttl = None;
+    let mut last_owner: Option<Vec<DNSLabel>> = None;
+    let mut last_class = DnsClass::IN;
+    let mut records = Vec::new();
+
+    for entry in entries(text)? {
+        let at = |e: io::Error| invalid(format!("line {}: {}", entry.line, e));
+        let mut tokens = entry.tokens.iter().peekable();
+
+        let first = &entry.tokens[0];
+        if !first.quoted && first.text.starts_with('$') {
+            let argument = entry
+                .tokens
+                .get(1)
+                .ok_or_else(|| at(invalid(format!("{} needs an argument", first.text))))?;
+            match first.text.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => origin = parse_name(&argument.text, &origin).map_err(at)?,
+                "$TTL" => default_ttl = Some(parse_ttl(&argument.text).map_err(at)?),
+                directive => {
+                    return Err(at(invalid(format!("unsupported directive {}", directive))))
+                }
+            }
+            continue;
+        }
+
+        let owner = if entry.blank_owner {
+            last_owner
+                .clone()
+                .ok_or_else(|| at(invalid("record without owner")))?
+        } else {
+            let owner = tokens.next().unwrap();
+            parse_name(&owner.text, &origin).map_err(at)?
+        };
+
+        let mut ttl = None;
+        let mut class = None;
+        let rtype = loop {
+            let token = tokens
+                .next()
+                .ok_or_else(|| at(invalid("missing record type")))?;
+            if ttl.is_none()
+                && token
+                    .text
+                    .bytes()
+                    .next()
+                    .is_some_and(|b| b.is_ascii_digit())
+            {
+                ttl = Some(parse_ttl(&token.text).map_err(at)?);
+            } else if class.is_none() && is_class(&token.text) {
+                class = Some(token.text.parse::<DnsClass>().map_err(at)?);
+            } else {
+                break token.text.parse::<ResourceRecordType>().map_err(at)?;
+            }
+        };
+
+        let rdata_tokens: Vec<Token> = tokens.cloned().collect();
+        let rdata = parse_rdata(rtype, &rdata_tokens, &origin).map_err(at)?;
+
+        let class = class.unwrap_or(last_class);
+        let ttl = match (ttl, default_ttl, &rdata) {
+            (Some(ttl), _, _) => ttl,
+            (None, Some(ttl), _) => ttl,
+            // Without $TTL, RFC 1035 falls back to the SOA minimum
+            (None, None, DnsRecordData::SOA { minimum, .. }) => *minimum,
+            (None, None, _) => records
+                .last()
+                .map(|record: &DnsResourceRecord| record.ttl)
+                .ok_or_else(|| at(invalid("no TTL given")))?,
+        };
+
+        last_owner = Some(owner.clone());
+        last_class = class;
+        records.push(DnsResourceRecord {
+            name: owner,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        });
+    }
+
+    Ok(records)
+}
+
+fn is_class(token: &str) -> bool {
+    let upper = token.to_ascii_uppercase();
+    matches!(upper.as_str(), "IN" | "CH" | "HS") || upper.starts_with("CLASS")
+}
+
+/// Resolves `@` and names relative to `origin`.
+pub fn parse_name(name: &str, origin: &[DNSLabel]) -> io::Result<Vec<DNSLabel>> {
+    if name == "@" {
+        return Ok(origin.to_vec());
+    }
+    let mut labels =
+        labels_from_str(name).map_err(|_| invalid(format!("invalid name {:?}", name)))?;
+    if !name.ends_with('.') {
+        labels.extend_from_slice(origin);
+    }
+    if labels.iter().any(|label| label.0.is_empty()) {
+        return Err(invalid(format!("invalid name {:?}", name)));
+    }
+    Ok(labels)
+}
+
+/// Parses a TTL given in seconds or with BIND-style units, e.g. `1w2d3h4m5s`.
+pub fn parse_ttl(ttl: &str) -> io::Result<u32> {
+    if let Ok(seconds) = ttl.parse() {
+        return Ok(seconds);
+    }
+
+    let mut total: u64 = 0;
+    let mut value: Option<u64> = None;
+    for c in ttl.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = Some(value.unwrap_or(0) * 10 + digit as u64);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(invalid(format!("invalid TTL {:?}", ttl))),
+        };
+        total += value
+            .take()
+            .ok_or_else(|| invalid(format!("invalid TTL {:?}", ttl)))?
+            * unit;
+    }
+    if value.is_some() || total > u32::MAX as u64 {
+        return Err(invalid(format!("invalid TTL {:?}", ttl)));
+    }
+    Ok(total as u32)
+}
+
+// Parses the presentation format of RDATA
+fn parse_rdata(
+    rtype: ResourceRecordType,
+    tokens: &[Token],
+    origin: &[DNSLabel],
+) -> io::Result<DnsRecordData> {
+    if tokens
+        .first()
+        .is_some_and(|token| !token.quoted && token.text == "\\#")
+    {
+        return parse_generic_rdata(rtype, &tokens[1..]);
+    }
+
+    let field = |index: usize| -> io::Result<&str> {
+        tokens
+            .get(index)
+            .map(|token| token.text.as_str())
+            .ok_or_else(|| invalid(format!("{} record is missing fields", rtype)))
+    };
+    let name = |index: usize| -> io::Result<String> {
+        Ok(labels_to_string(&parse_name(field(index)?, origin)?))
+    };
+    let number = |index: usize| -> io::Result<u16> {
+        field(index)?
+            .parse()
+            .map_err(|_| invalid(format!("invalid number in {} record", rtype)))
+    };
+    let byte = |index: usize| -> io::Result<u8> {
+        field(index)?
+            .parse()
+            .map_err(|_| invalid(format!("invalid number in {} record", rtype)))
+    };
+    // Base64 and hex data may be split into several tokens
+    let joined = |from: usize| -> String {
+        tokens[from..]
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect()
+    };
+    let expect_fields = |count: usize| -> io::Result<()> {
+        if tokens.len() == count {
+            Ok(())
+        } else {
+            Err(invalid(format!("{} record takes {} fields", rtype, count)))
+        }
+    };
+
+    let rdata = match rtype {
+        ResourceRecordType::A => {
+            expect_fields(1)?;
+            DnsRecordData::A(
+                field(0)?
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| invalid("invalid IPv4 address"))?,
+            )
+        }
+        ResourceRecordType::AAAA => {
+            expect_fields(1)?;
+            DnsRecordData::AAAA(
+                field(0)?
+                    .parse::<Ipv6Addr>()
+                    .map_err(|_| invalid("invalid IPv6 address"))?,
+            )
+        }
+        ResourceRecordType::NS => {
+            expect_fields(1)?;
+            DnsRecordData::NS(name(0)?)
+        }
+        ResourceRecordType::CNAME => {
+            expect_fields(1)?;
+            DnsRecordData::CNAME(name(0)?)
+        }
+        ResourceRecordType::DNAME => {
+            expect_fields(1)?;
+            DnsRecordData::DNAME(name(0)?)
+        }
+        ResourceRecordType::PTR => {
+            expect_fields(1)?;
+            DnsRecordData::PTR(name(0)?)
+        }
+        ResourceRecordType::MD => {
+            expect_fields(1)?;
+            DnsRecordData::MD(name(0)?)
+        }
+        ResourceRecordType::MF => {
+            expect_fields(1)?;
+            DnsRecordData::MF(name(0)?)
+        }
+        ResourceRecordType::MB => {
+            expect_fields(1)?;
+            DnsRecordData::MB(name(0)?)
+        }
+        ResourceRecordType::MG => {
+            expect_fields(1)?;
+            DnsRecordData::MG(name(0)?)
+        }
+        ResourceRecordType::MR => {
+            expect_fields(1)?;
+            DnsRecordData::MR(name(0)?)
+        }
+        ResourceRecordType::MX => {
+            expect_fields(2)?;
+            DnsRecordData::MX(number(0)?, name(1)?)
+        }
+        ResourceRecordType::SOA => {
+            expect_fields(7)?;
+            DnsRecordData::SOA {
+                mname: name(0)?,
+                rname: name(1)?,
+                serial: field(2)?
+                    .parse()
+                    .map_err(|_| invalid("invalid SOA serial"))?,
+                refresh: parse_ttl(field(3)?)?,
+                retry: parse_ttl(field(4)?)?,
+                expire: parse_ttl(field(5)?)?,
+                minimum: parse_ttl(field(6)?)?,
+            }
+        }
+        ResourceRecordType::SRV => {
+            expect_fields(4)?;
+            DnsRecordData::SRV {
+                priority: number(0)?,
+                weight: number(1)?,
+                port: number(2)?,
+                target: name(3)?,
+            }
+        }
+        ResourceRecordType::TXT => {
+            if tokens.is_empty() {
+                return Err(invalid("TXT record needs at least one string"));
+            }
+            DnsRecordData::TXT(
+                tokens
+                    .iter()
+                    .map(|token| unescape(&token.text))
+                    .collect::<io::Result<_>>()?,
+            )
+        }
+        ResourceRecordType::SVCB | ResourceRecordType::HTTPS => {
+            // Relative target names are completed here, the rest is up to SvcbData
+            let mut text = vec![field(0)?.to_string(), format!("{}.", name(1)?)];
+            for token in &tokens[2..] {
+                if token.quoted {
+                    text.push(format!("\"{}\"", token.text));
+                } else {
+                    text.push(token.text.clone());
+                }
+            }
+            let data: SvcbData = text.join(" ").parse()?;
+            if rtype == ResourceRecordType::SVCB {
+                DnsRecordData::SVCB(data)
+            } else {
+                DnsRecordData::HTTPS(data)
+            }
+        }
+        ResourceRecordType::DS | ResourceRecordType::CDS => {
+            if tokens.len() < 4 {
+                return Err(invalid(format!("{} record is missing fields", rtype)));
+            }
+            let (key_tag, algorithm, digest_type) = (number(0)?, byte(1)?, byte(2)?);
+            let digest = parse_hex(&joined(3))?;
+            if rtype == ResourceRecordType::DS {
+                DnsRecordData::DS {
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                }
+            } else {
+                DnsRecordData::CDS {
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                }
+            }
+        }
+        ResourceRecordType::DNSKEY | ResourceRecordType::CDNSKEY => {
+            if tokens.len() < 4 {
+                return Err(invalid(format!("{} record is missing fields", rtype)));
+            }
+            let (flags, protocol, algorithm) = (number(0)?, byte(1)?, byte(2)?);
+            let public_key = BASE64
+                .decode(joined(3))
+                .map_err(|_| invalid(format!("invalid base64 in {} record", rtype)))?;
+            if rtype == ResourceRecordType::DNSKEY {
+                DnsRecordData::DNSKEY {
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                }
+            } else {
+                DnsRecordData::CDNSKEY {
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                }
+            }
+        }
+        ResourceRecordType::RRSIG => {
+            if tokens.len() < 9 {
+                return Err(invalid("RRSIG record is missing fields"));
+            }
+            DnsRecordData::RRSIG(RrsigData {
+                type_covered: field(0)?.parse()?,
+                algorithm: byte(1)?,
+                labels: byte(2)?,
+                original_ttl: parse_ttl(field(3)?)?,
+                expiration: parse_timestamp(field(4)?)?,
+                inception: parse_timestamp(field(5)?)?,
+                key_tag: number(6)?,
+                signer_name: name(7)?,
+                signature: BASE64
+                    .decode(joined(8))
+                    .map_err(|_| invalid("invalid base64 in RRSIG record"))?,
+            })
+        }
+        ResourceRecordType::NSEC => DnsRecordData::NSEC {
+            next_domain: name(0)?,
+            types: tokens[1..]
+                .iter()
+                .map(|token| token.text.parse())
+                .collect::<io::Result<_>>()?,
+        },
+        ResourceRecordType::NSEC3 => {
+            if tokens.len() < 5 {
+                return Err(invalid("NSEC3 record is missing fields"));
+            }
+            DnsRecordData::NSEC3 {
+                hash_algorithm: byte(0)?,
+                flags: byte(1)?,
+                iterations: number(2)?,
+                salt: parse_salt(field(3)?)?,
+                next_hashed: base32hex_decode(field(4)?)
+                    .ok_or_else(|| invalid("invalid next hashed owner name"))?,
+                types: tokens[5..]
+                    .iter()
+                    .map(|token| token.text.parse())
+                    .collect::<io::Result<_>>()?,
+            }
+        }
+        ResourceRecordType::NSEC3PARAM => {
+            expect_fields(4)?;
+            DnsRecordData::NSEC3PARAM {
+                hash_algorithm: byte(0)?,
+                flags: byte(1)?,
+                iterations: number(2)?,
+                salt: parse_salt(field(3)?)?,
+            }
+        }
+        rtype => {
+            return Err(invalid(format!(
+                "{} records are only supported in the \\# form",
+                rtype
+            )))
+        }
+    };
+    Ok(rdata)
+}
+
+// Parses the generic RDATA form of RFC 3597, section 5
+fn parse_generic_rdata(rtype: ResourceRecordType, tokens: &[Token]) -> io::Result<DnsRecordData> {
+    let length: usize = tokens
+        .first()
+        .and_then(|token| token.text.parse().ok())
+        .ok_or_else(|| invalid("missing RDATA length"))?;
+    let hex: String = tokens[1..]
+        .iter()
+        .map(|token| token.text.as_str())
+        .collect();
+    if hex.len() != length * 2 || length > u16::MAX as usize {
+        return Err(invalid("RDATA length does not match the data"));
+    }
+    let data = parse_hex(&hex)?;
+
+    let mut cursor = Cursor::new(&data[..]);
+    DnsRecordData::read(rtype, &mut cursor, data.len() as u16)
+}
+
+pub fn parse_hex(hex: &str) -> io::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(invalid("invalid hex data"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid("invalid hex data"))
+}
+
+// An NSEC3 salt in hex, or "-" for none
+fn parse_salt(salt: &str) -> io::Result<Vec<u8>> {
+    match salt {
+        "-" => Ok(vec![]),
+        salt => parse_hex(salt),
+    }
+}
+
+// Resolves "\c" and "\DDD" escapes of a character-string
+pub(crate) fn unescape(value: &str) -> io::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let digits: String = std::iter::once(d).chain(chars.by_ref().take(2)).collect();
+                let byte: u8 = digits
+                    .parse()
+                    .ok()
+                    .filter(|_| digits.len() == 3)
+                    .ok_or_else(|| invalid("invalid \\DDD escape"))?;
+                result.push(byte);
+            }
+            Some(c) => result.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            None => return Err(invalid("dangling escape")),
+        }
+    }
+    Ok(result)
+}
+
+// Escapes a character-string for the presentation format
+pub(crate) fn escape(value: &[u8]) -> String {
+    let mut result = String::new();
+    for &b in value {
+        match b {
+            b'"' | b'\\' => {
+                result.push('\\');
+                result.push(b as char);
+            }
+            0x21..=0x7E => result.push(b as char),
+            _ => result.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                2h 15m 1w 300 )
+        IN  NS  ns1
+        IN  NS  ns.other.net.
+ns1         A   192.0.2.53
+www     300 A   192.0.2.1
+            AAAA 2001:db8::1
+alias       CNAME www
+txt         TXT "hello world" second\;part
+*.wild      A   192.0.2.99
+sub         NS  ns.sub
+ns.sub      A   192.0.2.54
+old         DNAME example.net.
+a.b.c       A   192.0.2.3
+svc         HTTPS 1 . alpn="h2,h3" port=443
+pool        SVCB 0 svc
+raw         TYPE1234 \# 3 abcdef
+"#;
+
+    fn name(name: &str) -> Vec<DNSLabel> {
+        labels_from_str(name).unwrap()
+    }
+
+    #[test]
+    fn test_parse_master_file() {
+        let zone = Zone::parse(EXAMPLE, "example.com.").unwrap();
+
+        assert_eq!(zone.serial(), Some(2024010101));
+        assert_eq!(zone.soa().unwrap().ttl, 3600);
+        match &zone.soa().unwrap().rdata {
+            DnsRecordData::SOA {
+                mname,
+                refresh,
+                expire,
+                ..
+            } => {
+                assert_eq!(mname, "ns1.example.com");
+                assert_eq!(*refresh, 7200);
+                assert_eq!(*expire, 604800);
+            }
+            rdata => panic!("unexpected rdata {:?}", rdata),
+        }
+
+        let www = zone.node(&name("www.example.com")).unwrap();
+        assert_eq!(www.len(), 2);
+        // Records without a TTL get the $TTL default (RFC 2308), not the previous TTL
+        assert_eq!(www[0].ttl, 300);
+        assert_eq!(www[1].ttl, 3600);
+
+        assert_eq!(
+            zone.rrset(&name("txt.example.com"), ResourceRecordType::TXT)[0].rdata,
+            DnsRecordData::TXT(vec![b"hello world".to_vec(), b"second;part".to_vec()])
+        );
+        match &zone.rrset(&name("pool.example.com"), ResourceRecordType::SVCB)[0].rdata {
+            DnsRecordData::SVCB(data) => assert_eq!(data.target, "svc.example.com"),
+            rdata => panic!("unexpected rdata {:?}", rdata),
+        }
+        assert_eq!(
+            zone.rrset(&name("raw.example.com"), ResourceRecordType::Unknown(1234))[0].rdata,
+            DnsRecordData::Unknown(ResourceRecordType::Unknown(1234), vec![0xab, 0xcd, 0xef])
+        );
+    }
+
+    #[test]
+    fn test_master_file_round_trip() {
+        let zone = Zone::parse(EXAMPLE, "example.com.").unwrap();
+        let text = zone.to_master_file();
+        assert!(text.starts_with("example.com. 3600 IN SOA ns1.example.com. "));
+        assert!(text.contains("txt.example.com. 3600 IN TXT \"hello\\032world\" \"second;part\"\n"));
+        assert!(text.contains("raw.example.com. 3600 IN TYPE1234 \\# 3 ABCDEF\n"));
+
+        let reparsed = Zone::parse(&text, "example.com.").unwrap();
+        assert!(zone.records().eq(reparsed.records()));
+    }
+
+    #[test]
+    fn test_txt_keeps_bytes_above_0x7f() {
+        let bytes: Vec<u8> = (0x80..=0xFF).collect();
+        let rdata = DnsRecordData::TXT(vec![bytes.clone(), b"ascii".to_vec()]);
+
+        let mut wire = Vec::new();
+        rdata.write(&mut wire).unwrap();
+        assert_eq!(wire.len(), 1 + bytes.len() + 1 + 5);
+        assert_eq!(&wire[1..129], &bytes[..]);
+        let mut cursor = Cursor::new(&wire[..]);
+        let read = DnsRecordData::read(ResourceRecordType::TXT, &mut cursor, wire.len() as u16);
+        assert_eq!(read.unwrap(), rdata);
+
+        let text = format!(
+            "$TTL 60\n@ SOA ns hostmaster 1 2 3 4 5\ntxt TXT {}\n",
+            rdata
+        );
+        let zone = Zone::parse(&text, "example.com.").unwrap();
+        assert_eq!(
+            zone.rrset(&name("txt.example.com"), ResourceRecordType::TXT)[0].rdata,
+            rdata
+        );
+    }
+
+    #[test]
+    fn test_parse_errors_carry_line_numbers() {
+        let error =
+            Zone::parse("@ 1 IN SOA a b 1 2 3 4 5\nwww A 999.1.1.1\n", "example.").unwrap_err();
+        assert!(error.to_string().starts_with("line 2:"), "{}", error);
+        assert!(Zone::parse("www 1 A 192.0.2.1\n", "example.").is_err()); // no SOA
+        assert!(parse_ttl("1h30").is_err());
+        assert_eq!(parse_ttl("1h30m").unwrap(), 5400);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let zone = Zone::parse(EXAMPLE, "example.com.").unwrap();
+
+        assert!(matches!(
+            zone.lookup(&name("WWW.example.com"), ResourceRecordType::A),
+            Lookup::Answer(records) if records.len() == 1
+        ));
+        assert_eq!(
+            zone.lookup(&name("www.example.com"), ResourceRecordType::MX),
+            Lookup::NoData
+        );
+        assert!(matches!(
+            zone.lookup(&name("alias.example.com"), ResourceRecordType::A),
+            Lookup::Cname(_)
+        ));
+        assert_eq!(
+            zone.lookup(&name("nope.example.com"), ResourceRecordType::A),
+            Lookup::NxDomain
+        );
+        // Empty non-terminal
+        assert_eq!(
+            zone.lookup(&name("b.c.example.com"), ResourceRecordType::A),
+            Lookup::NoData
+        );
+        match zone.lookup(&name("x.y.wild.example.com"), ResourceRecordType::A) {
+            Lookup::Answer(records) => assert_eq!(records[0].name, name("x.y.wild.example.com")),
+            lookup => panic!("unexpected lookup {:?}", lookup),
+        }
+        match zone.lookup(&name("www.sub.example.com"), ResourceRecordType::A) {
+            Lookup::Referral { ns, glue } => {
+                assert_eq!(ns.len(), 1);
+                assert_eq!(glue.len(), 1);
+            }
+            lookup => panic!("unexpected lookup {:?}", lookup),
+        }
+        match zone.lookup(&name("a.old.example.com"), ResourceRecordType::A) {
+            Lookup::Dname { cname, .. } => {
+                assert_eq!(cname.rdata, DnsRecordData::CNAME("a.example.net".into()))
+            }
+            lookup => panic!("unexpected lookup {:?}", lookup),
+        }
+    }
+}